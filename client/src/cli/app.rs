@@ -1,45 +1,231 @@
+use std::collections::HashMap;
+use std::error::Error;
 use std::time::Duration;
 
-use anyhow::{ Context, Result };
-use crossterm::event::{Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use anyhow::{ anyhow, Context, Result };
+use crossterm::event::{Event, KeyCode, KeyEvent, KeyEventKind};
+use itertools::Itertools;
 use ratatui::prelude::Rect;
+use protocol::{shared, LinkStatus, Username};
+use protocol::field_lens::MESSAGE_ID_LEN;
 
+use crate::comms::Connection;
+use crate::delivery::DeliveryState;
+use crate::history::{self, HistoryEntry};
+use crate::identity::SafetyNumber;
+use crate::storage::{storage, conn_map};
 use super::term::Term;
 use super::root::Root;
 
-#[derive(Debug)]
+// longest to wait for a message from the server each time round the event loop
+const SERVER_POLL: Duration = Duration::from_millis(16);
+
+// how many notices from comms to hold on to, oldest dropped first
+const NOTICES_KEPT: usize = 100;
+
 pub struct App {
     term: Term,
     should_quit: bool,
     context: AppContext,
+
+    // verified connection to the server
+    connection: Connection,
 }
 
-#[derive(Debug, Default, Clone, Copy)]
+#[derive(Debug, Default, Clone)]
 pub struct AppContext {
     pub tab_index: usize,
+
+    // selected message of the open conversation
     pub row_index: usize,
+
+    // our connections, and which of them we have the conversation with open
+    pub conversations: Vec<Username>,
+    pub conversation_index: usize,
+
+    // the open conversation as it reads now (see storage::read_history)
+    pub history: Vec<HistoryEntry>,
+
+    // how far each of our messages in it has got (see comms::Connection::delivery_state)
+    pub deliveries: HashMap<[u8; MESSAGE_ID_LEN], DeliveryState>,
+
+    // health of, and round-trip time over, the link to the server (see comms::Connection)
+    pub link_status: Option<LinkStatus>,
+    pub latency: Option<Duration>,
+
+    // connections typing to us in the open conversation (see comms::Connection::typing_users)
+    pub typing: Vec<Username>,
+
+    // whether the safety number for the open conversation is shown over it, and
+    // the number, once we have the other user's identity key (see cli::safety)
+    pub showing_safety: bool,
+    pub safety_number: Option<SafetyNumber>,
+
+    // what comms has told us, oldest first (see comms::Connection::take_notices)
+    pub notices: Vec<String>,
+
+    // the message being written to the open conversation, while there is one
+    pub compose: Option<String>,
+}
+
+impl AppContext {
+    pub fn open_conversation(&self) -> Option<&Username> {
+        self.conversations.get(self.conversation_index)
+    }
+
+    /**
+    Picks up how far each of our messages in the open conversation has got
+    */
+    pub fn update_deliveries(&mut self, connection: &Connection) {
+        self.deliveries = self.history
+            .iter()
+            .filter_map(|entry| {
+                let message_id = entry.message.message_id;
+                Some((message_id, connection.delivery_state(&message_id)?))
+            })
+            .collect();
+    }
+
+    /**
+    Lets the senders of the messages shown in the open conversation, up to and
+    including the selected one, know we've read them (see
+    comms::Connection::mark_read for which are sent a receipt)
+    */
+    pub fn mark_shown_read(&self, connection: &mut Connection) -> Result<(), Box<dyn Error>> {
+        let Some(open) = self.open_conversation() else {
+            return Ok(());
+        };
+        let shown = self.history.iter().take(self.row_index + 1);
+        for entry in shown.filter(|entry| entry.message.send_uname == *open) {
+            connection.mark_read(&entry.message)?;
+        }
+        Ok(())
+    }
+
+    fn add_notices(&mut self, notices: Vec<String>) {
+        self.notices.extend(notices);
+        let excess = self.notices.len().saturating_sub(NOTICES_KEPT);
+        self.notices.drain(..excess);
+    }
 }
 
 impl App {
-    fn new() -> Result<Self> {
-        Ok(Self {
+    fn new(connection: Connection) -> Result<Self> {
+        let mut app = Self {
             term: Term::start()?,
             should_quit: false,
             context: AppContext::default(),
-        })
+            connection,
+        };
+        app.refresh()?;
+        Ok(app)
     }
 
-    pub fn run() -> Result<()> {
+    /**
+    Runs the UI over a connection we've already verified on, until the user
+    quits or the connection fails
+    */
+    pub fn run(connection: Connection) -> Result<()> {
         install_panic_hook();
-        let mut app = Self::new()?;
+        let mut app = Self::new(connection)?;
         while !app.should_quit {
             app.draw()?;
             app.handle_events()?;
+            app.handle_server()?;
         }
         Term::stop()?;
         Ok(())
     }
 
+    /**
+    Handles whatever the server has sent, pings it if due, and picks up the
+    state of the link
+    */
+    fn handle_server(&mut self) -> Result<()> {
+        self.connection.tick().map_err(comms_error)?;
+        let mut handled = false;
+        while let Some(notices) = self.connection.poll_message(SERVER_POLL).map_err(comms_error)? {
+            self.context.add_notices(notices);
+            handled = true;
+        }
+        if handled {
+            self.refresh()?;
+        }
+        // along with anything our own requests raised
+        self.context.add_notices(self.connection.take_notices());
+
+        let open = self.context.open_conversation().cloned();
+        self.context.typing = self.connection
+            .typing_users()
+            .into_iter()
+            .filter(|uname| Some(uname) == open.as_ref())
+            .collect();
+        self.context.link_status = self.connection.link_status();
+        self.context.latency = self.connection.latency();
+        Ok(())
+    }
+
+    /**
+    Reloads our connections and the open conversation from storage. The
+    selected message stays selected, unless it was the latest, in which case
+    the selection moves on to anything new, and is marked read along with
+    everything before it.
+    */
+    fn refresh(&mut self) -> Result<()> {
+        let context = &mut self.context;
+        let open = context.open_conversation().cloned();
+        context.conversations = conn_map::get_map()
+            .keys()
+            .filter_map(|uname| Username::new(uname).ok())
+            .sorted()
+            .collect();
+        // the same conversation stays open, even if a new connection is listed above it
+        context.conversation_index = open
+            .and_then(|open| context.conversations.iter().position(|uname| *uname == open))
+            .unwrap_or(0);
+
+        let at_latest = context.row_index + 1 >= context.history.len();
+        context.history = context.open_conversation()
+            .map(storage::read_history)
+            .transpose()?
+            .unwrap_or_default();
+        if at_latest || context.row_index >= context.history.len() {
+            context.row_index = context.history.len().saturating_sub(1);
+        }
+
+        context.update_deliveries(&self.connection);
+        context.mark_shown_read(&mut self.connection).map_err(comms_error)?;
+
+        context.safety_number = match context.open_conversation() {
+            Some(open) if context.showing_safety => self.connection.safety_number(open).map_err(comms_error)?,
+            _ => None,
+        };
+        Ok(())
+    }
+
+    // Opens the conversation with the connection at index, at its latest message
+    fn switch_conversation(&mut self, index: usize) -> Result<()> {
+        self.context.conversation_index = index;
+        self.context.history.clear();
+        self.refresh()
+    }
+
+    /**
+    Shows or hides the safety number for the open conversation. If we don't
+    have the other user's identity key yet, asks the server for it; the number
+    is shown once it arrives.
+    */
+    fn toggle_safety_number(&mut self) -> Result<()> {
+        self.context.showing_safety = !self.context.showing_safety;
+        self.refresh()?;
+        if let Some(open) = self.context.open_conversation() {
+            if self.context.showing_safety && self.context.safety_number.is_none() {
+                self.connection.request_identity_key(open).map_err(comms_error)?;
+            }
+        }
+        Ok(())
+    }
+
     fn draw(&mut self) -> Result<()> {
         self.term
             .draw(|frame| frame.render_widget(Root::new(&self.context), frame.size()))
@@ -62,31 +248,138 @@ impl App {
             return Ok(());
         }
 
+        if self.context.compose.is_some() {
+            return self.handle_compose_key(key);
+        }
+
         let context = &mut self.context;
-        const TAB_COUNT: usize = 1;
         match key.code {
+            KeyCode::Char('i') | KeyCode::Enter if context.open_conversation().is_some() => {
+                context.compose = Some(String::new());
+            }
             KeyCode::Char('q') | KeyCode::Esc => {
                 self.should_quit = true;
             }
-            KeyCode::Tab | KeyCode::BackTab if key.modifiers.contains(KeyModifiers::SHIFT) => {
-                let tab_index = context.tab_index + TAB_COUNT; // to wrap around properly
-                context.tab_index = tab_index.saturating_sub(1) % TAB_COUNT;
-                context.row_index = 0;
-            }
-            KeyCode::Tab | KeyCode::BackTab => {
-                context.tab_index = context.tab_index.saturating_add(1) % TAB_COUNT;
-                context.row_index = 0;
-            }
             KeyCode::Up | KeyCode::Char('k') => {
                 context.row_index = context.row_index.saturating_sub(1);
             }
             KeyCode::Down | KeyCode::Char('j') => {
-                context.row_index = context.row_index.saturating_add(1);
+                context.row_index = (context.row_index + 1).min(context.history.len().saturating_sub(1));
+            }
+            KeyCode::Char('p') => {
+                // jumps from a reply to the message it answers, if we have it
+                let parent = context.history
+                    .get(context.row_index)
+                    .and_then(|entry| history::position(&context.history, entry.message.in_reply_to?));
+                if let Some(index) = parent {
+                    context.row_index = index;
+                }
+            }
+            KeyCode::Char('s') => {
+                self.toggle_safety_number()?;
+            }
+            KeyCode::Left | KeyCode::Char('h') if !context.conversations.is_empty() => {
+                let count = context.conversations.len();
+                let index = (context.conversation_index + count - 1) % count;
+                self.switch_conversation(index)?;
+            }
+            KeyCode::Right | KeyCode::Char('l') if !context.conversations.is_empty() => {
+                let index = (context.conversation_index + 1) % context.conversations.len();
+                self.switch_conversation(index)?;
             }
             _ => {}
         };
+        // moving the selection down shows more of the conversation
+        self.context.mark_shown_read(&mut self.connection).map_err(comms_error)
+    }
+
+    /**
+    Handles a key while writing a message to the open conversation: the other
+    user is told we're typing as it's written, and that we've stopped if it's
+    cleared or abandoned. Enter sends it, or runs it if it's a command (see
+    run_command); Esc abandons it.
+    */
+    fn handle_compose_key(&mut self, key: KeyEvent) -> Result<()> {
+        let (Some(open), Some(compose)) = (self.context.open_conversation().cloned(), self.context.compose.as_mut()) else {
+            self.context.compose = None;
+            return Ok(());
+        };
+
+        match key.code {
+            KeyCode::Esc => {
+                self.context.compose = None;
+                self.connection.stopped_typing(&open).map_err(comms_error)?;
+            }
+            KeyCode::Enter => {
+                let text = compose.clone();
+                self.context.compose = None;
+                if text.is_empty() {
+                    self.connection.stopped_typing(&open).map_err(comms_error)?;
+                    return Ok(());
+                }
+                let result = match text.strip_prefix('/') {
+                    Some(command) => self.run_command(&open, command),
+                    None => self.connection.send_chat_message(&open, &text).map(|_| ()),
+                };
+                if let Err(e) = result {
+                    self.context.add_notices(vec![format!("Couldn't {}: {}", text, e)]);
+                }
+                self.refresh()?;
+            }
+            KeyCode::Backspace => {
+                compose.pop();
+                if compose.is_empty() {
+                    self.connection.stopped_typing(&open).map_err(comms_error)?;
+                }
+            }
+            KeyCode::Char(c) => {
+                compose.push(c);
+                // commands aren't for the other user to see coming
+                if !compose.starts_with('/') {
+                    self.connection.typing(&open).map_err(comms_error)?;
+                }
+            }
+            _ => {}
+        }
         Ok(())
     }
+
+    /**
+    Runs a command written in place of a message to the open conversation,
+    e.g. "/react 👍" to react to the selected message, or "/edit <text>" to
+    change it (if it's one of ours). The account's tokens are managed the same
+    way: "/rotate" (or "/rotate keep", to issue one for another device),
+    "/tokens" and "/revoke <token id>".
+    */
+    fn run_command(&mut self, open: &Username, command: &str) -> Result<(), Box<dyn Error>> {
+        let (name, arg) = command.split_once(' ').unwrap_or((command, ""));
+        match (name, arg) {
+            ("rotate", "") => return self.connection.rotate_token(false),
+            ("rotate", "keep") => return self.connection.rotate_token(true),
+            ("rotate", _) => return Err("usage: /rotate [keep]".into()),
+            ("tokens", _) => return self.connection.list_tokens(),
+            ("revoke", _) => {
+                let token_id = shared::token_id_from_string(arg).ok_or("usage: /revoke <token id>")?;
+                return self.connection.revoke_token(token_id);
+            }
+            _ => {}
+        }
+
+        let selected = self.context.history.get(self.context.row_index).map(|entry| entry.message.message_id);
+        match (name, selected) {
+            ("react", Some(message_id)) => self.connection.add_reaction(open, message_id, arg),
+            ("unreact", Some(message_id)) => self.connection.remove_reaction(open, message_id, arg),
+            ("edit", Some(message_id)) => self.connection.edit_message(open, message_id, arg),
+            ("delete", Some(message_id)) => self.connection.delete_message(open, message_id),
+            ("react" | "unreact" | "edit" | "delete", None) => Err("no message selected".into()),
+            _ => Err(format!("no command /{}", name).into()),
+        }
+    }
+}
+
+// comms errors aren't Send, so can't be wrapped by anyhow as they are
+fn comms_error(e: Box<dyn Error>) -> anyhow::Error {
+    anyhow!("{}", e)
 }
 
 pub fn install_panic_hook() {
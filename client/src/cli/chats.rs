@@ -0,0 +1,154 @@
+/*
+The conversation view: our connections down the side, and the open
+conversation as it reads now (see history.rs), with who's typing to us and the
+message we're writing under it.
+Replies quote the message they answer, our messages have ticks showing how far
+they've got, and reactions are counted under the messages they're to.
+*/
+
+use ratatui::{prelude::*, widgets::*};
+
+use crate::delivery::DeliveryState;
+use crate::history::{self, HistoryEntry};
+use crate::typing;
+use super::app::AppContext;
+use super::theme::THEME;
+use super::root::layout;
+
+// width of the list of connections
+const CONVERSATIONS_WIDTH: u16 = 16;
+
+pub struct ChatsTab<'a> {
+    context: &'a AppContext,
+}
+
+impl<'a> ChatsTab<'a> {
+    pub fn new(context: &'a AppContext) -> Self {
+        ChatsTab { context }
+    }
+}
+
+impl Widget for ChatsTab<'_> {
+    fn render(self, area: Rect, buf: &mut Buffer) {
+        let area = area.inner(&Margin {
+            vertical: 1,
+            horizontal: 1,
+        });
+        Clear.render(area, buf);
+        let area = layout(area, Direction::Horizontal, vec![CONVERSATIONS_WIDTH, 0]);
+        self.render_conversations(area[0], buf);
+        self.render_conversation(area[1], buf);
+    }
+}
+
+impl ChatsTab<'_> {
+    fn render_conversations(&self, area: Rect, buf: &mut Buffer) {
+        let theme = THEME.chats;
+        let conversations = &self.context.conversations;
+        if conversations.is_empty() {
+            Paragraph::new("No connections yet").wrap(Wrap { trim: true }).render(area, buf);
+            return;
+        }
+
+        let items: Vec<ListItem> = conversations.iter().map(|uname| ListItem::new(uname.as_str())).collect();
+        let mut state = ListState::default().with_selected(Some(self.context.conversation_index));
+        StatefulWidget::render(
+            List::new(items)
+                .highlight_style(theme.selected_item)
+                .highlight_symbol(">>"),
+            area,
+            buf,
+            &mut state,
+        );
+    }
+
+    fn render_conversation(&self, area: Rect, buf: &mut Buffer) {
+        let theme = THEME.chats;
+        let block = Block::new()
+            .padding(Padding::new(1, 1, 0, 0))
+            .borders(Borders::LEFT)
+            .border_type(BorderType::Thick);
+        let inner = block.inner(area);
+        block.render(area, buf);
+
+        let area = layout(inner, Direction::Vertical, vec![0, 1, 1]);
+        let history = &self.context.history;
+        if history.is_empty() {
+            Paragraph::new("No messages yet").render(area[0], buf);
+        } else {
+            let items: Vec<ListItem> = history
+                .iter()
+                .map(|entry| {
+                    let delivery = self.context.deliveries.get(&entry.message.message_id);
+                    ListItem::new(message_lines(history, entry, delivery))
+                }).collect();
+            let mut state = ListState::default().with_selected(Some(self.context.row_index));
+            StatefulWidget::render(
+                List::new(items).highlight_style(theme.selected_item),
+                area[0],
+                buf,
+                &mut state,
+            );
+        }
+
+        if let Some(label) = typing::typing_label(&self.context.typing) {
+            Paragraph::new(Span::styled(label, theme.typing)).render(area[1], buf);
+        }
+        if let Some(compose) = &self.context.compose {
+            Paragraph::new(Line::from(vec![
+                Span::styled("> ", theme.header),
+                Span::styled(format!("{}█", compose), theme.compose),
+            ])).render(area[2], buf);
+        }
+    }
+}
+
+// A message as shown in the conversation, e.g. "Harry: hello (edited)", with
+// the message it replies to quoted above it, ticks after it if it's one of ours,
+// and how it's been reacted to under it
+fn message_lines(history: &[HistoryEntry], entry: &HistoryEntry, delivery: Option<&DeliveryState>) -> Vec<Line<'static>> {
+    let theme = THEME.chats;
+    let mut lines = Vec::new();
+    if entry.message.in_reply_to.is_some() {
+        let quote = history::quote_preview(history::parent(history, entry));
+        lines.push(Line::styled(format!("┃ {}", quote), theme.quote));
+    }
+
+    let sender = Span::styled(format!("{}: ", entry.message.send_uname), theme.header);
+    let Some(text) = entry.text() else {
+        lines.push(Line::from(vec![sender, Span::styled("message deleted", theme.quote)]));
+        return lines;
+    };
+
+    let first = lines.len();
+    lines.extend(text.lines().map(|line| Line::from(line.to_string())));
+    if lines.len() == first {
+        lines.push(Line::default());
+    }
+    lines[first].spans.insert(0, sender);
+    if entry.is_edited() {
+        if let Some(last) = lines.last_mut() {
+            last.spans.push(Span::styled(" (edited)", theme.quote));
+        }
+    }
+    if let (Some(state), Some(last)) = (delivery, lines.last_mut()) {
+        if !state.ticks().is_empty() {
+            last.spans.push(Span::styled(format!(" {}", state.ticks()), ticks_style(*state)));
+        }
+    }
+    if let Some(label) = history::reaction_label(&entry.reaction_counts()) {
+        lines.push(Line::styled(label, theme.reactions));
+    }
+
+    lines
+}
+
+fn ticks_style(state: DeliveryState) -> Style {
+    let theme = THEME.receipts;
+    match state {
+        DeliveryState::Pending | DeliveryState::Sent => theme.sent,
+        DeliveryState::Delivered => theme.delivered,
+        DeliveryState::Read => theme.read,
+        DeliveryState::Failed => theme.failed,
+    }
+}
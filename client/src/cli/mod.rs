@@ -5,4 +5,5 @@ pub mod app;
 pub mod root;
 pub mod term;
 pub mod theme;
-pub mod email;
\ No newline at end of file
+pub mod chats;
+pub mod safety;
\ No newline at end of file
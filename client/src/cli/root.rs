@@ -1,11 +1,20 @@
 use std::rc::Rc;
 
 use itertools::Itertools;
+use protocol::LinkStatus;
 use ratatui::{prelude::*, widgets::*};
 
 use super::app::AppContext;
 use super::theme::THEME;
-use super::email::EmailTab;
+use super::chats::ChatsTab;
+use super::safety::SafetyNumberView;
+
+// size of the safety number view: room for a key change warning, the number and both fingerprints
+const SAFETY_WIDTH: u16 = 72;
+const SAFETY_HEIGHT: u16 = 13;
+
+// how many of the latest notices from comms are shown above the key bindings
+const NOTICE_LINES: u16 = 2;
 
 pub struct Root<'a> {
     context: &'a super::app::AppContext,
@@ -20,42 +29,106 @@ impl<'a> Root<'a> {
 impl Widget for Root<'_> {
     fn render(self, area: Rect, buf: &mut Buffer) {
         // Block::new().style(THEME.root).render(area, buf);
-        let area = layout(area, Direction::Vertical, vec![1, 0, 1]);
+        let area = layout(area, Direction::Vertical, vec![1, 0, NOTICE_LINES, 1]);
         self.render_title_bar(area[0], buf);
         self.render_selected_tab(area[1], buf);
-        self.render_bottom_bar(area[2], buf);
+        self.render_notices(area[2], buf);
+        self.render_bottom_bar(area[3], buf);
     }
 }
 
 impl Root<'_> {
     fn render_title_bar(&self, area: Rect, buf: &mut Buffer) {
-        let area = layout(area, Direction::Horizontal, vec![0, 45]);
+        let area = layout(area, Direction::Horizontal, vec![0, 14, 45]);
 
-        Paragraph::new(Span::styled("Ratatui", THEME.app_title)).render(area[0], buf);
-        let titles = vec!["", " Recipe ", " Email ", " Traceroute ", " Weather "];
+        Paragraph::new(Span::styled("cli_chat", THEME.app_title)).render(area[0], buf);
+        self.render_link_status(area[1], buf);
+        let titles = vec![" Chats "];
         Tabs::new(titles)
             // .style(THEME.tabs)
             // .highlight_style(THEME.tabs_selected)
             .select(self.context.tab_index)
             .divider("")
-            .render(area[1], buf);
+            .render(area[2], buf);
+    }
+
+    // e.g. "● 42ms", nothing if the server doesn't do heartbeats
+    fn render_link_status(&self, area: Rect, buf: &mut Buffer) {
+        let Some(link_status) = self.context.link_status else {
+            return;
+        };
+
+        let (label, style) = match link_status {
+            LinkStatus::Healthy => {
+                let latency = self.context.latency
+                    .map_or("--".to_string(), |latency| format!("{}ms", latency.as_millis()));
+                (latency, THEME.link.healthy)
+            }
+            LinkStatus::Degraded => ("degraded".to_string(), THEME.link.degraded),
+            LinkStatus::Dead => ("offline".to_string(), THEME.link.dead),
+        };
+        Paragraph::new(Span::styled(format!("● {}", label), style))
+            .alignment(Alignment::Right)
+            .render(area, buf);
     }
 
     fn render_selected_tab(&self, area: Rect, buf: &mut Buffer) {
-        let row_index = self.context.row_index;
         match self.context.tab_index {
-            0 => EmailTab::new(row_index).render(area, buf),
+            0 => ChatsTab::new(self.context).render(area, buf),
             _ => unreachable!(),
         };
+        if self.context.showing_safety {
+            self.render_safety_number(area, buf);
+        }
+    }
+
+    // the safety number for the open conversation, over the middle of it
+    fn render_safety_number(&self, area: Rect, buf: &mut Buffer) {
+        let Some(open) = self.context.open_conversation() else {
+            return;
+        };
+        let area = centered(area, SAFETY_WIDTH, SAFETY_HEIGHT);
+        match &self.context.safety_number {
+            Some(safety_number) => SafetyNumberView::new(safety_number).render(area, buf),
+            None => {
+                let block = Block::new()
+                    .title(format!(" Safety number with {} ", open))
+                    .borders(Borders::ALL)
+                    .border_type(BorderType::Rounded)
+                    .padding(Padding::new(2, 2, 1, 0));
+                Clear.render(area, buf);
+                Paragraph::new(Span::styled(format!("Fetching {}'s identity key...", open), THEME.safety.label))
+                    .block(block)
+                    .render(area, buf);
+            }
+        }
+    }
+
+    // the latest of what comms has told us, oldest first
+    fn render_notices(&self, area: Rect, buf: &mut Buffer) {
+        let notices = &self.context.notices;
+        let latest = notices.len().saturating_sub(NOTICE_LINES as usize);
+        let lines = notices[latest..]
+            .iter()
+            .map(|notice| Line::styled(notice.as_str(), THEME.notices))
+            .collect_vec();
+        Paragraph::new(lines).render(area.inner(&Margin { vertical: 0, horizontal: 1 }), buf);
     }
 
     fn render_bottom_bar(&self, area: Rect, buf: &mut Buffer) {
-        let keys = [
-            ("↑/k", "Up"),
-            ("↓/j", "Down"),
-            ("Tab", "Next"),
-            ("Q", "Quit"),
-        ];
+        let keys: &[(&str, &str)] = if self.context.compose.is_some() {
+            &[("Enter", "Send"), ("Esc", "Cancel")]
+        } else {
+            &[
+                ("↑/k", "Up"),
+                ("↓/j", "Down"),
+                ("←/→", "Chat"),
+                ("I", "Write"),
+                ("P", "Parent"),
+                ("S", "Safety"),
+                ("Q", "Quit"),
+            ]
+        };
         let spans = keys
             .iter()
             .flat_map(|(key, desc)| {
@@ -89,3 +162,15 @@ pub fn layout(area: Rect, direction: Direction, heights: Vec<u16>) -> Rc<[Rect]>
         .constraints(constraints)
         .split(area)
 }
+
+// An area of the given size in the middle of area (or all of it, if it's smaller)
+fn centered(area: Rect, width: u16, height: u16) -> Rect {
+    let width = width.min(area.width);
+    let height = height.min(area.height);
+    Rect {
+        x: area.x + (area.width - width) / 2,
+        y: area.y + (area.height - height) / 2,
+        width,
+        height,
+    }
+}
@@ -0,0 +1,61 @@
+use itertools::Itertools;
+use ratatui::{prelude::*, widgets::*};
+
+use crate::identity::SafetyNumber;
+use super::theme::THEME;
+
+/**
+Shows the safety number for a conversation (see comms::Connection::safety_number),
+laid out so two users can read it to each other, along with both fingerprints
+*/
+pub struct SafetyNumberView<'a> {
+    safety_number: &'a SafetyNumber,
+}
+
+impl<'a> SafetyNumberView<'a> {
+    pub fn new(safety_number: &'a SafetyNumber) -> Self {
+        SafetyNumberView { safety_number }
+    }
+}
+
+impl Widget for SafetyNumberView<'_> {
+    fn render(self, area: Rect, buf: &mut Buffer) {
+        let theme = THEME.safety;
+        let safety_number = self.safety_number;
+        let block = Block::new()
+            .title(format!(" Safety number with {} ", safety_number.contact))
+            .borders(Borders::ALL)
+            .border_type(BorderType::Rounded)
+            .padding(Padding::new(2, 2, 1, 0));
+        let inner = block.inner(area);
+        Clear.render(area, buf);
+        block.render(area, buf);
+
+        let mut lines = Vec::new();
+        if safety_number.key_changed {
+            lines.push(Line::styled(
+                format!("{}'s identity key has changed. Check this number with them before trusting it.", safety_number.contact),
+                theme.warning,
+            ));
+            lines.push(Line::default());
+        }
+
+        // three rows of four groups, as most people read them aloud
+        let groups = safety_number.safety_number.split(' ').collect_vec();
+        for row in groups.chunks(4) {
+            lines.push(Line::styled(row.join("  "), theme.number));
+        }
+        lines.push(Line::default());
+
+        lines.push(Line::from(vec![
+            Span::styled("You:  ", theme.label),
+            Span::styled(safety_number.own_fingerprint.as_str(), theme.fingerprint),
+        ]));
+        lines.push(Line::from(vec![
+            Span::styled(format!("{}:  ", safety_number.contact), theme.label),
+            Span::styled(safety_number.contact_fingerprint.as_str(), theme.fingerprint),
+        ]));
+
+        Paragraph::new(lines).wrap(Wrap { trim: true }).render(inner, buf);
+    }
+}
@@ -9,9 +9,13 @@ pub struct Theme {
     pub borders: Style,
     pub description: Style,
     pub description_title: Style,
+    pub notices: Style,
     pub key_binding: KeyBinding,
     pub logo: Logo,
-    pub email: Email,
+    pub chats: Chats,
+    pub receipts: Receipts,
+    pub link: Link,
+    pub safety: Safety,
     pub traceroute: Traceroute,
     pub recipe: Recipe,
 }
@@ -28,7 +32,8 @@ pub struct Logo {
     pub term: Color,
 }
 
-pub struct Email {
+// the conversation view (see chats.rs)
+pub struct Chats {
     pub tabs: Style,
     pub tabs_selected: Style,
     pub inbox: Style,
@@ -37,6 +42,33 @@ pub struct Email {
     pub header: Style,
     pub header_value: Style,
     pub body: Style,
+    pub typing: Style,
+    pub reactions: Style,
+    pub quote: Style,
+    pub compose: Style,
+}
+
+// delivery ticks next to sent messages (see delivery.rs)
+pub struct Receipts {
+    pub sent: Style,
+    pub delivered: Style,
+    pub read: Style,
+    pub failed: Style,
+}
+
+// link status indicator in the title bar
+pub struct Link {
+    pub healthy: Style,
+    pub degraded: Style,
+    pub dead: Style,
+}
+
+// safety number view (see safety.rs)
+pub struct Safety {
+    pub label: Style,
+    pub fingerprint: Style,
+    pub number: Style,
+    pub warning: Style,
 }
 
 pub struct Traceroute {
@@ -76,6 +108,7 @@ pub const THEME: Theme = Theme {
     borders: Style::new().fg(LIGHT_GRAY),
     description: Style::new().fg(LIGHT_GRAY).bg(DARK_BLUE),
     description_title: Style::new().fg(LIGHT_GRAY).add_modifier(Modifier::BOLD),
+    notices: Style::new().fg(MID_GRAY),
     logo: Logo {
         rat: WHITE,
         rat_eye: BLACK,
@@ -86,7 +119,7 @@ pub const THEME: Theme = Theme {
         key: Style::new().fg(BLACK).bg(DARK_GRAY),
         description: Style::new().fg(DARK_GRAY).bg(BLACK),
     },
-    email: Email {
+    chats: Chats {
         tabs: Style::new().fg(MID_GRAY).bg(DARK_BLUE),
         tabs_selected: Style::new()
             .fg(WHITE)
@@ -98,6 +131,27 @@ pub const THEME: Theme = Theme {
         header: Style::new().add_modifier(Modifier::BOLD),
         header_value: Style::new().fg(LIGHT_GRAY),
         body: Style::new().bg(DARK_BLUE).fg(LIGHT_GRAY),
+        typing: Style::new().fg(MID_GRAY).add_modifier(Modifier::ITALIC),
+        reactions: Style::new().fg(LIGHT_YELLOW),
+        quote: Style::new().fg(MID_GRAY),
+        compose: Style::new().fg(WHITE),
+    },
+    receipts: Receipts {
+        sent: Style::new().fg(MID_GRAY),
+        delivered: Style::new().fg(LIGHT_GRAY),
+        read: Style::new().fg(LIGHT_BLUE).add_modifier(Modifier::BOLD),
+        failed: Style::new().fg(LIGHT_RED),
+    },
+    link: Link {
+        healthy: Style::new().fg(LIGHT_GREEN),
+        degraded: Style::new().fg(LIGHT_YELLOW),
+        dead: Style::new().fg(LIGHT_RED).add_modifier(Modifier::BOLD),
+    },
+    safety: Safety {
+        label: Style::new().add_modifier(Modifier::BOLD),
+        fingerprint: Style::new().fg(LIGHT_GRAY),
+        number: Style::new().fg(WHITE).add_modifier(Modifier::BOLD),
+        warning: Style::new().fg(LIGHT_RED).add_modifier(Modifier::BOLD),
     },
     traceroute: Traceroute {
         header: Style::new()
@@ -1,68 +1,1822 @@
-/**
+/*
 Module - comms
 
 Handles all communication with the server.
 
+Messages too large for a single packet are split into Fragments on the way out
+and reassembled on the way in, if the server supports it.
+
+The connection is plain TCP or TLS, depending on the TransportConfig.
+
+The server never sees our PAT token after signup: to verify ourselves we answer
+the AuthChallenge it sends when we connect with a proof derived from the token
+(see protocol::auth). The token can be rotated (see rotate_token), which
+replaces .cli_chat/token, and other tokens on the account listed and revoked.
+
+If the server supports heartbeats, the connection pings it every so often (see
+Connection::tick) to measure latency and notice when the link degrades.
+
+Chat messages are end-to-end encrypted (see crypto.rs): connection requests and
+responses carry the public keys each side needs to agree a conversation key,
+and chat message bodies only leave the client as ciphertext. Each public key is
+signed with its sender's identity key, and only used once the signature checks
+out against the identity key we trust for them, so the server can't put its
+own keys in the middle.
+
+Chat messages are also signed with our identity key. Once connected with a
+user, we fetch their identity key from the server and only accept messages
+signed with the key we trust for them (see identity.rs).
+
+Group messages are signed and encrypted in the same way, under a group key.
+We generate the key for groups we create, and pass it on, sealed under our
+conversation key, to each connection we add to a group.
+
+If the server supports presence, it tells us each connection's presence (online,
+away or offline, plus an optional status) when we verify and whenever it
+changes. The latest is kept in storage::presence_map, next to conn_map.
+
+If the server supports typing indicators, we tell a connection when we start
+and stop typing to them, and keep track of who is typing to us (see typing.rs).
+
+Files can be sent to connections if the server supports file transfer (see
+transfer.rs). Once verified, we pick up any transfers a previous connection
+left unfinished: offering our outgoing files again, and asking for the rest of
+each incoming one.
+
+If the server supports edits, we can edit or delete messages we've sent to a
+connection, and apply their edits and deletions of messages they've sent us.
+Neither rewrites the conversation: both are stored alongside it, and only
+applied when it's read (see history.rs). Reactions to messages, if the server
+supports them, are signed and kept in the same way.
+
+If the server supports an inbox, messages sent to us while we were offline are
+waiting for us when we verify. We acknowledge every chat message once it's
+stored, so the server can delete its copy; one we already have (e.g. sent again
+because our last acknowledgement didn't get through) is just acknowledged again.
+
+If the server supports history, our conversation with a connection can be
+fetched from it (see sync_history), a batch at a time, from just after the
+last message we have. Fetched messages are checked and decrypted like any
+other and merged into storage, so a new machine can rebuild its connections;
+though only messages under conversation keys we still have can be read.
+
 See 'protocol' crate for explanation of the cli_chat protocol
 */
 
-use std::io::{Read, Write};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::env;
 use std::net::TcpStream;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use std::error::Error;
 
-use protocol::{Packet, ChatMessage, SignupReq, SignupResp, VerifyReq, VerifyResp, C2cConnReq, C2cConnResp};
-use protocol::{self, field_lens, message_types, errors, shared, status_codes};
-use message_types::{MessageType, method_num_to_message_type};
+use protocol::{Packet, PacketReader, PacketWriter, Message, ProtocolMessage, ProtocolError};
+use protocol::{ChatMessage, VerifyReq, VerifyResp, SignupResp, C2cConnReq, C2cConnResp, ConnResponse};
+use protocol::{Hello, Capabilities, NegotiatedSession, Receipt, ReceiptKind, Username};
+use protocol::{SignupReq, IdentityKeyPair, IdentityReq, IdentityResp};
+use protocol::{auth, AuthChallenge};
+use protocol::{Presence, PresenceState, PresenceUpdate};
+use protocol::Typing;
+use protocol::{FileOffer, FileAccept, FileChunk, FileComplete};
+use protocol::{MessageEdit, MessageDelete};
+use protocol::{Reaction, ReactionAction};
+use protocol::InboxAck;
+use protocol::{HistoryCursor, HistoryReq, HistoryResp};
+use protocol::{GroupCreate, GroupInfo, GroupInvite, GroupLeave, GroupMessage};
+use protocol::{RotateTokenReq, RotateTokenResp, ListTokensReq, ListTokensResp, RevokeTokenReq, RevokeTokenResp};
+use protocol::{Fragment, Reassembler};
+use protocol::{Ping, Pong, Heartbeat, HeartbeatConfig, LinkStatus};
+use protocol::{tls, Stream};
+use protocol::rustls::ClientConfig;
+use protocol::field_lens::{MESSAGE_ID_LEN, IDENTITY_KEY_LEN, TOKEN_LEN, TOKEN_ID_LEN, CHALLENGE_LEN, GROUP_ID_LEN};
+use protocol::field_lens::{TRANSFER_ID_LEN, FILE_CHUNK_LEN, MAX_FILE_LEN};
+use protocol::identity::fingerprint;
+use protocol::message_types::MessageType;
+use protocol::{self, shared};
+use protocol::status_codes::StatusCode;
 
-/**
-General handler for all protocol message-types
+use crate::storage::{storage, conn_map, presence_map};
+use crate::delivery::{DeliveryState, DeliveryTracker};
+use crate::typing::TypingTracker;
+use crate::transfer::{self, Direction, Transfer};
+use crate::history::HistoryEntry;
+use crate::helpers;
+use crate::crypto::{self, ConversationKey, KeyPair};
+use crate::identity::{KeyChanges, KeyCheck, SafetyNumber};
+
+// optional protocol features this client supports
+const CLIENT_CAPABILITIES: Capabilities = Capabilities::RECEIPTS
+    .union(Capabilities::FRAGMENTATION)
+    .union(Capabilities::HEARTBEAT)
+    .union(Capabilities::PRESENCE)
+    .union(Capabilities::TYPING)
+    .union(Capabilities::FILE_TRANSFER)
+    .union(Capabilities::EDITS)
+    .union(Capabilities::REACTIONS)
+    .union(Capabilities::INBOX)
+    .union(Capabilities::HISTORY);
 
-TODO: implement for rest of message types
+/**
+How to reach the server: over plain TCP, or over TLS checking the server's
+certificate against a CA or a pinned fingerprint (see protocol::tls)
 */
-fn handle_message(mut stream: TcpStream) -> Result<(), Box<dyn Error>> {
-    let packet = protocol::read_packet(stream)?;
-    let message_type = method_num_to_message_type(packet.method);
-    match message_type {
-        MessageType::VerifyReq => handle_verify_message(packet),
-        _ => Ok(())
-    };
+pub enum TransportConfig {
+    Plain,
+    Tls {
+        config: Arc<ClientConfig>,
+        // name the server's certificate must be issued for (ignored when pinning)
+        server_name: String,
+    },
+}
 
-    Ok(())
+impl TransportConfig {
+    /**
+    Reads the transport from the environment:
+      - CLI_CHAT_TLS_PIN: SHA-256 fingerprint (hex) of the server's certificate, or
+      - CLI_CHAT_TLS_CA: PEM file of the CA(s) to trust
+      - CLI_CHAT_TLS_SERVER_NAME: server name to check the certificate against (default localhost)
+
+    Neither PIN nor CA set means plain TCP.
+    */
+    pub fn from_env() -> Result<Self, Box<dyn Error>> {
+        let config = match (env::var("CLI_CHAT_TLS_PIN"), env::var("CLI_CHAT_TLS_CA")) {
+            (Ok(pin), _) => tls::client_config_with_pin(tls::parse_fingerprint(&pin)?)?,
+            (Err(_), Ok(ca_path)) => tls::client_config_with_ca(Path::new(&ca_path))?,
+            (Err(_), Err(_)) => return Ok(TransportConfig::Plain),
+        };
+        let server_name = env::var("CLI_CHAT_TLS_SERVER_NAME").unwrap_or_else(|_| "localhost".to_string());
+
+        Ok(TransportConfig::Tls { config, server_name })
+    }
+
+    /**
+    Opens a connection to the server at addr, doing the TLS handshake if needed
+    */
+    pub fn connect(&self, addr: &str) -> Result<Stream, Box<dyn Error>> {
+        let tcp = TcpStream::connect(addr)?;
+        match self {
+            TransportConfig::Plain => Ok(Stream::plain(tcp)),
+            TransportConfig::Tls { config, server_name } => {
+                Ok(Stream::tls_client(tcp, Arc::clone(config), server_name)?)
+            }
+        }
+    }
 }
 
 /**
-Handles server response to a VerifyReq message
+A client's connection to the server
 */
-fn handle_verify_message(packet: Packet) -> Result<(), Box<dyn Error>> {
-    let ver_msg = VerifyReq::deserialize(&packet.msg_buffer)?;
-    println!("Received verify response:\n\n{:?}", ver_msg);
-    Ok(())
+pub struct Connection {
+    uname: Username,
+    reader: PacketReader<Stream>,
+    writer: PacketWriter<Stream>,
+
+    // protocol version and features agreed with the server
+    session: NegotiatedSession,
+
+    // the server's latest AuthChallenge, until we answer it
+    challenge: Option<[u8; CHALLENGE_LEN]>,
+
+    // identity we've signed up with, until the server answers (see sign_up)
+    signup_identity: Option<IdentityKeyPair>,
+
+    // whether the server accepted our last VerifyReq, once it has answered
+    verified: Option<bool>,
+
+    // keep_current of each RotateTokenReq awaiting a response, oldest first
+    token_rotations: VecDeque<bool>,
+
+    // cursor of the HistoryReq awaiting a response in each conversation we're
+    // fetching history for; any other HistoryResp is ignored
+    history_reqs: HashMap<Username, HistoryCursor>,
+
+    // requests from users who have asked to connect with us, awaiting our response
+    pending_conn_reqs: Vec<C2cConnReq>,
+
+    // responses to our connection requests that arrived before the responder's
+    // identity key, so their public key can't be checked yet
+    unchecked_conn_resps: Vec<C2cConnResp>,
+
+    // delivery state of the chat messages we've sent
+    deliveries: DeliveryTracker,
+
+    // chat messages we've received since connecting, until they're marked read
+    unread: HashSet<[u8; MESSAGE_ID_LEN]>,
+
+    // who we're typing to, and who's typing to us
+    typing: TypingTracker,
+
+    // files offered to us, awaiting our response
+    file_offers: HashMap<[u8; TRANSFER_ID_LEN], Transfer>,
+
+    // contacts' changed identity keys, awaiting the user's say-so
+    key_changes: KeyChanges,
+
+    // members of the groups we're in, as last heard from the server
+    group_members: HashMap<[u8; GROUP_ID_LEN], Vec<Username>>,
+
+    // fragmented packets the server is part-way through sending us
+    reassembler: Reassembler,
+
+    // only used if the server agreed to heartbeats
+    heartbeat: Heartbeat,
+
+    // what's happened that the user should hear about, oldest first, until the
+    // UI takes it (see handle_message and take_notices)
+    notices: Vec<String>,
 }
 
-// TESTS //
+impl Connection {
+    /**
+    Sets up a connection over the given stream, starting with the Hello/HelloAck
+    handshake, after which the server challenges us to verify ourselves (see
+    verify). Fails if the server does not speak a compatible protocol version.
+    */
+    pub fn connect(stream: Stream, uname: &Username) -> Result<Self, Box<dyn Error>> {
+        Connection::connect_with_heartbeat(stream, uname, HeartbeatConfig::default())
+    }
+
+    /**
+    As connect, but with the given ping interval and timeout
+    */
+    pub fn connect_with_heartbeat(stream: Stream, uname: &Username, heartbeat: HeartbeatConfig) -> Result<Self, Box<dyn Error>> {
+        let mut reader = PacketReader::new(stream.try_clone()?);
+        let mut writer = PacketWriter::new(stream);
+
+        writer.write_packet(&Packet::wrap(&Hello::new(CLIENT_CAPABILITIES)))?;
+        let session = match reader.read_packet()?.decode()? {
+            ProtocolMessage::HelloAck(hello_ack) => NegotiatedSession::from_ack(&hello_ack)?,
+            message => return Err(Box::new(ProtocolError::UnexpectedMessage(message.message_type()))),
+        };
+        let challenge = match reader.read_packet()?.decode()? {
+            ProtocolMessage::AuthChallenge(auth_challenge) => *auth_challenge.challenge(),
+            message => return Err(Box::new(ProtocolError::UnexpectedMessage(message.message_type()))),
+        };
+
+        Ok(Connection {
+            uname: uname.clone(),
+            reader,
+            writer,
+            session,
+            challenge: Some(challenge),
+            signup_identity: None,
+            verified: None,
+            token_rotations: VecDeque::new(),
+            history_reqs: HashMap::new(),
+            pending_conn_reqs: Vec::new(),
+            unchecked_conn_resps: Vec::new(),
+            deliveries: DeliveryTracker::new(),
+            unread: HashSet::new(),
+            typing: TypingTracker::new(),
+            file_offers: HashMap::new(),
+            key_changes: KeyChanges::new(),
+            group_members: HashMap::new(),
+            reassembler: Reassembler::new(),
+            heartbeat: Heartbeat::new(heartbeat, Instant::now()),
+            notices: Vec::new(),
+        })
+    }
+
+    /**
+    Protocol version and optional features agreed with the server
+    */
+    pub fn session(&self) -> &NegotiatedSession {
+        &self.session
+    }
+
+    /**
+    Pings the server if a ping is due. Should be called regularly, e.g. from the
+    UI's event loop, along with handle_message.
+    */
+    pub fn tick(&mut self) -> Result<(), Box<dyn Error>> {
+        self.typing.expire(Instant::now());
+        if !self.session.allows(MessageType::Ping) {
+            return Ok(());
+        }
+
+        if let Some(ping) = self.heartbeat.poll(Instant::now()) {
+            self.send(&ping)?;
+        }
+        Ok(())
+    }
+
+    /**
+    Health of the link to the server, or None if the server doesn't do heartbeats
+    */
+    pub fn link_status(&self) -> Option<LinkStatus> {
+        if !self.session.allows(MessageType::Ping) {
+            return None;
+        }
+        Some(self.heartbeat.status(Instant::now()))
+    }
+
+    /**
+    Round-trip time to the server, as of the last answered ping
+    */
+    pub fn latency(&self) -> Option<Duration> {
+        self.heartbeat.latency()
+    }
+
+    /**
+    Sends a message, split into fragments if it's too large for one packet
+    (and the server agreed to fragmentation)
+    */
+    pub fn send<M: Message>(&mut self, message: &M) -> Result<(), Box<dyn Error>> {
+        let packet = Packet::wrap(message);
+        if self.session.allows(MessageType::Fragment) {
+            self.writer.write_fragmented(&packet)?;
+        } else {
+            self.writer.write_packet(&packet)?;
+        }
+        Ok(())
+    }
+
+    /**
+    Reads the next message from the server and handles it. Returns what the user
+    should hear about since notices were last taken, e.g. that a connection has
+    come online or a message was dropped, for the UI to show.
+    */
+    pub fn handle_message(&mut self) -> Result<Vec<String>, Box<dyn Error>> {
+        self.reader.get_ref().set_read_timeout(None)?;
+        let packet = self.reader.read_packet()?;
+        self.handle_packet(packet)?;
+        Ok(self.take_notices())
+    }
+
+    /**
+    As handle_message, but gives up waiting for a message after timeout, so a
+    UI can get on with other things in between. Returns None if there wasn't one.
+    */
+    pub fn poll_message(&mut self, timeout: Duration) -> Result<Option<Vec<String>>, Box<dyn Error>> {
+        self.reader.get_ref().set_read_timeout(Some(timeout))?;
+        match self.reader.read_packet() {
+            Ok(packet) => {
+                self.handle_packet(packet)?;
+                Ok(Some(self.take_notices()))
+            }
+            Err(e) if e.io_kind().is_some_and(is_timeout) => Ok(None),
+            Err(e) => Err(Box::new(e)),
+        }
+    }
+
+    /**
+    What the user should hear about since notices were last taken, including
+    anything raised by the user's own requests (e.g. trust_identity_key)
+    */
+    pub fn take_notices(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.notices)
+    }
+
+    fn notify(&mut self, notice: String) {
+        self.notices.push(notice);
+    }
+
+    fn handle_packet(&mut self, packet: Packet) -> Result<(), Box<dyn Error>> {
+        self.heartbeat.received(Instant::now());
+        match packet.decode()? {
+            ProtocolMessage::VerifyResp(verify_resp) => self.handle_verify_resp(verify_resp),
+            ProtocolMessage::SignupResp(signup_resp) => self.handle_signup_resp(signup_resp),
+            ProtocolMessage::ChatMessage(chat_message) => self.handle_chat_message(chat_message),
+            ProtocolMessage::Receipt(receipt) => self.handle_receipt(receipt),
+            ProtocolMessage::C2cConnReq(conn_req) => self.handle_conn_req(conn_req),
+            ProtocolMessage::C2cConnResp(conn_resp) => self.handle_conn_resp(conn_resp),
+            ProtocolMessage::Fragment(fragment) => self.handle_fragment(fragment),
+            ProtocolMessage::Ping(ping) => self.handle_ping(ping),
+            ProtocolMessage::Pong(pong) => self.handle_pong(pong),
+            ProtocolMessage::IdentityResp(identity_resp) => self.handle_identity_resp(identity_resp),
+            ProtocolMessage::AuthChallenge(auth_challenge) => self.handle_auth_challenge(auth_challenge),
+            ProtocolMessage::RotateTokenResp(rotate_resp) => self.handle_rotate_token_resp(rotate_resp),
+            ProtocolMessage::ListTokensResp(list_resp) => self.handle_list_tokens_resp(list_resp),
+            ProtocolMessage::RevokeTokenResp(revoke_resp) => self.handle_revoke_token_resp(revoke_resp),
+            ProtocolMessage::GroupInfo(group_info) => self.handle_group_info(group_info),
+            ProtocolMessage::GroupInvite(group_invite) => self.handle_group_invite(group_invite),
+            ProtocolMessage::GroupMessage(group_message) => self.handle_group_message(group_message),
+            ProtocolMessage::Presence(presence) => self.handle_presence(presence),
+            ProtocolMessage::Typing(typing) => self.handle_typing(typing),
+            ProtocolMessage::FileOffer(file_offer) => self.handle_file_offer(file_offer),
+            ProtocolMessage::FileAccept(file_accept) => self.handle_file_accept(file_accept),
+            ProtocolMessage::FileChunk(file_chunk) => self.handle_file_chunk(file_chunk),
+            ProtocolMessage::FileComplete(file_complete) => self.handle_file_complete(file_complete),
+            ProtocolMessage::MessageEdit(message_edit) => self.handle_message_edit(message_edit),
+            ProtocolMessage::MessageDelete(message_delete) => self.handle_message_delete(message_delete),
+            ProtocolMessage::Reaction(reaction) => self.handle_reaction(reaction),
+            ProtocolMessage::HistoryResp(history_resp) => self.handle_history_resp(history_resp),
+            message => {
+                self.notify(format!("Ignoring unexpected {:?} from server", message.message_type()));
+                Ok(())
+            }
+        }
+    }
+
+    /**
+    Verifies ourselves with the server by answering its outstanding challenge
+    with a proof that we hold token. If verification fails the server sends a
+    new challenge, which is picked up by handle_message.
+    */
+    pub fn verify(&mut self, token: &[u8; TOKEN_LEN]) -> Result<(), Box<dyn Error>> {
+        let Some(challenge) = self.challenge.take() else {
+            return Err("no challenge from the server to answer".into());
+        };
+
+        let proof = auth::prove(token, &challenge, &self.uname);
+        self.verified = None;
+        self.send(&VerifyReq::new(&self.uname, proof))
+    }
+
+    /**
+    Whether the server accepted our last VerifyReq, or None until it answers
+    */
+    pub fn verified(&self) -> Option<bool> {
+        self.verified
+    }
+
+    fn handle_auth_challenge(&mut self, auth_challenge: AuthChallenge) -> Result<(), Box<dyn Error>> {
+        self.challenge = Some(*auth_challenge.challenge());
+        Ok(())
+    }
+
+    /**
+    Asks the server for a new token. Unless keep_current is set, the new token
+    replaces the one we verified with, both on the server and in
+    .cli_chat/token; otherwise it's printed, for setting up another device.
+    */
+    pub fn rotate_token(&mut self, keep_current: bool) -> Result<(), Box<dyn Error>> {
+        self.send(&RotateTokenReq::new(keep_current))?;
+        self.token_rotations.push_back(keep_current);
+        Ok(())
+    }
+
+    /**
+    Asks the server for our account's active tokens
+    */
+    pub fn list_tokens(&mut self) -> Result<(), Box<dyn Error>> {
+        self.send(&ListTokensReq::new())
+    }
+
+    /**
+    Revokes one of our account's tokens (e.g. a lost device's), by its id as
+    listed by list_tokens
+    */
+    pub fn revoke_token(&mut self, token_id: [u8; TOKEN_ID_LEN]) -> Result<(), Box<dyn Error>> {
+        self.send(&RevokeTokenReq::new(token_id))
+    }
+
+    /**
+    Sets our presence and custom status (empty for none), as seen by our
+    connections. Setting Offline hides us from them until we set otherwise.
+    */
+    pub fn set_presence(&mut self, state: PresenceState, status: &str) -> Result<(), Box<dyn Error>> {
+        if !self.session.allows(MessageType::PresenceUpdate) {
+            return Err("server doesn't support presence".into());
+        }
+        self.send(&PresenceUpdate::new(state, status)?)
+    }
+
+    fn handle_rotate_token_resp(&mut self, rotate_resp: RotateTokenResp) -> Result<(), Box<dyn Error>> {
+        let Some(keep_current) = self.token_rotations.pop_front() else {
+            self.notify("Ignoring unrequested token from server".to_string());
+            return Ok(());
+        };
+        if rotate_resp.status_code() != StatusCode::Success {
+            self.notify(format!("Token rotation refused: {}", rotate_resp.status_code()));
+            return Ok(());
+        }
+
+        let token_id = shared::token_id_to_string(rotate_resp.token_id());
+        if keep_current {
+            self.notify(format!("New token {}: {}", token_id, shared::token_to_string(rotate_resp.token())));
+        } else {
+            storage::write_token(&rotate_resp.token())?;
+            self.notify(format!("Token rotated, now using token {}", token_id));
+        }
+        Ok(())
+    }
+
+    /**
+    Signs up for an account under our username, publishing the public half of
+    identity. The server's SignupResp carries the token to verify with; once
+    it arrives, the .cli_chat directory is created holding the token and
+    identity (see handle_signup_resp).
+    */
+    pub fn sign_up(&mut self, identity: IdentityKeyPair) -> Result<(), Box<dyn Error>> {
+        self.send(&SignupReq::new(&self.uname, identity.identity_key()))?;
+        self.signup_identity = Some(identity);
+        Ok(())
+    }
+
+    /**
+    Whether we've signed up and are still waiting on the server's answer
+    */
+    pub fn signing_up(&self) -> bool {
+        self.signup_identity.is_some()
+    }
+
+    fn handle_signup_resp(&mut self, signup_resp: SignupResp) -> Result<(), Box<dyn Error>> {
+        let Some(identity) = self.signup_identity.take() else {
+            self.notify("Ignoring unrequested signup response from server".to_string());
+            return Ok(());
+        };
+        if signup_resp.status_code() != StatusCode::Success {
+            self.notify(format!("Signup refused: {} may already be taken", self.uname));
+            return Ok(());
+        }
+
+        if storage::create_cli_chat_dir(&self.uname, signup_resp.token(), &identity).is_none() {
+            return Err("couldn't create .cli_chat directory".into());
+        }
+        self.notify(format!("Signed up as {}", self.uname));
+        Ok(())
+    }
+
+    /**
+    Sends a chat message to one of our connections, encrypted under our newest
+    conversation key with them and signed with our identity key, and stores it
+    (in the clear) alongside the rest of our conversation with them.
+
+    Returns the message's id, which its delivery state can be looked up by.
+    */
+    pub fn send_chat_message(&mut self, recv_uname: &Username, msg: &str) -> Result<[u8; MESSAGE_ID_LEN], Box<dyn Error>> {
+        self.send_and_store(ChatMessage::new(&self.uname, recv_uname, msg))
+    }
+
+    /**
+    Sends a chat message answering an earlier message (ours or theirs) in our
+    conversation with recv_uname, as send_chat_message does
+    */
+    pub fn send_reply(
+        &mut self,
+        recv_uname: &Username,
+        msg: &str,
+        in_reply_to: [u8; MESSAGE_ID_LEN]
+    ) -> Result<[u8; MESSAGE_ID_LEN], Box<dyn Error>> {
+        find_message(recv_uname, in_reply_to)?;
+        self.send_and_store(ChatMessage::reply(&self.uname, recv_uname, msg, in_reply_to))
+    }
+
+    fn send_and_store(&mut self, chat_message: ChatMessage) -> Result<[u8; MESSAGE_ID_LEN], Box<dyn Error>> {
+        let recv_uname = chat_message.recv_uname.clone();
+        let Some(key) = storage::read_conversation_keys(&recv_uname)?.pop() else {
+            return Err(format!("no conversation key for {}, connect with them first", recv_uname).into());
+        };
+
+        let message_id = chat_message.message_id();
+        let mut encrypted = chat_message.clone();
+        crypto::encrypt_message(&key, &mut encrypted)?;
+        encrypted.sign(&storage::read_identity()?);
+        self.send(&encrypted)?;
+        self.deliveries.track(message_id);
+
+        // the message arriving ends our typing indicator at their end
+        self.typing.stopped(&recv_uname, Instant::now());
+
+        storage::write_message(chat_message, &recv_uname)?;
+        Ok(message_id)
+    }
+
+    /**
+    Replaces the text of one of our messages to a connection. The edit is
+    sealed and signed as the message itself was, and stored alongside our
+    copy of the conversation, which keeps the original text.
+    */
+    pub fn edit_message(&mut self, recv_uname: &Username, message_id: [u8; MESSAGE_ID_LEN], msg: &str) -> Result<(), Box<dyn Error>> {
+        if !self.session.allows(MessageType::MessageEdit) {
+            return Err("server doesn't support editing messages".into());
+        }
+        editable_message(recv_uname, &self.uname, message_id)?;
+        let Some(key) = storage::read_conversation_keys(recv_uname)?.pop() else {
+            return Err(format!("no conversation key for {}, connect with them first", recv_uname).into());
+        };
+
+        let message_edit = MessageEdit::new(message_id, &self.uname, recv_uname, msg);
+        let mut encrypted = message_edit.clone();
+        crypto::encrypt_edit(&key, &mut encrypted)?;
+        encrypted.sign(&storage::read_identity()?);
+        self.send(&encrypted)?;
+
+        storage::write_edit(&message_edit, recv_uname)?;
+        Ok(())
+    }
+
+    /**
+    Retracts one of our messages to a connection, leaving a tombstone in its
+    place in both sides' copies of the conversation
+    */
+    pub fn delete_message(&mut self, recv_uname: &Username, message_id: [u8; MESSAGE_ID_LEN]) -> Result<(), Box<dyn Error>> {
+        if !self.session.allows(MessageType::MessageDelete) {
+            return Err("server doesn't support deleting messages".into());
+        }
+        editable_message(recv_uname, &self.uname, message_id)?;
+
+        let mut message_delete = MessageDelete::new(message_id, &self.uname, recv_uname);
+        message_delete.sign(&storage::read_identity()?);
+        self.send(&message_delete)?;
+
+        storage::write_deletion(&message_delete, recv_uname)?;
+        Ok(())
+    }
+
+    /**
+    Reacts to a message (ours or theirs) in our conversation with a connection.
+    Adding a reaction we've already added does nothing.
+    */
+    pub fn add_reaction(&mut self, conn_uname: &Username, message_id: [u8; MESSAGE_ID_LEN], reaction: &str) -> Result<(), Box<dyn Error>> {
+        self.send_reaction(conn_uname, message_id, ReactionAction::Add, reaction)
+    }
+
+    /**
+    Takes back one of our reactions to a message
+    */
+    pub fn remove_reaction(&mut self, conn_uname: &Username, message_id: [u8; MESSAGE_ID_LEN], reaction: &str) -> Result<(), Box<dyn Error>> {
+        self.send_reaction(conn_uname, message_id, ReactionAction::Remove, reaction)
+    }
+
+    fn send_reaction(
+        &mut self,
+        conn_uname: &Username,
+        message_id: [u8; MESSAGE_ID_LEN],
+        action: ReactionAction,
+        reaction: &str
+    ) -> Result<(), Box<dyn Error>> {
+        if !self.session.allows(MessageType::Reaction) {
+            return Err("server doesn't support reactions".into());
+        }
+        let mut reaction = Reaction::new(message_id, &self.uname, conn_uname, action, reaction)?;
+
+        let entry = find_message(conn_uname, message_id)?;
+        if entry.is_deleted() {
+            return Err(format!("message {} has been deleted", shared::message_id_to_string(message_id)).into());
+        }
+        let reacted = entry.reactions
+            .get(reaction.reaction())
+            .is_some_and(|unames| unames.contains(&self.uname));
+        if reacted == (action == ReactionAction::Add) {
+            return Ok(());
+        }
+
+        reaction.sign(&storage::read_identity()?);
+        self.send(&reaction)?;
+        storage::write_reaction(&reaction, conn_uname)?;
+        Ok(())
+    }
 
-pub mod tests {
-    use super::*;
-    use crate::storage::storage;
+    /**
+    Asks the server for the messages in our conversation with conn_uname that
+    we don't have yet, i.e. those after the last one we have (or all of them).
+    The rest of the conversation follows a batch at a time (see
+    handle_history_resp). Called for each connection once we're verified.
+    */
+    pub fn sync_history(&mut self, conn_uname: &Username) -> Result<(), Box<dyn Error>> {
+        if !self.session.allows(MessageType::HistoryReq) {
+            return Err("server doesn't support history".into());
+        }
+        if !conn_map::get_map().contains_key(conn_uname.as_str()) {
+            return Err(format!("{} is not a connection", conn_uname).into());
+        }
 
-    pub fn test_verify_req(mut stream: TcpStream) -> Result<(), Box<dyn Error>> {
-        let username = storage::read_username()?;
-        let token = storage::read_token()?;
-        let mut verify_req = VerifyReq::new(&protocol::shared::uname_to_string(username), token);
-        let mut packet = Packet::new(
-            MessageType::VerifyReq as u8, 
-            verify_req.length() as u32, 
-            verify_req.serialize());
+        let cursor = storage::read_messages(conn_uname)?
+            .last()
+            .map_or(HistoryCursor::START, |last| HistoryCursor::AfterMessage(last.message_id()));
+        self.request_history(conn_uname, cursor)
+    }
+
+    fn request_history(&mut self, conn_uname: &Username, cursor: HistoryCursor) -> Result<(), Box<dyn Error>> {
+        self.history_reqs.insert(conn_uname.clone(), cursor);
+        self.send(&HistoryReq::new(conn_uname, cursor))
+    }
+
+    /**
+    Called on each keystroke in our conversation with recv_uname; tells them
+    we're typing, at most once every TYPING_RESEND_INTERVAL
+    */
+    pub fn typing(&mut self, recv_uname: &Username) -> Result<(), Box<dyn Error>> {
+        if !self.session.allows(MessageType::Typing) || !self.typing.typed(recv_uname, Instant::now()) {
+            return Ok(());
+        }
+        self.send(&Typing::new(&self.uname, recv_uname, true))
+    }
+
+    /**
+    Called when we stop typing to recv_uname without sending (e.g. the input
+    was cleared or the conversation closed)
+    */
+    pub fn stopped_typing(&mut self, recv_uname: &Username) -> Result<(), Box<dyn Error>> {
+        if !self.session.allows(MessageType::Typing) || !self.typing.stopped(recv_uname, Instant::now()) {
+            return Ok(());
+        }
+        self.send(&Typing::new(&self.uname, recv_uname, false))
+    }
+
+    /**
+    Connections currently typing to us, for the conversation view
+    */
+    pub fn typing_users(&self) -> Vec<Username> {
+        self.typing.typing_users(Instant::now())
+    }
+
+    /**
+    Offers a connection the file at path, returning the transfer's id. The
+    file is sent once they accept it, and should be left in place until the
+    transfer completes.
+    */
+    pub fn send_file(&mut self, recv_uname: &Username, path: &Path) -> Result<[u8; TRANSFER_ID_LEN], Box<dyn Error>> {
+        if !self.session.allows(MessageType::FileOffer) {
+            return Err("server doesn't support file transfer".into());
+        }
+        let file_name = path.file_name()
+            .and_then(|file_name| file_name.to_str())
+            .ok_or("can't send a file without a (UTF-8) name")?;
+        transfer::validate_file_name(file_name)?;
+
+        let contents = fs::read(path)?;
+        if contents.len() > MAX_FILE_LEN {
+            return Err(format!("{} is too large to send (limit is {} bytes)", file_name, MAX_FILE_LEN).into());
+        }
+
+        let transfer = Transfer {
+            transfer_id: shared::generate_transfer_id(),
+            direction: Direction::Outgoing,
+            peer: recv_uname.clone(),
+            file_name: file_name.to_string(),
+            file_size: contents.len() as u64,
+            digest: helpers::sha256_from_bytes(contents),
+            source: Some(fs::canonicalize(path)?),
+        };
+        storage::write_transfer(&transfer)?;
+        self.offer_file(&transfer)?;
+
+        Ok(transfer.transfer_id)
+    }
+
+    fn offer_file(&mut self, transfer: &Transfer) -> Result<(), Box<dyn Error>> {
+        let Some(key) = storage::read_conversation_keys(&transfer.peer)?.pop() else {
+            return Err(format!("no conversation key for {}, connect with them first", transfer.peer).into());
+        };
+
+        let sealed_info = crypto::seal_file_info(
+            &key,
+            transfer.transfer_id,
+            &self.uname,
+            &transfer.peer,
+            transfer.file_size,
+            &transfer.encode_info()
+        )?;
+        let file_offer = FileOffer::new(transfer.transfer_id, &self.uname, &transfer.peer, transfer.file_size, sealed_info)?;
+        self.send(&file_offer)
+    }
+
+    /**
+    Files offered to us that we haven't accepted or declined yet
+    */
+    pub fn file_offers(&self) -> impl Iterator<Item = &Transfer> {
+        self.file_offers.values()
+    }
+
+    /**
+    Accepts a file offered to us; it's saved in the sender's attachments
+    folder once it has all arrived
+    */
+    pub fn accept_file(&mut self, transfer_id: [u8; TRANSFER_ID_LEN]) -> Result<(), Box<dyn Error>> {
+        let Some(transfer) = self.file_offers.remove(&transfer_id) else {
+            return Err(format!("no file offer {}", shared::transfer_id_to_string(transfer_id)).into());
+        };
+
+        storage::write_transfer(&transfer)?;
+        self.request_chunks(&transfer)
+    }
 
-        // stream.write_all(&packet.serialize());
-        stream.write_all(&verify_req.serialize());
-        // handle_message(stream).unwrap();
+    /**
+    Declines a file offered to us
+    */
+    pub fn decline_file(&mut self, transfer_id: [u8; TRANSFER_ID_LEN]) -> Result<(), Box<dyn Error>> {
+        let Some(transfer) = self.file_offers.remove(&transfer_id) else {
+            return Err(format!("no file offer {}", shared::transfer_id_to_string(transfer_id)).into());
+        };
 
+        self.send(&FileComplete::new(transfer_id, &transfer.peer, &self.uname, StatusCode::Failure))
+    }
+
+    /**
+    Picks up transfers left unfinished by an earlier connection: our
+    outgoing files are offered again (and sent on from wherever the recipient
+    got to, once they accept), and the rest of each incoming file asked for
+    */
+    pub fn resume_transfers(&mut self) -> Result<(), Box<dyn Error>> {
+        if !self.session.allows(MessageType::FileOffer) {
+            return Ok(());
+        }
+
+        for transfer in storage::read_transfers()? {
+            let resumed = match transfer.direction {
+                Direction::Outgoing => self.offer_file(&transfer),
+                Direction::Incoming => self.request_chunks(&transfer),
+            };
+            if let Err(e) = resumed {
+                self.notify(format!("Couldn't resume transfer of {}: {}", transfer.file_name, e));
+            }
+        }
         Ok(())
     }
 
-    pub fn test_verify_resp() {
-        let verify_resp = VerifyResp::new(status_codes::StatusCode::Success);
-        println!("{:?}", verify_resp);
+    // Accepts an incoming file from the first chunk we don't have (if any)
+    fn request_chunks(&mut self, transfer: &Transfer) -> Result<(), Box<dyn Error>> {
+        let received = storage::partial_file_len(transfer)?;
+        if received >= transfer.file_size {
+            return self.finish_transfer(transfer);
+        }
+
+        let next_chunk = (received / FILE_CHUNK_LEN as u64) as u32;
+        self.send(&FileAccept::new(transfer.transfer_id, &transfer.peer, &self.uname, next_chunk))
+    }
+
+    // Checks a fully received file against its digest, keeping it if it
+    // matches, and lets the sender know how it went
+    fn finish_transfer(&mut self, transfer: &Transfer) -> Result<(), Box<dyn Error>> {
+        // an empty file never gets a chunk, so may not have been created yet
+        storage::append_file_chunk(transfer, &[])?;
+
+        let status_code = if helpers::sha256_from_bytes(storage::read_partial_file(transfer)?) == transfer.digest {
+            let path = storage::finish_attachment(transfer)?;
+            self.notify(format!("Received {} from {}, saved to {}", transfer.file_name, transfer.peer, path.display()));
+            StatusCode::Success
+        } else {
+            self.notify(format!("{} from {} doesn't match its digest, discarding it", transfer.file_name, transfer.peer));
+            StatusCode::Failure
+        };
+        storage::remove_transfer(transfer)?;
+
+        self.send(&FileComplete::new(transfer.transfer_id, &transfer.peer, &self.uname, status_code))
+    }
+
+    /**
+    Creates a group, generating its first key, and returns its group id.
+    The server answers with a GroupInfo once the group exists.
+    */
+    pub fn create_group(&mut self, group_name: &str) -> Result<[u8; GROUP_ID_LEN], Box<dyn Error>> {
+        let group_create = GroupCreate::new(group_name)?;
+        let group_id = group_create.group_id();
+        storage::add_group_key(group_id, &ConversationKey::generate())?;
+        storage::add_group(group_id, group_name)?;
+
+        self.send(&group_create)?;
+        Ok(group_id)
     }
+
+    /**
+    Adds one of our connections to a group we're in, passing them the group's
+    newest key sealed under our newest conversation key with them
+    */
+    pub fn invite_to_group(&mut self, group_id: [u8; GROUP_ID_LEN], uname: &Username) -> Result<(), Box<dyn Error>> {
+        let Some(group_key) = storage::read_group_keys(group_id)?.pop() else {
+            return Err("no key for that group, are we in it?".into());
+        };
+        let Some(conversation_key) = storage::read_conversation_keys(uname)?.pop() else {
+            return Err(format!("no conversation key for {}, connect with them first", uname).into());
+        };
+
+        let sealed_key = crypto::seal_group_key(&conversation_key, group_id, &self.uname, uname, &group_key)?;
+        self.send(&GroupInvite::new(group_id, &self.uname, uname, sealed_key))
+    }
+
+    pub fn leave_group(&mut self, group_id: [u8; GROUP_ID_LEN]) -> Result<(), Box<dyn Error>> {
+        self.send(&GroupLeave::new(group_id))
+    }
+
+    /**
+    Members of a group we're in, or None if we aren't in it (this session)
+    */
+    pub fn group_members(&self, group_id: &[u8; GROUP_ID_LEN]) -> Option<&[Username]> {
+        self.group_members.get(group_id).map(Vec::as_slice)
+    }
+
+    /**
+    Sends a message to every member of a group, encrypted under the group's
+    newest key and signed with our identity key, and stores it (in the clear)
+    alongside the rest of the group's history.
+
+    Returns the message's id.
+    */
+    pub fn send_group_message(&mut self, group_id: [u8; GROUP_ID_LEN], msg: &str) -> Result<[u8; MESSAGE_ID_LEN], Box<dyn Error>> {
+        let Some(key) = storage::read_group_keys(group_id)?.pop() else {
+            return Err("no key for that group, are we in it?".into());
+        };
+
+        let group_message = GroupMessage::new(group_id, &self.uname, msg);
+        let mut encrypted = group_message.clone();
+        crypto::encrypt_group_message(&key, &mut encrypted)?;
+        encrypted.sign(&storage::read_identity()?);
+        self.send(&encrypted)?;
+
+        storage::write_group_message(&group_message)?;
+        Ok(group_message.message_id())
+    }
+
+    /**
+    Delivery state of a message we've sent, or None if we didn't send it
+    (this session)
+    */
+    pub fn delivery_state(&self, message_id: &[u8; MESSAGE_ID_LEN]) -> Option<DeliveryState> {
+        self.deliveries.state(message_id)
+    }
+
+    /**
+    Lets the sender of chat_message know we've read it, if it's one we've
+    received since connecting that we haven't already marked read. Messages
+    from before then (or fetched in a history sync) may have been read
+    elsewhere, so are left as they are.
+    */
+    pub fn mark_read(&mut self, chat_message: &ChatMessage) -> Result<(), Box<dyn Error>> {
+        if !self.unread.remove(&chat_message.message_id()) {
+            return Ok(());
+        }
+        self.send_receipt(&Receipt::for_message(ReceiptKind::Read, chat_message))
+    }
+
+    /**
+    Asks the server to pass a connection request on to the given user, along
+    with our (signed) half of the key agreement for the conversation
+    */
+    pub fn request_connection(&mut self, uname: &Username) -> Result<(), Box<dyn Error>> {
+        let key_pair = KeyPair::generate();
+        storage::write_pending_secret(uname, &key_pair)?;
+
+        let mut conn_req = C2cConnReq::new(&self.uname, uname, key_pair.public_key());
+        conn_req.sign(&storage::read_identity()?);
+        self.send(&conn_req)?;
+
+        // to check their half of the key agreement when they answer
+        self.request_identity_key(uname)
+    }
+
+    /**
+    Answers a pending connection request from req_uname.
+
+    On accept, req_uname is added to our connections, and we agree a new
+    conversation key with them (sending them our half of it). Accepting has to
+    wait until we have their identity key, to check their half.
+    */
+    pub fn respond_to_conn_req(&mut self, req_uname: &Username, response: ConnResponse) -> Result<(), Box<dyn Error>> {
+        let Some(index) = self.pending_conn_reqs.iter().position(|conn_req| conn_req.req_uname() == req_uname) else {
+            return Err(format!("no pending connection request from {}", req_uname).into());
+        };
+        if response == ConnResponse::Accept && storage::read_contact_identity(req_uname)?.is_none() {
+            self.request_identity_key(req_uname)?;
+            return Err(format!("no identity key for {} yet to check their request with, try again shortly", req_uname).into());
+        }
+        let conn_req = self.pending_conn_reqs.remove(index);
+        self.answer_conn_req(&conn_req, response)
+    }
+
+    pub fn pending_conn_reqs(&self) -> impl Iterator<Item = &Username> {
+        self.pending_conn_reqs.iter().map(|conn_req| conn_req.req_uname())
+    }
+
+    /**
+    Asks the server for a user's identity key (see handle_identity_resp)
+    */
+    pub fn request_identity_key(&mut self, uname: &Username) -> Result<(), Box<dyn Error>> {
+        self.send(&IdentityReq::new(uname))
+    }
+
+    /**
+    Accepts a contact's changed identity key, e.g. once the user has checked
+    the new safety number with them. Returns false if their key hasn't changed.
+    */
+    pub fn trust_identity_key(&mut self, uname: &Username) -> Result<bool, Box<dyn Error>> {
+        let Some(identity_key) = self.key_changes.take(uname) else {
+            return Ok(false);
+        };
+
+        storage::write_contact_identity(uname, &identity_key)?;
+        self.notify(format!("Now trusting {}'s new identity key ({})", uname, fingerprint(&identity_key)));
+        self.check_held_keys(uname)?;
+        Ok(true)
+    }
+
+    /**
+    Whether uname's identity key has changed since we first trusted it (and
+    the user hasn't yet accepted the change)
+    */
+    pub fn identity_key_changed(&self, uname: &Username) -> bool {
+        self.key_changes.get(uname).is_some()
+    }
+
+    /**
+    Safety number for our conversation with uname, for comparing out of band.
+    If their key has changed, the number is for their new key. None if we
+    don't have a key for them yet.
+    */
+    pub fn safety_number(&self, uname: &Username) -> Result<Option<SafetyNumber>, Box<dyn Error>> {
+        let identity_key = storage::read_identity()?.identity_key();
+        let (contact_identity_key, key_changed) = match self.key_changes.get(uname) {
+            Some(changed) => (*changed, true),
+            None => match storage::read_contact_identity(uname)? {
+                Some(trusted) => (trusted, false),
+                None => return Ok(None),
+            },
+        };
+
+        Ok(Some(SafetyNumber::new(&self.uname, &identity_key, uname, &contact_identity_key, key_changed)))
+    }
+
+    fn answer_conn_req(&mut self, conn_req: &C2cConnReq, response: ConnResponse) -> Result<(), Box<dyn Error>> {
+        let req_uname = conn_req.req_uname();
+        let public_key = match response {
+            ConnResponse::Accept | ConnResponse::AlreadyConnected => {
+                if signed_by_contact(req_uname, |identity_key| conn_req.verify_signature(identity_key))? != Some(true) {
+                    return Err(format!("{}'s key isn't signed with their trusted identity key", req_uname).into());
+                }
+                let key_pair = KeyPair::generate();
+                let key = ConversationKey::agree(&key_pair, conn_req.public_key())?;
+                storage::add_conversation_key(req_uname, &key)?;
+                Some(key_pair.public_key())
+            }
+            ConnResponse::Reject | ConnResponse::Block => None,
+        };
+
+        let mut conn_resp = C2cConnResp::new(req_uname, &self.uname, response, public_key);
+        if public_key.is_some() {
+            conn_resp.sign(&storage::read_identity()?);
+        }
+        self.send(&conn_resp)?;
+
+        if response == ConnResponse::Accept {
+            add_connection_if_new(req_uname)?;
+        }
+        Ok(())
+    }
+
+    /**
+    Handles another user asking to connect with us.
+
+    Requests whose public key isn't signed with the requester's trusted identity
+    key are dropped. If we're already connected, answers straight away
+    (agreeing a fresh conversation key, as they may have lost theirs), otherwise
+    queues the request for the user to respond to. A repeated request replaces
+    the earlier one, as only its public key will still be usable.
+
+    If we don't have the requester's identity key yet, the request is queued
+    while we fetch it, and checked once it arrives (see check_held_keys).
+    */
+    fn handle_conn_req(&mut self, conn_req: C2cConnReq) -> Result<(), Box<dyn Error>> {
+        let req_uname = conn_req.req_uname().clone();
+        let connected = conn_map::get_map().contains_key(req_uname.as_str());
+        match signed_by_contact(&req_uname, |identity_key| conn_req.verify_signature(identity_key))? {
+            Some(false) => {
+                self.notify(format!("Dropping connection request from {}: key not signed with their trusted identity key", req_uname));
+                return Ok(());
+            }
+            Some(true) if connected => return self.answer_conn_req(&conn_req, ConnResponse::AlreadyConnected),
+            Some(true) => {}
+            None => self.request_identity_key(&req_uname)?,
+        }
+
+        match self.pending_conn_reqs.iter_mut().find(|pending| pending.req_uname() == &req_uname) {
+            Some(pending) => *pending = conn_req,
+            None => {
+                if !connected {
+                    self.notify(format!("{} wants to connect", req_uname));
+                }
+                self.pending_conn_reqs.push(conn_req);
+            }
+        }
+        Ok(())
+    }
+
+    /**
+    Checks the keys in connection requests and responses from uname that
+    arrived before we had their identity key, now that we trust one: completing
+    the key agreement for our own requests, and answering theirs straight away
+    if we're already connected
+    */
+    fn check_held_keys(&mut self, uname: &Username) -> Result<(), Box<dyn Error>> {
+        let (held, unchecked) = std::mem::take(&mut self.unchecked_conn_resps)
+            .into_iter()
+            .partition::<Vec<_>, _>(|conn_resp| conn_resp.resp_uname() == uname);
+        self.unchecked_conn_resps = unchecked;
+        for conn_resp in held {
+            self.complete_key_agreement(&conn_resp)?;
+        }
+
+        let Some(index) = self.pending_conn_reqs.iter().position(|conn_req| conn_req.req_uname() == uname) else {
+            return Ok(());
+        };
+        let conn_req = &self.pending_conn_reqs[index];
+        match signed_by_contact(uname, |identity_key| conn_req.verify_signature(identity_key))? {
+            Some(false) => {
+                self.pending_conn_reqs.remove(index);
+                self.notify(format!("Dropping connection request from {}: key not signed with their trusted identity key", uname));
+            }
+            Some(true) if conn_map::get_map().contains_key(uname.as_str()) => {
+                let conn_req = self.pending_conn_reqs.remove(index);
+                self.answer_conn_req(&conn_req, ConnResponse::AlreadyConnected)?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /**
+    Checks, decrypts and stores a chat message relayed from one of our
+    connections, and lets the sender know it has been delivered. Messages not
+    signed with the sender's trusted identity key, or that we can't decrypt,
+    are dropped without a receipt.
+    */
+    fn handle_chat_message(&mut self, mut chat_message: ChatMessage) -> Result<(), Box<dyn Error>> {
+        let send_uname = chat_message.send_uname.clone();
+        let Some(identity_key) = storage::read_contact_identity(&send_uname)? else {
+            self.notify(format!("Dropping chat message from {}: no identity key for them yet", send_uname));
+            return self.request_identity_key(&send_uname);
+        };
+        if !chat_message.verify_signature(&identity_key) {
+            self.notify(format!("Dropping chat message from {}: not signed with their trusted identity key", send_uname));
+            return Ok(());
+        }
+
+        // already have it (e.g. stored by the server again, our ack having been lost)
+        let message_id = chat_message.message_id();
+        if find_message(&send_uname, message_id).is_ok() {
+            return self.send_inbox_ack(message_id);
+        }
+
+        let keys = storage::read_conversation_keys(&send_uname)?;
+        if let Err(e) = crypto::decrypt_message(&keys, &mut chat_message) {
+            self.notify(format!("Dropping chat message from {}: {}", send_uname, e));
+            return Ok(());
+        }
+        self.typing.update(&send_uname, false, Instant::now());
+
+        let receipt = Receipt::for_message(ReceiptKind::Delivered, &chat_message);
+        storage::write_message(chat_message, &send_uname)?;
+        self.unread.insert(message_id);
+
+        self.send_receipt(&receipt)?;
+        self.send_inbox_ack(message_id)
+    }
+
+    /**
+    Merges a batch of our conversation with a connection, fetched from the
+    server, into storage, then asks for the next batch if there is one. Only
+    the answer to our outstanding HistoryReq for the conversation is taken.
+
+    Messages are checked like those we receive: any not between us and the
+    connection, not signed with the sender's identity key, or that we can't
+    decrypt (e.g. under a conversation key from a previous install) are left
+    out.
+    */
+    fn handle_history_resp(&mut self, history_resp: HistoryResp) -> Result<(), Box<dyn Error>> {
+        let conn_uname = history_resp.conn_uname().clone();
+        if self.history_reqs.get(&conn_uname) != Some(&history_resp.cursor()) {
+            self.notify(format!("Ignoring unrequested history with {} from server", conn_uname));
+            return Ok(());
+        }
+        self.history_reqs.remove(&conn_uname);
+        if history_resp.status_code() != StatusCode::Success {
+            return self.retry_history(&conn_uname, history_resp.cursor());
+        }
+        let Some(conn_identity_key) = storage::read_contact_identity(&conn_uname)? else {
+            self.notify(format!("Can't check history with {} without their identity key; sync again once we have it", conn_uname));
+            return self.request_identity_key(&conn_uname);
+        };
+        let identity_key = storage::read_identity()?.identity_key();
+
+        let more = history_resp.more();
+        let messages = history_resp.into_messages();
+        let next_cursor = messages.last().map(|last| HistoryCursor::AfterMessage(last.message_id()));
+        let fetched = messages.len();
+
+        let keys = storage::read_conversation_keys(&conn_uname)?;
+        let mut readable = Vec::new();
+        for mut chat_message in messages {
+            let signing_key = if chat_message.send_uname == self.uname && chat_message.recv_uname == conn_uname {
+                &identity_key
+            } else if chat_message.send_uname == conn_uname && chat_message.recv_uname == self.uname {
+                &conn_identity_key
+            } else {
+                continue;
+            };
+            if !chat_message.verify_signature(signing_key) || crypto::decrypt_message(&keys, &mut chat_message).is_err() {
+                continue;
+            }
+            readable.push(chat_message);
+        }
+
+        let skipped = fetched - readable.len();
+        let added = storage::merge_messages(readable, &conn_uname)?;
+        self.notify(format!("Fetched {} message(s) with {}: {} new, {} unreadable", fetched, conn_uname, added, skipped));
+
+        match next_cursor {
+            Some(cursor) if more => self.request_history(&conn_uname, cursor),
+            _ => Ok(()),
+        }
+    }
+
+    // The server doesn't have the message we asked for history after (e.g. it
+    // was sent before the server kept history, or has since been dropped from
+    // it), so ask again from when it was sent
+    fn retry_history(&mut self, conn_uname: &Username, cursor: HistoryCursor) -> Result<(), Box<dyn Error>> {
+        let HistoryCursor::AfterMessage(message_id) = cursor else {
+            self.notify(format!("Couldn't fetch history with {}", conn_uname));
+            return Ok(());
+        };
+        let Ok(entry) = find_message(conn_uname, message_id) else {
+            self.notify(format!("Couldn't fetch history with {}", conn_uname));
+            return Ok(());
+        };
+
+        let cursor = HistoryCursor::AfterTime(entry.message.timestamp());
+        self.request_history(conn_uname, cursor)
+    }
+
+    /**
+    Checks, decrypts and stores a connection's edit of a message they sent us.
+    Edits not signed with the sender's trusted identity key, or of messages
+    they didn't send (or have since deleted), are dropped.
+    */
+    fn handle_message_edit(&mut self, mut message_edit: MessageEdit) -> Result<(), Box<dyn Error>> {
+        let send_uname = message_edit.send_uname().clone();
+        if message_edit.recv_uname() != &self.uname {
+            self.notify(format!("Ignoring message edit from {} for someone else", send_uname));
+            return Ok(());
+        }
+        let Some(identity_key) = storage::read_contact_identity(&send_uname)? else {
+            self.notify(format!("Dropping message edit from {}: no identity key for them yet", send_uname));
+            return self.request_identity_key(&send_uname);
+        };
+        if !message_edit.verify_signature(&identity_key) {
+            self.notify(format!("Dropping message edit from {}: not signed with their trusted identity key", send_uname));
+            return Ok(());
+        }
+
+        let entry = match editable_message(&send_uname, &send_uname, message_edit.message_id()) {
+            Ok(entry) => entry,
+            Err(e) => {
+                self.notify(format!("Dropping message edit from {}: {}", send_uname, e));
+                return Ok(());
+            }
+        };
+        // already have it (e.g. sent again after a reconnect)
+        if entry.edits.iter().any(|edit| edit.timestamp() == message_edit.timestamp()) {
+            return Ok(());
+        }
+
+        let keys = storage::read_conversation_keys(&send_uname)?;
+        if let Err(e) = crypto::decrypt_edit(&keys, &mut message_edit) {
+            self.notify(format!("Dropping message edit from {}: {}", send_uname, e));
+            return Ok(());
+        }
+        storage::write_edit(&message_edit, &send_uname)?;
+        Ok(())
+    }
+
+    /**
+    Checks and stores a connection's deletion of a message they sent us
+    */
+    fn handle_message_delete(&mut self, message_delete: MessageDelete) -> Result<(), Box<dyn Error>> {
+        let send_uname = message_delete.send_uname().clone();
+        if message_delete.recv_uname() != &self.uname {
+            self.notify(format!("Ignoring message deletion from {} for someone else", send_uname));
+            return Ok(());
+        }
+        let Some(identity_key) = storage::read_contact_identity(&send_uname)? else {
+            self.notify(format!("Dropping message deletion from {}: no identity key for them yet", send_uname));
+            return self.request_identity_key(&send_uname);
+        };
+        if !message_delete.verify_signature(&identity_key) {
+            self.notify(format!("Dropping message deletion from {}: not signed with their trusted identity key", send_uname));
+            return Ok(());
+        }
+
+        if let Err(e) = editable_message(&send_uname, &send_uname, message_delete.message_id()) {
+            self.notify(format!("Dropping message deletion from {}: {}", send_uname, e));
+            return Ok(());
+        }
+        storage::write_deletion(&message_delete, &send_uname)?;
+        Ok(())
+    }
+
+    /**
+    Checks and stores a connection's reaction to a message in our conversation with them
+    */
+    fn handle_reaction(&mut self, reaction: Reaction) -> Result<(), Box<dyn Error>> {
+        let send_uname = reaction.send_uname().clone();
+        if reaction.recv_uname() != &self.uname {
+            self.notify(format!("Ignoring reaction from {} for someone else", send_uname));
+            return Ok(());
+        }
+        let Some(identity_key) = storage::read_contact_identity(&send_uname)? else {
+            self.notify(format!("Dropping reaction from {}: no identity key for them yet", send_uname));
+            return self.request_identity_key(&send_uname);
+        };
+        if !reaction.verify_signature(&identity_key) {
+            self.notify(format!("Dropping reaction from {}: not signed with their trusted identity key", send_uname));
+            return Ok(());
+        }
+
+        if let Err(e) = find_message(&send_uname, reaction.message_id()) {
+            self.notify(format!("Dropping reaction from {}: {}", send_uname, e));
+            return Ok(());
+        }
+
+        storage::write_reaction(&reaction, &send_uname)?;
+        Ok(())
+    }
+
+    fn handle_verify_resp(&mut self, verify_resp: VerifyResp) -> Result<(), Box<dyn Error>> {
+        self.verified = Some(verify_resp.status_code == StatusCode::Success);
+        if verify_resp.status_code != StatusCode::Success {
+            return Ok(());
+        }
+        self.resume_transfers()?;
+
+        // catch up on anything said while we were away, e.g. from another device
+        if self.session.allows(MessageType::HistoryReq) {
+            let conn_unames: Vec<Username> = conn_map::get_map()
+                .keys()
+                .filter_map(|uname| Username::new(uname).ok())
+                .collect();
+            for conn_uname in conn_unames {
+                self.sync_history(&conn_uname)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_file_offer(&mut self, file_offer: FileOffer) -> Result<(), Box<dyn Error>> {
+        let send_uname = file_offer.send_uname().clone();
+        let transfer_id = file_offer.transfer_id();
+        if file_offer.recv_uname() != &self.uname {
+            self.notify(format!("Ignoring file offer from {} for someone else", send_uname));
+            return Ok(());
+        }
+
+        // an offer we've already accepted has been made again after a reconnect
+        if let Some(transfer) = storage::read_transfer(transfer_id)? {
+            if transfer.direction == Direction::Incoming && transfer.peer == send_uname {
+                return self.request_chunks(&transfer);
+            }
+            self.notify(format!("Ignoring file offer from {} reusing transfer id {}", send_uname, shared::transfer_id_to_string(transfer_id)));
+            return Ok(());
+        }
+
+        let keys = storage::read_conversation_keys(&send_uname)?;
+        let info = match crypto::open_file_info(&keys, &file_offer) {
+            Ok(info) => info,
+            Err(e) => {
+                self.notify(format!("Dropping file offer from {}: {}", send_uname, e));
+                return Ok(());
+            }
+        };
+        let (digest, file_name) = match Transfer::decode_info(&info) {
+            Ok(info) => info,
+            Err(e) => {
+                self.notify(format!("Dropping file offer from {}: {}", send_uname, e));
+                return Ok(());
+            }
+        };
+
+        self.notify(format!("{} wants to send you {} ({} bytes)", send_uname, file_name, file_offer.file_size()));
+        self.file_offers.insert(transfer_id, Transfer {
+            transfer_id,
+            direction: Direction::Incoming,
+            peer: send_uname,
+            file_name,
+            file_size: file_offer.file_size(),
+            digest,
+            source: None,
+        });
+        Ok(())
+    }
+
+    /**
+    Sends the recipient of one of our files every chunk from the one they
+    asked for onwards
+    */
+    fn handle_file_accept(&mut self, file_accept: FileAccept) -> Result<(), Box<dyn Error>> {
+        let Some(transfer) = storage::read_transfer(file_accept.transfer_id())? else {
+            self.notify(format!("Ignoring file accept for unknown transfer from {}", file_accept.recv_uname()));
+            return Ok(());
+        };
+        if transfer.direction != Direction::Outgoing || transfer.peer != *file_accept.recv_uname() {
+            self.notify(format!("Ignoring file accept from {} for someone else's transfer", file_accept.recv_uname()));
+            return Ok(());
+        }
+
+        let Some(source) = &transfer.source else {
+            return Ok(());
+        };
+        let contents = fs::read(source)?;
+        if contents.len() as u64 != transfer.file_size || helpers::sha256_from_bytes(contents.clone()) != transfer.digest {
+            self.notify(format!("{} has changed since it was offered, not sending it", transfer.file_name));
+            return Ok(());
+        }
+
+        let Some(key) = storage::read_conversation_keys(&transfer.peer)?.pop() else {
+            return Err(format!("no conversation key for {}", transfer.peer).into());
+        };
+        for index in file_accept.next_chunk()..transfer.chunk_count() {
+            let data = crypto::encrypt_file_chunk(
+                &key,
+                transfer.transfer_id,
+                &self.uname,
+                &transfer.peer,
+                index,
+                &contents[transfer.chunk_range(index)]
+            )?;
+            self.send(&FileChunk::new(transfer.transfer_id, &self.uname, &transfer.peer, index, data))?;
+        }
+        Ok(())
+    }
+
+    /**
+    Adds the next chunk to an incoming file, and checks the file against its
+    digest once the last chunk is in
+    */
+    fn handle_file_chunk(&mut self, file_chunk: FileChunk) -> Result<(), Box<dyn Error>> {
+        let Some(transfer) = storage::read_transfer(file_chunk.transfer_id())? else {
+            return Ok(());
+        };
+        if transfer.direction != Direction::Incoming || transfer.peer != *file_chunk.send_uname() {
+            self.notify(format!("Ignoring file chunk from {} for someone else's transfer", file_chunk.send_uname()));
+            return Ok(());
+        }
+
+        // chunks arrive in order, so anything else is left over from before a resume
+        let received = storage::partial_file_len(&transfer)?;
+        if file_chunk.index() as u64 != received / FILE_CHUNK_LEN as u64 || received >= transfer.file_size {
+            return Ok(());
+        }
+
+        let keys = storage::read_conversation_keys(&transfer.peer)?;
+        let data = match crypto::decrypt_file_chunk(&keys, &file_chunk) {
+            Ok(data) => data,
+            Err(e) => {
+                self.notify(format!("Dropping chunk {} of {}: {}", file_chunk.index(), transfer.file_name, e));
+                return Ok(());
+            }
+        };
+        if data.len() != transfer.chunk_range(file_chunk.index()).len() {
+            self.notify(format!("Dropping chunk {} of {}: wrong length", file_chunk.index(), transfer.file_name));
+            return Ok(());
+        }
+        storage::append_file_chunk(&transfer, &data)?;
+        if received + (data.len() as u64) < transfer.file_size {
+            return Ok(());
+        }
+
+        self.finish_transfer(&transfer)
+    }
+
+    fn handle_typing(&mut self, typing: Typing) -> Result<(), Box<dyn Error>> {
+        let send_uname = typing.send_uname();
+        if typing.recv_uname() != &self.uname || !conn_map::get_map().contains_key(send_uname.as_str()) {
+            self.notify(format!("Ignoring typing indicator from non-connection {}", send_uname));
+            return Ok(());
+        }
+
+        self.typing.update(send_uname, typing.typing(), Instant::now());
+        Ok(())
+    }
+
+    fn handle_group_info(&mut self, group_info: GroupInfo) -> Result<(), Box<dyn Error>> {
+        let group_id = group_info.group_id();
+        let group_name = group_info.group_name();
+        if group_info.status_code() != StatusCode::Success {
+            self.notify(format!("Group request refused for group {}", shared::group_id_to_string(group_id)));
+            return Ok(());
+        }
+
+        if !group_info.members().contains(&self.uname) {
+            self.group_members.remove(&group_id);
+            self.notify(format!("You are no longer in group {}", group_name));
+            return Ok(());
+        }
+
+        storage::add_group(group_id, group_name)?;
+        let members: Vec<&str> = group_info.members().iter().map(Username::as_str).collect();
+        self.notify(format!("Group {}: {}", group_name, members.join(", ")));
+        self.group_members.insert(group_id, group_info.members().to_vec());
+        Ok(())
+    }
+
+    /**
+    Stores the key for a group we've been added to (the group's name and
+    members follow in a GroupInfo)
+    */
+    fn handle_group_invite(&mut self, group_invite: GroupInvite) -> Result<(), Box<dyn Error>> {
+        let inviter = group_invite.inviter();
+        if group_invite.invitee() != &self.uname {
+            self.notify(format!("Ignoring group invite meant for {}", group_invite.invitee()));
+            return Ok(());
+        }
+
+        let conversation_keys = storage::read_conversation_keys(inviter)?;
+        let group_key = match crypto::open_group_key(
+            &conversation_keys,
+            group_invite.group_id(),
+            inviter,
+            &self.uname,
+            group_invite.sealed_key()
+        ) {
+            Ok(group_key) => group_key,
+            Err(e) => {
+                self.notify(format!("Dropping group invite from {}: {}", inviter, e));
+                return Ok(());
+            }
+        };
+
+        storage::add_group_key(group_invite.group_id(), &group_key)?;
+        self.notify(format!("{} added you to a group", inviter));
+        Ok(())
+    }
+
+    /**
+    Checks, decrypts and stores a message to one of our groups
+    */
+    fn handle_group_message(&mut self, mut group_message: GroupMessage) -> Result<(), Box<dyn Error>> {
+        let send_uname = group_message.send_uname.clone();
+        let Some(identity_key) = storage::read_contact_identity(&send_uname)? else {
+            self.notify(format!("Dropping group message from {}: no identity key for them yet", send_uname));
+            return self.request_identity_key(&send_uname);
+        };
+        if !group_message.verify_signature(&identity_key) {
+            self.notify(format!("Dropping group message from {}: not signed with their trusted identity key", send_uname));
+            return Ok(());
+        }
+
+        let keys = storage::read_group_keys(group_message.group_id)?;
+        if let Err(e) = crypto::decrypt_group_message(&keys, &mut group_message) {
+            self.notify(format!("Dropping group message from {}: {}", send_uname, e));
+            return Ok(());
+        }
+
+        storage::write_group_message(&group_message)?;
+        Ok(())
+    }
+
+    /**
+    Adds a fragment to the packet it belongs to, handling that packet once all
+    of it has arrived. A packet that can't be reassembled is dropped.
+    */
+    fn handle_fragment(&mut self, fragment: Fragment) -> Result<(), Box<dyn Error>> {
+        match self.reassembler.add(fragment) {
+            Ok(Some(packet)) => self.handle_packet(packet),
+            Ok(None) => Ok(()),
+            Err(e) => {
+                self.notify(format!("Dropping fragmented message from server: {}", e));
+                Ok(())
+            }
+        }
+    }
+
+    fn handle_ping(&mut self, ping: Ping) -> Result<(), Box<dyn Error>> {
+        if !self.session.allows(MessageType::Pong) {
+            return Ok(());
+        }
+        self.send(&ping.pong())
+    }
+
+    fn handle_pong(&mut self, pong: Pong) -> Result<(), Box<dyn Error>> {
+        self.heartbeat.handle_pong(&pong, Instant::now());
+        Ok(())
+    }
+
+    /**
+    Checks a user's identity key from the server against the one we trust
+    for them, trusting it if it's the first we've seen, and warning the user
+    if it has changed
+    */
+    fn handle_identity_resp(&mut self, identity_resp: IdentityResp) -> Result<(), Box<dyn Error>> {
+        let uname = identity_resp.uname();
+        let Some(identity_key) = identity_resp.identity_key() else {
+            self.notify(format!("Server has no identity key for {}", uname));
+            return Ok(());
+        };
+
+        let trusted = storage::read_contact_identity(uname)?;
+        match KeyCheck::compare(trusted.as_ref(), identity_key) {
+            KeyCheck::New => {
+                storage::write_contact_identity(uname, identity_key)?;
+                self.notify(format!("Trusting {}'s identity key ({})", uname, fingerprint(identity_key)));
+                self.check_held_keys(uname)?;
+            }
+            KeyCheck::Unchanged => self.check_held_keys(uname)?,
+            KeyCheck::Changed { previous } => {
+                self.notify(format!(
+                    "WARNING: {}'s identity key has changed from {} to {}. \
+                    Compare safety numbers with them before accepting the new key.",
+                    uname,
+                    fingerprint(&previous),
+                    fingerprint(identity_key)
+                ));
+                self.key_changes.add(uname, *identity_key);
+            }
+        }
+        Ok(())
+    }
+
+    /**
+    Updates the delivery state of one of our messages
+    */
+    fn handle_receipt(&mut self, receipt: Receipt) -> Result<(), Box<dyn Error>> {
+        if *receipt.send_uname() != self.uname {
+            self.notify("Ignoring receipt for someone else's message".to_string());
+            return Ok(());
+        }
+
+        match self.deliveries.update(receipt.message_id(), receipt.kind()) {
+            Some(DeliveryState::Failed) => {
+                self.notify(format!("{}'s inbox is full, so the server rejected your message", receipt.recv_uname()));
+            }
+            Some(_) => {}
+            None => self.notify("Ignoring receipt for unknown message".to_string()),
+        }
+        Ok(())
+    }
+
+    // Receipts are only sent if the server agreed to them
+    fn send_receipt(&mut self, receipt: &Receipt) -> Result<(), Box<dyn Error>> {
+        if !self.session.allows(MessageType::Receipt) {
+            return Ok(());
+        }
+        self.send(receipt)
+    }
+
+    // Lets the server delete its stored copy of a chat message, if it has one
+    fn send_inbox_ack(&mut self, message_id: [u8; MESSAGE_ID_LEN]) -> Result<(), Box<dyn Error>> {
+        if !self.session.allows(MessageType::InboxAck) {
+            return Ok(());
+        }
+        self.send(&InboxAck::new(message_id))
+    }
+
+    /**
+    Handles the response to one of our connection requests, completing the key
+    agreement if the other user sent their half. If we don't have their identity
+    key yet, their half is held until it arrives (see check_held_keys).
+    */
+    fn handle_conn_resp(&mut self, conn_resp: C2cConnResp) -> Result<(), Box<dyn Error>> {
+        let resp_uname = conn_resp.resp_uname().clone();
+        match conn_resp.response() {
+            ConnResponse::Accept | ConnResponse::AlreadyConnected => {
+                add_connection_if_new(&resp_uname)?;
+                self.notify(format!("Connected with {}", resp_uname));
+                if conn_resp.public_key().is_some() && storage::read_contact_identity(&resp_uname)?.is_none() {
+                    self.unchecked_conn_resps.push(conn_resp);
+                    return self.request_identity_key(&resp_uname);
+                }
+                self.complete_key_agreement(&conn_resp)?;
+            }
+            response => {
+                storage::take_pending_secret(&resp_uname)?;
+                self.notify(format!("Connection request to {} refused: {}", resp_uname, response));
+            }
+        }
+        Ok(())
+    }
+
+    /**
+    Handles the end of a file transfer we offered
+    */
+    fn handle_file_complete(&mut self, file_complete: FileComplete) -> Result<(), Box<dyn Error>> {
+        let Some(transfer) = storage::read_transfer(file_complete.transfer_id())? else {
+            return Ok(());
+        };
+        if transfer.direction != Direction::Outgoing || transfer.peer != *file_complete.recv_uname() {
+            self.notify(format!("Ignoring file complete from {} for someone else's transfer", file_complete.recv_uname()));
+            return Ok(());
+        }
+
+        storage::remove_transfer(&transfer)?;
+        match file_complete.status_code() {
+            StatusCode::Success => self.notify(format!("{} received {}", transfer.peer, transfer.file_name)),
+            _ => self.notify(format!("{} didn't take {}", transfer.peer, transfer.file_name)),
+        }
+        Ok(())
+    }
+
+    /**
+    Handles server response to a ListTokensReq message
+    */
+    fn handle_list_tokens_resp(&mut self, list_resp: ListTokensResp) -> Result<(), Box<dyn Error>> {
+        self.notify("Active tokens:".to_string());
+        for token in list_resp.tokens() {
+            let current = if token.current { " (current)" } else { "" };
+            self.notify(format!("  {} issued at {}{}", shared::token_id_to_string(token.token_id), token.issued_at, current));
+        }
+        Ok(())
+    }
+
+    /**
+    Handles server response to a RevokeTokenReq message
+    */
+    fn handle_revoke_token_resp(&mut self, revoke_resp: RevokeTokenResp) -> Result<(), Box<dyn Error>> {
+        let token_id = shared::token_id_to_string(revoke_resp.token_id());
+        match revoke_resp.status_code() {
+            StatusCode::Success => self.notify(format!("Token {} revoked", token_id)),
+            _ => self.notify(format!("No active token {} to revoke", token_id)),
+        }
+        Ok(())
+    }
+
+    /**
+    Records a connection's latest presence for display
+    */
+    fn handle_presence(&mut self, presence: Presence) -> Result<(), Box<dyn Error>> {
+        let uname = presence.uname().to_string();
+        if !conn_map::get_map().contains_key(&uname) {
+            self.notify(format!("Ignoring presence of non-connection {}", uname));
+            return Ok(());
+        }
+
+        self.notify(format!("{} is {}", uname, presence.state()));
+        presence_map::insert(uname, presence_map::PresenceEntry {
+            state: presence.state(),
+            status: presence.status().to_string(),
+        });
+        Ok(())
+    }
+
+    /**
+    Completes the key agreement for one of our connection requests with the other
+    user's half, as long as it's signed with their trusted identity key. Otherwise
+    the key is refused, and our half thrown away, leaving us without a key for the
+    conversation.
+    */
+    fn complete_key_agreement(&mut self, conn_resp: &C2cConnResp) -> Result<(), Box<dyn Error>> {
+        let resp_uname = conn_resp.resp_uname();
+        let key_pair = storage::take_pending_secret(resp_uname)?;
+        let Some(public_key) = conn_resp.public_key() else {
+            return Ok(());
+        };
+        let Some(key_pair) = key_pair else {
+            self.notify(format!("Ignoring key from {}: no connection request of ours to match it", resp_uname));
+            return Ok(());
+        };
+        if signed_by_contact(resp_uname, |identity_key| conn_resp.verify_signature(identity_key))? != Some(true) {
+            self.notify(format!("WARNING: refusing key from {}: not signed with their trusted identity key", resp_uname));
+            return Ok(());
+        }
+
+        let key = ConversationKey::agree(&key_pair, public_key)?;
+        storage::add_conversation_key(resp_uname, &key)?;
+        Ok(())
+    }
+}
+
+/**
+Finds the message with the given id in our conversation with conn_uname
+*/
+fn find_message(conn_uname: &Username, message_id: [u8; MESSAGE_ID_LEN]) -> Result<HistoryEntry, Box<dyn Error>> {
+    if !conn_map::get_map().contains_key(conn_uname.as_str()) {
+        return Err(format!("{} is not a connection", conn_uname).into());
+    }
+    storage::read_history(conn_uname)?
+        .into_iter()
+        .find(|entry| entry.message.message_id == message_id)
+        .ok_or_else(|| format!("no message {} with {}", shared::message_id_to_string(message_id), conn_uname).into())
+}
+
+/**
+Finds the message send_uname sent with the given id in our conversation with
+conn_uname, as long as it can still be changed (i.e. hasn't been deleted)
+*/
+fn editable_message(
+    conn_uname: &Username,
+    send_uname: &Username,
+    message_id: [u8; MESSAGE_ID_LEN]
+) -> Result<HistoryEntry, Box<dyn Error>> {
+    let entry = find_message(conn_uname, message_id)?;
+    if &entry.message.send_uname != send_uname {
+        return Err(format!("message {} wasn't sent by {}", shared::message_id_to_string(message_id), send_uname).into());
+    }
+    if entry.is_deleted() {
+        return Err(format!("message {} has been deleted", shared::message_id_to_string(message_id)).into());
+    }
+
+    Ok(entry)
+}
+
+/**
+Whether something from uname passes verify with the identity key we trust for
+them, or None if we don't have one for them yet
+*/
+fn signed_by_contact(
+    uname: &Username,
+    verify: impl Fn(&[u8; IDENTITY_KEY_LEN]) -> bool
+) -> Result<Option<bool>, Box<dyn Error>> {
+    Ok(storage::read_contact_identity(uname)?.map(|identity_key| verify(&identity_key)))
+}
+
+fn add_connection_if_new(uname: &Username) -> Result<(), Box<dyn Error>> {
+    if !conn_map::get_map().contains_key(uname.as_str()) {
+        storage::add_new_connection(uname)?;
+    }
+    Ok(())
+}
+
+fn is_timeout(kind: io::ErrorKind) -> bool {
+    kind == io::ErrorKind::WouldBlock || kind == io::ErrorKind::TimedOut
 }
@@ -0,0 +1,458 @@
+/*
+Module - crypto
+
+End-to-end encryption of chat messages between two connections.
+
+When a connection request is accepted, each side contributes an X25519 public
+key (carried in the C2cConnReq and C2cConnResp), and both derive the same
+conversation key from the shared secret with HKDF-SHA256. The server relays
+the public keys but never learns the conversation key. Each public key is
+signed with its sender's identity key and checked before it's used (see
+comms.rs), so the server can't swap in keys of its own either.
+
+Every ChatMessage body is then sealed with XChaCha20-Poly1305 under that key
+and sent as a protocol::Envelope. The message's id, timestamp and usernames
+(and, for a reply, the id of the message it answers) are authenticated along
+with the body, so the server can't pass a message off as part of another
+conversation, replay it under a new id, or make it answer something else.
+
+Each conversation key has an id, carried in the envelope, so a conversation
+can be re-keyed (e.g. by connecting again) without losing older messages.
+
+Group messages are sealed the same way, under a random group key shared by
+the group's members. A member passes the group key on to a user they invite
+sealed under their own conversation key with that user, bound to the group id
+and both usernames.
+
+Files sent to a connection (see transfer.rs) are sealed under the conversation
+key too: the offered file's name and digest bound to the transfer, and each
+chunk bound to the transfer and its place in the file.
+
+A chat message's edited text (see protocol::edit) is sealed in the same way as
+the message itself, bound to the message being edited and when it was edited.
+*/
+
+use std::error::Error;
+use std::fmt;
+
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use hkdf::Hkdf;
+use sha2::Sha256;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use protocol::{ChatMessage, GroupMessage, Envelope, ProtocolError, Username};
+use protocol::{FileOffer, FileChunk};
+use protocol::MessageEdit;
+use protocol::field_lens::{PUBLIC_KEY_LEN, KEY_ID_LEN, GROUP_ID_LEN, TRANSFER_ID_LEN};
+
+pub const KEY_LEN: usize = 32;
+
+// domain separation for the conversation key derivation (envelope version 1)
+const KDF_INFO: &[u8] = b"cli-chat conversation key v1";
+
+// domain separation for what's sealed in file transfers
+const FILE_INFO_LABEL: &[u8] = b"file info";
+const FILE_CHUNK_LABEL: &[u8] = b"file chunk";
+
+// domain separation for edited chat message bodies
+const EDIT_LABEL: &[u8] = b"message edit";
+
+#[derive(Debug)]
+pub enum CryptoError {
+    // peer's public key is a low-order point, which would make the shared secret guessable
+    WeakPublicKey,
+
+    // envelope sealed under a key we don't have
+    UnknownKey(u32),
+
+    // body isn't a well-formed envelope
+    Envelope(ProtocolError),
+
+    // body too long for the cipher
+    Encrypt,
+
+    // sealed group key doesn't hold a key
+    InvalidGroupKey,
+
+    // wrong key, or the message was tampered with
+    Decrypt,
+}
+
+impl fmt::Display for CryptoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CryptoError::WeakPublicKey => write!(f, "peer sent a weak public key"),
+            CryptoError::UnknownKey(key_id) => write!(f, "no conversation key with id {:#010x}", key_id),
+            CryptoError::Envelope(e) => write!(f, "invalid envelope: {}", e),
+            CryptoError::Encrypt => write!(f, "message failed to encrypt"),
+            CryptoError::Decrypt => write!(f, "message failed to decrypt"),
+            CryptoError::InvalidGroupKey => write!(f, "sealed group key is malformed"),
+        }
+    }
+}
+
+impl Error for CryptoError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            CryptoError::Envelope(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/**
+Our half of a key agreement
+*/
+pub struct KeyPair {
+    secret: StaticSecret,
+}
+
+impl KeyPair {
+    pub fn generate() -> Self {
+        KeyPair { secret: StaticSecret::random_from_rng(OsRng) }
+    }
+
+    pub fn from_secret_bytes(secret: [u8; KEY_LEN]) -> Self {
+        KeyPair { secret: StaticSecret::from(secret) }
+    }
+
+    pub fn secret_bytes(&self) -> [u8; KEY_LEN] {
+        self.secret.to_bytes()
+    }
+
+    pub fn public_key(&self) -> [u8; PUBLIC_KEY_LEN] {
+        PublicKey::from(&self.secret).to_bytes()
+    }
+}
+
+/**
+Symmetric key shared by the two users in a conversation (or by the members of
+a group)
+*/
+#[derive(Clone, PartialEq, Eq)]
+pub struct ConversationKey {
+    key_id: u32,
+    key: [u8; KEY_LEN],
+}
+
+impl ConversationKey {
+    pub fn new(key_id: u32, key: [u8; KEY_LEN]) -> Self {
+        ConversationKey { key_id, key }
+    }
+
+    /**
+    A fresh, random key, e.g. for a new group
+    */
+    pub fn generate() -> Self {
+        ConversationKey::new(OsRng.next_u32(), XChaCha20Poly1305::generate_key(&mut OsRng).into())
+    }
+
+    /**
+    Derives the conversation key from our key pair and the peer's public key.
+    Both sides get the same key (and key id), whichever of them asked to connect.
+    */
+    pub fn agree(own: &KeyPair, peer_public_key: &[u8; PUBLIC_KEY_LEN]) -> Result<Self, CryptoError> {
+        let shared_secret = own.secret.diffie_hellman(&PublicKey::from(*peer_public_key));
+        if !shared_secret.was_contributory() {
+            return Err(CryptoError::WeakPublicKey);
+        }
+
+        // bind the key to both public keys, in an order both sides agree on
+        let own_public_key = own.public_key();
+        let (first, second) = if own_public_key <= *peer_public_key {
+            (&own_public_key, peer_public_key)
+        } else {
+            (peer_public_key, &own_public_key)
+        };
+        let mut info = KDF_INFO.to_vec();
+        info.extend_from_slice(first);
+        info.extend_from_slice(second);
+
+        let mut okm = [0u8; KEY_LEN + KEY_ID_LEN];
+        Hkdf::<Sha256>::new(None, shared_secret.as_bytes())
+            .expand(&info, &mut okm)
+            .expect("output length is valid for HKDF-SHA256");
+
+        let mut key = [0u8; KEY_LEN];
+        key.copy_from_slice(&okm[..KEY_LEN]);
+        let mut key_id = [0u8; KEY_ID_LEN];
+        key_id.copy_from_slice(&okm[KEY_LEN..]);
+
+        Ok(ConversationKey::new(u32::from_be_bytes(key_id), key))
+    }
+
+    pub fn key_id(&self) -> u32 {
+        self.key_id
+    }
+
+    pub fn key_bytes(&self) -> &[u8; KEY_LEN] {
+        &self.key
+    }
+}
+
+// never print the key itself
+impl fmt::Debug for ConversationKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ConversationKey {{ key_id: {:#010x} }}", self.key_id)
+    }
+}
+
+/**
+Replaces a chat message's plaintext body with an envelope sealed under key
+*/
+pub fn encrypt_message(key: &ConversationKey, chat_message: &mut ChatMessage) -> Result<(), CryptoError> {
+    let body = seal(key, &chat_associated_data(chat_message), &chat_message.msg_buffer)?;
+    chat_message.set_body(body);
+    Ok(())
+}
+
+/**
+Replaces a chat message's envelope with the plaintext body, using whichever of
+the conversation's keys it was sealed under
+*/
+pub fn decrypt_message(keys: &[ConversationKey], chat_message: &mut ChatMessage) -> Result<(), CryptoError> {
+    let body = open(keys, &chat_associated_data(chat_message), &chat_message.msg_buffer)?;
+    chat_message.set_body(body);
+    Ok(())
+}
+
+/**
+Replaces an edit's plaintext body with an envelope sealed under the conversation key
+*/
+pub fn encrypt_edit(key: &ConversationKey, message_edit: &mut MessageEdit) -> Result<(), CryptoError> {
+    let body = seal(key, &edit_associated_data(message_edit), message_edit.body())?;
+    message_edit.set_body(body);
+    Ok(())
+}
+
+/**
+Replaces an edit's envelope with the plaintext body, using whichever of the
+conversation's keys it was sealed under
+*/
+pub fn decrypt_edit(keys: &[ConversationKey], message_edit: &mut MessageEdit) -> Result<(), CryptoError> {
+    let body = open(keys, &edit_associated_data(message_edit), message_edit.body())?;
+    message_edit.set_body(body);
+    Ok(())
+}
+
+/**
+Replaces a group message's plaintext body with an envelope sealed under the group key
+*/
+pub fn encrypt_group_message(key: &ConversationKey, group_message: &mut GroupMessage) -> Result<(), CryptoError> {
+    let body = seal(key, &group_associated_data(group_message), &group_message.msg_buffer)?;
+    group_message.set_body(body);
+    Ok(())
+}
+
+/**
+Replaces a group message's envelope with the plaintext body, using whichever of
+the group's keys it was sealed under
+*/
+pub fn decrypt_group_message(keys: &[ConversationKey], group_message: &mut GroupMessage) -> Result<(), CryptoError> {
+    let body = open(keys, &group_associated_data(group_message), &group_message.msg_buffer)?;
+    group_message.set_body(body);
+    Ok(())
+}
+
+/**
+Seals a group key for an invitee, under our conversation key with them
+*/
+pub fn seal_group_key(
+    conversation_key: &ConversationKey,
+    group_id: [u8; GROUP_ID_LEN],
+    inviter: &Username,
+    invitee: &Username,
+    group_key: &ConversationKey
+) -> Result<Vec<u8>, CryptoError> {
+    let mut plaintext = Vec::with_capacity(KEY_ID_LEN + KEY_LEN);
+    plaintext.extend_from_slice(&group_key.key_id.to_be_bytes());
+    plaintext.extend_from_slice(&group_key.key);
+
+    seal(conversation_key, &invite_associated_data(group_id, inviter, invitee), &plaintext)
+}
+
+/**
+Opens a group key sealed for us by the inviter, using whichever of our
+conversation keys with them it was sealed under
+*/
+pub fn open_group_key(
+    conversation_keys: &[ConversationKey],
+    group_id: [u8; GROUP_ID_LEN],
+    inviter: &Username,
+    invitee: &Username,
+    sealed_key: &[u8]
+) -> Result<ConversationKey, CryptoError> {
+    let plaintext = open(conversation_keys, &invite_associated_data(group_id, inviter, invitee), sealed_key)?;
+    if plaintext.len() != KEY_ID_LEN + KEY_LEN {
+        return Err(CryptoError::InvalidGroupKey);
+    }
+
+    let (key_id, key) = plaintext.split_at(KEY_ID_LEN);
+    Ok(ConversationKey::new(
+        u32::from_be_bytes(key_id.try_into().unwrap()),
+        key.try_into().unwrap()
+    ))
+}
+
+/**
+Seals an offered file's details (see transfer::Transfer::encode_info) for its
+recipient, under our conversation key with them
+*/
+pub fn seal_file_info(
+    key: &ConversationKey,
+    transfer_id: [u8; TRANSFER_ID_LEN],
+    send_uname: &Username,
+    recv_uname: &Username,
+    file_size: u64,
+    info: &[u8]
+) -> Result<Vec<u8>, CryptoError> {
+    seal(key, &file_info_associated_data(transfer_id, send_uname, recv_uname, file_size), info)
+}
+
+/**
+Opens the file details sealed in an offer, using whichever of our
+conversation keys with the sender it was sealed under
+*/
+pub fn open_file_info(keys: &[ConversationKey], file_offer: &FileOffer) -> Result<Vec<u8>, CryptoError> {
+    let aad = file_info_associated_data(
+        file_offer.transfer_id(),
+        file_offer.send_uname(),
+        file_offer.recv_uname(),
+        file_offer.file_size()
+    );
+    open(keys, &aad, file_offer.sealed_info())
+}
+
+/**
+Seals one chunk of a file for its recipient
+*/
+pub fn encrypt_file_chunk(
+    key: &ConversationKey,
+    transfer_id: [u8; TRANSFER_ID_LEN],
+    send_uname: &Username,
+    recv_uname: &Username,
+    index: u32,
+    data: &[u8]
+) -> Result<Vec<u8>, CryptoError> {
+    seal(key, &chunk_associated_data(transfer_id, send_uname, recv_uname, index), data)
+}
+
+/**
+Opens a chunk of a file sealed by encrypt_file_chunk
+*/
+pub fn decrypt_file_chunk(keys: &[ConversationKey], file_chunk: &FileChunk) -> Result<Vec<u8>, CryptoError> {
+    let aad = chunk_associated_data(
+        file_chunk.transfer_id(),
+        file_chunk.send_uname(),
+        file_chunk.recv_uname(),
+        file_chunk.index()
+    );
+    open(keys, &aad, file_chunk.data())
+}
+
+// Seals plaintext in a serialized envelope, authenticating aad (and the envelope's header) with it
+fn seal(key: &ConversationKey, aad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, CryptoError> {
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let header = Envelope::new(key.key_id, nonce.into(), Vec::new()).header();
+    let aad = [aad, &header].concat();
+
+    let cipher = XChaCha20Poly1305::new(key.key_bytes().into());
+    let ciphertext = cipher
+        .encrypt(&nonce, Payload { msg: plaintext, aad: &aad })
+        .map_err(|_| CryptoError::Encrypt)?;
+
+    Ok(Envelope::new(key.key_id, nonce.into(), ciphertext).serialize())
+}
+
+// Opens a serialized envelope sealed by seal, with whichever of keys it names
+fn open(keys: &[ConversationKey], aad: &[u8], body: &[u8]) -> Result<Vec<u8>, CryptoError> {
+    let envelope = Envelope::deserialize(body).map_err(CryptoError::Envelope)?;
+    let key = keys
+        .iter()
+        .rev()
+        .find(|key| key.key_id == envelope.key_id())
+        .ok_or(CryptoError::UnknownKey(envelope.key_id()))?;
+    let aad = [aad, &envelope.header()].concat();
+
+    let cipher = XChaCha20Poly1305::new(key.key_bytes().into());
+    cipher
+        .decrypt(XNonce::from_slice(envelope.nonce()), Payload { msg: envelope.ciphertext(), aad: &aad })
+        .map_err(|_| CryptoError::Decrypt)
+}
+
+// Everything in the chat message besides the body
+fn chat_associated_data(chat_message: &ChatMessage) -> Vec<u8> {
+    let mut aad = Vec::new();
+    aad.extend_from_slice(&chat_message.message_id);
+    aad.extend_from_slice(&chat_message.timestamp.to_be_bytes());
+    chat_message.send_uname.encode(&mut aad);
+    chat_message.recv_uname.encode(&mut aad);
+    chat_message.encode_in_reply_to(&mut aad);
+
+    aad
+}
+
+// Everything in the edit besides the body
+fn edit_associated_data(message_edit: &MessageEdit) -> Vec<u8> {
+    let mut aad = EDIT_LABEL.to_vec();
+    aad.extend_from_slice(&message_edit.message_id());
+    aad.extend_from_slice(&message_edit.timestamp().to_be_bytes());
+    message_edit.send_uname().encode(&mut aad);
+    message_edit.recv_uname().encode(&mut aad);
+
+    aad
+}
+
+// Everything in the group message besides the body
+fn group_associated_data(group_message: &GroupMessage) -> Vec<u8> {
+    let mut aad = Vec::new();
+    aad.extend_from_slice(&group_message.message_id);
+    aad.extend_from_slice(&group_message.timestamp.to_be_bytes());
+    aad.extend_from_slice(&group_message.group_id);
+    group_message.send_uname.encode(&mut aad);
+
+    aad
+}
+
+// Which group the key is for, and who it's from and to
+fn invite_associated_data(group_id: [u8; GROUP_ID_LEN], inviter: &Username, invitee: &Username) -> Vec<u8> {
+    let mut aad = group_id.to_vec();
+    inviter.encode(&mut aad);
+    invitee.encode(&mut aad);
+
+    aad
+}
+
+// Which transfer the file details are for, and between whom
+fn file_info_associated_data(
+    transfer_id: [u8; TRANSFER_ID_LEN],
+    send_uname: &Username,
+    recv_uname: &Username,
+    file_size: u64
+) -> Vec<u8> {
+    let mut aad = FILE_INFO_LABEL.to_vec();
+    aad.extend_from_slice(&transfer_id);
+    send_uname.encode(&mut aad);
+    recv_uname.encode(&mut aad);
+    aad.extend_from_slice(&file_size.to_be_bytes());
+
+    aad
+}
+
+// Which transfer the chunk is part of, between whom, and where it goes in the file
+fn chunk_associated_data(
+    transfer_id: [u8; TRANSFER_ID_LEN],
+    send_uname: &Username,
+    recv_uname: &Username,
+    index: u32
+) -> Vec<u8> {
+    let mut aad = FILE_CHUNK_LABEL.to_vec();
+    aad.extend_from_slice(&transfer_id);
+    send_uname.encode(&mut aad);
+    recv_uname.encode(&mut aad);
+    aad.extend_from_slice(&index.to_be_bytes());
+
+    aad
+}
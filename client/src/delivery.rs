@@ -0,0 +1,86 @@
+/*
+Module - delivery
+
+Tracks how far each chat message we've sent has got, based on the receipts
+(see protocol::receipt) that come back for it, so the UI can show ticks
+next to sent messages.
+*/
+
+use std::collections::HashMap;
+
+use protocol::ReceiptKind;
+use protocol::field_lens::MESSAGE_ID_LEN;
+
+/**
+Delivery state of a sent message. Only ever moves forwards; Failed is last as
+nothing follows it.
+*/
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum DeliveryState {
+    // written to the server, no receipt yet
+    Pending,
+    Sent,
+    Delivered,
+    Read,
+    // server couldn't take the message (see ReceiptKind::Rejected)
+    Failed,
+}
+
+impl DeliveryState {
+    pub fn from_receipt(kind: ReceiptKind) -> Self {
+        match kind {
+            ReceiptKind::Accepted => DeliveryState::Sent,
+            ReceiptKind::Delivered => DeliveryState::Delivered,
+            ReceiptKind::Read => DeliveryState::Read,
+            ReceiptKind::Rejected => DeliveryState::Failed,
+        }
+    }
+
+    /**
+    Ticks shown next to a sent message (see theme.rs for how each is styled)
+    */
+    pub fn ticks(&self) -> &'static str {
+        match self {
+            DeliveryState::Pending => "",
+            DeliveryState::Sent => "✓",
+            DeliveryState::Delivered | DeliveryState::Read => "✓✓",
+            DeliveryState::Failed => "!",
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct DeliveryTracker {
+    states: HashMap<[u8; MESSAGE_ID_LEN], DeliveryState>,
+}
+
+impl DeliveryTracker {
+    pub fn new() -> Self {
+        DeliveryTracker::default()
+    }
+
+    /**
+    Starts tracking a message we've just sent
+    */
+    pub fn track(&mut self, message_id: [u8; MESSAGE_ID_LEN]) {
+        self.states.entry(message_id).or_insert(DeliveryState::Pending);
+    }
+
+    /**
+    Applies a receipt to one of our messages, returning its new state.
+
+    Receipts can arrive out of order (e.g. Read before Delivered), so a receipt
+    never moves a message backwards. Receipts for messages we aren't tracking
+    are ignored.
+    */
+    pub fn update(&mut self, message_id: [u8; MESSAGE_ID_LEN], kind: ReceiptKind) -> Option<DeliveryState> {
+        let state = self.states.get_mut(&message_id)?;
+        *state = (*state).max(DeliveryState::from_receipt(kind));
+
+        Some(*state)
+    }
+
+    pub fn state(&self, message_id: &[u8; MESSAGE_ID_LEN]) -> Option<DeliveryState> {
+        self.states.get(message_id).copied()
+    }
+}
@@ -0,0 +1,196 @@
+/*
+Module - history
+
+A conversation as it reads now, with edits and deletions (see protocol::edit)
+and reactions (see protocol::reaction) applied to the messages they refer to.
+
+Nothing is rewritten in place: a conversation's messages, edits, deletions and
+reactions are each kept in their own append-only file (see storage), and only
+put together here, when the conversation is read. An edited message keeps every
+earlier version of its text, and a deleted one leaves a tombstone behind.
+
+A reply is shown under a short quote of the message it answers (see
+quote_preview), which can be jumped to (see position).
+*/
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use itertools::Itertools;
+
+use protocol::{ChatMessage, MessageEdit, MessageDelete, Reaction, ReactionAction, Username};
+use protocol::field_lens::MESSAGE_ID_LEN;
+
+// most characters of a message quoted above a reply to it
+pub const QUOTE_PREVIEW_LEN: usize = 40;
+
+#[derive(Debug, Clone)]
+pub struct HistoryEntry {
+    // the message as first sent
+    pub message: ChatMessage,
+
+    // its edits, oldest first
+    pub edits: Vec<MessageEdit>,
+
+    // set once the message has been deleted
+    pub deletion: Option<MessageDelete>,
+
+    // who has reacted to the message with each reaction
+    pub reactions: BTreeMap<String, BTreeSet<Username>>,
+}
+
+impl HistoryEntry {
+    /**
+    The message's current text, or None once it has been deleted
+    */
+    pub fn text(&self) -> Option<String> {
+        if self.is_deleted() {
+            return None;
+        }
+        let body = self.edits.last().map_or(&self.message.msg_buffer[..], MessageEdit::body);
+
+        Some(String::from_utf8_lossy(body).into_owned())
+    }
+
+    pub fn is_edited(&self) -> bool {
+        !self.edits.is_empty()
+    }
+
+    pub fn is_deleted(&self) -> bool {
+        self.deletion.is_some()
+    }
+
+    /**
+    How many users have reacted to the message with each reaction, most
+    popular first
+    */
+    pub fn reaction_counts(&self) -> Vec<(&str, usize)> {
+        self.reactions
+            .iter()
+            .map(|(reaction, unames)| (reaction.as_str(), unames.len()))
+            .sorted_by_key(|&(_, count)| std::cmp::Reverse(count))
+            .collect()
+    }
+}
+
+/**
+Applies edits, deletions and reactions to the messages they refer to, keeping
+the messages in order. Only edits and deletions from a message's own sender
+are applied; the rest, and anything for messages we don't have, are ignored.
+*/
+pub fn apply(
+    messages: Vec<ChatMessage>,
+    edits: Vec<MessageEdit>,
+    deletions: Vec<MessageDelete>,
+    mut reactions: Vec<Reaction>
+) -> Vec<HistoryEntry> {
+    let mut entries: Vec<HistoryEntry> = messages
+        .into_iter()
+        .map(|message| HistoryEntry { message, edits: Vec::new(), deletion: None, reactions: BTreeMap::new() })
+        .collect();
+
+    for edit in edits {
+        if let Some(entry) = find(&mut entries, edit.message_id(), edit.send_uname()) {
+            entry.edits.push(edit);
+        }
+    }
+    for deletion in deletions {
+        if let Some(entry) = find(&mut entries, deletion.message_id(), deletion.send_uname()) {
+            // the first deletion stands
+            entry.deletion.get_or_insert(deletion);
+        }
+    }
+
+    // edits can arrive out of order (e.g. one resent after a reconnect)
+    for entry in &mut entries {
+        entry.edits.sort_by_key(MessageEdit::timestamp);
+    }
+
+    // ...as can reactions, where a user's latest change to each one stands
+    reactions.sort_by_key(Reaction::timestamp);
+    for reaction in reactions {
+        let Some(entry) = entries.iter_mut().find(|entry| entry.message.message_id == reaction.message_id()) else {
+            continue;
+        };
+        let unames = entry.reactions.entry(reaction.reaction().to_string()).or_default();
+        match reaction.action() {
+            ReactionAction::Add => {
+                unames.insert(reaction.send_uname().clone());
+            }
+            ReactionAction::Remove => {
+                unames.remove(reaction.send_uname());
+            }
+        }
+        if unames.is_empty() {
+            entry.reactions.remove(reaction.reaction());
+        }
+    }
+
+    entries
+}
+
+/**
+Where in the conversation the given message is, e.g. to jump from a reply to
+the message it answers
+*/
+pub fn position(entries: &[HistoryEntry], message_id: [u8; MESSAGE_ID_LEN]) -> Option<usize> {
+    entries.iter().position(|entry| entry.message.message_id == message_id)
+}
+
+/**
+The message a reply answers, if it is one and we have that message
+*/
+pub fn parent<'a>(entries: &'a [HistoryEntry], entry: &HistoryEntry) -> Option<&'a HistoryEntry> {
+    let index = position(entries, entry.message.in_reply_to?)?;
+    Some(&entries[index])
+}
+
+/**
+What the conversation view quotes above a reply, given the message it answers
+(None if we don't have it), e.g. "Harry: im not grumba grandpa guy"
+*/
+pub fn quote_preview(parent: Option<&HistoryEntry>) -> String {
+    match parent {
+        None => "message not found".to_string(),
+        Some(parent) => match parent.text() {
+            Some(text) => quote(parent.message.send_uname.as_str(), &text),
+            None => format!("{}: message deleted", parent.message.send_uname),
+        },
+    }
+}
+
+/**
+Quotes the first line of a message, cut down to QUOTE_PREVIEW_LEN characters
+*/
+pub fn quote(send_uname: &str, text: &str) -> String {
+    let line = text.lines().next().unwrap_or_default();
+    if line.chars().count() > QUOTE_PREVIEW_LEN || line.len() < text.trim_end().len() {
+        let cut: String = line.chars().take(QUOTE_PREVIEW_LEN).collect();
+        return format!("{}: {}…", send_uname, cut.trim_end());
+    }
+
+    format!("{}: {}", send_uname, line)
+}
+
+// Entry for the given message, if it was sent by send_uname
+fn find<'a>(
+    entries: &'a mut [HistoryEntry],
+    message_id: [u8; MESSAGE_ID_LEN],
+    send_uname: &Username
+) -> Option<&'a mut HistoryEntry> {
+    entries
+        .iter_mut()
+        .find(|entry| entry.message.message_id == message_id)
+        .filter(|entry| &entry.message.send_uname == send_uname)
+}
+
+/**
+What the conversation view shows under a message with reactions, e.g.
+"👍 2  🎉 1", or None if it has none
+*/
+pub fn reaction_label(counts: &[(&str, usize)]) -> Option<String> {
+    if counts.is_empty() {
+        return None;
+    }
+
+    Some(counts.iter().map(|(reaction, count)| format!("{} {}", reaction, count)).join("  "))
+}
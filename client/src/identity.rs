@@ -0,0 +1,104 @@
+/*
+Module - identity
+
+Keeps track of which identity key (see protocol::identity) we trust for each
+of our contacts.
+
+The first key we see for a contact is trusted (trust on first use). If the
+server later hands out a different key, the change is reported rather than
+silently accepted: the old key stays trusted, so messages signed with the new
+one are refused, until the user has compared safety numbers with the contact
+and accepted the new key.
+*/
+
+use std::collections::HashMap;
+
+use protocol::Username;
+use protocol::field_lens::IDENTITY_KEY_LEN;
+use protocol::identity;
+
+/**
+How a contact's identity key compares with the one we already trust
+*/
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum KeyCheck {
+    // first key we've seen for them
+    New,
+    Unchanged,
+    Changed { previous: [u8; IDENTITY_KEY_LEN] },
+}
+
+impl KeyCheck {
+    pub fn compare(trusted: Option<&[u8; IDENTITY_KEY_LEN]>, identity_key: &[u8; IDENTITY_KEY_LEN]) -> Self {
+        match trusted {
+            None => KeyCheck::New,
+            Some(trusted) if trusted == identity_key => KeyCheck::Unchanged,
+            Some(trusted) => KeyCheck::Changed { previous: *trusted },
+        }
+    }
+}
+
+/**
+Changed identity keys that the user has yet to accept
+*/
+#[derive(Debug, Default)]
+pub struct KeyChanges {
+    changed: HashMap<Username, [u8; IDENTITY_KEY_LEN]>,
+}
+
+impl KeyChanges {
+    pub fn new() -> Self {
+        KeyChanges::default()
+    }
+
+    /**
+    Records a contact's new, not yet accepted, key (replacing any earlier one)
+    */
+    pub fn add(&mut self, uname: &Username, identity_key: [u8; IDENTITY_KEY_LEN]) {
+        self.changed.insert(uname.clone(), identity_key);
+    }
+
+    pub fn get(&self, uname: &Username) -> Option<&[u8; IDENTITY_KEY_LEN]> {
+        self.changed.get(uname)
+    }
+
+    /**
+    Removes and returns a contact's new key, once the user has accepted it
+    */
+    pub fn take(&mut self, uname: &Username) -> Option<[u8; IDENTITY_KEY_LEN]> {
+        self.changed.remove(uname)
+    }
+}
+
+/**
+Everything the user needs to verify a contact's identity key out of band
+(see cli::safety for how it's shown)
+*/
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SafetyNumber {
+    pub contact: Username,
+    pub own_fingerprint: String,
+    pub contact_fingerprint: String,
+    pub safety_number: String,
+
+    // the contact has a new key we haven't accepted; the number is for the new key
+    pub key_changed: bool,
+}
+
+impl SafetyNumber {
+    pub fn new(
+        uname: &Username,
+        identity_key: &[u8; IDENTITY_KEY_LEN],
+        contact: &Username,
+        contact_identity_key: &[u8; IDENTITY_KEY_LEN],
+        key_changed: bool
+    ) -> Self {
+        SafetyNumber {
+            contact: contact.clone(),
+            own_fingerprint: identity::fingerprint(identity_key),
+            contact_fingerprint: identity::fingerprint(contact_identity_key),
+            safety_number: identity::safety_number(uname, identity_key, contact, contact_identity_key),
+            key_changed,
+        }
+    }
+}
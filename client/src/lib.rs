@@ -1,4 +1,10 @@
 pub mod storage;
 pub mod cli;
 pub mod comms;
+pub mod delivery;
+pub mod typing;
+pub mod transfer;
+pub mod history;
+pub mod crypto;
+pub mod identity;
 pub mod helpers;
\ No newline at end of file
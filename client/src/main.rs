@@ -1,28 +1,65 @@
-use std::io::{self};
-use std::net::TcpStream;
+/*
+cli_chat client
 
-use client::{storage, comms, cli, helpers};
+The first time it's run, signs up with the server under the username given as
+its argument, creating the .cli_chat directory (see storage) to hold the token
+and identity key the account verifies with. After that the username is read
+from there, so no argument is needed.
 
-fn main() -> io::Result<()> {
-    if !storage::storage::dir_exists() {
-        let username = "snacky";
-        let mut username_bytes = [0u8; 50];
-        username_bytes[..username.len()].copy_from_slice(username.as_bytes());
-        storage::storage::create_cli_chat_dir(username_bytes, protocol::shared::generate_token());
+Once verified, the UI (see cli::app) takes over the connection.
+*/
+
+use std::env;
+use std::error::Error;
+
+use protocol::{IdentityKeyPair, Username};
+
+use client::storage::storage;
+use client::comms::{Connection, TransportConfig};
+use client::cli::app::App;
+
+// where the server listens (see server/src/main.rs)
+const SERVER_ADDR: &str = "127.0.0.1:8081";
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let signing_up = !storage::dir_exists();
+    let uname = if signing_up { uname_from_args()? } else { storage::read_username()? };
+
+    let stream = TransportConfig::from_env()?.connect(SERVER_ADDR)?;
+    let mut connection = Connection::connect(stream, &uname)?;
+    if signing_up {
+        connection.sign_up(IdentityKeyPair::generate())?;
+        while connection.signing_up() {
+            print_notices(connection.handle_message()?);
+        }
+        if !storage::dir_exists() {
+            return Err(format!("couldn't sign up as {}", uname).into());
+        }
+    }
+    storage::init_conn_map();
+
+    connection.verify(&storage::read_token()?)?;
+    while connection.verified().is_none() {
+        print_notices(connection.handle_message()?);
+    }
+    if connection.verified() != Some(true) {
+        return Err("server refused our token".into());
     }
 
-    // cli::app::App::run().unwrap();
-
-    let mut stream = TcpStream::connect("127.0.0.1:8081")?;
-    comms::tests::test_verify_req(stream);
-    // storage::test_get_conn_map();
-    // storage::test_add_conn();
-    // let username = storage::read_username().unwrap();
-    // let digest = helpers::sha256_from_bytes(username.to_vec());
-    // println!("{}", digest);
-    // storage::storage::test_singelton_map();
-    // storage::storage::test_read_message();
-    // storage::storage_tests::tests::test_read_message();
-    
+    App::run(connection)?;
     Ok(())
 }
+
+fn uname_from_args() -> Result<Username, Box<dyn Error>> {
+    let Some(uname) = env::args().nth(1) else {
+        return Err("usage: client <username> (needed the first time, to sign up)".into());
+    };
+    Ok(Username::new(&uname)?)
+}
+
+// Until the UI takes over, what comms tells us is printed as it comes
+fn print_notices(notices: Vec<String>) {
+    for notice in notices {
+        println!("{}", notice);
+    }
+}
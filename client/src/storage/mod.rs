@@ -1,3 +1,5 @@
+#[allow(clippy::module_inception)]
 pub mod storage;
 pub mod storage_tests;
-pub mod conn_map;
\ No newline at end of file
+pub mod conn_map;
+pub mod presence_map;
\ No newline at end of file
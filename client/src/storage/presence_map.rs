@@ -0,0 +1,44 @@
+/*
+Defines a singleton hashmap to store the last known presence of each
+connection, by username. It's kept in memory only, as the server sends
+it afresh every time we verify.
+*/
+
+
+use lazy_static::lazy_static;
+use std::sync::Mutex;
+use std::collections::HashMap;
+
+use protocol::PresenceState;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PresenceEntry {
+    pub state: PresenceState,
+    pub status: String,
+}
+
+pub struct PresenceMap {
+    pub presence_map: HashMap<String, PresenceEntry>,
+}
+
+lazy_static! {
+    static ref MODULE_DATA: Mutex<PresenceMap> = Mutex::new(PresenceMap {
+        presence_map: HashMap::new(),
+    });
+}
+
+pub fn get_map() -> HashMap<String, PresenceEntry> {
+    MODULE_DATA.lock().unwrap().presence_map.clone()
+}
+
+pub fn get(key: &str) -> Option<PresenceEntry> {
+    MODULE_DATA.lock().unwrap().presence_map.get(key).cloned()
+}
+
+pub fn insert(key: String, value: PresenceEntry) {
+    MODULE_DATA.lock().unwrap().presence_map.insert(key, value);
+}
+
+pub fn remove(key: &str) {
+    MODULE_DATA.lock().unwrap().presence_map.remove(key);
+}
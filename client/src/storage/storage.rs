@@ -1,4 +1,4 @@
-/**
+/*
 Module - storage
 
 Handles storing and retreiving of locally stored user data.
@@ -13,16 +13,49 @@ The directory structure looks like:
         | connection-list
         | connections
             conn1
+            edits1
+            deletions1
+            reactions1
             conn2
             ...
+        | groups-list
+        | groups
+            group_G1
+            group_G2
+            ...
+        | attachments
+            conn1
+                file1
+                .T.part
+                ...
+            ...
+        | transfers
+            T1
+            T2
+            ...
+        | keys
+            identity
+            conn1
+            conn1.pending
+            conn1.identity
+            group_G1
+            ...
+
+username:
+    - the user's own (validated) username, as plain text
+
+token:
+    - the PAT token this client verifies with (32 bytes)
+    - replaced atomically (written to token.new, then renamed over token) when
+      the token is rotated, so a crash never leaves a partly written token
 
 connection-list:
     - stores usernames for each valid connection
     - from this, construct HashMap<String, X>
         where X is an id used to uniquely identify connection file (conn_X)
-    - format:
-        {conn_uname_1} (50 bytes)
-        {conn_uname_2} (50 bytes)
+    - format (usernames never contain newlines):
+        {conn_uname_1}\n
+        {conn_uname_2}\n
         ...
 
 connX:
@@ -30,42 +63,148 @@ connX:
     - the format is as follows:
         {Message 1}
         Magic bytes (4 bytes)
-        msg_length (4 bytes)
-        send_uname (50 bytes)
-        msg_buffer (variable)
-        \n
+        record_length (4 bytes)
+        serialized ChatMessage (record_length bytes):
+            msg_length (4 bytes)
+            message_id (16 bytes)
+            timestamp (8 bytes)
+            signature (64 bytes)
+            send_uname (length-prefixed, variable)
+            recv_uname (length-prefixed, variable)
+            msg_buffer (variable)
+            reply flag (1 byte)
+            in_reply_to (16 bytes, replies only)
         {Message 2}
         ...
+    - messages are stored decrypted (see keys, below)
+    - messages fetched from the server (see comms::sync_history) are merged in
+      at the end, skipping any already there
+    - records are only ever appended: edits, deletions and reactions are kept
+      alongside, in editsX, deletionsX and reactionsX, and applied when the
+      conversation is read (see read_history)
+
+editsX:
+    - every edit of a message in connX (ours or X's), in the order they were
+      sent or received, in the same format as connX; each record is a
+      serialized MessageEdit (stored decrypted), so a message's earlier text
+      is never lost
+
+deletionsX:
+    - a tombstone for each message in connX that has been deleted, in the same
+      format as connX; each record is a serialized MessageDelete
+
+reactionsX:
+    - every reaction added to or removed from a message in connX, by either
+      of us, in the same format as connX; each record is a serialized Reaction
+
+groups-list:
+    - the groups we've been in, one per line, as
+        {group id, in hex} {group name}\n
+      (group names never contain control characters)
+
+groups/group_G:
+    - all messages for group G (its id, in hex), in the same format as connX,
+      each record being a serialized GroupMessage (stored decrypted)
+
+attachments/connX:
+    - files received from connection X, under the names they were sent with
+      (with a number added if a file of that name has already been received)
+    - .T.part holds the chunks received so far of transfer T (its id, in hex),
+      in order; it is renamed once the whole file has arrived and checked out
+
+transfers/T:
+    - an unfinished file transfer T (its id, in hex), in either direction
+      (see transfer.rs); removed once the transfer completes or is declined
+    - format (file names never contain control characters):
+        {in|out}\n
+        {peer username}\n
+        {file size}\n
+        {SHA-256 digest, in hex}\n
+        {file name}\n
+        {path of file being sent (outgoing only)}\n
+
+keys/connX:
+    - the conversation keys agreed with connection X (see crypto.rs),
+      oldest first; the newest is used to encrypt, and any can decrypt
+    - format, repeated for each key:
+        key_id (4 bytes)
+        key (32 bytes)
+
+keys/group_G:
+    - the keys for group G's messages, in the same format as keys/connX; the
+      group's creator generates the first, and members receive it when invited
+
+keys/identity:
+    - the secret half of our own identity key pair (see protocol::identity),
+      generated at signup
+    - 32 bytes
+
+keys/connX.identity:
+    - connection X's identity key, as first seen (trust on first use); it is
+      only replaced once the user has accepted a changed key
+    - 32 bytes
+
+keys/connX.pending:
+    - our X25519 secret for a connection request we've sent to X, kept until
+      X's response (and public key) arrives
+    - 32 bytes
+
+Files under keys are only readable by the user (on unix).
 */
 
+use std::collections::HashSet;
 use std::fs::{self, File, OpenOptions};
 use home::home_dir;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::io::{self, Read, Write, BufRead};
-use protocol::{self, field_lens, ChatMessage};
+#[cfg(unix)]
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use protocol::{self, field_lens, ChatMessage, GroupMessage, Message, Username, IdentityKeyPair};
+use protocol::{MessageEdit, MessageDelete, Reaction};
+use protocol::shared;
+use protocol::field_lens::{KEY_ID_LEN, IDENTITY_KEY_LEN, GROUP_ID_LEN, TRANSFER_ID_LEN};
+use protocol::identity::IDENTITY_SECRET_LEN;
 use super::conn_map;
+use crate::crypto::{ConversationKey, KeyPair, KEY_LEN};
+use crate::transfer::{Direction, Transfer};
+use crate::history::{self, HistoryEntry};
 
 pub const ROOT_DIR_NAME: &str = ".cli_chat";
 pub const TOKEN_FN: &str = "token";
+pub const NEW_TOKEN_FN: &str = "token.new";
 pub const UNAME_FN: &str = "username";
 pub const CONN_LIST_FN: &str = "connections-list";
 pub const CONN_DIR_NAME: &str = "connections";
 pub const CONN_FILE_PREFIX: &str = "conn";
+pub const EDITS_FILE_PREFIX: &str = "edits";
+pub const DELETIONS_FILE_PREFIX: &str = "deletions";
+pub const REACTIONS_FILE_PREFIX: &str = "reactions";
+pub const KEYS_DIR_NAME: &str = "keys";
+pub const PENDING_KEY_SUFFIX: &str = "pending";
+pub const IDENTITY_FN: &str = "identity";
+pub const IDENTITY_KEY_SUFFIX: &str = "identity";
+pub const GROUP_LIST_FN: &str = "groups-list";
+pub const GROUP_DIR_NAME: &str = "groups";
+pub const GROUP_FILE_PREFIX: &str = "group";
+pub const ATTACHMENTS_DIR_NAME: &str = "attachments";
+pub const TRANSFERS_DIR_NAME: &str = "transfers";
+pub const PARTIAL_FILE_SUFFIX: &str = "part";
 
 pub const NUM_MAGIC_BYTES: usize = 4;
 pub const MAGIC_BYTES: [u8; NUM_MAGIC_BYTES] = [114, 97, 99, 107];
+pub const RECORD_LEN_LEN: usize = 4;
 
 /**
 Returns path of cli_chat root directory
 */
 fn get_root_dir() -> Option<PathBuf> {
     match home_dir() {
-        Some (mut home) => {
+        Some (home) => {
             Some(home.join(ROOT_DIR_NAME))
         }
         None => {
             eprintln!("Unable to determine user's home directory.");
-            return None;
+            None
         }
     }
 }
@@ -75,14 +214,14 @@ Checks for the existence of the client's '.cli_chat' directory
 */
 pub fn dir_exists() -> bool {
     let dir_path = get_root_dir().unwrap();
-    return dir_path.exists() && dir_path.is_dir();
+    dir_path.exists() && dir_path.is_dir()
 }
 
 /**
 Creates a fresh '.cli_chat' directory for a new user.
 */
-pub fn create_cli_chat_dir(uname: [u8; field_lens::UNAME_LEN], 
-    token: [u8; field_lens::TOKEN_LEN]) -> Option<PathBuf> {
+pub fn create_cli_chat_dir(uname: &Username, 
+    token: [u8; field_lens::TOKEN_LEN], identity: &IdentityKeyPair) -> Option<PathBuf> {
 
     let dir_path = get_root_dir()?;
 
@@ -93,23 +232,29 @@ pub fn create_cli_chat_dir(uname: [u8; field_lens::UNAME_LEN],
     }
 
     // create token file
-    let mut token_file = create_cli_chat_file(dir_path.clone(), TOKEN_FN);
+    let token_file = create_cli_chat_file(dir_path.clone(), TOKEN_FN);
     if token_file.is_none() {
         println!("Error creating token file");
         return None;
     }
-    token_file.unwrap().write(&token);
+    if let Err(err) = token_file.unwrap().write_all(&token) {
+        eprintln!("Error writing token file: {}", err);
+        return None;
+    }
 
     // create username file
-    let mut uname_file = create_cli_chat_file(dir_path.clone(), UNAME_FN);
+    let uname_file = create_cli_chat_file(dir_path.clone(), UNAME_FN);
     if uname_file.is_none() {
         println!("Error creating username file");
         return None;
     }
-    uname_file.unwrap().write(&uname);
+    if let Err(err) = uname_file.unwrap().write_all(uname.as_str().as_bytes()) {
+        eprintln!("Error writing username file: {}", err);
+        return None;
+    }
 
     // create connections-list file
-    let mut conn_list_file = create_cli_chat_file(dir_path.clone(), CONN_LIST_FN);
+    let conn_list_file = create_cli_chat_file(dir_path.clone(), CONN_LIST_FN);
     if conn_list_file.is_none() {
         println!("Error creating connections-list file");
         return None;
@@ -122,7 +267,19 @@ pub fn create_cli_chat_dir(uname: [u8; field_lens::UNAME_LEN],
         return None;
     }
 
-    return Some(dir_path);
+    // create keys directory
+    if let Err(err) = get_keys_dir() {
+        eprintln!("Error creating {} directory: {}", KEYS_DIR_NAME, err);
+        return None;
+    }
+
+    // create identity file
+    if let Err(err) = write_identity(identity) {
+        eprintln!("Error writing identity file: {}", err);
+        return None;
+    }
+
+    Some(dir_path)
 }
 
 fn create_cli_chat_file(base_path: PathBuf, name: &str) -> Option<File> {
@@ -132,6 +289,7 @@ fn create_cli_chat_file(base_path: PathBuf, name: &str) -> Option<File> {
         .read(true)
         .write(true)
         .create(true)
+        .truncate(false)
         .open(&path)
     {
         Err(err) => {
@@ -145,9 +303,8 @@ fn create_cli_chat_file(base_path: PathBuf, name: &str) -> Option<File> {
 fn open_cli_chat_file(name: &str) -> Option<File> {
     let cli_root = get_root_dir().unwrap();
     let path = cli_root.join(name);
-    let mut cli_chat_file = OpenOptions::new()
+    let cli_chat_file = OpenOptions::new()
         .read(true)
-        .write(true)
         .append(true)
         .create(true)
         .open(&path);
@@ -155,8 +312,7 @@ fn open_cli_chat_file(name: &str) -> Option<File> {
     match cli_chat_file {
         Err(err) => {
             eprintln!("Error opening {} file: {}", name, err);
-            return None;
-
+            None
         }
         Ok(file) => Some(file)
     }
@@ -165,12 +321,12 @@ fn open_cli_chat_file(name: &str) -> Option<File> {
 /**
 Reads username from .cli_chat/username
 */
-pub fn read_username() -> io::Result<[u8; field_lens::UNAME_LEN]> {
+pub fn read_username() -> io::Result<Username> {
     let mut uname_file = open_cli_chat_file(UNAME_FN).unwrap();
-    let mut uname_buffer = [0u8; field_lens::UNAME_LEN];
-    uname_file.read(&mut uname_buffer);
+    let mut uname = String::new();
+    uname_file.read_to_string(&mut uname)?;
 
-    Ok(uname_buffer)
+    Username::new(&uname).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
 }
 
 /**
@@ -179,20 +335,45 @@ Reads token from .cli_chat/token
 pub fn read_token() -> io::Result<[u8; field_lens::TOKEN_LEN]> {
     let mut token_file = open_cli_chat_file(TOKEN_FN).unwrap();
     let mut token_buffer = [0u8; field_lens::TOKEN_LEN];
-    token_file.read(&mut token_buffer);
+    token_file.read_exact(&mut token_buffer)?;
 
     Ok(token_buffer)
 }
 
+/**
+Replaces the token in .cli_chat/token with a newly rotated one.
+
+The new token is written and synced to a separate file first, then renamed
+over the old one, so the token file always holds one whole token or the other.
+*/
+pub fn write_token(token: &[u8; field_lens::TOKEN_LEN]) -> io::Result<()> {
+    let cli_root = get_root_dir()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no home directory"))?;
+    let new_path = cli_root.join(NEW_TOKEN_FN);
+
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut new_file = options.open(&new_path)?;
+    new_file.write_all(token)?;
+    new_file.sync_all()?;
+
+    fs::rename(&new_path, cli_root.join(TOKEN_FN))
+}
+
 /**
 Initialises the connections map from the 'connections-list' file
 */
 pub fn init_conn_map() {
-    let mut conn_list_file = open_cli_chat_file(CONN_LIST_FN).unwrap();
+    let conn_list_file = open_cli_chat_file(CONN_LIST_FN).unwrap();
     let reader = io::BufReader::new(conn_list_file);
-    for (i, line) in reader.lines().enumerate() {
-        let uname = line.unwrap();
-        conn_map::insert(uname.clone(), uname.clone());
+    for line in reader.lines() {
+        let line = line.unwrap();
+        match Username::new(&line) {
+            Ok(uname) => conn_map::insert(uname.to_string(), uname.to_string()),
+            Err(e) => println!("Skipping invalid connection '{}': {}", line, e),
+        }
     }
 }
 
@@ -204,69 +385,193 @@ Adds a new connection
 
 NOTE: - here, we assume adding this connection is valid
 */
-pub fn add_new_connection(uname: String) -> Result<(), io::Error> {
+pub fn add_new_connection(uname: &Username) -> Result<(), io::Error> {
     
     // add to 'connections-list'
     let mut conn_list_file = open_cli_chat_file(CONN_LIST_FN).unwrap();
-    writeln!(conn_list_file, "{}", &uname);
+    writeln!(conn_list_file, "{}", uname)?;
 
     // create connections/connX file
     let base_path = get_root_dir().unwrap();
     create_cli_chat_file(base_path.join(CONN_DIR_NAME), 
-        &get_conn_file_name(uname.clone()));
+        &get_conn_file_name(uname));
 
     // add entry to connections map
-    conn_map::insert(uname.clone(), uname.clone());
+    conn_map::insert(uname.to_string(), uname.to_string());
 
     Ok(())
 }
 
-fn get_conn_file_name(uname: String) -> String {
-    return format!("{}_{}", CONN_FILE_PREFIX, uname);
+// NOTE: safe to use as a file name, as usernames can't contain path characters
+fn get_conn_file_name(uname: &Username) -> String {
+    format!("{}_{}", CONN_FILE_PREFIX, uname)
 }
 
-fn get_conn_file_path(uname: String) -> PathBuf {
+fn get_conn_file_path(uname: &Username) -> PathBuf {
     let base_path = get_root_dir().unwrap();
     let file_name = get_conn_file_name(uname);
-    return base_path.join(CONN_DIR_NAME).join(file_name);
+    base_path.join(CONN_DIR_NAME).join(file_name)
 }
 
 /**
 Writes given message to corresponding connX file
 */
-pub fn write_message(chat_message: ChatMessage, conn_uname: String) -> Option<usize> {
-    let file_path = get_conn_file_path(conn_uname);
-    let mut file = OpenOptions::new()
-        .append(true)
-        .open(file_path).unwrap();
+pub fn write_message(chat_message: ChatMessage, conn_uname: &Username) -> io::Result<()> {
+    write_record(&get_conn_file_path(conn_uname), &chat_message)
+}
 
-    // write magic bytes
-    let mut bytes_written = file.write(&MAGIC_BYTES).unwrap();
-    if bytes_written != NUM_MAGIC_BYTES {
-        println!("Error writting message: magic bytes");
-        return None;
+/**
+Reads all messages from connX file into list
+*/
+pub fn read_messages(uname: &Username) -> io::Result<Vec<ChatMessage>> {
+    read_records(&get_conn_file_path(uname), ChatMessage::fixed_size())
+}
+
+/**
+Adds messages (e.g. fetched from the server) to the corresponding connX file,
+skipping any it already has. Each goes in after the last message sent no
+later than it, so messages fetched in the server's order keep that order, and
+ones fetched from earlier in the conversation go in before those sent since.
+
+Returns how many were added.
+*/
+pub fn merge_messages(messages: Vec<ChatMessage>, conn_uname: &Username) -> io::Result<usize> {
+    let mut merged = read_messages(conn_uname)?;
+    let mut message_ids: HashSet<_> = merged
+        .iter()
+        .map(ChatMessage::message_id)
+        .collect();
+
+    let mut added = 0;
+    for chat_message in messages {
+        if !message_ids.insert(chat_message.message_id()) {
+            continue;
+        }
+        let pos = merged
+            .iter()
+            .rposition(|earlier| earlier.timestamp() <= chat_message.timestamp())
+            .map_or(0, |pos| pos + 1);
+        merged.insert(pos, chat_message);
+        added += 1;
     }
 
-    // write message
-    let ser_chat_message = chat_message.serialize();
-    bytes_written += file.write(&ser_chat_message).unwrap();
-    if bytes_written != chat_message.length() + NUM_MAGIC_BYTES {
-        println!("Error writting message: message");
-        return None;
+    if added > 0 {
+        rewrite_records(&get_conn_file_path(conn_uname), &merged)?;
     }
-    return Some(bytes_written);
+    Ok(added)
 }
 
 /**
-Reads all messages from connX file into list
+Writes an edit of one of the messages in the corresponding connX file
 */
-pub fn read_messages(uname: String) -> Option<Vec<ChatMessage>> {
-    let file_path = get_conn_file_path(uname);
-    let file = OpenOptions::new()
-        .read(true)
-        .open(file_path).unwrap();
+pub fn write_edit(message_edit: &MessageEdit, conn_uname: &Username) -> io::Result<()> {
+    write_record(&get_conn_side_file_path(conn_uname, EDITS_FILE_PREFIX), message_edit)
+}
+
+/**
+Reads all edits of messages in connX, from editsX (empty if there are none)
+*/
+pub fn read_edits(uname: &Username) -> io::Result<Vec<MessageEdit>> {
+    let file_path = get_conn_side_file_path(uname, EDITS_FILE_PREFIX);
+    if !file_path.exists() {
+        return Ok(Vec::new());
+    }
+    read_records(&file_path, MessageEdit::fixed_size())
+}
+
+/**
+Writes a tombstone for one of the messages in the corresponding connX file
+*/
+pub fn write_deletion(message_delete: &MessageDelete, conn_uname: &Username) -> io::Result<()> {
+    write_record(&get_conn_side_file_path(conn_uname, DELETIONS_FILE_PREFIX), message_delete)
+}
+
+/**
+Reads all tombstones for messages in connX, from deletionsX (empty if there are none)
+*/
+pub fn read_deletions(uname: &Username) -> io::Result<Vec<MessageDelete>> {
+    let file_path = get_conn_side_file_path(uname, DELETIONS_FILE_PREFIX);
+    if !file_path.exists() {
+        return Ok(Vec::new());
+    }
+    read_records(&file_path, MessageDelete::fixed_size())
+}
+
+/**
+Writes an added or removed reaction to one of the messages in the
+corresponding connX file
+*/
+pub fn write_reaction(reaction: &Reaction, conn_uname: &Username) -> io::Result<()> {
+    write_record(&get_conn_side_file_path(conn_uname, REACTIONS_FILE_PREFIX), reaction)
+}
+
+/**
+Reads all reactions to messages in connX, from reactionsX (empty if there are none)
+*/
+pub fn read_reactions(uname: &Username) -> io::Result<Vec<Reaction>> {
+    let file_path = get_conn_side_file_path(uname, REACTIONS_FILE_PREFIX);
+    if !file_path.exists() {
+        return Ok(Vec::new());
+    }
+    read_records(&file_path, Reaction::fixed_size())
+}
+
+/**
+Reads our conversation with a connection as it reads now, with edits,
+deletions and reactions applied (see history.rs)
+*/
+pub fn read_history(uname: &Username) -> io::Result<Vec<HistoryEntry>> {
+    Ok(history::apply(read_messages(uname)?, read_edits(uname)?, read_deletions(uname)?, read_reactions(uname)?))
+}
+
+// editsX, deletionsX or reactionsX, next to connX (the prefixes keep them apart, whatever the username)
+fn get_conn_side_file_path(uname: &Username, prefix: &str) -> PathBuf {
+    let base_path = get_root_dir().unwrap();
+    base_path.join(CONN_DIR_NAME).join(format!("{}_{}", prefix, uname))
+}
+
+// Appends a message to a connX/group_G file (or one alongside), as a record:
+// magic bytes, record length, message
+fn write_record<M: Message>(file_path: &Path, message: &M) -> io::Result<()> {
+    // written in one go, so a record is never left half-written alongside others
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(file_path)?;
+    file.write_all(&record(message))
+}
+
+// Replaces the records in a connX/group_G file with messages. They're written
+// to a new file that's swapped in once complete, so a crash part-way through
+// leaves the old records as they were. ('+' can't be in a username, so the new
+// file can't be another user's.)
+fn rewrite_records<M: Message>(file_path: &Path, messages: &[M]) -> io::Result<()> {
+    let mut new_path = file_path.as_os_str().to_owned();
+    new_path.push("+new");
+    let new_path = PathBuf::from(new_path);
+    let mut new_file = File::create(&new_path)?;
+    new_file.write_all(&messages.iter().flat_map(record).collect::<Vec<u8>>())?;
+    new_file.sync_all()?;
+    fs::rename(&new_path, file_path)
+}
+
+// A message as a record: magic bytes, then its length, then the message
+fn record<M: Message>(message: &M) -> Vec<u8> {
+    let ser_message = message.serialize();
+    let mut record = Vec::with_capacity(NUM_MAGIC_BYTES + RECORD_LEN_LEN + ser_message.len());
+    record.extend_from_slice(&MAGIC_BYTES);
+    record.extend_from_slice(&(ser_message.len() as u32).to_be_bytes());
+    record.extend_from_slice(&ser_message);
+    record
+}
+
+// Reads all records from a connX/group_G file; none is shorter than min_len.
+// A record cut short at the end of the file (e.g. by a crash while it was
+// being written) is left out.
+fn read_records<M: Message>(file_path: &Path, min_len: usize) -> io::Result<Vec<M>> {
+    let file = File::open(file_path)?;
     let mut file_reader = io::BufReader::new(file);
-    let mut messages: Vec<ChatMessage> = Vec::new();
+    let mut messages: Vec<M> = Vec::new();
 
     loop {
         // read magic bytes
@@ -274,38 +579,467 @@ pub fn read_messages(uname: String) -> Option<Vec<ChatMessage>> {
         match file_reader.read_exact(&mut magic_bytes_buffer) {
             Ok(_) => {}
             Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => {break;} // eof
-            Err(e) => {return None;}
+            Err(e) => {return Err(e);}
         }
         if !magic_bytes_buffer.iter().eq(MAGIC_BYTES.iter()) {
-            println!("Error reading message: magic bytes");
-            return None;
+            return Err(corrupt(file_path, "bad magic bytes"));
         }
 
-        // read length field
-        let mut length_buffer = [0u8; field_lens::MSGLEN_LEN];
+        // read record length field
+        let mut length_buffer = [0u8; RECORD_LEN_LEN];
         match file_reader.read_exact(&mut length_buffer) {
             Ok(_) => {}
             Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => {break;} // eof
-            Err(e) => {return None;}
+            Err(e) => {return Err(e);}
         }
-        let msg_length: u32 = u32::from_be_bytes(length_buffer);
-        if (msg_length as usize) == 0 {
-            println!("Error reading message: length");
-            return None;
+        let record_length = u32::from_be_bytes(length_buffer) as usize;
+        if record_length < min_len || record_length > field_lens::MAX_MESSAGE_LEN {
+            return Err(corrupt(file_path, "bad record length"));
         }
 
         // read message
-        let chat_message_length = ChatMessage::fixed_size() + (msg_length as usize);
-        let mut message_buffer = Vec::new();
-        message_buffer.extend_from_slice(&length_buffer);
-        message_buffer.resize(chat_message_length, 0);
-        match file_reader.read_exact(&mut message_buffer[field_lens::MSGLEN_LEN..]) {
+        let mut message_buffer = vec![0u8; record_length];
+        match file_reader.read_exact(&mut message_buffer) {
             Ok(_) => {}
             Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => {break;} // eof
-            Err(e) => {return None;}
+            Err(e) => {return Err(e);}
+        }
+        let message = M::deserialize(&message_buffer).map_err(|e| corrupt(file_path, &e.to_string()))?;
+        messages.push(message);
+    }
+    Ok(messages)
+}
+
+fn corrupt(file_path: &Path, reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{} is corrupt: {}", file_path.display(), reason))
+}
+
+/**
+Returns path of the keys directory, creating it if needed (directories
+created before encryption was added won't have one)
+*/
+fn get_keys_dir() -> io::Result<PathBuf> {
+    let keys_path = get_root_dir()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no home directory"))?
+        .join(KEYS_DIR_NAME);
+
+    let mut builder = fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    builder.mode(0o700);
+    builder.create(&keys_path)?;
+
+    Ok(keys_path)
+}
+
+// Opens a file under keys, readable only by the user
+fn open_key_file(name: &str, options: &mut OpenOptions) -> io::Result<File> {
+    #[cfg(unix)]
+    options.mode(0o600);
+    options.open(get_keys_dir()?.join(name))
+}
+
+fn get_pending_key_file_name(uname: &Username) -> String {
+    format!("{}.{}", get_conn_file_name(uname), PENDING_KEY_SUFFIX)
+}
+
+/**
+Appends a newly agreed conversation key for the given connection
+*/
+pub fn add_conversation_key(uname: &Username, key: &ConversationKey) -> io::Result<()> {
+    append_key(&get_conn_file_name(uname), key)
+}
+
+/**
+Reads all conversation keys for the given connection, oldest first. No keys
+(e.g. a connection made before encryption was added) is an empty list.
+*/
+pub fn read_conversation_keys(uname: &Username) -> io::Result<Vec<ConversationKey>> {
+    read_keys(&get_conn_file_name(uname))
+}
+
+fn append_key(file_name: &str, key: &ConversationKey) -> io::Result<()> {
+    let mut record = Vec::with_capacity(KEY_ID_LEN + KEY_LEN);
+    record.extend_from_slice(&key.key_id().to_be_bytes());
+    record.extend_from_slice(key.key_bytes());
+
+    let mut file = open_key_file(file_name, OpenOptions::new().append(true).create(true))?;
+    file.write_all(&record)
+}
+
+fn read_keys(file_name: &str) -> io::Result<Vec<ConversationKey>> {
+    let mut bytes = Vec::new();
+    match open_key_file(file_name, OpenOptions::new().read(true)) {
+        Ok(mut file) => file.read_to_end(&mut bytes)?,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    if bytes.len() % (KEY_ID_LEN + KEY_LEN) != 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "truncated key file"));
+    }
+
+    let keys = bytes
+        .chunks_exact(KEY_ID_LEN + KEY_LEN)
+        .map(|record| {
+            let (key_id, key) = record.split_at(KEY_ID_LEN);
+            ConversationKey::new(
+                u32::from_be_bytes(key_id.try_into().unwrap()),
+                key.try_into().unwrap()
+            )
+        })
+        .collect();
+    Ok(keys)
+}
+
+/**
+Stores our secret for a connection request to uname, replacing any earlier one
+*/
+pub fn write_pending_secret(uname: &Username, key_pair: &KeyPair) -> io::Result<()> {
+    let mut file = open_key_file(
+        &get_pending_key_file_name(uname),
+        OpenOptions::new().write(true).create(true).truncate(true)
+    )?;
+    file.write_all(&key_pair.secret_bytes())
+}
+
+/**
+Removes and returns our secret for a connection request to uname, if we sent one
+*/
+pub fn take_pending_secret(uname: &Username) -> io::Result<Option<KeyPair>> {
+    let file_name = get_pending_key_file_name(uname);
+    let mut secret = [0u8; KEY_LEN];
+    match open_key_file(&file_name, OpenOptions::new().read(true)) {
+        Ok(mut file) => file.read_exact(&mut secret)?,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    fs::remove_file(get_keys_dir()?.join(file_name))?;
+
+    Ok(Some(KeyPair::from_secret_bytes(secret)))
+}
+
+/**
+Stores our own identity key pair
+*/
+pub fn write_identity(identity: &IdentityKeyPair) -> io::Result<()> {
+    let mut file = open_key_file(IDENTITY_FN, OpenOptions::new().write(true).create(true).truncate(true))?;
+    file.write_all(&identity.secret_bytes())
+}
+
+/**
+Reads our own identity key pair from .cli_chat/keys/identity
+*/
+pub fn read_identity() -> io::Result<IdentityKeyPair> {
+    let mut secret = [0u8; IDENTITY_SECRET_LEN];
+    open_key_file(IDENTITY_FN, OpenOptions::new().read(true))?.read_exact(&mut secret)?;
+
+    Ok(IdentityKeyPair::from_secret_bytes(secret))
+}
+
+fn get_contact_identity_file_name(uname: &Username) -> String {
+    format!("{}.{}", get_conn_file_name(uname), IDENTITY_KEY_SUFFIX)
+}
+
+/**
+Stores the identity key we trust for the given user, replacing any earlier one
+*/
+pub fn write_contact_identity(uname: &Username, identity_key: &[u8; IDENTITY_KEY_LEN]) -> io::Result<()> {
+    let mut file = open_key_file(
+        &get_contact_identity_file_name(uname),
+        OpenOptions::new().write(true).create(true).truncate(true)
+    )?;
+    file.write_all(identity_key)
+}
+
+/**
+Reads the identity key we trust for the given user, if we've seen one
+*/
+pub fn read_contact_identity(uname: &Username) -> io::Result<Option<[u8; IDENTITY_KEY_LEN]>> {
+    let mut identity_key = [0u8; IDENTITY_KEY_LEN];
+    match open_key_file(&get_contact_identity_file_name(uname), OpenOptions::new().read(true)) {
+        Ok(mut file) => file.read_exact(&mut identity_key)?,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    }
+
+    Ok(Some(identity_key))
+}
+
+// NOTE: group ids are written in hex, so are safe to use in file names
+fn get_group_file_name(group_id: [u8; GROUP_ID_LEN]) -> String {
+    format!("{}_{}", GROUP_FILE_PREFIX, shared::group_id_to_string(group_id))
+}
+
+/**
+Returns path of the group_G file for the given group, creating it (and the
+groups directory) if needed
+*/
+fn get_group_file_path(group_id: [u8; GROUP_ID_LEN]) -> io::Result<PathBuf> {
+    let group_dir = get_root_dir()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no home directory"))?
+        .join(GROUP_DIR_NAME);
+    fs::create_dir_all(&group_dir)?;
+
+    let file_path = group_dir.join(get_group_file_name(group_id));
+    OpenOptions::new().append(true).create(true).open(&file_path)?;
+    Ok(file_path)
+}
+
+/**
+Adds a group to 'groups-list', if it isn't there already
+*/
+pub fn add_group(group_id: [u8; GROUP_ID_LEN], group_name: &str) -> io::Result<()> {
+    if read_groups()?.iter().any(|(id, _)| *id == group_id) {
+        return Ok(());
+    }
+
+    let mut group_list_file = open_cli_chat_file(GROUP_LIST_FN)
+        .ok_or_else(|| io::Error::other("can't open groups-list"))?;
+    writeln!(group_list_file, "{} {}", shared::group_id_to_string(group_id), group_name)
+}
+
+/**
+Reads the (group id, group name) of every group in 'groups-list'
+*/
+pub fn read_groups() -> io::Result<Vec<([u8; GROUP_ID_LEN], String)>> {
+    let group_list_file = open_cli_chat_file(GROUP_LIST_FN)
+        .ok_or_else(|| io::Error::other("can't open groups-list"))?;
+
+    let mut groups = Vec::new();
+    for line in io::BufReader::new(group_list_file).lines() {
+        let line = line?;
+        match line.split_once(' ').and_then(|(id, name)| Some((parse_group_id(id)?, name))) {
+            Some((group_id, group_name)) => groups.push((group_id, group_name.to_string())),
+            None => println!("Skipping invalid group '{}'", line),
         }
-        let chat_message = ChatMessage::deserialize(&message_buffer).unwrap();
-        messages.push(chat_message);
     }
-    return Some(messages);
-}
\ No newline at end of file
+    Ok(groups)
+}
+
+fn parse_group_id(hex: &str) -> Option<[u8; GROUP_ID_LEN]> {
+    if hex.len() != GROUP_ID_LEN * 2 || !hex.is_ascii() {
+        return None;
+    }
+
+    let mut group_id = [0u8; GROUP_ID_LEN];
+    for (i, byte) in group_id.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).ok()?;
+    }
+    Some(group_id)
+}
+
+/**
+Writes given message to its group's group_G file
+*/
+pub fn write_group_message(group_message: &GroupMessage) -> io::Result<()> {
+    write_record(&get_group_file_path(group_message.group_id)?, group_message)
+}
+
+/**
+Reads all messages from a group's group_G file into list
+*/
+pub fn read_group_messages(group_id: [u8; GROUP_ID_LEN]) -> io::Result<Vec<GroupMessage>> {
+    read_records(&get_group_file_path(group_id)?, GroupMessage::fixed_size())
+}
+
+/**
+Appends a key for the given group's messages
+*/
+pub fn add_group_key(group_id: [u8; GROUP_ID_LEN], key: &ConversationKey) -> io::Result<()> {
+    append_key(&get_group_file_name(group_id), key)
+}
+
+/**
+Reads all keys for the given group's messages, oldest first (empty if we
+have none, i.e. aren't in the group)
+*/
+pub fn read_group_keys(group_id: [u8; GROUP_ID_LEN]) -> io::Result<Vec<ConversationKey>> {
+    read_keys(&get_group_file_name(group_id))
+}
+
+/**
+Returns path of the transfers directory, creating it if needed
+*/
+fn get_transfers_dir() -> io::Result<PathBuf> {
+    let transfers_dir = get_root_dir()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no home directory"))?
+        .join(TRANSFERS_DIR_NAME);
+    fs::create_dir_all(&transfers_dir)?;
+    Ok(transfers_dir)
+}
+
+/**
+Returns path of the attachments folder for files from uname, creating it if needed
+*/
+pub fn get_attachments_dir(uname: &Username) -> io::Result<PathBuf> {
+    let attachments_dir = get_root_dir()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no home directory"))?
+        .join(ATTACHMENTS_DIR_NAME)
+        .join(get_conn_file_name(uname));
+    fs::create_dir_all(&attachments_dir)?;
+    Ok(attachments_dir)
+}
+
+/**
+Records an unfinished transfer in transfers/T, replacing any earlier record of it
+*/
+pub fn write_transfer(transfer: &Transfer) -> io::Result<()> {
+    let direction = match transfer.direction {
+        Direction::Outgoing => "out",
+        Direction::Incoming => "in",
+    };
+    let mut record = format!(
+        "{}\n{}\n{}\n{}\n{}\n",
+        direction,
+        transfer.peer,
+        transfer.file_size,
+        transfer.digest,
+        transfer.file_name
+    );
+    if let Some(source) = &transfer.source {
+        let source = source.to_str()
+            .filter(|source| !source.contains('\n'))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "file path can't be recorded"))?;
+        record.push_str(source);
+        record.push('\n');
+    }
+
+    fs::write(get_transfers_dir()?.join(shared::transfer_id_to_string(transfer.transfer_id)), record)
+}
+
+/**
+Reads the record of a transfer, if it's unfinished
+*/
+pub fn read_transfer(transfer_id: [u8; TRANSFER_ID_LEN]) -> io::Result<Option<Transfer>> {
+    let path = get_transfers_dir()?.join(shared::transfer_id_to_string(transfer_id));
+    let record = match fs::read_to_string(path) {
+        Ok(record) => record,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+
+    parse_transfer(transfer_id, &record)
+        .map(Some)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid transfer record"))
+}
+
+/**
+Reads the records of all unfinished transfers
+*/
+pub fn read_transfers() -> io::Result<Vec<Transfer>> {
+    let mut transfers = Vec::new();
+    for entry in fs::read_dir(get_transfers_dir()?)? {
+        let file_name = entry?.file_name();
+        let Some(transfer_id) = file_name.to_str().and_then(parse_transfer_id) else {
+            continue;
+        };
+        match read_transfer(transfer_id) {
+            Ok(Some(transfer)) => transfers.push(transfer),
+            Ok(None) => {}
+            Err(e) => println!("Skipping transfer {}: {}", shared::transfer_id_to_string(transfer_id), e),
+        }
+    }
+    Ok(transfers)
+}
+
+/**
+Forgets a finished (or abandoned) transfer, along with any part-received file
+*/
+pub fn remove_transfer(transfer: &Transfer) -> io::Result<()> {
+    if transfer.direction == Direction::Incoming {
+        match fs::remove_file(get_partial_file_path(transfer)?) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+            result => result?,
+        }
+    }
+    match fs::remove_file(get_transfers_dir()?.join(shared::transfer_id_to_string(transfer.transfer_id))) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+fn parse_transfer(transfer_id: [u8; TRANSFER_ID_LEN], record: &str) -> Option<Transfer> {
+    let mut lines = record.lines();
+    let direction = match lines.next()? {
+        "out" => Direction::Outgoing,
+        "in" => Direction::Incoming,
+        _ => return None,
+    };
+    let peer = Username::new(lines.next()?).ok()?;
+    let file_size = lines.next()?.parse().ok()?;
+    let digest = lines.next()?.to_string();
+    let file_name = lines.next()?.to_string();
+    let source = match direction {
+        Direction::Outgoing => Some(PathBuf::from(lines.next()?)),
+        Direction::Incoming => None,
+    };
+
+    Some(Transfer { transfer_id, direction, peer, file_name, file_size, digest, source })
+}
+
+fn parse_transfer_id(hex: &str) -> Option<[u8; TRANSFER_ID_LEN]> {
+    // transfer ids are the same size as group ids, and written the same way
+    parse_group_id(hex)
+}
+
+// NOTE: hidden, and named by transfer id, so it can't clash with a received file
+fn get_partial_file_path(transfer: &Transfer) -> io::Result<PathBuf> {
+    let file_name = format!(".{}.{}", shared::transfer_id_to_string(transfer.transfer_id), PARTIAL_FILE_SUFFIX);
+    Ok(get_attachments_dir(&transfer.peer)?.join(file_name))
+}
+
+/**
+How many bytes of an incoming file have been received so far
+*/
+pub fn partial_file_len(transfer: &Transfer) -> io::Result<u64> {
+    match fs::metadata(get_partial_file_path(transfer)?) {
+        Ok(metadata) => Ok(metadata.len()),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(0),
+        Err(e) => Err(e),
+    }
+}
+
+/**
+Appends the next chunk of an incoming file
+*/
+pub fn append_file_chunk(transfer: &Transfer, chunk: &[u8]) -> io::Result<()> {
+    let mut partial_file = OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(get_partial_file_path(transfer)?)?;
+    partial_file.write_all(chunk)?;
+    partial_file.sync_all()
+}
+
+/**
+Reads the whole of an incoming file received so far
+*/
+pub fn read_partial_file(transfer: &Transfer) -> io::Result<Vec<u8>> {
+    fs::read(get_partial_file_path(transfer)?)
+}
+
+/**
+Moves a fully received file to its name in the sender's attachments folder
+(numbered, e.g. 'notes (1).txt', if that name is taken), returning its path
+*/
+pub fn finish_attachment(transfer: &Transfer) -> io::Result<PathBuf> {
+    let attachments_dir = get_attachments_dir(&transfer.peer)?;
+    let file_name = Path::new(&transfer.file_name);
+    let stem = file_name.file_stem().and_then(|stem| stem.to_str()).unwrap_or(&transfer.file_name);
+    let extension = file_name.extension().and_then(|extension| extension.to_str());
+
+    let mut path = attachments_dir.join(&transfer.file_name);
+    let mut n = 1;
+    while path.exists() {
+        let numbered = match extension {
+            Some(extension) => format!("{} ({}).{}", stem, n, extension),
+            None => format!("{} ({})", stem, n),
+        };
+        path = attachments_dir.join(numbered);
+        n += 1;
+    }
+
+    fs::rename(get_partial_file_path(transfer)?, &path)?;
+    Ok(path)
+}
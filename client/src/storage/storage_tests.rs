@@ -1,7 +1,7 @@
 pub mod tests {
     use crate::storage::storage::{add_new_connection, read_messages, write_message};    
     use crate::storage::conn_map;
-    use protocol::ChatMessage;
+    use protocol::{ChatMessage, Username};
 
     pub const HARRY_UNAME: &str = "Harry";
     pub const EDDIE_UNAME: &str = "Eddie";
@@ -27,17 +27,16 @@ pub mod tests {
 
     pub fn test_read_message() {
         setup();
-        let harryUname = String::from(HARRY_UNAME);
-        let eddieUname = String::from(EDDIE_UNAME);
-        let kerryUname = String::from(KERRY_UNAME);
+        let eddie_uname = Username::new(EDDIE_UNAME).unwrap();
+        let kerry_uname = Username::new(KERRY_UNAME).unwrap();
         // read eddie messages
-        let eddie_messages = read_messages(eddieUname.clone()).unwrap();
+        let eddie_messages = read_messages(&eddie_uname).unwrap();
         for msg in eddie_messages {
             println!("{:?}", msg);
         }
 
         // read kerry messages
-        let kerry_messages = read_messages(kerryUname.clone()).unwrap();    
+        let kerry_messages = read_messages(&kerry_uname).unwrap();    
         for msg in kerry_messages {
             println!("{:?}", msg);
         }   
@@ -45,24 +44,24 @@ pub mod tests {
 
     fn setup() {
         // Create usernames
-        let harryUname = String::from(HARRY_UNAME);
-        let eddieUname = String::from(EDDIE_UNAME);
-        let kerryUname = String::from(KERRY_UNAME);
+        let harry_uname = Username::new(HARRY_UNAME).unwrap();
+        let eddie_uname = Username::new(EDDIE_UNAME).unwrap();
+        let kerry_uname = Username::new(KERRY_UNAME).unwrap();
 
         // Add new connections (users)
-        add_new_connection(eddieUname.clone());
-        add_new_connection(kerryUname.clone());
+        add_new_connection(&eddie_uname).unwrap();
+        add_new_connection(&kerry_uname).unwrap();
 
         // Create messages
-        let chat_message1 = ChatMessage::new(&harryUname, &eddieUname, MSG1);
-        let chat_message2 = ChatMessage::new(&eddieUname, &harryUname, MSG2);
-        let chat_message3 = ChatMessage::new(&harryUname, &kerryUname, MSG3);
-        let chat_message4 = ChatMessage::new(&kerryUname, &harryUname, MSG4);
+        let chat_message1 = ChatMessage::new(&harry_uname, &eddie_uname, MSG1);
+        let chat_message2 = ChatMessage::new(&eddie_uname, &harry_uname, MSG2);
+        let chat_message3 = ChatMessage::new(&harry_uname, &kerry_uname, MSG3);
+        let chat_message4 = ChatMessage::new(&kerry_uname, &harry_uname, MSG4);
 
         // Write messages
-        write_message(chat_message1, eddieUname.clone()).unwrap();
-        write_message(chat_message2, eddieUname.clone()).unwrap();
-        write_message(chat_message3, kerryUname.clone()).unwrap();  
-        write_message(chat_message4, kerryUname.clone()).unwrap();
+        write_message(chat_message1, &eddie_uname).unwrap();
+        write_message(chat_message2, &eddie_uname).unwrap();
+        write_message(chat_message3, &kerry_uname).unwrap();  
+        write_message(chat_message4, &kerry_uname).unwrap();
     }
 }
@@ -0,0 +1,106 @@
+/*
+Module - transfer
+
+Files sent to and received from connections (see protocol::transfer).
+
+Each unfinished transfer, in either direction, is recorded under
+.cli_chat/transfers until it completes, so it can be picked up again after a
+reconnect (see comms::Connection::resume_transfers). A file being received is
+written chunk by chunk into the sender's attachments folder, and only given its
+real name once its SHA-256 digest has been checked.
+*/
+
+use std::path::PathBuf;
+
+use protocol::Username;
+use protocol::field_lens::{TRANSFER_ID_LEN, FILE_CHUNK_LEN};
+use protocol::transfer;
+
+// longest file name we'll accept, as most file systems allow
+pub const MAX_FILE_NAME_LEN: usize = 255;
+
+// a SHA-256 digest, as written by helpers::sha256_from_bytes
+pub const DIGEST_HEX_LEN: usize = 64;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Direction {
+    Outgoing,
+    Incoming,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Transfer {
+    pub transfer_id: [u8; TRANSFER_ID_LEN],
+    pub direction: Direction,
+
+    // who the file is going to or coming from
+    pub peer: Username,
+
+    pub file_name: String,
+    pub file_size: u64,
+
+    // SHA-256 of the whole file, in hex
+    pub digest: String,
+
+    // where an outgoing file is read from
+    pub source: Option<PathBuf>,
+}
+
+impl Transfer {
+    pub fn chunk_count(&self) -> u32 {
+        transfer::chunk_count(self.file_size)
+    }
+
+    /**
+    Range of the file's bytes sent in the given chunk
+    */
+    pub fn chunk_range(&self, index: u32) -> std::ops::Range<usize> {
+        let start = index as usize * FILE_CHUNK_LEN;
+        let end = (start + FILE_CHUNK_LEN).min(self.file_size as usize);
+
+        start..end
+    }
+
+    /**
+    The file's details as sealed in a FileOffer: its digest, then its name
+    */
+    pub fn encode_info(&self) -> Vec<u8> {
+        [self.digest.as_bytes(), self.file_name.as_bytes()].concat()
+    }
+
+    /**
+    Reads (digest, file name) from an offer's opened details
+    */
+    pub fn decode_info(info: &[u8]) -> Result<(String, String), &'static str> {
+        if info.len() < DIGEST_HEX_LEN {
+            return Err("file details are too short");
+        }
+        let (digest, file_name) = info.split_at(DIGEST_HEX_LEN);
+        let digest = std::str::from_utf8(digest).map_err(|_| "digest is not valid UTF-8")?;
+        if !digest.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+            return Err("digest is not hex");
+        }
+        let file_name = std::str::from_utf8(file_name).map_err(|_| "file name is not valid UTF-8")?;
+        validate_file_name(file_name)?;
+
+        Ok((digest.to_ascii_lowercase(), file_name.to_string()))
+    }
+}
+
+/**
+Checks a file name is safe to save a received file under: a single path
+component, no control characters, and at most MAX_FILE_NAME_LEN bytes
+*/
+pub fn validate_file_name(file_name: &str) -> Result<(), &'static str> {
+    if file_name.is_empty() || file_name.len() > MAX_FILE_NAME_LEN {
+        return Err("file name must be 1 to 255 bytes long");
+    }
+    if file_name == "." || file_name == ".." || file_name.contains(['/', '\\']) {
+        return Err("file name must not be a path");
+    }
+    if file_name.chars().any(char::is_control) {
+        return Err("file name must not contain control characters");
+    }
+
+    Ok(())
+}
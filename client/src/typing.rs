@@ -0,0 +1,115 @@
+/*
+Module - typing
+
+Tracks "is typing" indicators (see protocol::typing) in both directions.
+
+Outgoing indicators are throttled: however fast the user types, a connection
+is told we're typing at most once per TYPING_RESEND_INTERVAL. Incoming
+indicators expire if the sender doesn't repeat them within TYPING_TIMEOUT, so
+a lost "stopped typing" (or a dropped connection) doesn't leave one showing
+forever.
+*/
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use protocol::Username;
+
+// how often to repeat that we're still typing
+pub const TYPING_RESEND_INTERVAL: Duration = Duration::from_secs(3);
+
+// how long an incoming indicator lasts without being repeated
+pub const TYPING_TIMEOUT: Duration = Duration::from_secs(6);
+
+#[derive(Debug, Default)]
+pub struct TypingTracker {
+    // when we last told each connection we're typing to them
+    sent: HashMap<Username, Instant>,
+
+    // when each connection last told us they're typing to us
+    received: HashMap<Username, Instant>,
+}
+
+impl TypingTracker {
+    pub fn new() -> Self {
+        TypingTracker::default()
+    }
+
+    /**
+    Records a keystroke in our conversation with recv_uname. Returns true if
+    they should be told we're typing.
+    */
+    pub fn typed(&mut self, recv_uname: &Username, now: Instant) -> bool {
+        let due = self.sent
+            .get(recv_uname)
+            .is_none_or(|&last| now.duration_since(last) >= TYPING_RESEND_INTERVAL);
+        if due {
+            self.sent.insert(recv_uname.clone(), now);
+        }
+
+        due
+    }
+
+    /**
+    Records that we've stopped typing to recv_uname (e.g. cleared or sent the
+    message). Returns true if they should be told, i.e. they may still be
+    showing that we're typing.
+    */
+    pub fn stopped(&mut self, recv_uname: &Username, now: Instant) -> bool {
+        self.sent
+            .remove(recv_uname)
+            .is_some_and(|last| now.duration_since(last) < TYPING_TIMEOUT)
+    }
+
+    /**
+    Applies an indicator from send_uname
+    */
+    pub fn update(&mut self, send_uname: &Username, typing: bool, now: Instant) {
+        if typing {
+            self.received.insert(send_uname.clone(), now);
+        } else {
+            self.received.remove(send_uname);
+        }
+    }
+
+    pub fn is_typing(&self, uname: &Username, now: Instant) -> bool {
+        self.received
+            .get(uname)
+            .is_some_and(|&last| now.duration_since(last) < TYPING_TIMEOUT)
+    }
+
+    /**
+    Connections currently typing to us, in username order
+    */
+    pub fn typing_users(&self, now: Instant) -> Vec<Username> {
+        let mut unames: Vec<Username> = self.received
+            .keys()
+            .filter(|uname| self.is_typing(uname, now))
+            .cloned()
+            .collect();
+        unames.sort();
+
+        unames
+    }
+
+    /**
+    Forgets indicators that have timed out
+    */
+    pub fn expire(&mut self, now: Instant) {
+        self.received.retain(|_, last| now.duration_since(*last) < TYPING_TIMEOUT);
+        self.sent.retain(|_, last| now.duration_since(*last) < TYPING_TIMEOUT);
+    }
+}
+
+/**
+What the conversation view shows while connections are typing, e.g.
+"Harry is typing…", or None if no one is
+*/
+pub fn typing_label(unames: &[Username]) -> Option<String> {
+    match unames {
+        [] => None,
+        [uname] => Some(format!("{} is typing…", uname)),
+        [uname1, uname2] => Some(format!("{} and {} are typing…", uname1, uname2)),
+        _ => Some("Several people are typing…".to_string()),
+    }
+}
@@ -0,0 +1,69 @@
+/*
+A stand-in for the server's side of a client's session, for tests that run a
+Connection over a real socket
+*/
+
+// each test file uses only some of these
+#![allow(dead_code)]
+
+use std::net::{TcpListener, TcpStream};
+
+use client::comms::Connection;
+use protocol::{auth, AuthChallenge, Capabilities, HelloAck, Message};
+use protocol::{Packet, PacketReader, PacketWriter, ProtocolMessage, Stream, Username, VerifyResp};
+use protocol::field_lens::TOKEN_LEN;
+use protocol::hello::PROTOCOL_VERSION;
+use protocol::status_codes::StatusCode;
+
+// One session with the client, standing in for the server's side of it
+pub struct FakeSession {
+    reader: PacketReader<TcpStream>,
+    writer: PacketWriter<TcpStream>,
+}
+
+impl FakeSession {
+    // Accepts a client and answers its Hello, agreeing to capabilities, returning
+    // the challenge it was sent
+    pub fn accept(listener: &TcpListener, capabilities: Capabilities) -> (Self, AuthChallenge) {
+        let (tcp, _) = listener.accept().unwrap();
+        let mut session = FakeSession {
+            reader: PacketReader::new(tcp.try_clone().unwrap()),
+            writer: PacketWriter::new(tcp),
+        };
+        assert!(matches!(session.recv(), ProtocolMessage::Hello(_)));
+        session.send(&HelloAck::new(StatusCode::Success, PROTOCOL_VERSION, capabilities));
+        let auth_challenge = AuthChallenge::new();
+        session.send(&auth_challenge);
+        (session, auth_challenge)
+    }
+
+    // Expects the challenge to be answered with a proof from token
+    pub fn expect_verify(&mut self, uname: &Username, auth_challenge: &AuthChallenge, token: &[u8; TOKEN_LEN]) {
+        let ProtocolMessage::VerifyReq(verify_req) = self.recv() else {
+            panic!("expected VerifyReq");
+        };
+        let verifier = auth::derive_verifier(token);
+        assert!(auth::check_proof(&verifier, auth_challenge.challenge(), uname, &verify_req.proof));
+        self.send(&VerifyResp::new(StatusCode::Success));
+    }
+
+    pub fn send<M: Message>(&mut self, message: &M) {
+        self.writer.write_packet(&Packet::wrap(message)).unwrap();
+    }
+
+    pub fn recv(&mut self) -> ProtocolMessage {
+        self.reader.read_packet().unwrap().decode().unwrap()
+    }
+
+    // Expects the client to have hung up without sending anything more
+    pub fn expect_closed(&mut self) {
+        if let Ok(packet) = self.reader.read_packet() {
+            panic!("expected the client to hang up, got {:?}", packet);
+        }
+    }
+}
+
+pub fn connect(listener: &TcpListener, uname: &Username) -> Connection {
+    let tcp = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    Connection::connect(Stream::plain(tcp), uname).unwrap()
+}
@@ -0,0 +1,234 @@
+use client::crypto::{self, ConversationKey, CryptoError, KeyPair};
+use protocol::{ChatMessage, Envelope, GroupMessage, Username};
+use protocol::{FileOffer, FileChunk};
+use protocol::MessageEdit;
+
+fn chat_message(body: &str) -> ChatMessage {
+    ChatMessage::new(&Username::new("Harry").unwrap(), &Username::new("Eddie").unwrap(), body)
+}
+
+fn agreed_keys() -> (ConversationKey, ConversationKey) {
+    let requester = KeyPair::generate();
+    let responder = KeyPair::generate();
+    (
+        ConversationKey::agree(&requester, &responder.public_key()).unwrap(),
+        ConversationKey::agree(&responder, &requester.public_key()).unwrap(),
+    )
+}
+
+#[test]
+fn both_sides_agree_the_same_key() {
+    let (requester_key, responder_key) = agreed_keys();
+    assert_eq!(requester_key, responder_key);
+
+    // a fresh agreement gives a new key (and id)
+    let (other_key, _) = agreed_keys();
+    assert_ne!(other_key.key_bytes(), requester_key.key_bytes());
+    assert_ne!(other_key.key_id(), requester_key.key_id());
+}
+
+#[test]
+fn encrypted_message_decrypts_for_the_recipient() {
+    let (sender_key, recipient_key) = agreed_keys();
+    let mut message = chat_message("im not grumba grandpa guy");
+    crypto::encrypt_message(&sender_key, &mut message).unwrap();
+
+    // only the envelope goes over the wire
+    let envelope = Envelope::deserialize(&message.msg_buffer).unwrap();
+    assert_eq!(envelope.key_id(), sender_key.key_id());
+    assert_eq!(message.msg_length as usize, message.msg_buffer.len());
+    assert!(!message.msg_buffer.windows(5).any(|window| window == b"grumb"));
+
+    crypto::decrypt_message(&[recipient_key], &mut message).unwrap();
+    assert_eq!(message.msg_buffer, b"im not grumba grandpa guy");
+}
+
+#[test]
+fn older_keys_still_decrypt_after_rekeying() {
+    let (old_key, _) = agreed_keys();
+    let (new_key, _) = agreed_keys();
+    let mut message = chat_message("sent before rekeying");
+    crypto::encrypt_message(&old_key, &mut message).unwrap();
+
+    crypto::decrypt_message(&[old_key, new_key], &mut message).unwrap();
+    assert_eq!(message.msg_buffer, b"sent before rekeying");
+}
+
+#[test]
+fn message_under_unknown_key_is_rejected() {
+    let (key, _) = agreed_keys();
+    let (other_key, _) = agreed_keys();
+    let mut message = chat_message("hello");
+    crypto::encrypt_message(&key, &mut message).unwrap();
+
+    assert!(matches!(
+        crypto::decrypt_message(&[other_key], &mut message),
+        Err(CryptoError::UnknownKey(key_id)) if key_id == key.key_id()
+    ));
+}
+
+#[test]
+fn tampering_is_detected() {
+    let (key, _) = agreed_keys();
+
+    // body
+    let mut message = chat_message("hello");
+    crypto::encrypt_message(&key, &mut message).unwrap();
+    *message.msg_buffer.last_mut().unwrap() ^= 1;
+    assert!(matches!(crypto::decrypt_message(std::slice::from_ref(&key), &mut message), Err(CryptoError::Decrypt)));
+
+    // metadata the server could otherwise rewrite
+    let mut message = chat_message("hello");
+    crypto::encrypt_message(&key, &mut message).unwrap();
+    message.recv_uname = Username::new("Kerry").unwrap();
+    assert!(matches!(crypto::decrypt_message(std::slice::from_ref(&key), &mut message), Err(CryptoError::Decrypt)));
+
+    let mut message = chat_message("hello");
+    crypto::encrypt_message(&key, &mut message).unwrap();
+    message.message_id = [0u8; 16];
+    assert!(matches!(crypto::decrypt_message(std::slice::from_ref(&key), &mut message), Err(CryptoError::Decrypt)));
+
+    // plaintext body
+    let mut message = chat_message("hello");
+    assert!(matches!(crypto::decrypt_message(&[key], &mut message), Err(CryptoError::Envelope(_))));
+}
+
+#[test]
+fn reply_is_bound_to_the_message_it_answers() {
+    let (key, _) = agreed_keys();
+    let mut reply = ChatMessage::reply(
+        &Username::new("Harry").unwrap(),
+        &Username::new("Eddie").unwrap(),
+        "no",
+        [7u8; 16]
+    );
+    crypto::encrypt_message(&key, &mut reply).unwrap();
+
+    // e.g. the server making it answer a different question
+    reply.in_reply_to = Some([8u8; 16]);
+    assert!(matches!(crypto::decrypt_message(std::slice::from_ref(&key), &mut reply), Err(CryptoError::Decrypt)));
+
+    reply.in_reply_to = Some([7u8; 16]);
+    crypto::decrypt_message(&[key], &mut reply).unwrap();
+    assert_eq!(reply.msg_buffer, b"no");
+}
+
+#[test]
+fn whether_a_message_is_a_reply_is_bound() {
+    let (key, _) = agreed_keys();
+    let harry = Username::new("Harry").unwrap();
+    let eddie = Username::new("Eddie").unwrap();
+
+    let mut plain = ChatMessage::new(&harry, &eddie, "yes");
+    crypto::encrypt_message(&key, &mut plain).unwrap();
+    plain.in_reply_to = Some([7u8; 16]);
+    assert!(matches!(crypto::decrypt_message(std::slice::from_ref(&key), &mut plain), Err(CryptoError::Decrypt)));
+
+    let mut reply = ChatMessage::reply(&harry, &eddie, "yes", [7u8; 16]);
+    crypto::encrypt_message(&key, &mut reply).unwrap();
+    reply.in_reply_to = None;
+    assert!(matches!(crypto::decrypt_message(&[key], &mut reply), Err(CryptoError::Decrypt)));
+}
+
+#[test]
+fn weak_public_key_is_rejected() {
+    let key_pair = KeyPair::generate();
+    assert!(matches!(ConversationKey::agree(&key_pair, &[0u8; 32]), Err(CryptoError::WeakPublicKey)));
+}
+
+#[test]
+fn key_pair_survives_storage_as_bytes() {
+    let key_pair = KeyPair::generate();
+    let restored = KeyPair::from_secret_bytes(key_pair.secret_bytes());
+    assert_eq!(restored.public_key(), key_pair.public_key());
+}
+
+#[test]
+fn group_message_decrypts_with_the_group_key() {
+    let group_key = ConversationKey::generate();
+    let group_id = [4u8; 16];
+    let mut message = GroupMessage::new(group_id, &Username::new("Harry").unwrap(), "hello team");
+    crypto::encrypt_group_message(&group_key, &mut message).unwrap();
+    assert!(!message.msg_buffer.windows(4).any(|window| window == b"team"));
+
+    // bound to the group it was sent to
+    let mut moved = message.clone();
+    moved.group_id = [5u8; 16];
+    assert!(matches!(
+        crypto::decrypt_group_message(std::slice::from_ref(&group_key), &mut moved),
+        Err(CryptoError::Decrypt)
+    ));
+
+    crypto::decrypt_group_message(&[group_key], &mut message).unwrap();
+    assert_eq!(message.msg_buffer, b"hello team");
+}
+
+#[test]
+fn sealed_group_key_opens_only_for_the_invitee() {
+    let (inviter_key, invitee_key) = agreed_keys();
+    let group_key = ConversationKey::generate();
+    let group_id = [4u8; 16];
+    let harry = Username::new("Harry").unwrap();
+    let eddie = Username::new("Eddie").unwrap();
+    let kerry = Username::new("Kerry").unwrap();
+
+    let sealed = crypto::seal_group_key(&inviter_key, group_id, &harry, &eddie, &group_key).unwrap();
+    let opened = crypto::open_group_key(std::slice::from_ref(&invitee_key), group_id, &harry, &eddie, &sealed).unwrap();
+    assert_eq!(opened, group_key);
+
+    // the server can't redirect it to someone else, or another group
+    assert!(crypto::open_group_key(std::slice::from_ref(&invitee_key), group_id, &harry, &kerry, &sealed).is_err());
+    assert!(crypto::open_group_key(&[invitee_key], [5u8; 16], &harry, &eddie, &sealed).is_err());
+}
+
+#[test]
+fn file_chunks_are_bound_to_their_place_in_the_transfer() {
+    let (sender_key, recipient_key) = agreed_keys();
+    let (harry, eddie) = (Username::new("Harry").unwrap(), Username::new("Eddie").unwrap());
+    let data = crypto::encrypt_file_chunk(&sender_key, [3u8; 16], &harry, &eddie, 7, b"log line").unwrap();
+
+    let chunk = FileChunk::new([3u8; 16], &harry, &eddie, 7, data.clone());
+    assert_eq!(crypto::decrypt_file_chunk(std::slice::from_ref(&recipient_key), &chunk).unwrap(), b"log line");
+
+    // the server can't reorder chunks, or move them to another transfer
+    let moved = FileChunk::new([3u8; 16], &harry, &eddie, 8, data.clone());
+    assert!(matches!(crypto::decrypt_file_chunk(std::slice::from_ref(&recipient_key), &moved), Err(CryptoError::Decrypt)));
+    let moved = FileChunk::new([4u8; 16], &harry, &eddie, 7, data);
+    assert!(matches!(crypto::decrypt_file_chunk(&[recipient_key], &moved), Err(CryptoError::Decrypt)));
+}
+
+#[test]
+fn file_info_opens_only_for_its_offer() {
+    let (sender_key, recipient_key) = agreed_keys();
+    let (harry, eddie) = (Username::new("Harry").unwrap(), Username::new("Eddie").unwrap());
+    let sealed = crypto::seal_file_info(&sender_key, [3u8; 16], &harry, &eddie, 42, b"info").unwrap();
+
+    let offer = FileOffer::new([3u8; 16], &harry, &eddie, 42, sealed.clone()).unwrap();
+    assert_eq!(crypto::open_file_info(std::slice::from_ref(&recipient_key), &offer).unwrap(), b"info");
+
+    // ...and the size can't be changed on the way
+    let resized = FileOffer::new([3u8; 16], &harry, &eddie, 43, sealed).unwrap();
+    assert!(matches!(crypto::open_file_info(&[recipient_key], &resized), Err(CryptoError::Decrypt)));
+}
+
+#[test]
+fn edit_is_bound_to_the_message_it_edits() {
+    let (sender_key, recipient_key) = agreed_keys();
+    let (harry, eddie) = (Username::new("Harry").unwrap(), Username::new("Eddie").unwrap());
+    let mut message_edit = MessageEdit::new([3u8; 16], &harry, &eddie, "grumpy, not grumba");
+    crypto::encrypt_edit(&sender_key, &mut message_edit).unwrap();
+    assert!(Envelope::deserialize(message_edit.body()).is_ok());
+
+    // the server can't move the new text onto another message, or pass it off as one
+    let mut moved = MessageEdit::new([4u8; 16], &harry, &eddie, "");
+    moved.set_body(message_edit.body().to_vec());
+    assert!(matches!(crypto::decrypt_edit(std::slice::from_ref(&recipient_key), &mut moved), Err(CryptoError::Decrypt)));
+    let mut chat_message = chat_message("");
+    chat_message.message_id = message_edit.message_id();
+    chat_message.timestamp = message_edit.timestamp();
+    chat_message.set_body(message_edit.body().to_vec());
+    assert!(matches!(crypto::decrypt_message(std::slice::from_ref(&recipient_key), &mut chat_message), Err(CryptoError::Decrypt)));
+
+    crypto::decrypt_edit(&[recipient_key], &mut message_edit).unwrap();
+    assert_eq!(message_edit.body(), b"grumpy, not grumba");
+}
@@ -0,0 +1,50 @@
+use client::delivery::{DeliveryState, DeliveryTracker};
+use protocol::ReceiptKind;
+
+const MESSAGE_ID: [u8; 16] = [1u8; 16];
+
+#[test]
+fn receipts_move_message_through_states() {
+    let mut tracker = DeliveryTracker::new();
+    tracker.track(MESSAGE_ID);
+    assert_eq!(tracker.state(&MESSAGE_ID), Some(DeliveryState::Pending));
+
+    assert_eq!(tracker.update(MESSAGE_ID, ReceiptKind::Accepted), Some(DeliveryState::Sent));
+    assert_eq!(tracker.update(MESSAGE_ID, ReceiptKind::Delivered), Some(DeliveryState::Delivered));
+    assert_eq!(tracker.update(MESSAGE_ID, ReceiptKind::Read), Some(DeliveryState::Read));
+}
+
+#[test]
+fn late_receipt_never_moves_message_backwards() {
+    let mut tracker = DeliveryTracker::new();
+    tracker.track(MESSAGE_ID);
+
+    tracker.update(MESSAGE_ID, ReceiptKind::Read);
+    assert_eq!(tracker.update(MESSAGE_ID, ReceiptKind::Accepted), Some(DeliveryState::Read));
+    assert_eq!(tracker.update(MESSAGE_ID, ReceiptKind::Delivered), Some(DeliveryState::Read));
+}
+
+#[test]
+fn rejected_message_stays_failed() {
+    let mut tracker = DeliveryTracker::new();
+    tracker.track(MESSAGE_ID);
+
+    assert_eq!(tracker.update(MESSAGE_ID, ReceiptKind::Rejected), Some(DeliveryState::Failed));
+    assert_eq!(tracker.update(MESSAGE_ID, ReceiptKind::Accepted), Some(DeliveryState::Failed));
+}
+
+#[test]
+fn ignores_receipt_for_unknown_message() {
+    let mut tracker = DeliveryTracker::new();
+    assert_eq!(tracker.update(MESSAGE_ID, ReceiptKind::Delivered), None);
+    assert_eq!(tracker.state(&MESSAGE_ID), None);
+}
+
+#[test]
+fn ticks_for_each_state() {
+    assert_eq!(DeliveryState::Pending.ticks(), "");
+    assert_eq!(DeliveryState::Sent.ticks(), "✓");
+    assert_eq!(DeliveryState::Delivered.ticks(), "✓✓");
+    assert_eq!(DeliveryState::Read.ticks(), "✓✓");
+    assert_eq!(DeliveryState::Failed.ticks(), "!");
+}
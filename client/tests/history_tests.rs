@@ -0,0 +1,132 @@
+use client::history;
+use protocol::{ChatMessage, MessageEdit, MessageDelete, Reaction, ReactionAction, Username};
+
+fn uname(uname: &str) -> Username {
+    Username::new(uname).unwrap()
+}
+
+#[test]
+fn edits_keep_the_earlier_text() {
+    let message = ChatMessage::new(&uname("Harry"), &uname("Eddie"), "im not grumba");
+    let first = MessageEdit::new(message.message_id, &uname("Harry"), &uname("Eddie"), "im not grumpa");
+    let second = MessageEdit::new(message.message_id, &uname("Harry"), &uname("Eddie"), "im not grumpy");
+
+    let entries = history::apply(vec![message], vec![first, second], Vec::new(), Vec::new());
+    assert_eq!(entries.len(), 1);
+    assert!(entries[0].is_edited());
+    assert_eq!(entries[0].text().unwrap(), "im not grumpy");
+    assert_eq!(entries[0].message.msg_buffer, b"im not grumba");
+    assert_eq!(entries[0].edits[0].body(), b"im not grumpa");
+}
+
+#[test]
+fn edits_apply_in_the_order_they_were_made() {
+    let message = ChatMessage::new(&uname("Harry"), &uname("Eddie"), "first");
+    let earlier = MessageEdit::new(message.message_id, &uname("Harry"), &uname("Eddie"), "second");
+    std::thread::sleep(std::time::Duration::from_millis(2));
+    let later = MessageEdit::new(message.message_id, &uname("Harry"), &uname("Eddie"), "third");
+
+    // e.g. the earlier edit was resent after a reconnect
+    let entries = history::apply(vec![message], vec![later, earlier], Vec::new(), Vec::new());
+    assert_eq!(entries[0].text().unwrap(), "third");
+}
+
+#[test]
+fn deletion_leaves_a_tombstone() {
+    let kept = ChatMessage::new(&uname("Eddie"), &uname("Harry"), "kept");
+    let deleted = ChatMessage::new(&uname("Harry"), &uname("Eddie"), "regrettable");
+    let deletion = MessageDelete::new(deleted.message_id, &uname("Harry"), &uname("Eddie"));
+
+    let entries = history::apply(vec![kept, deleted], Vec::new(), vec![deletion], Vec::new());
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries[0].text().unwrap(), "kept");
+    assert!(entries[1].is_deleted());
+    assert_eq!(entries[1].text(), None);
+    assert_eq!(entries[1].message.msg_buffer, b"regrettable");
+}
+
+#[test]
+fn only_the_original_sender_can_change_a_message() {
+    let message = ChatMessage::new(&uname("Harry"), &uname("Eddie"), "mine");
+    let edit = MessageEdit::new(message.message_id, &uname("Eddie"), &uname("Harry"), "yours now");
+    let deletion = MessageDelete::new(message.message_id, &uname("Eddie"), &uname("Harry"));
+
+    // ...and changes to messages we don't have are ignored
+    let stray = MessageEdit::new([9u8; 16], &uname("Harry"), &uname("Eddie"), "what?");
+
+    let entries = history::apply(vec![message], vec![edit, stray], vec![deletion], Vec::new());
+    assert!(!entries[0].is_edited());
+    assert!(!entries[0].is_deleted());
+    assert_eq!(entries[0].text().unwrap(), "mine");
+}
+
+fn reaction(message: &ChatMessage, send_uname: &str, action: ReactionAction, reaction: &str) -> Reaction {
+    let recv_uname = if send_uname == "Harry" { "Eddie" } else { "Harry" };
+    Reaction::new(message.message_id, &uname(send_uname), &uname(recv_uname), action, reaction).unwrap()
+}
+
+#[test]
+fn reactions_are_counted_once_per_user() {
+    let message = ChatMessage::new(&uname("Harry"), &uname("Eddie"), "lunch?");
+    let reactions = vec![
+        reaction(&message, "Eddie", ReactionAction::Add, "👍"),
+        reaction(&message, "Eddie", ReactionAction::Add, "👍"),
+        reaction(&message, "Harry", ReactionAction::Add, "👍"),
+        reaction(&message, "Eddie", ReactionAction::Add, "🍕"),
+    ];
+
+    let entries = history::apply(vec![message], Vec::new(), Vec::new(), reactions);
+    assert_eq!(entries[0].reaction_counts(), vec![("👍", 2), ("🍕", 1)]);
+    assert!(entries[0].reactions["👍"].contains(&uname("Harry")));
+}
+
+#[test]
+fn removed_reactions_are_taken_back() {
+    let message = ChatMessage::new(&uname("Harry"), &uname("Eddie"), "lunch?");
+    let added = reaction(&message, "Eddie", ReactionAction::Add, "👍");
+    std::thread::sleep(std::time::Duration::from_millis(2));
+    let removed = reaction(&message, "Eddie", ReactionAction::Remove, "👍");
+
+    // the latest change stands, whatever order they arrived in
+    let entries = history::apply(vec![message], Vec::new(), Vec::new(), vec![removed, added]);
+    assert!(entries[0].reactions.is_empty());
+    assert!(entries[0].reaction_counts().is_empty());
+}
+
+#[test]
+fn reaction_labels() {
+    assert_eq!(history::reaction_label(&[]), None);
+    assert_eq!(history::reaction_label(&[("👍", 2), ("🍕", 1)]).unwrap(), "👍 2  🍕 1");
+}
+
+#[test]
+fn replies_quote_and_find_their_parent() {
+    let parent = ChatMessage::new(&uname("Eddie"), &uname("Harry"), "are you grumba grandpa guy?\nbe honest");
+    let reply = ChatMessage::reply(&uname("Harry"), &uname("Eddie"), "no", parent.message_id);
+    let orphan = ChatMessage::reply(&uname("Harry"), &uname("Eddie"), "what?", [9u8; 16]);
+
+    let entries = history::apply(vec![parent, reply, orphan], Vec::new(), Vec::new(), Vec::new());
+    assert_eq!(history::position(&entries, entries[0].message.message_id), Some(0));
+    assert!(history::parent(&entries, &entries[0]).is_none());
+
+    let parent = history::parent(&entries, &entries[1]);
+    assert_eq!(history::quote_preview(parent), "Eddie: are you grumba grandpa guy?…");
+    assert_eq!(history::quote_preview(history::parent(&entries, &entries[2])), "message not found");
+}
+
+#[test]
+fn quotes_are_cut_short() {
+    assert_eq!(history::quote("Harry", "short"), "Harry: short");
+    let quoted = history::quote("Harry", &"a".repeat(100));
+    assert_eq!(quoted, format!("Harry: {}…", "a".repeat(history::QUOTE_PREVIEW_LEN)));
+}
+
+#[test]
+fn replies_to_deleted_messages_say_so() {
+    let parent = ChatMessage::new(&uname("Eddie"), &uname("Harry"), "regrettable");
+    let deletion = MessageDelete::new(parent.message_id, &uname("Eddie"), &uname("Harry"));
+    let reply = ChatMessage::reply(&uname("Harry"), &uname("Eddie"), "lol", parent.message_id);
+
+    let entries = history::apply(vec![parent, reply], Vec::new(), vec![deletion], Vec::new());
+    assert_eq!(history::quote_preview(history::parent(&entries, &entries[1])), "Eddie: message deleted");
+}
@@ -0,0 +1,39 @@
+use client::identity::{KeyChanges, KeyCheck, SafetyNumber};
+use protocol::{IdentityKeyPair, Username};
+
+#[test]
+fn first_key_is_new_then_compared() {
+    let key = IdentityKeyPair::generate().identity_key();
+    let other_key = IdentityKeyPair::generate().identity_key();
+
+    assert_eq!(KeyCheck::compare(None, &key), KeyCheck::New);
+    assert_eq!(KeyCheck::compare(Some(&key), &key), KeyCheck::Unchanged);
+    assert_eq!(KeyCheck::compare(Some(&key), &other_key), KeyCheck::Changed { previous: key });
+}
+
+#[test]
+fn changed_key_is_held_until_taken() {
+    let eddie = Username::new("Eddie").unwrap();
+    let key = IdentityKeyPair::generate().identity_key();
+    let mut key_changes = KeyChanges::new();
+    assert_eq!(key_changes.get(&eddie), None);
+
+    key_changes.add(&eddie, key);
+    assert_eq!(key_changes.get(&eddie), Some(&key));
+    assert_eq!(key_changes.take(&eddie), Some(key));
+    assert_eq!(key_changes.take(&eddie), None);
+}
+
+#[test]
+fn safety_number_matches_on_both_sides() {
+    let harry = Username::new("Harry").unwrap();
+    let eddie = Username::new("Eddie").unwrap();
+    let harry_key = IdentityKeyPair::generate().identity_key();
+    let eddie_key = IdentityKeyPair::generate().identity_key();
+
+    let harrys_view = SafetyNumber::new(&harry, &harry_key, &eddie, &eddie_key, false);
+    let eddies_view = SafetyNumber::new(&eddie, &eddie_key, &harry, &harry_key, false);
+    assert_eq!(harrys_view.safety_number, eddies_view.safety_number);
+    assert_eq!(harrys_view.own_fingerprint, eddies_view.contact_fingerprint);
+    assert_eq!(harrys_view.contact, eddie);
+}
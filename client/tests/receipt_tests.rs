@@ -0,0 +1,109 @@
+mod common;
+
+use std::env;
+use std::net::TcpListener;
+use std::path::Path;
+use std::thread;
+
+use ratatui::prelude::*;
+use ratatui::widgets::Widget;
+
+use client::cli::app::AppContext;
+use client::cli::chats::ChatsTab;
+use client::cli::theme::THEME;
+use client::comms::Connection;
+use client::crypto::ConversationKey;
+use client::storage::storage;
+use protocol::{shared, Capabilities, IdentityKeyPair, ProtocolMessage, ReceiptKind, Username};
+
+use common::{connect, FakeSession};
+
+// Sets up .cli_chat in home for uname, connected with conn_uname under key
+fn set_up(home: &Path, uname: &Username, identity: &IdentityKeyPair, conn_uname: &Username,
+    conn_identity: &IdentityKeyPair, key: &ConversationKey) {
+    env::set_var("HOME", home);
+    storage::create_cli_chat_dir(uname, shared::generate_token(), identity).unwrap();
+    storage::write_contact_identity(conn_uname, &conn_identity.identity_key()).unwrap();
+    storage::add_conversation_key(conn_uname, key).unwrap();
+}
+
+// The conversation view with conn_uname open at its latest message, as read from storage
+fn open_conversation(conn_uname: &Username, connection: &Connection) -> AppContext {
+    let mut context = AppContext {
+        conversations: vec![conn_uname.clone()],
+        history: storage::read_history(conn_uname).unwrap(),
+        ..AppContext::default()
+    };
+    context.row_index = context.history.len() - 1;
+    context.update_deliveries(connection);
+    context
+}
+
+#[test]
+fn displaying_a_message_marks_it_read_for_its_sender() {
+    let harry = Username::new("harry").unwrap();
+    let eddie = Username::new("eddie").unwrap();
+    let (harry_identity, eddie_identity) = (IdentityKeyPair::generate(), IdentityKeyPair::generate());
+    let key = ConversationKey::generate();
+
+    // each has their own .cli_chat; HOME is switched to whoever's turn it is
+    let harry_home = tempfile::tempdir().unwrap();
+    let eddie_home = tempfile::tempdir().unwrap();
+    set_up(eddie_home.path(), &eddie, &eddie_identity, &harry, &harry_identity, &key);
+    set_up(harry_home.path(), &harry, &harry_identity, &eddie, &eddie_identity, &key);
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let server_listener = listener.try_clone().unwrap();
+    let server = thread::spawn(move || {
+        let (harry_session, _) = FakeSession::accept(&server_listener, Capabilities::RECEIPTS);
+        let (eddie_session, _) = FakeSession::accept(&server_listener, Capabilities::RECEIPTS);
+        (harry_session, eddie_session)
+    });
+    let mut harry_connection = connect(&listener, &harry);
+    let mut eddie_connection = connect(&listener, &eddie);
+    let (mut harry_session, mut eddie_session) = server.join().unwrap();
+
+    let message_id = harry_connection.send_chat_message(&eddie, "are you there?").unwrap();
+    let ProtocolMessage::ChatMessage(chat_message) = harry_session.recv() else {
+        panic!("expected ChatMessage");
+    };
+    // ...and another, selected in harry's view, so the first is shown unhighlighted
+    harry_connection.send_chat_message(&eddie, "hello?").unwrap();
+
+    env::set_var("HOME", eddie_home.path());
+    eddie_session.send(&chat_message);
+    eddie_connection.handle_message().unwrap();
+    let ProtocolMessage::Receipt(delivered) = eddie_session.recv() else {
+        panic!("expected Receipt");
+    };
+    assert_eq!(delivered.kind(), ReceiptKind::Delivered);
+
+    // shown at the bottom of eddie's open conversation, so read, and only once
+    let eddie_context = open_conversation(&harry, &eddie_connection);
+    eddie_context.mark_shown_read(&mut eddie_connection).unwrap();
+    eddie_context.mark_shown_read(&mut eddie_connection).unwrap();
+    let ProtocolMessage::Receipt(read) = eddie_session.recv() else {
+        panic!("expected Receipt");
+    };
+    assert_eq!(read.kind(), ReceiptKind::Read);
+    assert_eq!(read.message_id(), message_id);
+    drop(eddie_connection);
+    eddie_session.expect_closed();
+
+    env::set_var("HOME", harry_home.path());
+    harry_session.send(&read);
+    harry_connection.handle_message().unwrap();
+
+    let harry_context = open_conversation(&eddie, &harry_connection);
+    let area = Rect::new(0, 0, 60, 8);
+    let mut buf = Buffer::empty(area);
+    ChatsTab::new(&harry_context).render(area, &mut buf);
+    let ticks = buf.content()
+        .iter()
+        .find(|cell| cell.symbol() == "✓")
+        .expect("no ticks shown");
+    assert_eq!(ticks.fg, THEME.receipts.read.fg.unwrap());
+    let text: String = buf.content().iter().map(|cell| cell.symbol()).collect();
+    assert!(text.contains("are you there? ✓✓"));
+    assert!(!text.contains("hello? ✓"));
+}
@@ -0,0 +1,64 @@
+mod common;
+
+use std::env;
+use std::net::TcpListener;
+use std::thread;
+
+use client::comms::Connection;
+use client::storage::storage;
+use protocol::{shared, Capabilities, IdentityKeyPair, ProtocolMessage, RotateTokenResp, Username};
+use protocol::status_codes::StatusCode;
+
+use common::{connect, FakeSession};
+
+fn verify(connection: &mut Connection) {
+    connection.verify(&storage::read_token().unwrap()).unwrap();
+    while connection.verified().is_none() {
+        connection.handle_message().unwrap();
+    }
+    assert_eq!(connection.verified(), Some(true));
+}
+
+#[test]
+fn rotated_token_replaces_the_old_one() {
+    let home = tempfile::tempdir().unwrap();
+    env::set_var("HOME", home.path());
+
+    let uname = Username::new("harry").unwrap();
+    let old_token = shared::generate_token();
+    storage::create_cli_chat_dir(&uname, old_token, &IdentityKeyPair::generate()).unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let server_listener = listener.try_clone().unwrap();
+    let server_uname = uname.clone();
+    let server = thread::spawn(move || {
+        let (mut session, auth_challenge) = FakeSession::accept(&server_listener, Capabilities::NONE);
+        session.expect_verify(&server_uname, &auth_challenge, &old_token);
+        let ProtocolMessage::RotateTokenReq(rotate_req) = session.recv() else {
+            panic!("expected RotateTokenReq");
+        };
+        assert!(!rotate_req.keep_current());
+        let rotate_resp = RotateTokenResp::new(StatusCode::Success);
+        session.send(&rotate_resp);
+
+        // the next session verifies with the new token
+        let (mut session, auth_challenge) = FakeSession::accept(&server_listener, Capabilities::NONE);
+        session.expect_verify(&server_uname, &auth_challenge, &rotate_resp.token());
+        rotate_resp.token()
+    });
+
+    let mut connection = connect(&listener, &uname);
+    verify(&mut connection);
+    connection.rotate_token(false).unwrap();
+    let notices = connection.handle_message().unwrap();
+    assert!(notices[0].starts_with("Token rotated"));
+
+    let new_token = storage::read_token().unwrap();
+    assert_ne!(new_token, old_token);
+    let cli_root = home.path().join(storage::ROOT_DIR_NAME);
+    assert!(!cli_root.join(storage::NEW_TOKEN_FN).exists());
+
+    let mut connection = connect(&listener, &uname);
+    verify(&mut connection);
+    assert_eq!(server.join().unwrap(), new_token);
+}
@@ -0,0 +1,53 @@
+use client::helpers;
+use client::transfer::{self, Direction, Transfer};
+use protocol::Username;
+use protocol::field_lens::FILE_CHUNK_LEN;
+
+fn transfer(file_size: u64) -> Transfer {
+    Transfer {
+        transfer_id: [1u8; 16],
+        direction: Direction::Incoming,
+        peer: Username::new("Harry").unwrap(),
+        file_name: "server.log".to_string(),
+        file_size,
+        digest: helpers::sha256_from_bytes(b"contents".to_vec()),
+        source: None,
+    }
+}
+
+#[test]
+fn file_is_split_into_chunks() {
+    let transfer = transfer(2 * FILE_CHUNK_LEN as u64 + 10);
+    assert_eq!(transfer.chunk_count(), 3);
+    assert_eq!(transfer.chunk_range(0), 0..FILE_CHUNK_LEN);
+    assert_eq!(transfer.chunk_range(2), 2 * FILE_CHUNK_LEN..2 * FILE_CHUNK_LEN + 10);
+
+    assert_eq!(self::transfer(FILE_CHUNK_LEN as u64).chunk_count(), 1);
+    assert_eq!(self::transfer(0).chunk_count(), 0);
+}
+
+#[test]
+fn info_round_trip() {
+    let transfer = transfer(8);
+    let (digest, file_name) = Transfer::decode_info(&transfer.encode_info()).unwrap();
+    assert_eq!(digest, transfer.digest);
+    assert_eq!(file_name, "server.log");
+
+    assert!(Transfer::decode_info(&transfer.encode_info()[..10]).is_err());
+    assert!(Transfer::decode_info(&[b'z'; 70]).is_err());
+}
+
+#[test]
+fn file_names_must_be_safe_to_save() {
+    assert!(transfer::validate_file_name("screenshot 1.png").is_ok());
+    assert!(transfer::validate_file_name("").is_err());
+    assert!(transfer::validate_file_name("..").is_err());
+    assert!(transfer::validate_file_name("../../.bashrc").is_err());
+    assert!(transfer::validate_file_name("dir\\file").is_err());
+    assert!(transfer::validate_file_name("two\nlines").is_err());
+    assert!(transfer::validate_file_name(&"a".repeat(256)).is_err());
+
+    let mut info = transfer(8).encode_info();
+    info.extend_from_slice(b"/etc/passwd");
+    assert!(Transfer::decode_info(&info).is_err());
+}
@@ -0,0 +1,80 @@
+use std::time::{Duration, Instant};
+
+use client::typing::{self, TypingTracker, TYPING_RESEND_INTERVAL, TYPING_TIMEOUT};
+use protocol::Username;
+
+fn uname(uname: &str) -> Username {
+    Username::new(uname).unwrap()
+}
+
+#[test]
+fn keystrokes_are_throttled() {
+    let mut tracker = TypingTracker::new();
+    let start = Instant::now();
+
+    assert!(tracker.typed(&uname("Harry"), start));
+    assert!(!tracker.typed(&uname("Harry"), start + Duration::from_millis(100)));
+    assert!(!tracker.typed(&uname("Harry"), start + TYPING_RESEND_INTERVAL - Duration::from_millis(1)));
+    assert!(tracker.typed(&uname("Harry"), start + TYPING_RESEND_INTERVAL));
+
+    // each conversation is throttled separately
+    assert!(tracker.typed(&uname("Eddie"), start + Duration::from_millis(100)));
+}
+
+#[test]
+fn stopping_is_only_sent_after_typing() {
+    let mut tracker = TypingTracker::new();
+    let start = Instant::now();
+    assert!(!tracker.stopped(&uname("Harry"), start));
+
+    tracker.typed(&uname("Harry"), start);
+    assert!(tracker.stopped(&uname("Harry"), start + Duration::from_secs(1)));
+    assert!(!tracker.stopped(&uname("Harry"), start + Duration::from_secs(1)));
+
+    // ...and typing again straight after stopping is sent
+    assert!(tracker.typed(&uname("Harry"), start + Duration::from_secs(1)));
+
+    // no need once their end has expired it anyway
+    assert!(!tracker.stopped(&uname("Harry"), start + Duration::from_secs(1) + TYPING_TIMEOUT));
+}
+
+#[test]
+fn incoming_indicators_expire() {
+    let mut tracker = TypingTracker::new();
+    let start = Instant::now();
+
+    tracker.update(&uname("Harry"), true, start);
+    assert!(tracker.is_typing(&uname("Harry"), start + TYPING_TIMEOUT - Duration::from_millis(1)));
+    assert!(!tracker.is_typing(&uname("Harry"), start + TYPING_TIMEOUT));
+
+    // repeats keep it going
+    tracker.update(&uname("Harry"), true, start + TYPING_RESEND_INTERVAL);
+    assert!(tracker.is_typing(&uname("Harry"), start + TYPING_TIMEOUT));
+
+    tracker.expire(start + TYPING_RESEND_INTERVAL + TYPING_TIMEOUT);
+    assert!(tracker.typing_users(start).is_empty());
+}
+
+#[test]
+fn stopped_clears_indicator() {
+    let mut tracker = TypingTracker::new();
+    let start = Instant::now();
+
+    tracker.update(&uname("Kerry"), true, start);
+    tracker.update(&uname("Harry"), true, start);
+    assert_eq!(tracker.typing_users(start), vec![uname("Harry"), uname("Kerry")]);
+
+    tracker.update(&uname("Kerry"), false, start);
+    assert_eq!(tracker.typing_users(start), vec![uname("Harry")]);
+}
+
+#[test]
+fn labels() {
+    assert_eq!(typing::typing_label(&[]), None);
+    assert_eq!(typing::typing_label(&[uname("Harry")]).unwrap(), "Harry is typing…");
+    assert_eq!(typing::typing_label(&[uname("Harry"), uname("Eddie")]).unwrap(), "Harry and Eddie are typing…");
+    assert_eq!(
+        typing::typing_label(&[uname("Harry"), uname("Eddie"), uname("Kerry")]).unwrap(),
+        "Several people are typing…"
+    );
+}
@@ -0,0 +1,127 @@
+use std::fmt;
+
+use hmac::{ Hmac, Mac };
+use sha2::Sha256;
+
+use crate::field_lens::{ TOKEN_LEN, CHALLENGE_LEN, VERIFIER_LEN, PROOF_LEN };
+use crate::errors::ProtocolError;
+use crate::decode::Decoder;
+use crate::message::Message;
+use crate::message_types::MessageType;
+use crate::username::Username;
+use crate::identity::{ self, IdentityKeyPair };
+
+type HmacSha256 = Hmac<Sha256>;
+
+// domain separation for the auth key derivation and the proof
+const AUTH_KEY_LABEL: &[u8] = b"cli-chat auth key v2";
+const PROOF_LABEL: &[u8] = b"cli-chat verify v2";
+
+/**
+Derives the verifier the server stores in place of a user's PAT token.
+
+The token is the seed of an Ed25519 key pair (the 'auth key'), and the
+verifier is its public half. The client answers challenges by signing them
+with the secret half (see prove), so the token itself never leaves the client
+after signup, and the server only ever holds what's needed to check an answer:
+a leak of the server's verifiers is no help in answering its challenges.
+*/
+pub fn derive_verifier(token: &[u8; TOKEN_LEN]) -> [u8; VERIFIER_LEN] {
+    auth_key(token).identity_key()
+}
+
+/**
+Client side: answers the server's challenge, proving it holds the token for uname
+*/
+pub fn prove(token: &[u8; TOKEN_LEN], challenge: &[u8; CHALLENGE_LEN], uname: &Username) -> [u8; PROOF_LEN] {
+    auth_key(token).sign(&proof_bytes(challenge, uname))
+}
+
+/**
+Server side: checks a client's answer to a challenge against uname's stored verifier
+*/
+pub fn check_proof(
+    verifier: &[u8; VERIFIER_LEN],
+    challenge: &[u8; CHALLENGE_LEN],
+    uname: &Username,
+    proof: &[u8; PROOF_LEN]
+) -> bool {
+    identity::verify_signature(verifier, &proof_bytes(challenge, uname), proof)
+}
+
+// the key pair seeded by a token: HMAC-SHA256, keyed by the token, of AUTH_KEY_LABEL
+fn auth_key(token: &[u8; TOKEN_LEN]) -> IdentityKeyPair {
+    let mut mac = HmacSha256::new_from_slice(token).expect("HMAC takes keys of any length");
+    mac.update(AUTH_KEY_LABEL);
+
+    IdentityKeyPair::from_secret_bytes(mac.finalize().into_bytes().into())
+}
+
+// what a proof signs: the challenge and the username
+fn proof_bytes(challenge: &[u8; CHALLENGE_LEN], uname: &Username) -> Vec<u8> {
+    let mut buffer = PROOF_LABEL.to_vec();
+    buffer.extend_from_slice(challenge);
+    uname.encode(&mut buffer);
+
+    buffer
+}
+
+/**
+Protocol message: server challenging the client to prove it holds a PAT token.
+
+Sent after the Hello/HelloAck handshake, and again after every VerifyReq that
+fails. Each challenge can only be answered once.
+*/
+pub struct AuthChallenge {
+    challenge: [u8; CHALLENGE_LEN],
+}
+
+impl AuthChallenge {
+    // a fresh, random challenge
+    pub fn new() -> Self {
+        AuthChallenge { challenge: rand::random() }
+    }
+
+    pub fn challenge(&self) -> &[u8; CHALLENGE_LEN] {
+        &self.challenge
+    }
+}
+
+impl Default for AuthChallenge {
+    fn default() -> Self {
+        AuthChallenge::new()
+    }
+}
+
+impl Message for AuthChallenge {
+    const MESSAGE_TYPE: MessageType = MessageType::AuthChallenge;
+
+    fn serialize(&self) -> Vec<u8> {
+        self.challenge.to_vec()
+    }
+
+    fn deserialize(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        ProtocolError::check_len(bytes, CHALLENGE_LEN)?;
+
+        let mut decoder = Decoder::new(bytes);
+        let challenge = decoder.read_array::<CHALLENGE_LEN>()?;
+
+        Ok (AuthChallenge {
+            challenge
+        })
+    }
+
+    fn length(&self) -> usize {
+        CHALLENGE_LEN
+    }
+}
+
+impl fmt::Debug for AuthChallenge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "AuthChallenge {{ challenge: {} }}",
+            crate::shared::token_to_string(self.challenge)
+        )
+    }
+}
@@ -1,83 +1,183 @@
 use std::fmt;
-use std::error::Error;
-use crate::field_lens::{ UNAME_LEN, MSGLEN_LEN, TOKEN_LEN, METHOD_LEN, ERR_CODE_LEN };
-use crate::errors::LengthError;
+use crate::field_lens::{ MSGLEN_LEN, MESSAGE_ID_LEN, TIMESTAMP_LEN, IDENTITY_KEY_LEN, SIGNATURE_LEN, REPLY_FLAG_LEN };
+use crate::errors::ProtocolError;
+use crate::decode::Decoder;
+use crate::message::Message;
+use crate::message_types::MessageType;
+use crate::username::Username;
+use crate::identity::{ self, IdentityKeyPair };
 
 /**
 Protocol message: chat message between clients (main 'unit' of the protocol)
+
+Every message is stamped by its sender with a random message id, used to spot
+retransmissions, and the time it was sent (milliseconds since the unix epoch).
+
+A reply carries the id of the message it answers (in_reply_to). On the wire
+the body is followed by a flag saying whether the message is a reply, then the
+id if it is, so where the body ends never depends on what comes after it.
+
+The sender signs the message with their identity key (see identity.rs). The
+signature covers every other field, including the (encrypted) body.
 */
+#[derive(Clone)]
 pub struct ChatMessage {
     pub msg_length: u32,
-    pub send_uname: [u8; UNAME_LEN],
-    pub recv_uname: [u8; UNAME_LEN],
+    pub message_id: [u8; MESSAGE_ID_LEN],
+    pub timestamp: u64,
+    pub signature: [u8; SIGNATURE_LEN],
+    pub send_uname: Username,
+    pub recv_uname: Username,
     pub msg_buffer: Vec<u8>,
+    pub in_reply_to: Option<[u8; MESSAGE_ID_LEN]>,
 }
 
 impl ChatMessage {
-    pub fn empty() -> Self {
+    pub fn new(send_uname: &Username, recv_uname: &Username, msg: &str) -> Self {
         ChatMessage {
-            msg_length: 0,
-            send_uname: [0; UNAME_LEN],
-            recv_uname: [0; UNAME_LEN],
-            msg_buffer: Vec::new(),
+            msg_length: msg.len() as u32,
+            message_id: crate::shared::generate_message_id(),
+            timestamp: crate::shared::timestamp_now(),
+            signature: [0u8; SIGNATURE_LEN],
+            send_uname: send_uname.clone(),
+            recv_uname: recv_uname.clone(),
+            msg_buffer: msg.as_bytes().to_vec(),
+            in_reply_to: None,
         }
     }
 
-    pub fn new(new_send_uname: &str, new_recv_uname: &str, msg: &str) -> Self {
-        let mut chat_message = ChatMessage::empty();
-        crate::shared::set_uname(&mut chat_message.send_uname, new_send_uname);
-        crate::shared::set_uname(&mut chat_message.recv_uname, new_recv_uname);
-        chat_message.msg_buffer.extend_from_slice(msg.as_bytes());
-        chat_message.msg_length = msg.len() as u32;
+    /**
+    New message answering the one with id in_reply_to
+    */
+    pub fn reply(send_uname: &Username, recv_uname: &Username, msg: &str, in_reply_to: [u8; MESSAGE_ID_LEN]) -> Self {
+        ChatMessage {
+            in_reply_to: Some(in_reply_to),
+            ..ChatMessage::new(send_uname, recv_uname, msg)
+        }
+    }
 
-        chat_message
+    pub fn message_id(&self) -> [u8; MESSAGE_ID_LEN] {
+        self.message_id
     }
 
-    pub fn serialize(&self) -> Vec<u8> {
-        let mut buffer = Vec::new();
+    /**
+    Time the message was sent, in milliseconds since the unix epoch (sender's clock)
+    */
+    pub fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
 
+    /**
+    Replaces the message body (and its length), e.g. with its encrypted form
+    */
+    pub fn set_body(&mut self, body: Vec<u8>) {
+        self.msg_length = body.len() as u32;
+        self.msg_buffer = body;
+    }
+
+    /**
+    Signs the message as it stands, so should be called after anything that
+    changes it (e.g. encrypting the body)
+    */
+    pub fn sign(&mut self, identity: &IdentityKeyPair) {
+        self.signature = identity.sign(&self.signed_bytes());
+    }
+
+    /**
+    Checks the message was signed by the holder of identity_key
+    */
+    pub fn verify_signature(&self, identity_key: &[u8; IDENTITY_KEY_LEN]) -> bool {
+        identity::verify_signature(identity_key, &self.signed_bytes(), &self.signature)
+    }
+
+    // everything but the signature itself
+    fn signed_bytes(&self) -> Vec<u8> {
+        let mut buffer = Vec::new();
         buffer.extend_from_slice(&self.msg_length.to_be_bytes());
-        buffer.extend_from_slice(&self.send_uname);
-        buffer.extend_from_slice(&self.recv_uname);
+        buffer.extend_from_slice(&self.message_id);
+        buffer.extend_from_slice(&self.timestamp.to_be_bytes());
+        self.send_uname.encode(&mut buffer);
+        self.recv_uname.encode(&mut buffer);
         buffer.extend_from_slice(&self.msg_buffer);
+        self.encode_in_reply_to(&mut buffer);
 
         buffer
     }
 
-    pub fn deserialize(bytes: &[u8]) -> Result<ChatMessage, Box<dyn Error>> {
-        if bytes.len() < Self::fixed_size() {
-            return Err(Box::new(LengthError));
+    /**
+    Appends the reply flag, then in_reply_to if there is one
+    */
+    pub fn encode_in_reply_to(&self, buffer: &mut Vec<u8>) {
+        match &self.in_reply_to {
+            Some(in_reply_to) => {
+                buffer.push(1);
+                buffer.extend_from_slice(in_reply_to);
+            }
+            None => buffer.push(0),
         }
+    }
 
-        let (fixed_size, variable_size) = bytes.split_at(Self::fixed_size());
+    // size of the fixed-width fields (everything but the usernames, message body and in_reply_to)
+    pub fn fixed_size() -> usize {
+        MSGLEN_LEN + MESSAGE_ID_LEN + TIMESTAMP_LEN + SIGNATURE_LEN + REPLY_FLAG_LEN
+    }
+}
 
-        let mut send_uname = [0u8; UNAME_LEN];
-        let mut recv_uname = [0u8; UNAME_LEN];
-        let msg_length = u32::from_be_bytes(fixed_size[..MSGLEN_LEN].try_into().unwrap());
-        
-        send_uname.copy_from_slice(&fixed_size[MSGLEN_LEN..(MSGLEN_LEN + UNAME_LEN)]);
-        recv_uname.copy_from_slice(&fixed_size[(MSGLEN_LEN + UNAME_LEN)..]);
+impl Message for ChatMessage {
+    const MESSAGE_TYPE: MessageType = MessageType::ChatMessage;
 
-        if variable_size.len() < msg_length as usize {
-            return Err(Box::new(LengthError));
-        }
+    fn serialize(&self) -> Vec<u8> {
+        let mut buffer = Vec::new();
 
-        let message = variable_size[..msg_length as usize].to_vec();
+        buffer.extend_from_slice(&self.msg_length.to_be_bytes());
+        buffer.extend_from_slice(&self.message_id);
+        buffer.extend_from_slice(&self.timestamp.to_be_bytes());
+        buffer.extend_from_slice(&self.signature);
+        self.send_uname.encode(&mut buffer);
+        self.recv_uname.encode(&mut buffer);
+        buffer.extend_from_slice(&self.msg_buffer);
+        self.encode_in_reply_to(&mut buffer);
 
-        Ok(ChatMessage {
-            msg_length: msg_length,
-            send_uname: send_uname,
-            recv_uname: recv_uname,
-            msg_buffer: message
-        })
+        buffer
     }
 
-    pub fn length(&self) -> usize {
-        ChatMessage::fixed_size() + self.msg_length as usize
+    fn deserialize(bytes: &[u8]) -> Result<ChatMessage, ProtocolError> {
+        ProtocolError::check_min_len(bytes, Self::fixed_size())?;
+
+        let mut decoder = Decoder::new(bytes);
+        let msg_length = decoder.read_u32()?;
+        let message_id = decoder.read_array::<MESSAGE_ID_LEN>()?;
+        let timestamp = decoder.read_u64()?;
+        let signature = decoder.read_array::<SIGNATURE_LEN>()?;
+        let send_uname = decoder.read_username("send_uname")?;
+        let recv_uname = decoder.read_username("recv_uname")?;
+
+        let message = decoder.read_bytes(msg_length as usize)?.to_vec();
+        let in_reply_to = match decoder.read_u8()? {
+            0 => None,
+            1 => Some(decoder.read_array::<MESSAGE_ID_LEN>()?),
+            flag => return Err(ProtocolError::InvalidValue { field: "in_reply_to", value: flag }),
+        };
+        decoder.finish()?;
+
+        Ok(ChatMessage {
+            msg_length,
+            message_id,
+            timestamp,
+            signature,
+            send_uname,
+            recv_uname,
+            msg_buffer: message,
+            in_reply_to
+        })
     }
 
-    pub fn fixed_size() -> usize {
-        MSGLEN_LEN + (2 * UNAME_LEN)
+    fn length(&self) -> usize {
+        ChatMessage::fixed_size()
+            + self.send_uname.encoded_len()
+            + self.recv_uname.encoded_len()
+            + self.msg_length as usize
+            + self.in_reply_to.map_or(0, |_| MESSAGE_ID_LEN)
     }
 }
 
@@ -86,12 +186,14 @@ impl fmt::Debug for ChatMessage {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
             f,
-            "ChatMessage {{ msglen: {}, send_uname: \"{}\", recv_uname: \"{}\", message: \"{}\" }}",
+            "ChatMessage {{ msglen: {}, message_id: {}, timestamp: {}, send_uname: \"{}\", recv_uname: \"{}\", message: \"{}\", in_reply_to: {} }}",
             self.msg_length,
-            String::from_utf8_lossy(&self.send_uname).to_string(),
-            String::from_utf8_lossy(&self.recv_uname).to_string(),
-            String::from_utf8_lossy(&self.msg_buffer).to_string()
+            crate::shared::message_id_to_string(self.message_id),
+            self.timestamp,
+            self.send_uname,
+            self.recv_uname,
+            String::from_utf8_lossy(&self.msg_buffer),
+            self.in_reply_to.map_or("none".to_string(), crate::shared::message_id_to_string)
         )
     }
 }
-
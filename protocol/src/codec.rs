@@ -0,0 +1,222 @@
+use std::io::{self, Read, Write};
+use crate::field_lens::MAX_PACKET_LEN;
+use crate::errors::ProtocolError;
+use crate::decode::Decoder;
+use crate::{ Packet, Fragment };
+
+// number of bytes requested from the underlying stream per read
+const READ_CHUNK_LEN: usize = 1024;
+
+/**
+Reads whole Packets from a byte stream.
+
+A packet on the wire is a fixed-size header (method + msg_length) followed by
+exactly msg_length bytes of message. The reader buffers whatever the stream
+hands back, so packets split across several reads, or several packets arriving
+in one read, both come out as whole Packets. Bytes past the end of one packet
+are kept for the next call to read_packet.
+
+If the stream returns an error (e.g. a read timeout) part-way through a packet,
+the bytes received so far are kept and the next call picks up where it left off.
+
+A packet over the maximum size is rejected with PacketTooLarge and its bytes are
+skipped as they arrive, so the next call returns the packet after it.
+*/
+pub struct PacketReader<R: Read> {
+    inner: R,
+    buffer: Vec<u8>,
+    max_packet_len: usize,
+    // bytes of a rejected packet still to be skipped
+    discard_len: usize,
+}
+
+impl<R: Read> PacketReader<R> {
+    pub fn new(inner: R) -> Self {
+        PacketReader::with_max_len(inner, MAX_PACKET_LEN)
+    }
+
+    /**
+    Creates a reader that rejects any packet (header included) larger than max_packet_len
+    */
+    pub fn with_max_len(inner: R, max_packet_len: usize) -> Self {
+        PacketReader {
+            inner,
+            buffer: Vec::new(),
+            max_packet_len,
+            discard_len: 0,
+        }
+    }
+
+    /**
+    Blocks until a whole packet has been read, the stream closes, or the stream errors
+    */
+    pub fn read_packet(&mut self) -> Result<Packet, ProtocolError> {
+        loop {
+            if let Some(packet) = self.next_buffered_packet()? {
+                return Ok(packet);
+            }
+
+            let mut chunk = [0u8; READ_CHUNK_LEN];
+            let bytes_read = match self.inner.read(&mut chunk) {
+                Ok(n) => n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(ProtocolError::Io(e)),
+            };
+
+            if bytes_read == 0 {
+                let msg = if self.buffer.is_empty() {
+                    "connection closed"
+                } else {
+                    "connection closed part-way through a packet"
+                };
+                return Err(ProtocolError::Io(io::Error::new(io::ErrorKind::UnexpectedEof, msg)));
+            }
+            self.buffer.extend_from_slice(&chunk[..bytes_read]);
+            self.discard_buffered();
+        }
+    }
+
+    /**
+    Number of bytes received but not yet returned as part of a packet
+    */
+    pub fn buffered_len(&self) -> usize {
+        self.buffer.len()
+    }
+
+    pub fn max_packet_len(&self) -> usize {
+        self.max_packet_len
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    // Pulls one packet off the front of the buffer, if a whole one is there
+    fn next_buffered_packet(&mut self) -> Result<Option<Packet>, ProtocolError> {
+        if self.buffer.len() < Packet::fixed_size() {
+            return Ok(None);
+        }
+
+        let (method, msg_length) = decode_header(&self.buffer)?;
+        let packet_len = match check_packet_len(msg_length, self.max_packet_len) {
+            Ok(packet_len) => packet_len,
+            Err(e) => {
+                self.discard_len = Packet::fixed_size().saturating_add(msg_length as usize);
+                self.discard_buffered();
+                return Err(e);
+            }
+        };
+        if self.buffer.len() < packet_len {
+            return Ok(None);
+        }
+
+        let msg_buffer = self.buffer[Packet::fixed_size()..packet_len].to_vec();
+        self.buffer.drain(..packet_len);
+
+        Ok(Some(Packet::new(method, msg_length, msg_buffer)))
+    }
+
+    // Drops as much of a rejected packet as has been received
+    fn discard_buffered(&mut self) {
+        let len = self.discard_len.min(self.buffer.len());
+        self.buffer.drain(..len);
+        self.discard_len -= len;
+    }
+}
+
+/**
+Writes whole Packets to a byte stream, refusing any that a PacketReader
+with the same maximum size would reject.
+*/
+pub struct PacketWriter<W: Write> {
+    inner: W,
+    max_packet_len: usize,
+}
+
+impl<W: Write> PacketWriter<W> {
+    pub fn new(inner: W) -> Self {
+        PacketWriter::with_max_len(inner, MAX_PACKET_LEN)
+    }
+
+    pub fn with_max_len(inner: W, max_packet_len: usize) -> Self {
+        PacketWriter {
+            inner,
+            max_packet_len,
+        }
+    }
+
+    pub fn write_packet(&mut self, packet: &Packet) -> Result<(), ProtocolError> {
+        if packet.msg_length as usize != packet.msg_buffer.len() {
+            return Err(ProtocolError::LengthMismatch {
+                expected: packet.msg_length as usize,
+                actual: packet.msg_buffer.len(),
+            });
+        }
+        check_packet_len(packet.msg_length, self.max_packet_len)?;
+
+        self.inner.write_all(&packet.serialize())?;
+        self.inner.flush()?;
+
+        Ok(())
+    }
+
+    /**
+    Writes packet whole if it fits, otherwise as a series of Fragments (see fragment.rs).
+
+    Only for peers that agreed to the FRAGMENTATION capability.
+    */
+    pub fn write_fragmented(&mut self, packet: &Packet) -> Result<(), ProtocolError> {
+        if packet.length() <= self.max_packet_len {
+            return self.write_packet(packet);
+        }
+
+        for fragment in Fragment::split(packet, self.max_packet_len)? {
+            self.write_packet(&Packet::wrap(&fragment))?;
+        }
+
+        Ok(())
+    }
+
+    pub fn max_packet_len(&self) -> usize {
+        self.max_packet_len
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+}
+
+/**
+Splits a packet header into its method and msg_length fields
+*/
+pub(crate) fn decode_header(header: &[u8]) -> Result<(u8, u32), ProtocolError> {
+    let mut decoder = Decoder::new(header);
+    let method = decoder.read_u8()?;
+    let msg_length = decoder.read_u32()?;
+
+    Ok((method, msg_length))
+}
+
+/**
+Returns the total length (header included) of a packet with the given
+msg_length, or an error if it exceeds max_packet_len
+*/
+pub(crate) fn check_packet_len(msg_length: u32, max_packet_len: usize) -> Result<usize, ProtocolError> {
+    let packet_len = Packet::fixed_size().saturating_add(msg_length as usize);
+    if packet_len > max_packet_len {
+        return Err(ProtocolError::PacketTooLarge {
+            packet_len,
+            max_packet_len,
+        });
+    }
+
+    Ok(packet_len)
+}
@@ -1,12 +1,145 @@
-use crate::field_lens::UNAME_LEN;
 use std::fmt;
 
+use crate::field_lens::{ RESPONSE_LEN, PUBLIC_KEY_LEN, KEY_FLAG_LEN, IDENTITY_KEY_LEN, SIGNATURE_LEN };
+use crate::errors::ProtocolError;
+use crate::decode::Decoder;
+use crate::message::Message;
+use crate::message_types::MessageType;
+use crate::username::Username;
+use crate::identity::{ self, IdentityKeyPair };
+
+// domain separation for what each side of the key agreement signs
+const CONN_REQ_LABEL: &[u8] = b"connection request key";
+const CONN_RESP_LABEL: &[u8] = b"connection response key";
+
+/**
+A client's answer to a connection request
+*/
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ConnResponse {
+    Accept = 0,
+    Reject = 1,
+    // reject, and have the server refuse any further requests from the requester
+    Block = 2,
+    AlreadyConnected = 3,
+}
+
+impl ConnResponse {
+    pub fn decode(response: u8) -> Result<Self, ProtocolError> {
+        match response {
+            0 => Ok(ConnResponse::Accept),
+            1 => Ok(ConnResponse::Reject),
+            2 => Ok(ConnResponse::Block),
+            3 => Ok(ConnResponse::AlreadyConnected),
+            _ => Err(ProtocolError::InvalidValue { field: "response", value: response }),
+        }
+    }
+}
+
+impl fmt::Display for ConnResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnResponse::Accept => write!(f, "Accept"),
+            ConnResponse::Reject => write!(f, "Reject"),
+            ConnResponse::Block => write!(f, "Block"),
+            ConnResponse::AlreadyConnected => write!(f, "AlreadyConnected"),
+        }
+    }
+}
+
 /**
-Protocol message: client requesting to connect with another client
+Protocol message: client requesting to connect with another client.
+
+Carries the requester's X25519 public key for the conversation's key agreement,
+signed (along with both usernames) with the requester's identity key, so the
+server can't swap in a key of its own.
 */
 pub struct C2cConnReq {
-    req_uname: [u8; UNAME_LEN],
-    resp_uname: [u8; UNAME_LEN],
+    req_uname: Username,
+    resp_uname: Username,
+    public_key: [u8; PUBLIC_KEY_LEN],
+    signature: [u8; SIGNATURE_LEN],
+}
+
+impl C2cConnReq {
+    pub fn new(req_uname: &Username, resp_uname: &Username, public_key: [u8; PUBLIC_KEY_LEN]) -> Self {
+        C2cConnReq {
+            req_uname: req_uname.clone(),
+            resp_uname: resp_uname.clone(),
+            public_key,
+            signature: [0u8; SIGNATURE_LEN],
+        }
+    }
+
+    // user asking to connect
+    pub fn req_uname(&self) -> &Username {
+        &self.req_uname
+    }
+
+    // user being asked
+    pub fn resp_uname(&self) -> &Username {
+        &self.resp_uname
+    }
+
+    pub fn public_key(&self) -> &[u8; PUBLIC_KEY_LEN] {
+        &self.public_key
+    }
+
+    pub fn sign(&mut self, identity: &IdentityKeyPair) {
+        self.signature = identity.sign(&self.signed_bytes());
+    }
+
+    /**
+    Checks the public key was signed by the holder of identity_key, for this
+    pair of users
+    */
+    pub fn verify_signature(&self, identity_key: &[u8; IDENTITY_KEY_LEN]) -> bool {
+        identity::verify_signature(identity_key, &self.signed_bytes(), &self.signature)
+    }
+
+    // everything but the signature itself
+    fn signed_bytes(&self) -> Vec<u8> {
+        let mut buffer = CONN_REQ_LABEL.to_vec();
+        self.req_uname.encode(&mut buffer);
+        self.resp_uname.encode(&mut buffer);
+        buffer.extend_from_slice(&self.public_key);
+
+        buffer
+    }
+}
+
+impl Message for C2cConnReq {
+    const MESSAGE_TYPE: MessageType = MessageType::C2cConnReq;
+
+    fn serialize(&self) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        self.req_uname.encode(&mut buffer);
+        self.resp_uname.encode(&mut buffer);
+        buffer.extend_from_slice(&self.public_key);
+        buffer.extend_from_slice(&self.signature);
+
+        buffer
+    }
+
+    fn deserialize(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        let mut decoder = Decoder::new(bytes);
+        let req_uname = decoder.read_username("req_uname")?;
+        let resp_uname = decoder.read_username("resp_uname")?;
+        let public_key = decoder.read_array::<PUBLIC_KEY_LEN>()?;
+        let signature = decoder.read_array::<SIGNATURE_LEN>()?;
+        decoder.finish()?;
+
+        Ok (C2cConnReq {
+            req_uname,
+            resp_uname,
+            public_key,
+            signature
+        })
+    }
+
+    fn length(&self) -> usize {
+        self.req_uname.encoded_len() + self.resp_uname.encoded_len() + PUBLIC_KEY_LEN + SIGNATURE_LEN
+    }
 }
 
 impl fmt::Debug for C2cConnReq {
@@ -14,29 +147,146 @@ impl fmt::Debug for C2cConnReq {
         write!(
             f,
             "C2cConnReq {{ req_uname: \"{}\", resp_uname: \"{}\" }}",
-            String::from_utf8_lossy(&self.req_uname).to_string(),
-            String::from_utf8_lossy(&self.resp_uname).to_string()
+            self.req_uname(),
+            self.resp_uname()
         )
     }
 }
 
 /**
-Protocol message: client responding to connection request from other client
+Protocol message: client responding to connection request from other client.
+
+An accepting client includes its own X25519 public key, signed like a
+C2cConnReq's; responses the server generates itself (e.g. for a blocked
+requester) carry none. On the wire the key and its signature are preceded by a
+flag byte saying whether they are present.
 */
 pub struct C2cConnResp {
-    req_uname: [u8; UNAME_LEN],
-    resp_uname: [u8; UNAME_LEN],
-    response: u8
+    req_uname: Username,
+    resp_uname: Username,
+    response: ConnResponse,
+    public_key: Option<[u8; PUBLIC_KEY_LEN]>,
+    signature: [u8; SIGNATURE_LEN],
+}
+
+impl C2cConnResp {
+    pub fn new(
+        req_uname: &Username,
+        resp_uname: &Username,
+        response: ConnResponse,
+        public_key: Option<[u8; PUBLIC_KEY_LEN]>
+    ) -> Self {
+        C2cConnResp {
+            req_uname: req_uname.clone(),
+            resp_uname: resp_uname.clone(),
+            response,
+            public_key,
+            signature: [0u8; SIGNATURE_LEN],
+        }
+    }
+
+    // user who asked to connect
+    pub fn req_uname(&self) -> &Username {
+        &self.req_uname
+    }
+
+    // user who was asked
+    pub fn resp_uname(&self) -> &Username {
+        &self.resp_uname
+    }
+
+    pub fn response(&self) -> ConnResponse {
+        self.response
+    }
+
+    pub fn public_key(&self) -> Option<&[u8; PUBLIC_KEY_LEN]> {
+        self.public_key.as_ref()
+    }
+
+    /**
+    Signs the public key, so should only be called on responses carrying one
+    */
+    pub fn sign(&mut self, identity: &IdentityKeyPair) {
+        self.signature = identity.sign(&self.signed_bytes());
+    }
+
+    /**
+    Checks the public key was signed by the holder of identity_key, for this
+    pair of users. False if there is no key.
+    */
+    pub fn verify_signature(&self, identity_key: &[u8; IDENTITY_KEY_LEN]) -> bool {
+        self.public_key.is_some() && identity::verify_signature(identity_key, &self.signed_bytes(), &self.signature)
+    }
+
+    // the usernames and public key
+    fn signed_bytes(&self) -> Vec<u8> {
+        let mut buffer = CONN_RESP_LABEL.to_vec();
+        self.req_uname.encode(&mut buffer);
+        self.resp_uname.encode(&mut buffer);
+        if let Some(public_key) = &self.public_key {
+            buffer.extend_from_slice(public_key);
+        }
+
+        buffer
+    }
+}
+
+impl Message for C2cConnResp {
+    const MESSAGE_TYPE: MessageType = MessageType::C2cConnResp;
+
+    fn serialize(&self) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        self.req_uname.encode(&mut buffer);
+        self.resp_uname.encode(&mut buffer);
+        buffer.push(self.response as u8);
+        match &self.public_key {
+            Some(public_key) => {
+                buffer.push(1);
+                buffer.extend_from_slice(public_key);
+                buffer.extend_from_slice(&self.signature);
+            }
+            None => buffer.push(0),
+        }
+
+        buffer
+    }
+
+    fn deserialize(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        let mut decoder = Decoder::new(bytes);
+        let req_uname = decoder.read_username("req_uname")?;
+        let resp_uname = decoder.read_username("resp_uname")?;
+        let response = ConnResponse::decode(decoder.read_u8()?)?;
+        let (public_key, signature) = match decoder.read_u8()? {
+            0 => (None, [0u8; SIGNATURE_LEN]),
+            1 => (Some(decoder.read_array::<PUBLIC_KEY_LEN>()?), decoder.read_array::<SIGNATURE_LEN>()?),
+            flag => return Err(ProtocolError::InvalidValue { field: "has_public_key", value: flag }),
+        };
+        decoder.finish()?;
+
+        Ok (C2cConnResp {
+            req_uname,
+            resp_uname,
+            response,
+            public_key,
+            signature
+        })
+    }
+
+    fn length(&self) -> usize {
+        let key_len = if self.public_key.is_some() { PUBLIC_KEY_LEN + SIGNATURE_LEN } else { 0 };
+        self.req_uname.encoded_len() + self.resp_uname.encoded_len() + RESPONSE_LEN + KEY_FLAG_LEN + key_len
+    }
 }
 
 impl fmt::Debug for C2cConnResp {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
             f,
-            "C2cConnResp {{ req_uname: \"{}\", resp_uname: \"{}\", response: {} }}",
-            String::from_utf8_lossy(&self.req_uname).to_string(),
-            String::from_utf8_lossy(&self.resp_uname).to_string(),
-            self.response
+            "C2cConnResp {{ req_uname: \"{}\", resp_uname: \"{}\", response: {}, public_key: {} }}",
+            self.req_uname(),
+            self.resp_uname(),
+            self.response,
+            if self.public_key.is_some() { "present" } else { "none" }
         )
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,104 @@
+use crate::errors::ProtocolError;
+use crate::username::Username;
+
+/**
+Bounds-checked cursor over a serialized message.
+
+Every deserialize reads its fields through a Decoder, so a short or malformed
+message turns into a ProtocolError rather than an out-of-bounds panic, whatever
+bytes a peer sends.
+*/
+pub struct Decoder<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Decoder<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Decoder {
+            bytes,
+            pos: 0,
+        }
+    }
+
+    /**
+    Takes the next len bytes
+    */
+    pub fn read_bytes(&mut self, len: usize) -> Result<&'a [u8], ProtocolError> {
+        let end = self.pos.saturating_add(len);
+        if end > self.bytes.len() {
+            return Err(ProtocolError::Truncated {
+                expected: end,
+                actual: self.bytes.len(),
+            });
+        }
+
+        let bytes = &self.bytes[self.pos..end];
+        self.pos = end;
+
+        Ok(bytes)
+    }
+
+    pub fn read_array<const N: usize>(&mut self) -> Result<[u8; N], ProtocolError> {
+        let mut array = [0u8; N];
+        array.copy_from_slice(self.read_bytes(N)?);
+
+        Ok(array)
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, ProtocolError> {
+        Ok(self.read_array::<1>()?[0])
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16, ProtocolError> {
+        Ok(u16::from_be_bytes(self.read_array()?))
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, ProtocolError> {
+        Ok(u32::from_be_bytes(self.read_array()?))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, ProtocolError> {
+        Ok(u64::from_be_bytes(self.read_array()?))
+    }
+
+    /**
+    Reads a length-prefixed username (see username.rs), checking it is valid.
+    field names the message field being read, for error reporting.
+    */
+    pub fn read_username(&mut self, field: &'static str) -> Result<Username, ProtocolError> {
+        let len = self.read_u8()? as usize;
+        let bytes = self.read_bytes(len)?;
+        let uname = std::str::from_utf8(bytes).map_err(|_| ProtocolError::InvalidUtf8 { field })?;
+
+        Username::new(uname).map_err(|reason| ProtocolError::InvalidUsername { field, reason })
+    }
+
+    /**
+    Takes everything not yet read
+    */
+    pub fn read_rest(&mut self) -> &'a [u8] {
+        let rest = &self.bytes[self.pos..];
+        self.pos = self.bytes.len();
+
+        rest
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.bytes.len() - self.pos
+    }
+
+    /**
+    Checks the whole message has been read, i.e. there are no unexpected trailing bytes
+    */
+    pub fn finish(self) -> Result<(), ProtocolError> {
+        if self.pos != self.bytes.len() {
+            return Err(ProtocolError::LengthMismatch {
+                expected: self.pos,
+                actual: self.bytes.len(),
+            });
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,299 @@
+/*
+Edits and deletions of chat messages already sent.
+
+Both refer to the earlier ChatMessage by its message id, and are stamped with
+the time they were made. Like chat messages, they're signed by their sender's
+identity key, and an edit's new body is end-to-end encrypted as an Envelope
+(see envelope.rs). Signatures are domain separated, so an edit or deletion
+can never be passed off as a chat message (or the other way round).
+
+The server relays them between connections but, keeping no messages, can't
+tell whose message is being edited: recipients only apply an edit or deletion
+from the message's original sender, and keep the message's earlier text (or a
+tombstone in its place) rather than rewriting it.
+*/
+
+use std::fmt;
+
+use crate::field_lens::{ MESSAGE_ID_LEN, TIMESTAMP_LEN, IDENTITY_KEY_LEN, SIGNATURE_LEN };
+use crate::errors::ProtocolError;
+use crate::decode::Decoder;
+use crate::message::Message;
+use crate::message_types::MessageType;
+use crate::username::Username;
+use crate::identity::{ self, IdentityKeyPair };
+
+// domain separation for what edits and deletions sign
+const EDIT_LABEL: &[u8] = b"message edit";
+const DELETE_LABEL: &[u8] = b"message delete";
+
+/**
+Protocol message: sender replacing the body of one of their chat messages to
+recv_uname (message_id being the chat message's id).
+
+body holds the new text (sealed, once encrypted) and runs to the end of the message.
+*/
+#[derive(Clone)]
+pub struct MessageEdit {
+    message_id: [u8; MESSAGE_ID_LEN],
+    timestamp: u64,
+    signature: [u8; SIGNATURE_LEN],
+    send_uname: Username,
+    recv_uname: Username,
+    body: Vec<u8>,
+}
+
+impl MessageEdit {
+    pub fn new(message_id: [u8; MESSAGE_ID_LEN], send_uname: &Username, recv_uname: &Username, msg: &str) -> Self {
+        MessageEdit {
+            message_id,
+            timestamp: crate::shared::timestamp_now(),
+            signature: [0u8; SIGNATURE_LEN],
+            send_uname: send_uname.clone(),
+            recv_uname: recv_uname.clone(),
+            body: msg.as_bytes().to_vec(),
+        }
+    }
+
+    /**
+    Id of the chat message being edited
+    */
+    pub fn message_id(&self) -> [u8; MESSAGE_ID_LEN] {
+        self.message_id
+    }
+
+    /**
+    Time the edit was made, in milliseconds since the unix epoch (sender's clock)
+    */
+    pub fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
+    pub fn send_uname(&self) -> &Username {
+        &self.send_uname
+    }
+
+    pub fn recv_uname(&self) -> &Username {
+        &self.recv_uname
+    }
+
+    pub fn body(&self) -> &[u8] {
+        &self.body
+    }
+
+    /**
+    Replaces the edit's body, e.g. with its encrypted form
+    */
+    pub fn set_body(&mut self, body: Vec<u8>) {
+        self.body = body;
+    }
+
+    /**
+    Signs the edit as it stands, so should be called after encrypting it
+    */
+    pub fn sign(&mut self, identity: &IdentityKeyPair) {
+        self.signature = identity.sign(&self.signed_bytes());
+    }
+
+    pub fn verify_signature(&self, identity_key: &[u8; IDENTITY_KEY_LEN]) -> bool {
+        identity::verify_signature(identity_key, &self.signed_bytes(), &self.signature)
+    }
+
+    // everything but the signature itself
+    fn signed_bytes(&self) -> Vec<u8> {
+        let mut buffer = EDIT_LABEL.to_vec();
+        buffer.extend_from_slice(&self.message_id);
+        buffer.extend_from_slice(&self.timestamp.to_be_bytes());
+        self.send_uname.encode(&mut buffer);
+        self.recv_uname.encode(&mut buffer);
+        buffer.extend_from_slice(&self.body);
+
+        buffer
+    }
+
+    // size of the fixed-width fields (everything but the usernames and body)
+    pub fn fixed_size() -> usize {
+        MESSAGE_ID_LEN + TIMESTAMP_LEN + SIGNATURE_LEN
+    }
+}
+
+impl Message for MessageEdit {
+    const MESSAGE_TYPE: MessageType = MessageType::MessageEdit;
+
+    fn serialize(&self) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        buffer.extend_from_slice(&self.message_id);
+        buffer.extend_from_slice(&self.timestamp.to_be_bytes());
+        buffer.extend_from_slice(&self.signature);
+        self.send_uname.encode(&mut buffer);
+        self.recv_uname.encode(&mut buffer);
+        buffer.extend_from_slice(&self.body);
+
+        buffer
+    }
+
+    fn deserialize(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        ProtocolError::check_min_len(bytes, Self::fixed_size())?;
+
+        let mut decoder = Decoder::new(bytes);
+        let message_id = decoder.read_array::<MESSAGE_ID_LEN>()?;
+        let timestamp = decoder.read_u64()?;
+        let signature = decoder.read_array::<SIGNATURE_LEN>()?;
+        let send_uname = decoder.read_username("send_uname")?;
+        let recv_uname = decoder.read_username("recv_uname")?;
+        let body = decoder.read_rest().to_vec();
+
+        Ok (MessageEdit {
+            message_id,
+            timestamp,
+            signature,
+            send_uname,
+            recv_uname,
+            body
+        })
+    }
+
+    fn length(&self) -> usize {
+        MessageEdit::fixed_size()
+            + self.send_uname.encoded_len()
+            + self.recv_uname.encoded_len()
+            + self.body.len()
+    }
+}
+
+impl fmt::Debug for MessageEdit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "MessageEdit {{ message_id: {}, timestamp: {}, send_uname: \"{}\", recv_uname: \"{}\", body: \"{}\" }}",
+            crate::shared::message_id_to_string(self.message_id),
+            self.timestamp,
+            self.send_uname,
+            self.recv_uname,
+            String::from_utf8_lossy(&self.body)
+        )
+    }
+}
+
+/**
+Protocol message: sender retracting one of their chat messages to recv_uname
+(message_id being the chat message's id)
+*/
+#[derive(Clone)]
+pub struct MessageDelete {
+    message_id: [u8; MESSAGE_ID_LEN],
+    timestamp: u64,
+    signature: [u8; SIGNATURE_LEN],
+    send_uname: Username,
+    recv_uname: Username,
+}
+
+impl MessageDelete {
+    pub fn new(message_id: [u8; MESSAGE_ID_LEN], send_uname: &Username, recv_uname: &Username) -> Self {
+        MessageDelete {
+            message_id,
+            timestamp: crate::shared::timestamp_now(),
+            signature: [0u8; SIGNATURE_LEN],
+            send_uname: send_uname.clone(),
+            recv_uname: recv_uname.clone(),
+        }
+    }
+
+    /**
+    Id of the chat message being deleted
+    */
+    pub fn message_id(&self) -> [u8; MESSAGE_ID_LEN] {
+        self.message_id
+    }
+
+    /**
+    Time the message was deleted, in milliseconds since the unix epoch (sender's clock)
+    */
+    pub fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
+    pub fn send_uname(&self) -> &Username {
+        &self.send_uname
+    }
+
+    pub fn recv_uname(&self) -> &Username {
+        &self.recv_uname
+    }
+
+    pub fn sign(&mut self, identity: &IdentityKeyPair) {
+        self.signature = identity.sign(&self.signed_bytes());
+    }
+
+    pub fn verify_signature(&self, identity_key: &[u8; IDENTITY_KEY_LEN]) -> bool {
+        identity::verify_signature(identity_key, &self.signed_bytes(), &self.signature)
+    }
+
+    // everything but the signature itself
+    fn signed_bytes(&self) -> Vec<u8> {
+        let mut buffer = DELETE_LABEL.to_vec();
+        buffer.extend_from_slice(&self.message_id);
+        buffer.extend_from_slice(&self.timestamp.to_be_bytes());
+        self.send_uname.encode(&mut buffer);
+        self.recv_uname.encode(&mut buffer);
+
+        buffer
+    }
+
+    // size of the fixed-width fields (everything but the usernames)
+    pub fn fixed_size() -> usize {
+        MESSAGE_ID_LEN + TIMESTAMP_LEN + SIGNATURE_LEN
+    }
+}
+
+impl Message for MessageDelete {
+    const MESSAGE_TYPE: MessageType = MessageType::MessageDelete;
+
+    fn serialize(&self) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        buffer.extend_from_slice(&self.message_id);
+        buffer.extend_from_slice(&self.timestamp.to_be_bytes());
+        buffer.extend_from_slice(&self.signature);
+        self.send_uname.encode(&mut buffer);
+        self.recv_uname.encode(&mut buffer);
+
+        buffer
+    }
+
+    fn deserialize(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        ProtocolError::check_min_len(bytes, Self::fixed_size())?;
+
+        let mut decoder = Decoder::new(bytes);
+        let message_id = decoder.read_array::<MESSAGE_ID_LEN>()?;
+        let timestamp = decoder.read_u64()?;
+        let signature = decoder.read_array::<SIGNATURE_LEN>()?;
+        let send_uname = decoder.read_username("send_uname")?;
+        let recv_uname = decoder.read_username("recv_uname")?;
+        decoder.finish()?;
+
+        Ok (MessageDelete {
+            message_id,
+            timestamp,
+            signature,
+            send_uname,
+            recv_uname
+        })
+    }
+
+    fn length(&self) -> usize {
+        MessageDelete::fixed_size() + self.send_uname.encoded_len() + self.recv_uname.encoded_len()
+    }
+}
+
+impl fmt::Debug for MessageDelete {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "MessageDelete {{ message_id: {}, timestamp: {}, send_uname: \"{}\", recv_uname: \"{}\" }}",
+            crate::shared::message_id_to_string(self.message_id),
+            self.timestamp,
+            self.send_uname,
+            self.recv_uname
+        )
+    }
+}
@@ -0,0 +1,120 @@
+use std::fmt;
+
+use crate::field_lens::{ ENVELOPE_VERSION_LEN, KEY_ID_LEN, ENVELOPE_NONCE_LEN, AEAD_TAG_LEN };
+use crate::errors::ProtocolError;
+use crate::decode::Decoder;
+
+// envelope format spoken by this crate
+pub const ENVELOPE_VERSION: u8 = 1;
+
+/**
+End-to-end encrypted body of a ChatMessage, as carried in its msg_buffer.
+
+Only the two clients in a conversation hold the key, so the server (which
+relays the ChatMessage) only ever sees this envelope. On the wire:
+
+```text
+    version (1 byte)      - envelope format, currently ENVELOPE_VERSION (1):
+                            XChaCha20-Poly1305 under a per-conversation key
+    key_id (4 bytes)      - which of the conversation's keys was used, so
+                            keys can be rotated without losing old messages
+    nonce (24 bytes)
+    ciphertext (variable) - encrypted message body followed by the 16-byte tag
+```
+
+Encryption itself is left to the clients; the envelope is only the format.
+*/
+#[derive(Clone, PartialEq, Eq)]
+pub struct Envelope {
+    version: u8,
+    key_id: u32,
+    nonce: [u8; ENVELOPE_NONCE_LEN],
+    ciphertext: Vec<u8>,
+}
+
+impl Envelope {
+    pub fn new(key_id: u32, nonce: [u8; ENVELOPE_NONCE_LEN], ciphertext: Vec<u8>) -> Self {
+        Envelope {
+            version: ENVELOPE_VERSION,
+            key_id,
+            nonce,
+            ciphertext,
+        }
+    }
+
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    pub fn key_id(&self) -> u32 {
+        self.key_id
+    }
+
+    pub fn nonce(&self) -> &[u8; ENVELOPE_NONCE_LEN] {
+        &self.nonce
+    }
+
+    pub fn ciphertext(&self) -> &[u8] {
+        &self.ciphertext
+    }
+
+    /**
+    The version and key_id fields, which should be authenticated along with the ciphertext
+    */
+    pub fn header(&self) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        buffer.push(self.version);
+        buffer.extend_from_slice(&self.key_id.to_be_bytes());
+
+        buffer
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut buffer = self.header();
+        buffer.extend_from_slice(&self.nonce);
+        buffer.extend_from_slice(&self.ciphertext);
+
+        buffer
+    }
+
+    pub fn deserialize(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        ProtocolError::check_min_len(bytes, Envelope::fixed_size())?;
+
+        let mut decoder = Decoder::new(bytes);
+        let version = decoder.read_u8()?;
+        if version != ENVELOPE_VERSION {
+            return Err(ProtocolError::InvalidValue { field: "envelope version", value: version });
+        }
+        let key_id = decoder.read_u32()?;
+        let nonce = decoder.read_array::<ENVELOPE_NONCE_LEN>()?;
+        let ciphertext = decoder.read_rest().to_vec();
+
+        Ok (Envelope {
+            version,
+            key_id,
+            nonce,
+            ciphertext
+        })
+    }
+
+    pub fn length(&self) -> usize {
+        ENVELOPE_VERSION_LEN + KEY_ID_LEN + ENVELOPE_NONCE_LEN + self.ciphertext.len()
+    }
+
+    // smallest possible envelope: header, nonce and the tag of an empty message
+    pub fn fixed_size() -> usize {
+        ENVELOPE_VERSION_LEN + KEY_ID_LEN + ENVELOPE_NONCE_LEN + AEAD_TAG_LEN
+    }
+}
+
+impl fmt::Debug for Envelope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Envelope {{ version: {}, key_id: {:#010x}, ciphertext: {} bytes }}",
+            self.version,
+            self.key_id,
+            self.ciphertext.len()
+        )
+    }
+}
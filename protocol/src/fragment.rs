@@ -0,0 +1,305 @@
+use std::collections::{ BTreeMap, HashMap };
+use std::fmt;
+use std::time::{ Duration, Instant };
+
+use crate::field_lens::{ MESSAGE_ID_LEN, FRAGMENT_INDEX_LEN, FRAGMENT_COUNT_LEN, MAX_MESSAGE_LEN };
+use crate::errors::ProtocolError;
+use crate::decode::Decoder;
+use crate::message::Message;
+use crate::message_types::MessageType;
+use crate::Packet;
+
+// most bytes a Reassembler will hold across all part-received packets
+pub const DEFAULT_MAX_BUFFERED_LEN: usize = 4 * MAX_MESSAGE_LEN;
+
+// how long a Reassembler waits for the rest of a packet after its first fragment
+pub const DEFAULT_REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/**
+Protocol message: one piece of a packet too large to send whole (needs the
+FRAGMENTATION capability).
+
+The large packet is serialized and cut into payloads that each fit in a
+MAX_PACKET_LEN packet once wrapped in a Fragment. Every fragment of the packet
+carries the same (random) message_id, its own index and the total number of
+fragments, so the receiver can put them back together in any order (see Reassembler).
+*/
+pub struct Fragment {
+    message_id: [u8; MESSAGE_ID_LEN],
+    index: u16,
+    total: u16,
+    payload: Vec<u8>,
+}
+
+impl Fragment {
+    pub fn new(message_id: [u8; MESSAGE_ID_LEN], index: u16, total: u16, payload: Vec<u8>) -> Self {
+        Fragment {
+            message_id,
+            index,
+            total,
+            payload,
+        }
+    }
+
+    /**
+    Cuts packet into fragments that each fit in a packet of at most max_packet_len bytes.
+
+    Fails if packet is larger than MAX_MESSAGE_LEN, or would need more fragments
+    than the index field can count.
+    */
+    pub fn split(packet: &Packet, max_packet_len: usize) -> Result<Vec<Fragment>, ProtocolError> {
+        let bytes = packet.serialize();
+        if bytes.len() > MAX_MESSAGE_LEN {
+            return Err(ProtocolError::MessageTooLarge {
+                message_len: bytes.len(),
+                max_message_len: MAX_MESSAGE_LEN,
+            });
+        }
+
+        let max_payload_len = max_packet_len.saturating_sub(Packet::fixed_size() + Fragment::fixed_size());
+        if max_payload_len == 0 {
+            return Err(ProtocolError::PacketTooLarge {
+                packet_len: Packet::fixed_size() + Fragment::fixed_size() + 1,
+                max_packet_len,
+            });
+        }
+
+        let total = bytes.len().div_ceil(max_payload_len);
+        let total = u16::try_from(total).map_err(|_| ProtocolError::MessageTooLarge {
+            message_len: bytes.len(),
+            max_message_len: max_payload_len * u16::MAX as usize,
+        })?;
+
+        let message_id = crate::shared::generate_message_id();
+        let fragments = bytes
+            .chunks(max_payload_len)
+            .zip(0..total)
+            .map(|(payload, index)| Fragment::new(message_id, index, total, payload.to_vec()))
+            .collect();
+
+        Ok(fragments)
+    }
+
+    pub fn message_id(&self) -> [u8; MESSAGE_ID_LEN] {
+        self.message_id
+    }
+
+    pub fn index(&self) -> u16 {
+        self.index
+    }
+
+    pub fn total(&self) -> u16 {
+        self.total
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        &self.payload
+    }
+
+    // length of the fixed-size fields, i.e. everything but the payload
+    pub fn fixed_size() -> usize {
+        MESSAGE_ID_LEN + FRAGMENT_INDEX_LEN + FRAGMENT_COUNT_LEN
+    }
+}
+
+impl Message for Fragment {
+    const MESSAGE_TYPE: MessageType = MessageType::Fragment;
+
+    fn serialize(&self) -> Vec<u8> {
+        let mut buffer = Vec::new();
+
+        buffer.extend_from_slice(&self.message_id);
+        buffer.extend_from_slice(&self.index.to_be_bytes());
+        buffer.extend_from_slice(&self.total.to_be_bytes());
+        buffer.extend_from_slice(&self.payload);
+
+        buffer
+    }
+
+    fn deserialize(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        let mut decoder = Decoder::new(bytes);
+        let message_id = decoder.read_array::<MESSAGE_ID_LEN>()?;
+        let index = decoder.read_u16()?;
+        let total = decoder.read_u16()?;
+        let payload = decoder.read_rest().to_vec();
+
+        // an empty fragment carries nothing, so can only be a mistake (or a way to waste memory)
+        if index >= total || payload.is_empty() {
+            return Err(ProtocolError::InvalidFragment { index, total });
+        }
+
+        Ok (Fragment {
+            message_id,
+            index,
+            total,
+            payload
+        })
+    }
+
+    fn length(&self) -> usize {
+        Fragment::fixed_size() + self.payload.len()
+    }
+}
+
+impl fmt::Debug for Fragment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Fragment {{ message_id: {}, index: {}, total: {}, payload: {} bytes }}",
+            crate::shared::message_id_to_string(self.message_id),
+            self.index,
+            self.total,
+            self.payload.len()
+        )
+    }
+}
+
+// fragments received so far for one packet
+struct PartialPacket {
+    total: u16,
+    payloads: BTreeMap<u16, Vec<u8>>,
+    // payload bytes received so far
+    message_len: usize,
+    // bytes held for this packet, counted against the Reassembler's limits
+    buffered_len: usize,
+    started: Instant,
+}
+
+/**
+Puts fragmented packets back together.
+
+Fragments can arrive in any order, and fragments of different packets can be
+interleaved. To stop a peer using up memory with fragments it never finishes:
+  - a packet is dropped if the rest of it hasn't arrived within the timeout
+  - a packet is dropped if it grows past MAX_MESSAGE_LEN
+  - a fragment is refused if it would take the bytes held across all part-received
+    packets past max_buffered_len (each fragment's header counts towards this too)
+*/
+pub struct Reassembler {
+    partials: HashMap<[u8; MESSAGE_ID_LEN], PartialPacket>,
+    buffered_len: usize,
+    max_buffered_len: usize,
+    timeout: Duration,
+}
+
+impl Reassembler {
+    pub fn new() -> Self {
+        Reassembler::with_limits(DEFAULT_MAX_BUFFERED_LEN, DEFAULT_REASSEMBLY_TIMEOUT)
+    }
+
+    pub fn with_limits(max_buffered_len: usize, timeout: Duration) -> Self {
+        Reassembler {
+            partials: HashMap::new(),
+            buffered_len: 0,
+            max_buffered_len,
+            timeout,
+        }
+    }
+
+    /**
+    Adds a fragment, returning the whole packet if this was its last missing fragment.
+
+    On error the packet the fragment belongs to is dropped, along with any of its
+    fragments already received. Repeats of an already-received fragment are ignored.
+    */
+    pub fn add(&mut self, fragment: Fragment) -> Result<Option<Packet>, ProtocolError> {
+        self.expire();
+
+        let Fragment { message_id, index, total, payload } = fragment;
+        if index >= total || payload.is_empty() {
+            return Err(ProtocolError::InvalidFragment { index, total });
+        }
+
+        let partial = self.partials.entry(message_id).or_insert_with(|| PartialPacket {
+            total,
+            payloads: BTreeMap::new(),
+            message_len: 0,
+            buffered_len: 0,
+            started: Instant::now(),
+        });
+        if partial.total != total {
+            self.drop_partial(&message_id);
+            return Err(ProtocolError::InvalidFragment { index, total });
+        }
+        if partial.payloads.contains_key(&index) {
+            return Ok(None);
+        }
+
+        let message_len = partial.message_len + payload.len();
+        if message_len > MAX_MESSAGE_LEN {
+            self.drop_partial(&message_id);
+            return Err(ProtocolError::MessageTooLarge { message_len, max_message_len: MAX_MESSAGE_LEN });
+        }
+
+        let fragment_len = Fragment::fixed_size() + payload.len();
+        if self.buffered_len + fragment_len > self.max_buffered_len {
+            let buffered_len = self.buffered_len + fragment_len;
+            self.drop_partial(&message_id);
+            return Err(ProtocolError::ReassemblyBufferFull { buffered_len, max_buffered_len: self.max_buffered_len });
+        }
+
+        partial.payloads.insert(index, payload);
+        partial.message_len = message_len;
+        partial.buffered_len += fragment_len;
+        self.buffered_len += fragment_len;
+        if partial.payloads.len() < total as usize {
+            return Ok(None);
+        }
+
+        let payloads = self.drop_partial(&message_id).map(|partial| partial.payloads).unwrap_or_default();
+        let bytes: Vec<u8> = payloads.into_values().flatten().collect();
+        let packet = Packet::deserialize(&bytes)?;
+        if packet.length() != bytes.len() {
+            return Err(ProtocolError::LengthMismatch { expected: packet.length(), actual: bytes.len() });
+        }
+
+        // fragments only ever carry whole packets, never other fragments
+        if packet.method == MessageType::Fragment as u8 {
+            return Err(ProtocolError::UnexpectedMessage(MessageType::Fragment));
+        }
+
+        Ok(Some(packet))
+    }
+
+    /**
+    Drops packets that have been waiting longer than the timeout for their
+    remaining fragments, returning how many were dropped
+    */
+    pub fn expire(&mut self) -> usize {
+        let timeout = self.timeout;
+        let expired: Vec<[u8; MESSAGE_ID_LEN]> = self.partials
+            .iter()
+            .filter(|(_, partial)| partial.started.elapsed() >= timeout)
+            .map(|(message_id, _)| *message_id)
+            .collect();
+
+        for message_id in &expired {
+            self.drop_partial(message_id);
+        }
+
+        expired.len()
+    }
+
+    // number of packets part-way through being reassembled
+    pub fn pending(&self) -> usize {
+        self.partials.len()
+    }
+
+    // bytes held for part-received packets
+    pub fn buffered_len(&self) -> usize {
+        self.buffered_len
+    }
+
+    fn drop_partial(&mut self, message_id: &[u8; MESSAGE_ID_LEN]) -> Option<PartialPacket> {
+        let partial = self.partials.remove(message_id)?;
+        self.buffered_len -= partial.buffered_len;
+
+        Some(partial)
+    }
+}
+
+impl Default for Reassembler {
+    fn default() -> Self {
+        Reassembler::new()
+    }
+}
@@ -0,0 +1,498 @@
+/*
+Group conversations.
+
+A group is known by a random group id, chosen by the client that creates it,
+and has a name and a member list kept by the server. Any member can add one of
+their connections to the group (GroupInvite), and any member can leave. The
+server tells every member about each change to the group with a GroupInfo.
+
+GroupMessages are fanned out by the server to every other member. Like chat
+messages, they are signed by their sender and carry an end-to-end encrypted
+body: members share a group key, which the group's creator generates and
+members pass on to the users they invite, sealed under their own conversation
+key with them (so the server never sees it).
+*/
+
+use std::fmt;
+
+use crate::field_lens::{ GROUP_ID_LEN, GROUP_NAME_PREFIX_LEN, MAX_GROUP_NAME_LEN, MEMBER_COUNT_LEN };
+use crate::field_lens::{ ERR_CODE_LEN, MESSAGE_ID_LEN, TIMESTAMP_LEN, IDENTITY_KEY_LEN, SIGNATURE_LEN };
+use crate::errors::ProtocolError;
+use crate::decode::Decoder;
+use crate::message::Message;
+use crate::message_types::MessageType;
+use crate::status_codes::{ self, StatusCode };
+use crate::username::Username;
+use crate::identity::{ self, IdentityKeyPair };
+
+/**
+Checks a group name is 1 to MAX_GROUP_NAME_LEN bytes, with no control characters
+(so it can be shown, and stored one per line)
+*/
+pub fn validate_group_name(group_name: &str) -> Result<(), ProtocolError> {
+    if group_name.is_empty() || group_name.len() > MAX_GROUP_NAME_LEN {
+        return Err(ProtocolError::InvalidGroupName("must be 1 to 50 bytes long"));
+    }
+    if group_name.chars().any(char::is_control) {
+        return Err(ProtocolError::InvalidGroupName("must not contain control characters"));
+    }
+
+    Ok(())
+}
+
+// group names are length-prefixed on the wire, like usernames
+fn encode_group_name(group_name: &str, buffer: &mut Vec<u8>) {
+    buffer.push(group_name.len() as u8);
+    buffer.extend_from_slice(group_name.as_bytes());
+}
+
+// allow_empty is for failed requests about groups the server doesn't know the name of
+fn read_group_name(decoder: &mut Decoder, allow_empty: bool) -> Result<String, ProtocolError> {
+    let len = decoder.read_u8()? as usize;
+    let bytes = decoder.read_bytes(len)?;
+    let group_name = std::str::from_utf8(bytes).map_err(|_| ProtocolError::InvalidUtf8 { field: "group_name" })?;
+    if !(allow_empty && group_name.is_empty()) {
+        validate_group_name(group_name)?;
+    }
+
+    Ok(group_name.to_string())
+}
+
+/**
+Protocol message: client creating a group, with itself as the only member.
+The server answers with a GroupInfo (Failure if the group id is taken).
+*/
+pub struct GroupCreate {
+    group_id: [u8; GROUP_ID_LEN],
+    group_name: String,
+}
+
+impl GroupCreate {
+    /**
+    Creates a group under a fresh, random group id
+    */
+    pub fn new(group_name: &str) -> Result<Self, ProtocolError> {
+        validate_group_name(group_name)?;
+
+        Ok(GroupCreate {
+            group_id: crate::shared::generate_group_id(),
+            group_name: group_name.to_string(),
+        })
+    }
+
+    pub fn group_id(&self) -> [u8; GROUP_ID_LEN] {
+        self.group_id
+    }
+
+    pub fn group_name(&self) -> &str {
+        &self.group_name
+    }
+}
+
+impl Message for GroupCreate {
+    const MESSAGE_TYPE: MessageType = MessageType::GroupCreate;
+
+    fn serialize(&self) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        buffer.extend_from_slice(&self.group_id);
+        encode_group_name(&self.group_name, &mut buffer);
+
+        buffer
+    }
+
+    fn deserialize(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        let mut decoder = Decoder::new(bytes);
+        let group_id = decoder.read_array::<GROUP_ID_LEN>()?;
+        let group_name = read_group_name(&mut decoder, false)?;
+        decoder.finish()?;
+
+        Ok (GroupCreate {
+            group_id,
+            group_name
+        })
+    }
+
+    fn length(&self) -> usize {
+        GROUP_ID_LEN + GROUP_NAME_PREFIX_LEN + self.group_name.len()
+    }
+}
+
+impl fmt::Debug for GroupCreate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "GroupCreate {{ group_id: {}, group_name: \"{}\" }}",
+            crate::shared::group_id_to_string(self.group_id),
+            self.group_name
+        )
+    }
+}
+
+/**
+Protocol message: server telling a group's members who is in it, after any
+change, or answering a group request (with Failure if it was refused).
+
+A member that has left (or whose request failed) isn't in the member list.
+On the wire the members are preceded by their count.
+*/
+pub struct GroupInfo {
+    status_code: StatusCode,
+    group_id: [u8; GROUP_ID_LEN],
+    group_name: String,
+    members: Vec<Username>,
+}
+
+impl GroupInfo {
+    pub fn new(group_id: [u8; GROUP_ID_LEN], group_name: &str, members: Vec<Username>) -> Self {
+        GroupInfo {
+            status_code: StatusCode::Success,
+            group_id,
+            group_name: group_name.to_string(),
+            members,
+        }
+    }
+
+    /**
+    Answer to a refused request about group_id. group_name can be empty, if the
+    server doesn't know the group.
+    */
+    pub fn failure(group_id: [u8; GROUP_ID_LEN], group_name: &str) -> Self {
+        GroupInfo {
+            status_code: StatusCode::Failure,
+            group_id,
+            group_name: group_name.to_string(),
+            members: Vec::new(),
+        }
+    }
+
+    pub fn status_code(&self) -> StatusCode {
+        self.status_code
+    }
+
+    pub fn group_id(&self) -> [u8; GROUP_ID_LEN] {
+        self.group_id
+    }
+
+    pub fn group_name(&self) -> &str {
+        &self.group_name
+    }
+
+    pub fn members(&self) -> &[Username] {
+        &self.members
+    }
+}
+
+impl Message for GroupInfo {
+    const MESSAGE_TYPE: MessageType = MessageType::GroupInfo;
+
+    fn serialize(&self) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        buffer.push(self.status_code as u8);
+        buffer.extend_from_slice(&self.group_id);
+        encode_group_name(&self.group_name, &mut buffer);
+        buffer.extend_from_slice(&(self.members.len() as u16).to_be_bytes());
+        for member in &self.members {
+            member.encode(&mut buffer);
+        }
+
+        buffer
+    }
+
+    fn deserialize(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        let mut decoder = Decoder::new(bytes);
+        let status_code = status_codes::decode_status_code(decoder.read_u8()?)?;
+        let group_id = decoder.read_array::<GROUP_ID_LEN>()?;
+        let group_name = read_group_name(&mut decoder, status_code != StatusCode::Success)?;
+        let count = decoder.read_u16()? as usize;
+        let mut members = Vec::with_capacity(count.min(decoder.remaining()));
+        for _ in 0..count {
+            members.push(decoder.read_username("member")?);
+        }
+        decoder.finish()?;
+
+        Ok (GroupInfo {
+            status_code,
+            group_id,
+            group_name,
+            members
+        })
+    }
+
+    fn length(&self) -> usize {
+        ERR_CODE_LEN + GROUP_ID_LEN + GROUP_NAME_PREFIX_LEN + self.group_name.len() + MEMBER_COUNT_LEN
+            + self.members.iter().map(Username::encoded_len).sum::<usize>()
+    }
+}
+
+impl fmt::Debug for GroupInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let members: Vec<&str> = self.members.iter().map(Username::as_str).collect();
+        write!(
+            f,
+            "GroupInfo {{ status_code: {}, group_id: {}, group_name: \"{}\", members: {:?} }}",
+            self.status_code,
+            crate::shared::group_id_to_string(self.group_id),
+            self.group_name,
+            members
+        )
+    }
+}
+
+/**
+Protocol message: a group member adding one of their connections to the group.
+
+The server adds the invitee to the member list and relays the invite on to
+them. It carries the group key, sealed in an Envelope under the inviter and
+invitee's conversation key (the server only checks it isn't empty).
+*/
+pub struct GroupInvite {
+    group_id: [u8; GROUP_ID_LEN],
+    inviter: Username,
+    invitee: Username,
+    sealed_key: Vec<u8>,
+}
+
+impl GroupInvite {
+    pub fn new(group_id: [u8; GROUP_ID_LEN], inviter: &Username, invitee: &Username, sealed_key: Vec<u8>) -> Self {
+        GroupInvite {
+            group_id,
+            inviter: inviter.clone(),
+            invitee: invitee.clone(),
+            sealed_key,
+        }
+    }
+
+    pub fn group_id(&self) -> [u8; GROUP_ID_LEN] {
+        self.group_id
+    }
+
+    pub fn inviter(&self) -> &Username {
+        &self.inviter
+    }
+
+    pub fn invitee(&self) -> &Username {
+        &self.invitee
+    }
+
+    pub fn sealed_key(&self) -> &[u8] {
+        &self.sealed_key
+    }
+}
+
+impl Message for GroupInvite {
+    const MESSAGE_TYPE: MessageType = MessageType::GroupInvite;
+
+    fn serialize(&self) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        buffer.extend_from_slice(&self.group_id);
+        self.inviter.encode(&mut buffer);
+        self.invitee.encode(&mut buffer);
+        buffer.extend_from_slice(&self.sealed_key);
+
+        buffer
+    }
+
+    fn deserialize(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        let mut decoder = Decoder::new(bytes);
+        let group_id = decoder.read_array::<GROUP_ID_LEN>()?;
+        let inviter = decoder.read_username("inviter")?;
+        let invitee = decoder.read_username("invitee")?;
+        let sealed_key = decoder.read_rest().to_vec();
+
+        Ok (GroupInvite {
+            group_id,
+            inviter,
+            invitee,
+            sealed_key
+        })
+    }
+
+    fn length(&self) -> usize {
+        GROUP_ID_LEN + self.inviter.encoded_len() + self.invitee.encoded_len() + self.sealed_key.len()
+    }
+}
+
+impl fmt::Debug for GroupInvite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "GroupInvite {{ group_id: {}, inviter: \"{}\", invitee: \"{}\", sealed_key: {} bytes }}",
+            crate::shared::group_id_to_string(self.group_id),
+            self.inviter,
+            self.invitee,
+            self.sealed_key.len()
+        )
+    }
+}
+
+/**
+Protocol message: client leaving a group. A group is deleted once its last
+member leaves.
+*/
+pub struct GroupLeave {
+    group_id: [u8; GROUP_ID_LEN],
+}
+
+impl GroupLeave {
+    pub fn new(group_id: [u8; GROUP_ID_LEN]) -> Self {
+        GroupLeave { group_id }
+    }
+
+    pub fn group_id(&self) -> [u8; GROUP_ID_LEN] {
+        self.group_id
+    }
+}
+
+impl Message for GroupLeave {
+    const MESSAGE_TYPE: MessageType = MessageType::GroupLeave;
+
+    fn serialize(&self) -> Vec<u8> {
+        self.group_id.to_vec()
+    }
+
+    fn deserialize(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        ProtocolError::check_len(bytes, GROUP_ID_LEN)?;
+
+        let mut decoder = Decoder::new(bytes);
+        let group_id = decoder.read_array::<GROUP_ID_LEN>()?;
+
+        Ok (GroupLeave {
+            group_id
+        })
+    }
+
+    fn length(&self) -> usize {
+        GROUP_ID_LEN
+    }
+}
+
+impl fmt::Debug for GroupLeave {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "GroupLeave {{ group_id: {} }}", crate::shared::group_id_to_string(self.group_id))
+    }
+}
+
+/**
+Protocol message: chat message to every member of a group.
+
+Stamped with a message id and send time, and signed by the sender, just as a
+ChatMessage is; the signature covers every other field.
+*/
+#[derive(Clone)]
+pub struct GroupMessage {
+    pub message_id: [u8; MESSAGE_ID_LEN],
+    pub timestamp: u64,
+    pub signature: [u8; SIGNATURE_LEN],
+    pub group_id: [u8; GROUP_ID_LEN],
+    pub send_uname: Username,
+    pub msg_buffer: Vec<u8>,
+}
+
+impl GroupMessage {
+    pub fn new(group_id: [u8; GROUP_ID_LEN], send_uname: &Username, msg: &str) -> Self {
+        GroupMessage {
+            message_id: crate::shared::generate_message_id(),
+            timestamp: crate::shared::timestamp_now(),
+            signature: [0u8; SIGNATURE_LEN],
+            group_id,
+            send_uname: send_uname.clone(),
+            msg_buffer: msg.as_bytes().to_vec(),
+        }
+    }
+
+    pub fn message_id(&self) -> [u8; MESSAGE_ID_LEN] {
+        self.message_id
+    }
+
+    pub fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
+    /**
+    Replaces the message body, e.g. with its encrypted form
+    */
+    pub fn set_body(&mut self, body: Vec<u8>) {
+        self.msg_buffer = body;
+    }
+
+    /**
+    Signs the message as it stands, so should be called after encrypting it
+    */
+    pub fn sign(&mut self, identity: &IdentityKeyPair) {
+        self.signature = identity.sign(&self.signed_bytes());
+    }
+
+    pub fn verify_signature(&self, identity_key: &[u8; IDENTITY_KEY_LEN]) -> bool {
+        identity::verify_signature(identity_key, &self.signed_bytes(), &self.signature)
+    }
+
+    // everything but the signature itself
+    fn signed_bytes(&self) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        buffer.extend_from_slice(&self.message_id);
+        buffer.extend_from_slice(&self.timestamp.to_be_bytes());
+        buffer.extend_from_slice(&self.group_id);
+        self.send_uname.encode(&mut buffer);
+        buffer.extend_from_slice(&self.msg_buffer);
+
+        buffer
+    }
+
+    // size of the fixed-width fields (everything but the username and message body)
+    pub fn fixed_size() -> usize {
+        MESSAGE_ID_LEN + TIMESTAMP_LEN + SIGNATURE_LEN + GROUP_ID_LEN
+    }
+}
+
+impl Message for GroupMessage {
+    const MESSAGE_TYPE: MessageType = MessageType::GroupMessage;
+
+    fn serialize(&self) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        buffer.extend_from_slice(&self.message_id);
+        buffer.extend_from_slice(&self.timestamp.to_be_bytes());
+        buffer.extend_from_slice(&self.signature);
+        buffer.extend_from_slice(&self.group_id);
+        self.send_uname.encode(&mut buffer);
+        buffer.extend_from_slice(&self.msg_buffer);
+
+        buffer
+    }
+
+    fn deserialize(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        let mut decoder = Decoder::new(bytes);
+        let message_id = decoder.read_array::<MESSAGE_ID_LEN>()?;
+        let timestamp = decoder.read_u64()?;
+        let signature = decoder.read_array::<SIGNATURE_LEN>()?;
+        let group_id = decoder.read_array::<GROUP_ID_LEN>()?;
+        let send_uname = decoder.read_username("send_uname")?;
+        let msg_buffer = decoder.read_rest().to_vec();
+
+        Ok (GroupMessage {
+            message_id,
+            timestamp,
+            signature,
+            group_id,
+            send_uname,
+            msg_buffer
+        })
+    }
+
+    fn length(&self) -> usize {
+        GroupMessage::fixed_size() + self.send_uname.encoded_len() + self.msg_buffer.len()
+    }
+}
+
+impl fmt::Debug for GroupMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "GroupMessage {{ message_id: {}, timestamp: {}, group_id: {}, send_uname: \"{}\", body: {} bytes }}",
+            crate::shared::message_id_to_string(self.message_id),
+            self.timestamp,
+            crate::shared::group_id_to_string(self.group_id),
+            self.send_uname,
+            self.msg_buffer.len()
+        )
+    }
+}
@@ -0,0 +1,260 @@
+use std::fmt;
+use std::time::{ Duration, Instant };
+
+use crate::field_lens::NONCE_LEN;
+use crate::errors::ProtocolError;
+use crate::decode::Decoder;
+use crate::message::Message;
+use crate::message_types::MessageType;
+
+// how often a Ping is sent, by default
+pub const DEFAULT_PING_INTERVAL: Duration = Duration::from_secs(15);
+
+// how long a peer can go without sending anything before it's considered gone, by default
+pub const DEFAULT_HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(45);
+
+/**
+Protocol message: asks the peer to answer with a Pong carrying the same nonce
+(needs the HEARTBEAT capability). Either end can send one.
+*/
+pub struct Ping {
+    nonce: u64,
+}
+
+/**
+Protocol message: answer to a Ping, echoing its nonce
+*/
+pub struct Pong {
+    nonce: u64,
+}
+
+impl Ping {
+    pub fn new(nonce: u64) -> Self {
+        Ping { nonce }
+    }
+
+    pub fn nonce(&self) -> u64 {
+        self.nonce
+    }
+
+    /**
+    Builds the Pong that answers this ping
+    */
+    pub fn pong(&self) -> Pong {
+        Pong::new(self.nonce)
+    }
+}
+
+impl Pong {
+    pub fn new(nonce: u64) -> Self {
+        Pong { nonce }
+    }
+
+    pub fn nonce(&self) -> u64 {
+        self.nonce
+    }
+}
+
+impl Message for Ping {
+    const MESSAGE_TYPE: MessageType = MessageType::Ping;
+
+    fn serialize(&self) -> Vec<u8> {
+        self.nonce.to_be_bytes().to_vec()
+    }
+
+    fn deserialize(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        ProtocolError::check_len(bytes, NONCE_LEN)?;
+
+        let mut decoder = Decoder::new(bytes);
+        Ok (Ping {
+            nonce: decoder.read_u64()?
+        })
+    }
+
+    fn length(&self) -> usize {
+        NONCE_LEN
+    }
+}
+
+impl Message for Pong {
+    const MESSAGE_TYPE: MessageType = MessageType::Pong;
+
+    fn serialize(&self) -> Vec<u8> {
+        self.nonce.to_be_bytes().to_vec()
+    }
+
+    fn deserialize(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        ProtocolError::check_len(bytes, NONCE_LEN)?;
+
+        let mut decoder = Decoder::new(bytes);
+        Ok (Pong {
+            nonce: decoder.read_u64()?
+        })
+    }
+
+    fn length(&self) -> usize {
+        NONCE_LEN
+    }
+}
+
+impl fmt::Debug for Ping {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Ping {{ nonce: {} }}", self.nonce)
+    }
+}
+
+impl fmt::Debug for Pong {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Pong {{ nonce: {} }}", self.nonce)
+    }
+}
+
+/**
+How often to ping the peer, and how long it can stay silent before the
+connection is given up on
+*/
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct HeartbeatConfig {
+    pub interval: Duration,
+    pub timeout: Duration,
+}
+
+impl HeartbeatConfig {
+    pub fn new(interval: Duration, timeout: Duration) -> Self {
+        HeartbeatConfig {
+            interval,
+            timeout,
+        }
+    }
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        HeartbeatConfig::new(DEFAULT_PING_INTERVAL, DEFAULT_HEARTBEAT_TIMEOUT)
+    }
+}
+
+/**
+Health of a connection, as judged by its heartbeat
+*/
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LinkStatus {
+    Healthy,
+    // our last ping has gone unanswered for longer than the ping interval
+    Degraded,
+    // nothing heard from the peer for longer than the timeout
+    Dead,
+}
+
+impl fmt::Display for LinkStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LinkStatus::Healthy => write!(f, "Healthy"),
+            LinkStatus::Degraded => write!(f, "Degraded"),
+            LinkStatus::Dead => write!(f, "Dead"),
+        }
+    }
+}
+
+/**
+One end's view of a connection's heartbeat.
+
+Any packet from the peer counts as a sign of life, so Pings are only really
+needed to keep a quiet connection alive and to measure round-trip latency.
+Every method takes the current time, so the owner decides how often to check.
+*/
+#[derive(Debug)]
+pub struct Heartbeat {
+    config: HeartbeatConfig,
+    next_nonce: u64,
+    // nonce of, and time we sent, the ping we're waiting on a pong for
+    outstanding: Option<(u64, Instant)>,
+    // when we sent the first of our currently unanswered pings
+    unanswered_since: Option<Instant>,
+    last_ping_sent: Option<Instant>,
+    last_received: Instant,
+    latency: Option<Duration>,
+}
+
+impl Heartbeat {
+    pub fn new(config: HeartbeatConfig, now: Instant) -> Self {
+        Heartbeat {
+            config,
+            next_nonce: 0,
+            outstanding: None,
+            unanswered_since: None,
+            last_ping_sent: None,
+            last_received: now,
+            latency: None,
+        }
+    }
+
+    pub fn config(&self) -> HeartbeatConfig {
+        self.config
+    }
+
+    /**
+    Notes that something arrived from the peer
+    */
+    pub fn received(&mut self, now: Instant) {
+        self.last_received = now;
+    }
+
+    /**
+    Returns a Ping to send if one is due, i.e. the ping interval has passed since
+    the last one. An unanswered ping is replaced, so a late Pong for it is ignored.
+    */
+    pub fn poll(&mut self, now: Instant) -> Option<Ping> {
+        let due = match self.last_ping_sent {
+            Some(sent) => now.saturating_duration_since(sent) >= self.config.interval,
+            None => now.saturating_duration_since(self.last_received) >= self.config.interval,
+        };
+        if !due {
+            return None;
+        }
+
+        let nonce = self.next_nonce;
+        self.next_nonce = self.next_nonce.wrapping_add(1);
+        self.outstanding = Some((nonce, now));
+        self.unanswered_since.get_or_insert(now);
+        self.last_ping_sent = Some(now);
+
+        Some(Ping::new(nonce))
+    }
+
+    /**
+    Handles the answer to one of our pings, returning the round-trip time if it
+    answers the ping we're waiting on
+    */
+    pub fn handle_pong(&mut self, pong: &Pong, now: Instant) -> Option<Duration> {
+        self.received(now);
+
+        let (nonce, sent) = self.outstanding?;
+        if pong.nonce() != nonce {
+            return None;
+        }
+
+        let latency = now.saturating_duration_since(sent);
+        self.outstanding = None;
+        self.unanswered_since = None;
+        self.latency = Some(latency);
+
+        Some(latency)
+    }
+
+    pub fn status(&self, now: Instant) -> LinkStatus {
+        if now.saturating_duration_since(self.last_received) >= self.config.timeout {
+            return LinkStatus::Dead;
+        }
+
+        match self.unanswered_since {
+            Some(sent) if now.saturating_duration_since(sent) >= self.config.interval => LinkStatus::Degraded,
+            _ => LinkStatus::Healthy,
+        }
+    }
+
+    // round-trip time of the most recently answered ping
+    pub fn latency(&self) -> Option<Duration> {
+        self.latency
+    }
+}
@@ -0,0 +1,343 @@
+use std::fmt;
+
+use crate::field_lens::{ VERSION_LEN, CAPABILITIES_LEN, ERR_CODE_LEN };
+use crate::status_codes::{ self, StatusCode };
+use crate::errors::ProtocolError;
+use crate::decode::Decoder;
+use crate::message::Message;
+use crate::message_types::MessageType;
+
+// protocol revision spoken by this crate; bump on any change to the wire format
+pub const PROTOCOL_VERSION: u16 = 21;
+
+// oldest protocol revision this crate can still talk to
+pub const MIN_PROTOCOL_VERSION: u16 = 21;
+
+/**
+Bitmap of optional protocol features a peer supports
+*/
+#[derive(Copy, Clone, PartialEq, Eq, Default)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+    pub const NONE: Capabilities = Capabilities(0);
+
+    // delivery and read receipts (see receipt.rs)
+    pub const RECEIPTS: Capabilities = Capabilities(1 << 0);
+
+    // splitting packets larger than MAX_PACKET_LEN into fragments (see fragment.rs)
+    pub const FRAGMENTATION: Capabilities = Capabilities(1 << 1);
+
+    // Ping/Pong heartbeats (see heartbeat.rs)
+    pub const HEARTBEAT: Capabilities = Capabilities(1 << 2);
+
+    // online/away/offline and custom status (see presence.rs)
+    pub const PRESENCE: Capabilities = Capabilities(1 << 3);
+
+    // "is typing" indicators (see typing.rs)
+    pub const TYPING: Capabilities = Capabilities(1 << 4);
+
+    // sending files between connections (see transfer.rs)
+    pub const FILE_TRANSFER: Capabilities = Capabilities(1 << 5);
+
+    // editing and deleting sent chat messages (see edit.rs)
+    pub const EDITS: Capabilities = Capabilities(1 << 6);
+
+    // reactions on chat messages (see reaction.rs)
+    pub const REACTIONS: Capabilities = Capabilities(1 << 7);
+
+    // chat messages stored for offline recipients (see inbox.rs)
+    pub const INBOX: Capabilities = Capabilities(1 << 8);
+
+    // fetching a conversation's chat messages from the server (see history.rs)
+    pub const HISTORY: Capabilities = Capabilities(1 << 9);
+
+    pub const fn from_bits(bits: u32) -> Self {
+        Capabilities(bits)
+    }
+
+    pub const fn bits(&self) -> u32 {
+        self.0
+    }
+
+    pub const fn union(self, other: Capabilities) -> Self {
+        Capabilities(self.0 | other.0)
+    }
+
+    pub const fn intersection(self, other: Capabilities) -> Self {
+        Capabilities(self.0 & other.0)
+    }
+
+    // true if every feature in other is also in self
+    pub const fn contains(&self, other: Capabilities) -> bool {
+        (self.0 & other.0) == other.0
+    }
+}
+
+impl fmt::Debug for Capabilities {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Capabilities({:#010b})", self.0)
+    }
+}
+
+/**
+Protocol message: first message of every session, client telling the server
+which protocol version and optional features it speaks
+*/
+pub struct Hello {
+    version: u16,
+    capabilities: Capabilities,
+}
+
+impl Hello {
+    pub fn new(capabilities: Capabilities) -> Self {
+        Hello {
+            version: PROTOCOL_VERSION,
+            capabilities,
+        }
+    }
+
+    pub fn version(&self) -> u16 {
+        self.version
+    }
+
+    pub fn capabilities(&self) -> Capabilities {
+        self.capabilities
+    }
+
+    fn fixed_size() -> usize {
+        VERSION_LEN + CAPABILITIES_LEN
+    }
+}
+
+impl Message for Hello {
+    const MESSAGE_TYPE: MessageType = MessageType::Hello;
+
+    fn serialize(&self) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        buffer.extend_from_slice(&self.version.to_be_bytes());
+        buffer.extend_from_slice(&self.capabilities.bits().to_be_bytes());
+
+        buffer
+    }
+
+    fn deserialize(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        ProtocolError::check_len(bytes, Hello::fixed_size())?;
+
+        let mut decoder = Decoder::new(bytes);
+        let version = decoder.read_u16()?;
+        let capabilities = decoder.read_u32()?;
+
+        Ok (Hello {
+            version,
+            capabilities: Capabilities::from_bits(capabilities)
+        })
+    }
+
+    fn length(&self) -> usize {
+        Hello::fixed_size()
+    }
+}
+
+impl fmt::Debug for Hello {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Hello {{ version: {}, capabilities: {:?} }}",
+            self.version,
+            self.capabilities
+        )
+    }
+}
+
+/**
+Protocol message: server's answer to a Hello.
+
+On success, carries the version and the features (those supported by both
+sides) that the rest of the session will use. If the client's version is not
+supported, the status code is IncompatibleVersion and the version field holds
+the server's own version.
+*/
+pub struct HelloAck {
+    status_code: StatusCode,
+    version: u16,
+    capabilities: Capabilities,
+}
+
+impl HelloAck {
+    pub fn new(status_code: StatusCode, version: u16, capabilities: Capabilities) -> Self {
+        HelloAck {
+            status_code,
+            version,
+            capabilities,
+        }
+    }
+
+    pub fn status_code(&self) -> StatusCode {
+        self.status_code
+    }
+
+    pub fn version(&self) -> u16 {
+        self.version
+    }
+
+    pub fn capabilities(&self) -> Capabilities {
+        self.capabilities
+    }
+
+    fn fixed_size() -> usize {
+        ERR_CODE_LEN + VERSION_LEN + CAPABILITIES_LEN
+    }
+}
+
+impl Message for HelloAck {
+    const MESSAGE_TYPE: MessageType = MessageType::HelloAck;
+
+    fn serialize(&self) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        buffer.push(self.status_code as u8);
+        buffer.extend_from_slice(&self.version.to_be_bytes());
+        buffer.extend_from_slice(&self.capabilities.bits().to_be_bytes());
+
+        buffer
+    }
+
+    fn deserialize(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        ProtocolError::check_len(bytes, HelloAck::fixed_size())?;
+
+        let mut decoder = Decoder::new(bytes);
+        let status_code = status_codes::decode_status_code(decoder.read_u8()?)?;
+        let version = decoder.read_u16()?;
+        let capabilities = decoder.read_u32()?;
+
+        Ok (HelloAck {
+            status_code,
+            version,
+            capabilities: Capabilities::from_bits(capabilities)
+        })
+    }
+
+    fn length(&self) -> usize {
+        HelloAck::fixed_size()
+    }
+}
+
+impl fmt::Debug for HelloAck {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "HelloAck {{ status_code: {}, version: {}, capabilities: {:?} }}",
+            self.status_code,
+            self.version,
+            self.capabilities
+        )
+    }
+}
+
+/**
+Outcome of a successful Hello/HelloAck exchange, held by both ends for the rest
+of the session
+*/
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct NegotiatedSession {
+    version: u16,
+    capabilities: Capabilities,
+}
+
+impl NegotiatedSession {
+    /**
+    Server side: works out the session to use with a client from its Hello,
+    given the features the server itself supports
+    */
+    pub fn negotiate(hello: &Hello, local_capabilities: Capabilities) -> Result<Self, ProtocolError> {
+        if hello.version() < MIN_PROTOCOL_VERSION {
+            return Err(ProtocolError::IncompatibleVersion {
+                local_version: PROTOCOL_VERSION,
+                remote_version: hello.version(),
+            });
+        }
+
+        Ok(NegotiatedSession {
+            version: hello.version().min(PROTOCOL_VERSION),
+            capabilities: hello.capabilities().intersection(local_capabilities),
+        })
+    }
+
+    /**
+    Client side: reads the negotiated session out of the server's HelloAck
+    */
+    pub fn from_ack(hello_ack: &HelloAck) -> Result<Self, ProtocolError> {
+        match hello_ack.status_code() {
+            StatusCode::Success => {}
+            StatusCode::IncompatibleVersion => {
+                return Err(ProtocolError::IncompatibleVersion {
+                    local_version: PROTOCOL_VERSION,
+                    remote_version: hello_ack.version(),
+                });
+            }
+            status_code => return Err(ProtocolError::UnexpectedStatus(status_code)),
+        }
+
+        if hello_ack.version() < MIN_PROTOCOL_VERSION || hello_ack.version() > PROTOCOL_VERSION {
+            return Err(ProtocolError::IncompatibleVersion {
+                local_version: PROTOCOL_VERSION,
+                remote_version: hello_ack.version(),
+            });
+        }
+
+        Ok(NegotiatedSession {
+            version: hello_ack.version(),
+            capabilities: hello_ack.capabilities(),
+        })
+    }
+
+    /**
+    Builds the HelloAck that tells the client about this session
+    */
+    pub fn hello_ack(&self) -> HelloAck {
+        HelloAck::new(StatusCode::Success, self.version, self.capabilities)
+    }
+
+    pub fn version(&self) -> u16 {
+        self.version
+    }
+
+    pub fn capabilities(&self) -> Capabilities {
+        self.capabilities
+    }
+
+    /**
+    True if the given message type may be sent in this session, i.e. both ends
+    agreed to the feature it belongs to (if any)
+    */
+    pub fn allows(&self, message_type: MessageType) -> bool {
+        self.supports(required_capabilities(message_type))
+    }
+
+    // true if both ends agreed to use the given feature(s)
+    pub fn supports(&self, capability: Capabilities) -> bool {
+        self.capabilities.contains(capability)
+    }
+}
+
+/**
+Optional feature a message type belongs to, or NONE for messages every peer understands
+*/
+pub fn required_capabilities(message_type: MessageType) -> Capabilities {
+    match message_type {
+        MessageType::Receipt => Capabilities::RECEIPTS,
+        MessageType::Fragment => Capabilities::FRAGMENTATION,
+        MessageType::Ping | MessageType::Pong => Capabilities::HEARTBEAT,
+        MessageType::PresenceUpdate | MessageType::Presence => Capabilities::PRESENCE,
+        MessageType::Typing => Capabilities::TYPING,
+        MessageType::FileOffer
+        | MessageType::FileAccept
+        | MessageType::FileChunk
+        | MessageType::FileComplete => Capabilities::FILE_TRANSFER,
+        MessageType::MessageEdit | MessageType::MessageDelete => Capabilities::EDITS,
+        MessageType::Reaction => Capabilities::REACTIONS,
+        MessageType::InboxAck => Capabilities::INBOX,
+        MessageType::HistoryReq | MessageType::HistoryResp => Capabilities::HISTORY,
+        _ => Capabilities::NONE,
+    }
+}
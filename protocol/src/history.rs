@@ -0,0 +1,275 @@
+use std::fmt;
+
+use crate::field_lens::{ MESSAGE_ID_LEN, TIMESTAMP_LEN, CURSOR_KIND_LEN, ERR_CODE_LEN, HISTORY_FLAG_LEN };
+use crate::field_lens::{ MESSAGE_COUNT_LEN, HISTORY_MESSAGE_LEN_LEN };
+use crate::errors::ProtocolError;
+use crate::decode::Decoder;
+use crate::message::Message;
+use crate::message_types::MessageType;
+use crate::status_codes::{ self, StatusCode };
+use crate::username::Username;
+use crate::ChatMessage;
+
+/**
+Where in a conversation a HistoryReq picks up from: just after a message the
+client already has, or after a point in time (milliseconds since the unix
+epoch, as stamped by each message's sender). AfterTime(0) is the whole
+conversation.
+*/
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum HistoryCursor {
+    AfterMessage([u8; MESSAGE_ID_LEN]),
+    AfterTime(u64),
+}
+
+impl HistoryCursor {
+    pub const START: HistoryCursor = HistoryCursor::AfterTime(0);
+
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        match self {
+            HistoryCursor::AfterMessage(message_id) => {
+                buffer.push(0);
+                buffer.extend_from_slice(message_id);
+            }
+            HistoryCursor::AfterTime(timestamp) => {
+                buffer.push(1);
+                buffer.extend_from_slice(&timestamp.to_be_bytes());
+            }
+        }
+    }
+
+    fn decode(decoder: &mut Decoder) -> Result<Self, ProtocolError> {
+        match decoder.read_u8()? {
+            0 => Ok(HistoryCursor::AfterMessage(decoder.read_array::<MESSAGE_ID_LEN>()?)),
+            1 => Ok(HistoryCursor::AfterTime(decoder.read_u64()?)),
+            kind => Err(ProtocolError::InvalidValue { field: "cursor", value: kind }),
+        }
+    }
+
+    fn encoded_len(&self) -> usize {
+        CURSOR_KIND_LEN + match self {
+            HistoryCursor::AfterMessage(_) => MESSAGE_ID_LEN,
+            HistoryCursor::AfterTime(_) => TIMESTAMP_LEN,
+        }
+    }
+}
+
+impl fmt::Display for HistoryCursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HistoryCursor::AfterMessage(message_id) => {
+                write!(f, "after message {}", crate::shared::message_id_to_string(*message_id))
+            }
+            HistoryCursor::AfterTime(timestamp) => write!(f, "after {}", timestamp),
+        }
+    }
+}
+
+/**
+Protocol message: client asking the server for the chat messages in its
+conversation with conn_uname that come after cursor, e.g. to rebuild its
+history on a new machine.
+
+The server answers with a HistoryResp holding the next batch of them; the
+client asks again from the last message of each batch until there are no more.
+*/
+pub struct HistoryReq {
+    conn_uname: Username,
+    cursor: HistoryCursor,
+}
+
+impl HistoryReq {
+    pub fn new(conn_uname: &Username, cursor: HistoryCursor) -> Self {
+        HistoryReq {
+            conn_uname: conn_uname.clone(),
+            cursor,
+        }
+    }
+
+    pub fn conn_uname(&self) -> &Username {
+        &self.conn_uname
+    }
+
+    pub fn cursor(&self) -> HistoryCursor {
+        self.cursor
+    }
+}
+
+impl Message for HistoryReq {
+    const MESSAGE_TYPE: MessageType = MessageType::HistoryReq;
+
+    fn serialize(&self) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        self.conn_uname.encode(&mut buffer);
+        self.cursor.encode(&mut buffer);
+
+        buffer
+    }
+
+    fn deserialize(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        let mut decoder = Decoder::new(bytes);
+        let conn_uname = decoder.read_username("conn_uname")?;
+        let cursor = HistoryCursor::decode(&mut decoder)?;
+        decoder.finish()?;
+
+        Ok (HistoryReq {
+            conn_uname,
+            cursor
+        })
+    }
+
+    fn length(&self) -> usize {
+        self.conn_uname.encoded_len() + self.cursor.encoded_len()
+    }
+}
+
+impl fmt::Debug for HistoryReq {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "HistoryReq {{ conn_uname: \"{}\", cursor: {} }}", self.conn_uname, self.cursor)
+    }
+}
+
+/**
+Protocol message: server answering a HistoryReq with the next batch of chat
+messages (as they were sent, so still signed and encrypted), oldest first.
+
+more is set if there are messages after this batch. The request's cursor is
+sent back with the answer. A Failure status (with no messages) means the
+client isn't connected with conn_uname, or the server doesn't have the message
+the cursor points after.
+
+On the wire: status, conn_uname, cursor, more flag, a message count, then each
+message as a 4 byte length followed by the serialized ChatMessage.
+*/
+pub struct HistoryResp {
+    status_code: StatusCode,
+    conn_uname: Username,
+    cursor: HistoryCursor,
+    more: bool,
+    messages: Vec<ChatMessage>,
+}
+
+impl HistoryResp {
+    pub fn new(conn_uname: &Username, cursor: HistoryCursor, messages: Vec<ChatMessage>, more: bool) -> Self {
+        HistoryResp {
+            status_code: StatusCode::Success,
+            conn_uname: conn_uname.clone(),
+            cursor,
+            more,
+            messages,
+        }
+    }
+
+    pub fn failure(conn_uname: &Username, cursor: HistoryCursor) -> Self {
+        HistoryResp {
+            status_code: StatusCode::Failure,
+            conn_uname: conn_uname.clone(),
+            cursor,
+            more: false,
+            messages: Vec::new(),
+        }
+    }
+
+    pub fn status_code(&self) -> StatusCode {
+        self.status_code
+    }
+
+    pub fn conn_uname(&self) -> &Username {
+        &self.conn_uname
+    }
+
+    pub fn cursor(&self) -> HistoryCursor {
+        self.cursor
+    }
+
+    pub fn more(&self) -> bool {
+        self.more
+    }
+
+    pub fn messages(&self) -> &[ChatMessage] {
+        &self.messages
+    }
+
+    pub fn into_messages(self) -> Vec<ChatMessage> {
+        self.messages
+    }
+
+    /**
+    How many bytes a message adds to a HistoryResp, to keep batches within
+    what can be sent
+    */
+    pub fn message_len(chat_message: &ChatMessage) -> usize {
+        HISTORY_MESSAGE_LEN_LEN + chat_message.length()
+    }
+}
+
+impl Message for HistoryResp {
+    const MESSAGE_TYPE: MessageType = MessageType::HistoryResp;
+
+    fn serialize(&self) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        buffer.push(self.status_code as u8);
+        self.conn_uname.encode(&mut buffer);
+        self.cursor.encode(&mut buffer);
+        buffer.push(self.more as u8);
+        buffer.extend_from_slice(&(self.messages.len() as u16).to_be_bytes());
+        for chat_message in &self.messages {
+            let message = chat_message.serialize();
+            buffer.extend_from_slice(&(message.len() as u32).to_be_bytes());
+            buffer.extend_from_slice(&message);
+        }
+
+        buffer
+    }
+
+    fn deserialize(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        let mut decoder = Decoder::new(bytes);
+        let status_code = status_codes::decode_status_code(decoder.read_u8()?)?;
+        let conn_uname = decoder.read_username("conn_uname")?;
+        let cursor = HistoryCursor::decode(&mut decoder)?;
+        let more = match decoder.read_u8()? {
+            0 => false,
+            1 => true,
+            flag => return Err(ProtocolError::InvalidValue { field: "more", value: flag }),
+        };
+
+        let count = decoder.read_u16()? as usize;
+        let mut messages = Vec::with_capacity(count.min(decoder.remaining() / HISTORY_MESSAGE_LEN_LEN));
+        for _ in 0..count {
+            let len = decoder.read_u32()? as usize;
+            messages.push(ChatMessage::deserialize(decoder.read_bytes(len)?)?);
+        }
+        decoder.finish()?;
+
+        Ok (HistoryResp {
+            status_code,
+            conn_uname,
+            cursor,
+            more,
+            messages
+        })
+    }
+
+    fn length(&self) -> usize {
+        ERR_CODE_LEN
+            + self.conn_uname.encoded_len()
+            + self.cursor.encoded_len()
+            + HISTORY_FLAG_LEN
+            + MESSAGE_COUNT_LEN
+            + self.messages.iter().map(HistoryResp::message_len).sum::<usize>()
+    }
+}
+
+impl fmt::Debug for HistoryResp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "HistoryResp {{ status_code: {}, conn_uname: \"{}\", cursor: {}, more: {}, messages: {} }}",
+            self.status_code,
+            self.conn_uname,
+            self.cursor,
+            self.more,
+            self.messages.len()
+        )
+    }
+}
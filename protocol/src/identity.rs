@@ -0,0 +1,239 @@
+use std::fmt;
+
+use ed25519_dalek::{ Signature, Signer, SigningKey, VerifyingKey };
+use sha2::{ Digest, Sha256, Sha512 };
+
+use crate::field_lens::{ IDENTITY_KEY_LEN, SIGNATURE_LEN, KEY_FLAG_LEN };
+use crate::errors::ProtocolError;
+use crate::decode::Decoder;
+use crate::message::Message;
+use crate::message_types::MessageType;
+use crate::username::Username;
+
+// length of an identity key's secret half
+pub const IDENTITY_SECRET_LEN: usize = 32;
+
+// digits in a safety number, shown in groups of SAFETY_NUMBER_GROUP_LEN
+pub const SAFETY_NUMBER_GROUPS: usize = 12;
+pub const SAFETY_NUMBER_GROUP_LEN: usize = 5;
+
+/**
+A user's long-term Ed25519 signing key pair.
+
+Generated by the client at signup, which publishes the public half (the
+'identity key') through the server. Every chat message the user sends is
+signed with it, so recipients can tell it really came from the holder of the
+username, and users can compare identity keys out of band (see safety_number).
+*/
+pub struct IdentityKeyPair {
+    signing_key: SigningKey,
+}
+
+impl IdentityKeyPair {
+    pub fn generate() -> Self {
+        let secret: [u8; IDENTITY_SECRET_LEN] = rand::random();
+        IdentityKeyPair::from_secret_bytes(secret)
+    }
+
+    pub fn from_secret_bytes(secret: [u8; IDENTITY_SECRET_LEN]) -> Self {
+        IdentityKeyPair { signing_key: SigningKey::from_bytes(&secret) }
+    }
+
+    pub fn secret_bytes(&self) -> [u8; IDENTITY_SECRET_LEN] {
+        self.signing_key.to_bytes()
+    }
+
+    pub fn identity_key(&self) -> [u8; IDENTITY_KEY_LEN] {
+        self.signing_key.verifying_key().to_bytes()
+    }
+
+    pub fn sign(&self, bytes: &[u8]) -> [u8; SIGNATURE_LEN] {
+        self.signing_key.sign(bytes).to_bytes()
+    }
+}
+
+/**
+Checks a signature made with the secret half of identity_key
+*/
+pub fn verify_signature(identity_key: &[u8; IDENTITY_KEY_LEN], bytes: &[u8], signature: &[u8; SIGNATURE_LEN]) -> bool {
+    match VerifyingKey::from_bytes(identity_key) {
+        Ok(verifying_key) => verifying_key.verify_strict(bytes, &Signature::from_bytes(signature)).is_ok(),
+        Err(_) => false,
+    }
+}
+
+/**
+Short, human-readable form of a single identity key: the first 16 bytes of its
+SHA-256, in groups of 4 hex digits
+*/
+pub fn fingerprint(identity_key: &[u8; IDENTITY_KEY_LEN]) -> String {
+    let digest = Sha256::digest(identity_key);
+    digest[..16]
+        .chunks(2)
+        .map(|pair| format!("{:02x}{:02x}", pair[0], pair[1]))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/**
+Safety number for a conversation between two users: 60 digits (in groups of
+5) derived from both usernames and identity keys.
+
+Both users get the same number whichever way round they pass themselves and
+the other user, so they can read it to each other (in person, over the phone)
+to confirm neither key has been swapped by the server.
+*/
+pub fn safety_number(
+    uname: &Username,
+    identity_key: &[u8; IDENTITY_KEY_LEN],
+    other_uname: &Username,
+    other_identity_key: &[u8; IDENTITY_KEY_LEN]
+) -> String {
+    let mut parties = [(uname, identity_key), (other_uname, other_identity_key)];
+    parties.sort();
+
+    let mut hasher = Sha512::new();
+    for (uname, identity_key) in parties {
+        let mut encoded_uname = Vec::new();
+        uname.encode(&mut encoded_uname);
+        hasher.update(&encoded_uname);
+        hasher.update(identity_key);
+    }
+    let digest = hasher.finalize();
+
+    // each group comes from 5 bytes of the digest (60 of its 64 bytes are used)
+    digest
+        .chunks_exact(5)
+        .take(SAFETY_NUMBER_GROUPS)
+        .map(|chunk| {
+            let value = chunk.iter().fold(0u64, |acc, &byte| (acc << 8) | byte as u64);
+            format!("{:0width$}", value % 100_000, width = SAFETY_NUMBER_GROUP_LEN)
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/**
+Protocol message: client asking the server for a user's published identity key
+*/
+pub struct IdentityReq {
+    uname: Username,
+}
+
+impl IdentityReq {
+    pub fn new(uname: &Username) -> Self {
+        IdentityReq { uname: uname.clone() }
+    }
+
+    pub fn uname(&self) -> &Username {
+        &self.uname
+    }
+}
+
+impl Message for IdentityReq {
+    const MESSAGE_TYPE: MessageType = MessageType::IdentityReq;
+
+    fn serialize(&self) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        self.uname.encode(&mut buffer);
+
+        buffer
+    }
+
+    fn deserialize(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        let mut decoder = Decoder::new(bytes);
+        let uname = decoder.read_username("uname")?;
+        decoder.finish()?;
+
+        Ok (IdentityReq {
+            uname
+        })
+    }
+
+    fn length(&self) -> usize {
+        self.uname.encoded_len()
+    }
+}
+
+impl fmt::Debug for IdentityReq {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "IdentityReq {{ uname: \"{}\" }}", self.uname)
+    }
+}
+
+/**
+Protocol message: server answering an IdentityReq with the user's identity key,
+or none if there is no such user. On the wire the key is preceded by a flag
+byte saying whether it is present.
+*/
+pub struct IdentityResp {
+    uname: Username,
+    identity_key: Option<[u8; IDENTITY_KEY_LEN]>,
+}
+
+impl IdentityResp {
+    pub fn new(uname: &Username, identity_key: Option<[u8; IDENTITY_KEY_LEN]>) -> Self {
+        IdentityResp {
+            uname: uname.clone(),
+            identity_key,
+        }
+    }
+
+    pub fn uname(&self) -> &Username {
+        &self.uname
+    }
+
+    pub fn identity_key(&self) -> Option<&[u8; IDENTITY_KEY_LEN]> {
+        self.identity_key.as_ref()
+    }
+}
+
+impl Message for IdentityResp {
+    const MESSAGE_TYPE: MessageType = MessageType::IdentityResp;
+
+    fn serialize(&self) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        self.uname.encode(&mut buffer);
+        match &self.identity_key {
+            Some(identity_key) => {
+                buffer.push(1);
+                buffer.extend_from_slice(identity_key);
+            }
+            None => buffer.push(0),
+        }
+
+        buffer
+    }
+
+    fn deserialize(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        let mut decoder = Decoder::new(bytes);
+        let uname = decoder.read_username("uname")?;
+        let identity_key = match decoder.read_u8()? {
+            0 => None,
+            1 => Some(decoder.read_array::<IDENTITY_KEY_LEN>()?),
+            flag => return Err(ProtocolError::InvalidValue { field: "has_identity_key", value: flag }),
+        };
+        decoder.finish()?;
+
+        Ok (IdentityResp {
+            uname,
+            identity_key
+        })
+    }
+
+    fn length(&self) -> usize {
+        let key_len = if self.identity_key.is_some() { IDENTITY_KEY_LEN } else { 0 };
+        self.uname.encoded_len() + KEY_FLAG_LEN + key_len
+    }
+}
+
+impl fmt::Debug for IdentityResp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "IdentityResp {{ uname: \"{}\", identity_key: {} }}",
+            self.uname,
+            self.identity_key.as_ref().map_or("none".to_string(), fingerprint)
+        )
+    }
+}
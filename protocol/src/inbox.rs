@@ -0,0 +1,65 @@
+use std::fmt;
+
+use crate::field_lens::MESSAGE_ID_LEN;
+use crate::errors::ProtocolError;
+use crate::decode::Decoder;
+use crate::message::Message;
+use crate::message_types::MessageType;
+
+/**
+Protocol message: client acknowledging a chat message the server stored for
+it while it was offline, by the message's id.
+
+The server keeps each stored message in the recipient's inbox, and sends it
+again every time they verify, until they acknowledge it; only then is it
+deleted. Acknowledging a message that isn't in the inbox does nothing, so
+clients can simply acknowledge every chat message once they've stored it.
+*/
+pub struct InboxAck {
+    message_id: [u8; MESSAGE_ID_LEN],
+}
+
+impl InboxAck {
+    pub fn new(message_id: [u8; MESSAGE_ID_LEN]) -> Self {
+        InboxAck { message_id }
+    }
+
+    /**
+    Id of the chat message acknowledged
+    */
+    pub fn message_id(&self) -> [u8; MESSAGE_ID_LEN] {
+        self.message_id
+    }
+}
+
+impl Message for InboxAck {
+    const MESSAGE_TYPE: MessageType = MessageType::InboxAck;
+
+    fn serialize(&self) -> Vec<u8> {
+        self.message_id.to_vec()
+    }
+
+    fn deserialize(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        let mut decoder = Decoder::new(bytes);
+        let message_id = decoder.read_array::<MESSAGE_ID_LEN>()?;
+        decoder.finish()?;
+
+        Ok (InboxAck {
+            message_id
+        })
+    }
+
+    fn length(&self) -> usize {
+        MESSAGE_ID_LEN
+    }
+}
+
+impl fmt::Debug for InboxAck {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "InboxAck {{ message_id: {} }}",
+            crate::shared::message_id_to_string(self.message_id)
+        )
+    }
+}
@@ -1,57 +1,245 @@
-/**
+/*!
 -----------------
 Cli-chat protocol
 -----------------
 
 Protocol units are 'messages', each of which is sent wrapped in a Packet (see packet.rs).
+Packets are read from and written to streams with a PacketReader/PacketWriter (see codec.rs).
+Usernames in every message are validated and length-prefixed (see username.rs).
+Connections are plain TCP or, optionally, TLS (see transport.rs and tls.rs).
 
 The message types are:
 
+```text
+    Hello/HelloAck:
+        - sent at the start of every connection, before VerifyReq
+        - client states its protocol version and the optional features it supports
+        - server answers with the negotiated version and the features both sides support,
+          or rejects the client with an IncompatibleVersion status
+
     ChatMessage:
         - client sending a chat message to a mutual connection
+        - stamped by the sender with a unique message id and the time it was sent
+        - a reply carries the id of the message it answers (in_reply_to)
+        - signed by the sender's identity key, checked by both the server and recipient
+        - body is end-to-end encrypted under the two users' conversation key and
+          carried in a versioned Envelope (see envelope.rs), so the server only
+          ever relays ciphertext
+        - can be longer than one packet allows, in which case it is sent as Fragments
+
+    Fragment (needs the FRAGMENTATION capability):
+        - one piece of a packet larger than MAX_PACKET_LEN, up to MAX_MESSAGE_LEN
+        - senders split large packets automatically, receivers reassemble them
+          (with a timeout and memory limits) and handle the result as if it had
+          arrived whole
+
+    Receipt (needs the RECEIPTS capability):
+        - tells a chat message's sender how far it has got, by message id
+        - Accepted is sent by the server once it has passed the message on,
+          Delivered and Read by the recipient's client (relayed by the server)
+
+    Ping/Pong (needs the HEARTBEAT capability):
+        - either end pings the other every so often, and the other answers with a Pong
+        - lets both ends tell a quiet connection from a dead one, and measure latency
+        - the server drops sessions it hasn't heard from within the heartbeat timeout
+
+    PresenceUpdate/Presence (needs the PRESENCE capability):
+        - client sets its presence (online, away or offline) and an optional short status
+        - server tells a user's mutual connections their presence whenever their session
+          verifies or ends, or they change it, and tells a newly verified user the
+          presence of each of their connections
+
+    Typing (needs the TYPING capability):
+        - client tells a connection it has started or stopped typing a message to them
+        - ephemeral: relayed to the recipient if they're online, never stored
+
+    FileOffer/FileAccept/FileChunk/FileComplete (needs the FILE_TRANSFER capability):
+        - end-to-end encrypted file transfer between connections, FILE_CHUNK_LEN bytes at a time
+        - the recipient says which chunk to start from when accepting, so transfers can resume
+        - relayed by the server, never stored (see transfer.rs)
+
+    MessageEdit/MessageDelete (needs the EDITS capability):
+        - sender replaces the body of, or retracts, a chat message they sent earlier,
+          referring to it by message id
+        - signed like a ChatMessage, and an edit's new body is end-to-end encrypted
+        - relayed by the server; recipients only accept them from the message's
+          original sender, and keep its earlier text or a tombstone (see edit.rs)
+
+    Reaction (needs the REACTIONS capability):
+        - sender adds or removes a short reaction (e.g. an emoji) on a chat message,
+          by message id; each user has at most one of each reaction on a message
+        - relayed by the server to the other side of the conversation (see reaction.rs)
+
+    InboxAck (needs the INBOX capability):
+        - chat messages for a recipient who is offline are stored by the server in their
+          inbox (up to a size limit), and sent to them in order when they next verify
+        - the recipient's client acknowledges each one once it has stored it, and only
+          then does the server delete it (see inbox.rs)
+
+    HistoryReq/HistoryResp (needs the HISTORY capability):
+        - client asks for the chat messages in one of its conversations after a cursor:
+          a message id it already has, or a point in time
+        - server keeps every chat message it accepts, and answers with the next batch of
+          them, as they were sent (signed and encrypted), saying whether there are more
+        - the client asks again from the end of each batch, and merges what it gets into
+          its own history, e.g. to rebuild it on a new machine (see history.rs)
+
+    AuthChallenge/VerifyReq/VerifyResp:
+        - sent at the start of every cli-chat session, straight after HelloAck
+        - server sends a random challenge, which the client answers with its username and
+          a signature of the challenge by a key pair derived from its PAT token
+        - the token itself is only ever sent once, in the SignupResp, and the server
+          stores only the public half of that key pair, its verifier (see auth.rs)
+        - a failed VerifyReq is answered with a fresh challenge, so the client can retry
 
-    VerifyReq/VerifyResp:
-        - sent at the start of every cli-chat session
-        - user authenticates themselves by sending their username and previously-assigned PAT token
+    RotateTokenReq/RotateTokenResp, ListTokensReq/ListTokensResp, RevokeTokenReq/RevokeTokenResp:
+        - verified clients manage their account's PAT tokens, each known by a token id
+        - rotating issues a new token, replacing the session's current one (or alongside
+          it, e.g. for another device)
+        - listing gives the id and issue time of each active token
+        - a revoked token fails its next VerifyReq (see token.rs)
     
     SignupReq/SignupResp:
-        - sends new user's chosen username to server
+        - sends new user's chosen username and identity key to server
         - on success, server sends back PAT token
     
+    IdentityReq/IdentityResp:
+        - client asks the server for another user's identity key (the public half of
+          the Ed25519 key pair they generated at signup), which the server publishes
+        - clients remember each contact's key, and warn if it changes
+        - every ChatMessage is signed by its sender's identity key (see identity.rs)
+    
+    GroupCreate/GroupInvite/GroupLeave/GroupInfo/GroupMessage:
+        - group conversations, known by a group id and kept (name and member list) by the server
+        - members can add their connections to a group, and leave it; every change is
+          sent to all members as a GroupInfo
+        - GroupMessages are signed by their sender and fanned out by the server to every
+          other member
+        - bodies are end-to-end encrypted under a group key that members pass to the
+          users they invite, sealed under their conversation key (see group.rs)
+
     C2cConnReq/C2cConnResp:
         - user requests to 'connect' with another user (based on username)
         - server relays this on to target user
+        - target user responds with one of Accept, Reject, Block or AlreadyConnected,
+          which the server relays back to the requesting user
+        - both messages carry an X25519 public key (the response only on Accept or
+          AlreadyConnected), from which the two clients agree a conversation key
+        - each public key is signed, with both usernames, by its sender's identity key
         - on accept, clients add each other to their respective 'connections-list' stores,
           and can now send messages to each other
+        - after a Block, the server refuses further requests between the two users
+```
 */
 
 // Bring in and re-export all protocol message types
+pub mod message;
 pub mod packet;
 pub mod chat_message;
 pub mod verify;
 pub mod signup;
 pub mod connect;
+pub mod hello;
+pub mod receipt;
+pub mod fragment;
+pub mod heartbeat;
+pub mod envelope;
+pub mod identity;
+pub mod auth;
+pub mod token;
+pub mod group;
+pub mod presence;
+pub mod typing;
+pub mod transfer;
+pub mod edit;
+pub mod reaction;
+pub mod inbox;
+pub mod history;
+pub mod transport;
+pub mod tls;
+pub mod codec;
+pub mod decode;
+pub mod username;
+pub use message::{ Message, ProtocolMessage };
 pub use packet::Packet;
 pub use chat_message::ChatMessage;
 pub use verify::{ VerifyReq, VerifyResp };
 pub use signup::{ SignupReq, SignupResp };
-pub use connect::{ C2cConnReq, C2cConnResp};
+pub use connect::{ C2cConnReq, C2cConnResp, ConnResponse };
+pub use hello::{ Hello, HelloAck, Capabilities, NegotiatedSession };
+pub use receipt::{ Receipt, ReceiptKind };
+pub use fragment::{ Fragment, Reassembler };
+pub use heartbeat::{ Ping, Pong, Heartbeat, HeartbeatConfig, LinkStatus };
+pub use envelope::Envelope;
+pub use identity::{ IdentityKeyPair, IdentityReq, IdentityResp };
+pub use auth::AuthChallenge;
+pub use token::{ RotateTokenReq, RotateTokenResp, ListTokensReq, ListTokensResp, TokenInfo };
+pub use token::{ RevokeTokenReq, RevokeTokenResp };
+pub use group::{ GroupCreate, GroupInfo, GroupInvite, GroupLeave, GroupMessage };
+pub use presence::{ PresenceState, PresenceUpdate, Presence };
+pub use typing::Typing;
+pub use transfer::{ FileOffer, FileAccept, FileChunk, FileComplete };
+pub use edit::{ MessageEdit, MessageDelete };
+pub use reaction::{ Reaction, ReactionAction };
+pub use inbox::InboxAck;
+pub use history::{ HistoryCursor, HistoryReq, HistoryResp };
+pub use transport::Stream;
+pub use tls::TlsError;
+pub use codec::{ PacketReader, PacketWriter };
+pub use decode::Decoder;
+pub use username::{ Username, UsernameError };
 
-use std::io::{Read, Write};
-use std::net::TcpStream;
-use std::error::Error;
+use std::io::Read;
+pub use errors::ProtocolError;
+
+// re-exported so users of Stream and tls.rs don't need their own (matching) rustls dependency
+pub use rustls;
 
 // protocol message types
 pub mod message_types {
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
     pub enum MessageType {
-        ChatMessage,
-        VerifyReq,
-        VerifyResp,
-        SignupReq,
-        SignupResp,
-        C2cConnReq,
-        C2cConnResp,
-        Invalid
+        ChatMessage = 0,
+        VerifyReq = 1,
+        VerifyResp = 2,
+        SignupReq = 3,
+        SignupResp = 4,
+        C2cConnReq = 5,
+        C2cConnResp = 6,
+        Hello = 7,
+        HelloAck = 8,
+        Receipt = 9,
+        Fragment = 10,
+        Ping = 11,
+        Pong = 12,
+        IdentityReq = 13,
+        IdentityResp = 14,
+        AuthChallenge = 15,
+        RotateTokenReq = 16,
+        RotateTokenResp = 17,
+        ListTokensReq = 18,
+        ListTokensResp = 19,
+        RevokeTokenReq = 20,
+        RevokeTokenResp = 21,
+        GroupCreate = 22,
+        GroupInfo = 23,
+        GroupInvite = 24,
+        GroupLeave = 25,
+        GroupMessage = 26,
+        PresenceUpdate = 27,
+        Presence = 28,
+        Typing = 29,
+        FileOffer = 30,
+        FileAccept = 31,
+        FileChunk = 32,
+        FileComplete = 33,
+        MessageEdit = 34,
+        MessageDelete = 35,
+        Reaction = 36,
+        InboxAck = 37,
+        HistoryReq = 38,
+        HistoryResp = 39,
+        Invalid = 255
     }
 
     pub fn method_num_to_message_type(index: u8) -> MessageType {
@@ -63,6 +251,39 @@ pub mod message_types {
             4 => MessageType::SignupResp,
             5 => MessageType::C2cConnReq,
             6 => MessageType::C2cConnResp,
+            7 => MessageType::Hello,
+            8 => MessageType::HelloAck,
+            9 => MessageType::Receipt,
+            10 => MessageType::Fragment,
+            11 => MessageType::Ping,
+            12 => MessageType::Pong,
+            13 => MessageType::IdentityReq,
+            14 => MessageType::IdentityResp,
+            15 => MessageType::AuthChallenge,
+            16 => MessageType::RotateTokenReq,
+            17 => MessageType::RotateTokenResp,
+            18 => MessageType::ListTokensReq,
+            19 => MessageType::ListTokensResp,
+            20 => MessageType::RevokeTokenReq,
+            21 => MessageType::RevokeTokenResp,
+            22 => MessageType::GroupCreate,
+            23 => MessageType::GroupInfo,
+            24 => MessageType::GroupInvite,
+            25 => MessageType::GroupLeave,
+            26 => MessageType::GroupMessage,
+            27 => MessageType::PresenceUpdate,
+            28 => MessageType::Presence,
+            29 => MessageType::Typing,
+            30 => MessageType::FileOffer,
+            31 => MessageType::FileAccept,
+            32 => MessageType::FileChunk,
+            33 => MessageType::FileComplete,
+            34 => MessageType::MessageEdit,
+            35 => MessageType::MessageDelete,
+            36 => MessageType::Reaction,
+            37 => MessageType::InboxAck,
+            38 => MessageType::HistoryReq,
+            39 => MessageType::HistoryResp,
             _ => MessageType::Invalid
         }
     }
@@ -70,27 +291,30 @@ pub mod message_types {
 
 // protocol status codes
 pub mod status_codes {
-    #[derive(Copy, Clone)]
+    use crate::errors::ProtocolError;
+
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
     pub enum StatusCode {
-        Success,
-        Failure,
-        Invalid
+        Success = 0,
+        Failure = 1,
+        IncompatibleVersion = 2,
     }
 
-    pub fn decode_status_code(status_code: u8) -> StatusCode {
+    pub fn decode_status_code(status_code: u8) -> Result<StatusCode, ProtocolError> {
         match status_code {
-            0 => StatusCode::Success,
-            1 => StatusCode::Failure,
-            _ => StatusCode::Invalid
+            0 => Ok(StatusCode::Success),
+            1 => Ok(StatusCode::Failure),
+            2 => Ok(StatusCode::IncompatibleVersion),
+            _ => Err(ProtocolError::InvalidStatus(status_code))
         }
     }
 
-    impl ToString for StatusCode {
-        fn to_string(&self) -> String {
+    impl std::fmt::Display for StatusCode {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
             match self {
-                StatusCode::Success => String::from("Success"),
-                StatusCode::Failure => String::from("Failure"),
-                StatusCode::Invalid => String::from("Invalid"),
+                StatusCode::Success => write!(f, "Success"),
+                StatusCode::Failure => write!(f, "Failure"),
+                StatusCode::IncompatibleVersion => write!(f, "IncompatibleVersion"),
             }
         }
     }
@@ -98,38 +322,265 @@ pub mod status_codes {
 
 // protocol message field lengths
 pub mod field_lens {
-    pub const UNAME_LEN: usize = 50;
+    pub const MIN_UNAME_LEN: usize = 2;
+    pub const MAX_UNAME_LEN: usize = 50;
+    pub const UNAME_PREFIX_LEN: usize = 1;
     pub const MSGLEN_LEN: usize = 4;
     pub const TOKEN_LEN: usize = 32;
+    pub const MESSAGE_ID_LEN: usize = 16;
+    pub const TIMESTAMP_LEN: usize = 8;
     pub const METHOD_LEN: usize = 1;
     pub const ERR_CODE_LEN: usize = 1;
+    pub const RESPONSE_LEN: usize = 1;
+    pub const RECEIPT_KIND_LEN: usize = 1;
+    pub const REPLY_FLAG_LEN: usize = 1;
+    pub const VERSION_LEN: usize = 2;
+    pub const CAPABILITIES_LEN: usize = 4;
+    pub const FRAGMENT_INDEX_LEN: usize = 2;
+    pub const FRAGMENT_COUNT_LEN: usize = 2;
+    pub const NONCE_LEN: usize = 8;
+    pub const PUBLIC_KEY_LEN: usize = 32;
+    pub const KEY_FLAG_LEN: usize = 1;
+    pub const ENVELOPE_VERSION_LEN: usize = 1;
+    pub const KEY_ID_LEN: usize = 4;
+    pub const ENVELOPE_NONCE_LEN: usize = 24;
+    pub const AEAD_TAG_LEN: usize = 16;
+    pub const IDENTITY_KEY_LEN: usize = 32;
+    pub const SIGNATURE_LEN: usize = 64;
+    pub const CHALLENGE_LEN: usize = 32;
+    pub const VERIFIER_LEN: usize = 32;
+    pub const PROOF_LEN: usize = 64;
+    pub const TOKEN_ID_LEN: usize = 8;
+    pub const TOKEN_FLAG_LEN: usize = 1;
+    pub const TOKEN_COUNT_LEN: usize = 2;
+    pub const GROUP_ID_LEN: usize = 16;
+    pub const GROUP_NAME_PREFIX_LEN: usize = 1;
+    pub const MAX_GROUP_NAME_LEN: usize = 50;
+    pub const MEMBER_COUNT_LEN: usize = 2;
+    pub const PRESENCE_STATE_LEN: usize = 1;
+    pub const STATUS_PREFIX_LEN: usize = 1;
+    pub const MAX_STATUS_LEN: usize = 64;
+    pub const TYPING_FLAG_LEN: usize = 1;
+    pub const TRANSFER_ID_LEN: usize = 16;
+    pub const FILE_SIZE_LEN: usize = 8;
+    pub const CHUNK_INDEX_LEN: usize = 4;
+    // file bytes per FileChunk, small enough that a sealed chunk between the
+    // longest usernames still fits in one packet
+    pub const FILE_CHUNK_LEN: usize = 768;
+    pub const MAX_FILE_LEN: usize = 16 << 20;
+    pub const REACTION_ACTION_LEN: usize = 1;
+    pub const REACTION_PREFIX_LEN: usize = 1;
+    pub const MAX_REACTION_LEN: usize = 32;
+    pub const CURSOR_KIND_LEN: usize = 1;
+    pub const HISTORY_FLAG_LEN: usize = 1;
+    pub const MESSAGE_COUNT_LEN: usize = 2;
+    pub const HISTORY_MESSAGE_LEN_LEN: usize = 4;
     pub const MAX_PACKET_LEN: usize = 1024;
+    // largest packet that can be sent, in fragments
+    pub const MAX_MESSAGE_LEN: usize = 1 << 20;
 }
 
 // custom errors
 pub mod errors {
     use std::fmt;
+    use std::io;
     use std::error::Error;
+    use crate::message_types::MessageType;
+    use crate::status_codes::StatusCode;
+    use crate::username::UsernameError;
 
+    /**
+    Everything that can go wrong reading, writing or decoding protocol packets and messages
+    */
     #[derive(Debug)]
-    pub struct LengthError; 
+    pub enum ProtocolError {
+        // underlying stream failed (includes read timeouts and the peer hanging up)
+        Io(io::Error),
+
+        // fewer bytes than the packet/message needs
+        Truncated { expected: usize, actual: usize },
+
+        // fixed-size message with more bytes than it should have
+        LengthMismatch { expected: usize, actual: usize },
+
+        PacketTooLarge { packet_len: usize, max_packet_len: usize },
+
+        // (reassembled) packet bigger than can be sent in fragments
+        MessageTooLarge { message_len: usize, max_message_len: usize },
+
+        // fragment index out of range, or total disagreeing with earlier fragments
+        InvalidFragment { index: u16, total: u16 },
+
+        // too many bytes held for part-received fragmented packets
+        ReassemblyBufferFull { buffered_len: usize, max_buffered_len: usize },
+
+        // packet method byte doesn't correspond to a known message type
+        UnknownMethod(u8),
 
-    impl fmt::Display for LengthError {
+        InvalidStatus(u8),
+
+        // enum-like field holding a value outside its defined range
+        InvalidValue { field: &'static str, value: u8 },
+
+        InvalidUtf8 { field: &'static str },
+
+        InvalidUsername { field: &'static str, reason: UsernameError },
+
+        InvalidGroupName(&'static str),
+
+        InvalidStatusText(&'static str),
+
+        InvalidReaction(&'static str),
+
+        // offered file bigger than can be transferred
+        FileTooLarge { file_len: u64, max_file_len: u64 },
+
+        // well-formed status that makes no sense where it was received
+        UnexpectedStatus(StatusCode),
+
+        // well-formed message that makes no sense where it was received
+        UnexpectedMessage(MessageType),
+
+        IncompatibleVersion { local_version: u16, remote_version: u16 },
+    }
+
+    impl ProtocolError {
+        /**
+        Checks a fixed-size field/message is exactly the expected length
+        */
+        pub fn check_len(bytes: &[u8], expected: usize) -> Result<(), ProtocolError> {
+            if bytes.len() < expected {
+                return Err(ProtocolError::Truncated { expected, actual: bytes.len() });
+            }
+            if bytes.len() > expected {
+                return Err(ProtocolError::LengthMismatch { expected, actual: bytes.len() });
+            }
+
+            Ok(())
+        }
+
+        /**
+        Checks a variable-size field/message is at least the expected length
+        */
+        pub fn check_min_len(bytes: &[u8], expected: usize) -> Result<(), ProtocolError> {
+            if bytes.len() < expected {
+                return Err(ProtocolError::Truncated { expected, actual: bytes.len() });
+            }
+
+            Ok(())
+        }
+
+        // underlying io error kind, if this is an io error
+        pub fn io_kind(&self) -> Option<io::ErrorKind> {
+            match self {
+                ProtocolError::Io(e) => Some(e.kind()),
+                _ => None,
+            }
+        }
+    }
+
+    impl fmt::Display for ProtocolError {
         fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-            write!(f, "incorrect message/packet length specified")
+            match self {
+                ProtocolError::Io(e) => write!(f, "io error: {}", e),
+                ProtocolError::Truncated { expected, actual } => {
+                    write!(f, "truncated: expected {} bytes, got {}", expected, actual)
+                }
+                ProtocolError::LengthMismatch { expected, actual } => {
+                    write!(f, "incorrect length: expected {} bytes, got {}", expected, actual)
+                }
+                ProtocolError::PacketTooLarge { packet_len, max_packet_len } => {
+                    write!(
+                        f,
+                        "packet of {} bytes exceeds maximum packet length of {} bytes",
+                        packet_len,
+                        max_packet_len
+                    )
+                }
+                ProtocolError::MessageTooLarge { message_len, max_message_len } => {
+                    write!(
+                        f,
+                        "message of {} bytes exceeds maximum message length of {} bytes",
+                        message_len,
+                        max_message_len
+                    )
+                }
+                ProtocolError::InvalidFragment { index, total } => {
+                    write!(f, "invalid fragment: index {} of {}", index, total)
+                }
+                ProtocolError::ReassemblyBufferFull { buffered_len, max_buffered_len } => {
+                    write!(
+                        f,
+                        "reassembly buffer full: {} bytes would exceed the limit of {} bytes",
+                        buffered_len,
+                        max_buffered_len
+                    )
+                }
+                ProtocolError::UnknownMethod(method) => write!(f, "unknown packet method: {}", method),
+                ProtocolError::InvalidStatus(status) => write!(f, "invalid status code: {}", status),
+                ProtocolError::InvalidValue { field, value } => {
+                    write!(f, "invalid value {} for field '{}'", value, field)
+                }
+                ProtocolError::InvalidUtf8 { field } => write!(f, "field '{}' is not valid UTF-8", field),
+                ProtocolError::InvalidUsername { field, reason } => {
+                    write!(f, "invalid username in field '{}': {}", field, reason)
+                }
+                ProtocolError::InvalidGroupName(reason) => write!(f, "invalid group name: {}", reason),
+                ProtocolError::InvalidStatusText(reason) => write!(f, "invalid status text: {}", reason),
+                ProtocolError::InvalidReaction(reason) => write!(f, "invalid reaction: {}", reason),
+                ProtocolError::FileTooLarge { file_len, max_file_len } => {
+                    write!(f, "file of {} bytes exceeds maximum file length of {} bytes", file_len, max_file_len)
+                }
+                ProtocolError::UnexpectedStatus(status) => write!(f, "unexpected status: {}", status),
+                ProtocolError::UnexpectedMessage(message_type) => {
+                    write!(f, "unexpected message: {:?}", message_type)
+                }
+                ProtocolError::IncompatibleVersion { local_version, remote_version } => {
+                    write!(
+                        f,
+                        "incompatible protocol version: we speak {}, peer speaks {}",
+                        local_version,
+                        remote_version
+                    )
+                }
+            }
         }
     }
 
-    impl Error for LengthError {}
+    impl Error for ProtocolError {
+        fn source(&self) -> Option<&(dyn Error + 'static)> {
+            match self {
+                ProtocolError::Io(e) => Some(e),
+                _ => None,
+            }
+        }
+    }
+
+    impl From<io::Error> for ProtocolError {
+        fn from(e: io::Error) -> Self {
+            ProtocolError::Io(e)
+        }
+    }
 }
 
-// Reads a 'Packet' (see packet.rs) from the given server TCP scoket
-pub fn read_packet(mut stream: TcpStream) -> Result<Packet, Box<dyn Error>> {
-    let mut packet_buffer = [0u8; field_lens::MAX_PACKET_LEN];
-    let bytes_read = stream.read(&mut packet_buffer)?;
-    let packet = Packet::deserialize(&packet_buffer)?;
+/**
+Reads a single 'Packet' (see packet.rs) from the given stream.
+
+Reads exactly the packet header, then exactly msg_length bytes, so nothing past
+the end of the packet is consumed. For reading a sequence of packets off a
+long-lived connection, prefer a PacketReader (see codec.rs).
+*/
+pub fn read_packet<R: Read>(stream: &mut R) -> Result<Packet, ProtocolError> {
+    let mut header = [0u8; field_lens::METHOD_LEN + field_lens::MSGLEN_LEN];
+    stream.read_exact(&mut header)?;
 
-    Ok(packet)
+    let (method, msg_length) = codec::decode_header(&header)?;
+    let packet_len = codec::check_packet_len(msg_length, field_lens::MAX_PACKET_LEN)?;
+
+    let mut msg_buffer = vec![0u8; packet_len - header.len()];
+    stream.read_exact(&mut msg_buffer)?;
+
+    Ok(Packet::new(method, msg_length, msg_buffer))
 }
 
 // miscellaneous helper functions used by all protocol code
@@ -137,18 +588,6 @@ pub mod shared {
     use rand::Rng;
     use crate::field_lens;
 
-    // Sets username field of an arbitrary protocol message 
-    pub fn set_uname(target: &mut [u8], new_send_uname: &str) {
-        if new_send_uname.len() <= field_lens::UNAME_LEN {
-            target[..new_send_uname.len()].copy_from_slice(new_send_uname.as_bytes());
-        } else {
-            println!(
-                "Username can be a maximum of {} characters",
-                crate::field_lens::UNAME_LEN
-            );
-        }
-    }
-
     // 32-byte token generator
     pub fn generate_token() -> [u8; field_lens::TOKEN_LEN] {
         let mut rng = rand::thread_rng();
@@ -157,25 +596,6 @@ pub mod shared {
         token
     }
 
-    /**
-    Converts a username from its byte-rep to string-rep
-
-    NOTE: conversion to string form truncates null bytes
-    */
-    pub fn uname_to_string(uname: [u8; crate::field_lens::UNAME_LEN]) -> String {
-        // Find the position of the first null byte (0) in the buffer
-        let null_byte_position = uname.iter().position(|&byte| byte == 0);
-    
-        // Create a slice of the buffer up to the null byte position (if found)
-        let uname_slice = match null_byte_position {
-            Some(pos) => &uname[..pos],
-            None => &uname,
-        };
-    
-        // Convert the slice to a String
-        String::from_utf8_lossy(uname_slice).to_string()
-    }
-
     // Converts a token from its byte-rep to string-rep
     pub fn token_to_string(token: [u8; crate::field_lens::TOKEN_LEN]) -> String {
         let mut result = String::from("0x");
@@ -185,4 +605,68 @@ pub mod shared {
 
         result
     }
+
+    // 16-byte random message id generator (collisions are vanishingly unlikely)
+    pub fn generate_message_id() -> [u8; field_lens::MESSAGE_ID_LEN] {
+        let mut rng = rand::thread_rng();
+        let message_id: [u8; field_lens::MESSAGE_ID_LEN] = rng.gen();
+
+        message_id
+    }
+
+    // 8-byte random token id generator
+    pub fn generate_token_id() -> [u8; field_lens::TOKEN_ID_LEN] {
+        rand::thread_rng().gen()
+    }
+
+    // 16-byte random group id generator
+    pub fn generate_group_id() -> [u8; field_lens::GROUP_ID_LEN] {
+        rand::thread_rng().gen()
+    }
+
+    // Converts a group id from its byte-rep to (hex) string-rep
+    pub fn group_id_to_string(group_id: [u8; field_lens::GROUP_ID_LEN]) -> String {
+        group_id.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    // 16-byte random file transfer id generator
+    pub fn generate_transfer_id() -> [u8; field_lens::TRANSFER_ID_LEN] {
+        rand::thread_rng().gen()
+    }
+
+    // Converts a transfer id from its byte-rep to (hex) string-rep
+    pub fn transfer_id_to_string(transfer_id: [u8; field_lens::TRANSFER_ID_LEN]) -> String {
+        transfer_id.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    // Converts a token id from its byte-rep to (hex) string-rep
+    pub fn token_id_to_string(token_id: [u8; field_lens::TOKEN_ID_LEN]) -> String {
+        token_id.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    // Converts a token id from its (hex) string-rep, as given by token_id_to_string, to its byte-rep
+    pub fn token_id_from_string(hex: &str) -> Option<[u8; field_lens::TOKEN_ID_LEN]> {
+        if hex.len() != field_lens::TOKEN_ID_LEN * 2 || !hex.is_ascii() {
+            return None;
+        }
+
+        let mut token_id = [0u8; field_lens::TOKEN_ID_LEN];
+        for (i, byte) in token_id.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).ok()?;
+        }
+        Some(token_id)
+    }
+
+    // Converts a message id from its byte-rep to (hex) string-rep
+    pub fn message_id_to_string(message_id: [u8; field_lens::MESSAGE_ID_LEN]) -> String {
+        message_id.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    // Current time in milliseconds since the unix epoch
+    pub fn timestamp_now() -> u64 {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_millis() as u64)
+            .unwrap_or(0)
+    }
 }
\ No newline at end of file
@@ -0,0 +1,172 @@
+use crate::message_types::{ MessageType, method_num_to_message_type };
+use crate::errors::ProtocolError;
+use crate::{ Packet, ChatMessage, VerifyReq, VerifyResp, SignupReq, SignupResp, C2cConnReq, C2cConnResp, Hello, HelloAck, Receipt, Fragment, Ping, Pong, IdentityReq, IdentityResp, AuthChallenge };
+use crate::{ PresenceUpdate, Presence };
+use crate::Typing;
+use crate::{ FileOffer, FileAccept, FileChunk, FileComplete };
+use crate::{ MessageEdit, MessageDelete };
+use crate::Reaction;
+use crate::InboxAck;
+use crate::{HistoryReq, HistoryResp};
+use crate::{ GroupCreate, GroupInfo, GroupInvite, GroupLeave, GroupMessage };
+use crate::{ RotateTokenReq, RotateTokenResp, ListTokensReq, ListTokensResp, RevokeTokenReq, RevokeTokenResp };
+
+/**
+Common interface of every protocol message.
+
+Ties a message to the MessageType it is sent under, which lets any message be
+wrapped in a Packet (see Packet::wrap) and decoded back out of one (see ProtocolMessage).
+*/
+pub trait Message: Sized {
+    const MESSAGE_TYPE: MessageType;
+
+    fn serialize(&self) -> Vec<u8>;
+
+    fn deserialize(bytes: &[u8]) -> Result<Self, ProtocolError>;
+
+    // serialized length of the message, in bytes
+    fn length(&self) -> usize;
+}
+
+/**
+Any protocol message, decoded from a Packet based on its method byte
+*/
+#[derive(Debug)]
+pub enum ProtocolMessage {
+    ChatMessage(ChatMessage),
+    VerifyReq(VerifyReq),
+    VerifyResp(VerifyResp),
+    SignupReq(SignupReq),
+    SignupResp(SignupResp),
+    C2cConnReq(C2cConnReq),
+    C2cConnResp(C2cConnResp),
+    Hello(Hello),
+    HelloAck(HelloAck),
+    Receipt(Receipt),
+    Fragment(Fragment),
+    Ping(Ping),
+    Pong(Pong),
+    IdentityReq(IdentityReq),
+    IdentityResp(IdentityResp),
+    AuthChallenge(AuthChallenge),
+    RotateTokenReq(RotateTokenReq),
+    RotateTokenResp(RotateTokenResp),
+    ListTokensReq(ListTokensReq),
+    ListTokensResp(ListTokensResp),
+    RevokeTokenReq(RevokeTokenReq),
+    RevokeTokenResp(RevokeTokenResp),
+    GroupCreate(GroupCreate),
+    GroupInfo(GroupInfo),
+    GroupInvite(GroupInvite),
+    GroupLeave(GroupLeave),
+    GroupMessage(GroupMessage),
+    PresenceUpdate(PresenceUpdate),
+    Presence(Presence),
+    Typing(Typing),
+    FileOffer(FileOffer),
+    FileAccept(FileAccept),
+    FileChunk(FileChunk),
+    FileComplete(FileComplete),
+    MessageEdit(MessageEdit),
+    MessageDelete(MessageDelete),
+    Reaction(Reaction),
+    InboxAck(InboxAck),
+    HistoryReq(HistoryReq),
+    HistoryResp(HistoryResp),
+}
+
+impl ProtocolMessage {
+    pub fn decode(packet: &Packet) -> Result<Self, ProtocolError> {
+        let bytes = &packet.msg_buffer;
+        let message = match method_num_to_message_type(packet.method) {
+            MessageType::ChatMessage => ProtocolMessage::ChatMessage(ChatMessage::deserialize(bytes)?),
+            MessageType::VerifyReq => ProtocolMessage::VerifyReq(VerifyReq::deserialize(bytes)?),
+            MessageType::VerifyResp => ProtocolMessage::VerifyResp(VerifyResp::deserialize(bytes)?),
+            MessageType::SignupReq => ProtocolMessage::SignupReq(SignupReq::deserialize(bytes)?),
+            MessageType::SignupResp => ProtocolMessage::SignupResp(SignupResp::deserialize(bytes)?),
+            MessageType::C2cConnReq => ProtocolMessage::C2cConnReq(C2cConnReq::deserialize(bytes)?),
+            MessageType::C2cConnResp => ProtocolMessage::C2cConnResp(C2cConnResp::deserialize(bytes)?),
+            MessageType::Hello => ProtocolMessage::Hello(Hello::deserialize(bytes)?),
+            MessageType::HelloAck => ProtocolMessage::HelloAck(HelloAck::deserialize(bytes)?),
+            MessageType::Receipt => ProtocolMessage::Receipt(Receipt::deserialize(bytes)?),
+            MessageType::Fragment => ProtocolMessage::Fragment(Fragment::deserialize(bytes)?),
+            MessageType::Ping => ProtocolMessage::Ping(Ping::deserialize(bytes)?),
+            MessageType::Pong => ProtocolMessage::Pong(Pong::deserialize(bytes)?),
+            MessageType::IdentityReq => ProtocolMessage::IdentityReq(IdentityReq::deserialize(bytes)?),
+            MessageType::IdentityResp => ProtocolMessage::IdentityResp(IdentityResp::deserialize(bytes)?),
+            MessageType::AuthChallenge => ProtocolMessage::AuthChallenge(AuthChallenge::deserialize(bytes)?),
+            MessageType::RotateTokenReq => ProtocolMessage::RotateTokenReq(RotateTokenReq::deserialize(bytes)?),
+            MessageType::RotateTokenResp => ProtocolMessage::RotateTokenResp(RotateTokenResp::deserialize(bytes)?),
+            MessageType::ListTokensReq => ProtocolMessage::ListTokensReq(ListTokensReq::deserialize(bytes)?),
+            MessageType::ListTokensResp => ProtocolMessage::ListTokensResp(ListTokensResp::deserialize(bytes)?),
+            MessageType::RevokeTokenReq => ProtocolMessage::RevokeTokenReq(RevokeTokenReq::deserialize(bytes)?),
+            MessageType::RevokeTokenResp => ProtocolMessage::RevokeTokenResp(RevokeTokenResp::deserialize(bytes)?),
+            MessageType::GroupCreate => ProtocolMessage::GroupCreate(GroupCreate::deserialize(bytes)?),
+            MessageType::GroupInfo => ProtocolMessage::GroupInfo(GroupInfo::deserialize(bytes)?),
+            MessageType::GroupInvite => ProtocolMessage::GroupInvite(GroupInvite::deserialize(bytes)?),
+            MessageType::GroupLeave => ProtocolMessage::GroupLeave(GroupLeave::deserialize(bytes)?),
+            MessageType::GroupMessage => ProtocolMessage::GroupMessage(GroupMessage::deserialize(bytes)?),
+            MessageType::PresenceUpdate => ProtocolMessage::PresenceUpdate(PresenceUpdate::deserialize(bytes)?),
+            MessageType::Presence => ProtocolMessage::Presence(Presence::deserialize(bytes)?),
+            MessageType::Typing => ProtocolMessage::Typing(Typing::deserialize(bytes)?),
+            MessageType::FileOffer => ProtocolMessage::FileOffer(FileOffer::deserialize(bytes)?),
+            MessageType::FileAccept => ProtocolMessage::FileAccept(FileAccept::deserialize(bytes)?),
+            MessageType::FileChunk => ProtocolMessage::FileChunk(FileChunk::deserialize(bytes)?),
+            MessageType::FileComplete => ProtocolMessage::FileComplete(FileComplete::deserialize(bytes)?),
+            MessageType::MessageEdit => ProtocolMessage::MessageEdit(MessageEdit::deserialize(bytes)?),
+            MessageType::MessageDelete => ProtocolMessage::MessageDelete(MessageDelete::deserialize(bytes)?),
+            MessageType::Reaction => ProtocolMessage::Reaction(Reaction::deserialize(bytes)?),
+            MessageType::InboxAck => ProtocolMessage::InboxAck(InboxAck::deserialize(bytes)?),
+            MessageType::HistoryReq => ProtocolMessage::HistoryReq(HistoryReq::deserialize(bytes)?),
+            MessageType::HistoryResp => ProtocolMessage::HistoryResp(HistoryResp::deserialize(bytes)?),
+            MessageType::Invalid => return Err(ProtocolError::UnknownMethod(packet.method)),
+        };
+
+        Ok(message)
+    }
+
+    pub fn message_type(&self) -> MessageType {
+        match self {
+            ProtocolMessage::ChatMessage(_) => MessageType::ChatMessage,
+            ProtocolMessage::VerifyReq(_) => MessageType::VerifyReq,
+            ProtocolMessage::VerifyResp(_) => MessageType::VerifyResp,
+            ProtocolMessage::SignupReq(_) => MessageType::SignupReq,
+            ProtocolMessage::SignupResp(_) => MessageType::SignupResp,
+            ProtocolMessage::C2cConnReq(_) => MessageType::C2cConnReq,
+            ProtocolMessage::C2cConnResp(_) => MessageType::C2cConnResp,
+            ProtocolMessage::Hello(_) => MessageType::Hello,
+            ProtocolMessage::HelloAck(_) => MessageType::HelloAck,
+            ProtocolMessage::Receipt(_) => MessageType::Receipt,
+            ProtocolMessage::Fragment(_) => MessageType::Fragment,
+            ProtocolMessage::Ping(_) => MessageType::Ping,
+            ProtocolMessage::Pong(_) => MessageType::Pong,
+            ProtocolMessage::IdentityReq(_) => MessageType::IdentityReq,
+            ProtocolMessage::IdentityResp(_) => MessageType::IdentityResp,
+            ProtocolMessage::AuthChallenge(_) => MessageType::AuthChallenge,
+            ProtocolMessage::RotateTokenReq(_) => MessageType::RotateTokenReq,
+            ProtocolMessage::RotateTokenResp(_) => MessageType::RotateTokenResp,
+            ProtocolMessage::ListTokensReq(_) => MessageType::ListTokensReq,
+            ProtocolMessage::ListTokensResp(_) => MessageType::ListTokensResp,
+            ProtocolMessage::RevokeTokenReq(_) => MessageType::RevokeTokenReq,
+            ProtocolMessage::RevokeTokenResp(_) => MessageType::RevokeTokenResp,
+            ProtocolMessage::GroupCreate(_) => MessageType::GroupCreate,
+            ProtocolMessage::GroupInfo(_) => MessageType::GroupInfo,
+            ProtocolMessage::GroupInvite(_) => MessageType::GroupInvite,
+            ProtocolMessage::GroupLeave(_) => MessageType::GroupLeave,
+            ProtocolMessage::GroupMessage(_) => MessageType::GroupMessage,
+            ProtocolMessage::PresenceUpdate(_) => MessageType::PresenceUpdate,
+            ProtocolMessage::Presence(_) => MessageType::Presence,
+            ProtocolMessage::Typing(_) => MessageType::Typing,
+            ProtocolMessage::FileOffer(_) => MessageType::FileOffer,
+            ProtocolMessage::FileAccept(_) => MessageType::FileAccept,
+            ProtocolMessage::FileChunk(_) => MessageType::FileChunk,
+            ProtocolMessage::FileComplete(_) => MessageType::FileComplete,
+            ProtocolMessage::MessageEdit(_) => MessageType::MessageEdit,
+            ProtocolMessage::MessageDelete(_) => MessageType::MessageDelete,
+            ProtocolMessage::Reaction(_) => MessageType::Reaction,
+            ProtocolMessage::InboxAck(_) => MessageType::InboxAck,
+            ProtocolMessage::HistoryReq(_) => MessageType::HistoryReq,
+            ProtocolMessage::HistoryResp(_) => MessageType::HistoryResp,
+        }
+    }
+}
@@ -1,9 +1,14 @@
 use crate::field_lens::{ MSGLEN_LEN, METHOD_LEN };
-use std::error::Error;
-use crate::errors::LengthError;
+use std::fmt;
+use crate::errors::ProtocolError;
+use crate::decode::Decoder;
+use crate::message::{ Message, ProtocolMessage };
 
 /**
 MTU (maximum transmission unit) of the protocol. Acts as a wrapper for all protocol messages.
+
+On the wire, a packet is a 1-byte method, a 4-byte (big-endian) msg_length,
+then msg_length bytes of serialized message. See codec.rs for reading/writing packets to a stream.
 */
 pub struct Packet {
     pub method: u8,
@@ -20,6 +25,21 @@ impl Packet {
         }
     }
 
+    /**
+    Wraps a protocol message in a packet, using the message's MessageType as the method
+    */
+    pub fn wrap<M: Message>(message: &M) -> Self {
+        let msg_buffer = message.serialize();
+        Packet::new(M::MESSAGE_TYPE as u8, msg_buffer.len() as u32, msg_buffer)
+    }
+
+    /**
+    Decodes the wrapped message, based on the packet's method
+    */
+    pub fn decode(&self) -> Result<ProtocolMessage, ProtocolError> {
+        ProtocolMessage::decode(self)
+    }
+
     pub fn serialize(&self) -> Vec<u8> {
         let mut buffer = Vec::new();
         buffer.push(self.method);
@@ -29,16 +49,11 @@ impl Packet {
         buffer
     }
 
-    pub fn deserialize(bytes: &[u8]) -> Result<Self, Box<dyn Error>> {
-        let mut method = 0;
-        let mut msg_length = 0;
-        let mut length_buffer = [0u8; MSGLEN_LEN];
-        
-        method = bytes[0];
-        length_buffer.copy_from_slice(&bytes[METHOD_LEN .. METHOD_LEN + MSGLEN_LEN]);
-        msg_length = u32::from_be_bytes(length_buffer);
-
-        let msg_buffer = bytes[Packet::fixed_size() .. (Packet::fixed_size() + msg_length as usize)].to_vec();
+    pub fn deserialize(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        let mut decoder = Decoder::new(bytes);
+        let method = decoder.read_u8()?;
+        let msg_length = decoder.read_u32()?;
+        let msg_buffer = decoder.read_bytes(msg_length as usize)?.to_vec();
 
         Ok(Packet {
             method,
@@ -48,10 +63,22 @@ impl Packet {
     }
 
     pub fn length(&self) -> usize {
-        return Packet::fixed_size();
+        Packet::fixed_size() + self.msg_length as usize
     }
 
-    fn fixed_size() -> usize {
+    pub fn fixed_size() -> usize {
         METHOD_LEN + MSGLEN_LEN
     }
-}
\ No newline at end of file
+}
+
+impl fmt::Debug for Packet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Packet {{ method: {}, msg_length: {}, msg_buffer: {:?} }}",
+            self.method,
+            self.msg_length,
+            self.msg_buffer
+        )
+    }
+}
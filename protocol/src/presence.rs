@@ -0,0 +1,220 @@
+use std::fmt;
+
+use crate::field_lens::{ PRESENCE_STATE_LEN, STATUS_PREFIX_LEN, MAX_STATUS_LEN };
+use crate::errors::ProtocolError;
+use crate::decode::Decoder;
+use crate::message::Message;
+use crate::message_types::MessageType;
+use crate::username::Username;
+
+/**
+Whether a user is around to chat
+*/
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PresenceState {
+    Online = 0,
+    Away = 1,
+    Offline = 2,
+}
+
+impl PresenceState {
+    pub fn decode(byte: u8) -> Result<Self, ProtocolError> {
+        match byte {
+            0 => Ok(PresenceState::Online),
+            1 => Ok(PresenceState::Away),
+            2 => Ok(PresenceState::Offline),
+            _ => Err(ProtocolError::InvalidValue { field: "state", value: byte }),
+        }
+    }
+}
+
+impl fmt::Display for PresenceState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PresenceState::Online => write!(f, "online"),
+            PresenceState::Away => write!(f, "away"),
+            PresenceState::Offline => write!(f, "offline"),
+        }
+    }
+}
+
+/**
+Checks a custom status is at most MAX_STATUS_LEN bytes, with no control
+characters (it can be empty, for no status)
+*/
+pub fn validate_status(status: &str) -> Result<(), ProtocolError> {
+    if status.len() > MAX_STATUS_LEN {
+        return Err(ProtocolError::InvalidStatusText("must be at most 64 bytes long"));
+    }
+    if status.chars().any(char::is_control) {
+        return Err(ProtocolError::InvalidStatusText("must not contain control characters"));
+    }
+
+    Ok(())
+}
+
+// status texts are length-prefixed on the wire, like usernames
+fn encode_status(status: &str, buffer: &mut Vec<u8>) {
+    buffer.push(status.len() as u8);
+    buffer.extend_from_slice(status.as_bytes());
+}
+
+fn read_status(decoder: &mut Decoder) -> Result<String, ProtocolError> {
+    let len = decoder.read_u8()? as usize;
+    let bytes = decoder.read_bytes(len)?;
+    let status = std::str::from_utf8(bytes).map_err(|_| ProtocolError::InvalidUtf8 { field: "status" })?;
+    validate_status(status)?;
+
+    Ok(status.to_string())
+}
+
+/**
+Protocol message: client setting its own presence and custom status.
+
+Setting Offline hides the user from their connections while still connected.
+*/
+pub struct PresenceUpdate {
+    state: PresenceState,
+    status: String,
+}
+
+impl PresenceUpdate {
+    pub fn new(state: PresenceState, status: &str) -> Result<Self, ProtocolError> {
+        validate_status(status)?;
+
+        Ok(PresenceUpdate {
+            state,
+            status: status.to_string(),
+        })
+    }
+
+    pub fn state(&self) -> PresenceState {
+        self.state
+    }
+
+    pub fn status(&self) -> &str {
+        &self.status
+    }
+}
+
+impl Message for PresenceUpdate {
+    const MESSAGE_TYPE: MessageType = MessageType::PresenceUpdate;
+
+    fn serialize(&self) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        buffer.push(self.state as u8);
+        encode_status(&self.status, &mut buffer);
+
+        buffer
+    }
+
+    fn deserialize(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        let mut decoder = Decoder::new(bytes);
+        let state = PresenceState::decode(decoder.read_u8()?)?;
+        let status = read_status(&mut decoder)?;
+        decoder.finish()?;
+
+        Ok (PresenceUpdate {
+            state,
+            status
+        })
+    }
+
+    fn length(&self) -> usize {
+        PRESENCE_STATE_LEN + STATUS_PREFIX_LEN + self.status.len()
+    }
+}
+
+impl fmt::Debug for PresenceUpdate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PresenceUpdate {{ state: {}, status: \"{}\" }}", self.state, self.status)
+    }
+}
+
+/**
+Protocol message: server telling a user about one of their connections'
+presence, when that connection's session verifies or ends, or they change it
+*/
+pub struct Presence {
+    uname: Username,
+    state: PresenceState,
+    status: String,
+}
+
+impl Presence {
+    pub fn new(uname: &Username, state: PresenceState, status: &str) -> Result<Self, ProtocolError> {
+        validate_status(status)?;
+
+        Ok(Presence {
+            uname: uname.clone(),
+            state,
+            status: status.to_string(),
+        })
+    }
+
+    /**
+    Presence of a user with no session (or who has hidden themselves)
+    */
+    pub fn offline(uname: &Username) -> Self {
+        Presence {
+            uname: uname.clone(),
+            state: PresenceState::Offline,
+            status: String::new(),
+        }
+    }
+
+    pub fn uname(&self) -> &Username {
+        &self.uname
+    }
+
+    pub fn state(&self) -> PresenceState {
+        self.state
+    }
+
+    pub fn status(&self) -> &str {
+        &self.status
+    }
+}
+
+impl Message for Presence {
+    const MESSAGE_TYPE: MessageType = MessageType::Presence;
+
+    fn serialize(&self) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        self.uname.encode(&mut buffer);
+        buffer.push(self.state as u8);
+        encode_status(&self.status, &mut buffer);
+
+        buffer
+    }
+
+    fn deserialize(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        let mut decoder = Decoder::new(bytes);
+        let uname = decoder.read_username("uname")?;
+        let state = PresenceState::decode(decoder.read_u8()?)?;
+        let status = read_status(&mut decoder)?;
+        decoder.finish()?;
+
+        Ok (Presence {
+            uname,
+            state,
+            status
+        })
+    }
+
+    fn length(&self) -> usize {
+        self.uname.encoded_len() + PRESENCE_STATE_LEN + STATUS_PREFIX_LEN + self.status.len()
+    }
+}
+
+impl fmt::Debug for Presence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Presence {{ uname: \"{}\", state: {}, status: \"{}\" }}",
+            self.uname,
+            self.state,
+            self.status
+        )
+    }
+}
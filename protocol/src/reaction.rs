@@ -0,0 +1,215 @@
+use std::fmt;
+
+use crate::field_lens::{ MESSAGE_ID_LEN, TIMESTAMP_LEN, IDENTITY_KEY_LEN, SIGNATURE_LEN };
+use crate::field_lens::{ REACTION_ACTION_LEN, REACTION_PREFIX_LEN, MAX_REACTION_LEN };
+use crate::errors::ProtocolError;
+use crate::decode::Decoder;
+use crate::message::Message;
+use crate::message_types::MessageType;
+use crate::username::Username;
+use crate::identity::{ self, IdentityKeyPair };
+
+// domain separation for what reactions sign (see edit.rs)
+const REACTION_LABEL: &[u8] = b"reaction";
+
+/**
+Whether a reaction is being added to a message or taken back
+*/
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ReactionAction {
+    Add = 0,
+    Remove = 1,
+}
+
+impl ReactionAction {
+    pub fn decode(byte: u8) -> Result<Self, ProtocolError> {
+        match byte {
+            0 => Ok(ReactionAction::Add),
+            1 => Ok(ReactionAction::Remove),
+            _ => Err(ProtocolError::InvalidValue { field: "action", value: byte }),
+        }
+    }
+}
+
+/**
+Checks a reaction is 1 to MAX_REACTION_LEN bytes (e.g. an emoji, or a short
+word), with no whitespace or control characters
+*/
+pub fn validate_reaction(reaction: &str) -> Result<(), ProtocolError> {
+    if reaction.is_empty() || reaction.len() > MAX_REACTION_LEN {
+        return Err(ProtocolError::InvalidReaction("must be 1 to 32 bytes long"));
+    }
+    if reaction.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        return Err(ProtocolError::InvalidReaction("must not contain whitespace or control characters"));
+    }
+
+    Ok(())
+}
+
+/**
+Protocol message: sender adding a reaction to (or removing one from) a chat
+message in their conversation with recv_uname, by the message's id.
+
+Each user has at most one of each reaction on a message, so adding one twice
+is the same as adding it once. The timestamp (milliseconds since the unix
+epoch, sender's clock) orders a user's changes to the same reaction.
+
+Like edits and deletions, reactions are signed by their sender's identity
+key, so the server can't add, take back or change anyone's reactions. They
+aren't sealed though: the server relays them between connections and can see
+the reaction itself, though not the message it's on.
+*/
+#[derive(Clone)]
+pub struct Reaction {
+    message_id: [u8; MESSAGE_ID_LEN],
+    timestamp: u64,
+    signature: [u8; SIGNATURE_LEN],
+    send_uname: Username,
+    recv_uname: Username,
+    action: ReactionAction,
+    reaction: String,
+}
+
+impl Reaction {
+    pub fn new(
+        message_id: [u8; MESSAGE_ID_LEN],
+        send_uname: &Username,
+        recv_uname: &Username,
+        action: ReactionAction,
+        reaction: &str
+    ) -> Result<Self, ProtocolError> {
+        validate_reaction(reaction)?;
+
+        Ok(Reaction {
+            message_id,
+            timestamp: crate::shared::timestamp_now(),
+            signature: [0u8; SIGNATURE_LEN],
+            send_uname: send_uname.clone(),
+            recv_uname: recv_uname.clone(),
+            action,
+            reaction: reaction.to_string(),
+        })
+    }
+
+    /**
+    Id of the chat message reacted to
+    */
+    pub fn message_id(&self) -> [u8; MESSAGE_ID_LEN] {
+        self.message_id
+    }
+
+    pub fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
+    pub fn send_uname(&self) -> &Username {
+        &self.send_uname
+    }
+
+    pub fn recv_uname(&self) -> &Username {
+        &self.recv_uname
+    }
+
+    pub fn action(&self) -> ReactionAction {
+        self.action
+    }
+
+    pub fn reaction(&self) -> &str {
+        &self.reaction
+    }
+
+    pub fn sign(&mut self, identity: &IdentityKeyPair) {
+        self.signature = identity.sign(&self.signed_bytes());
+    }
+
+    pub fn verify_signature(&self, identity_key: &[u8; IDENTITY_KEY_LEN]) -> bool {
+        identity::verify_signature(identity_key, &self.signed_bytes(), &self.signature)
+    }
+
+    // everything but the signature itself
+    fn signed_bytes(&self) -> Vec<u8> {
+        let mut buffer = REACTION_LABEL.to_vec();
+        buffer.extend_from_slice(&self.message_id);
+        buffer.extend_from_slice(&self.timestamp.to_be_bytes());
+        self.send_uname.encode(&mut buffer);
+        self.recv_uname.encode(&mut buffer);
+        buffer.push(self.action as u8);
+        buffer.push(self.reaction.len() as u8);
+        buffer.extend_from_slice(self.reaction.as_bytes());
+
+        buffer
+    }
+
+    // size of the fixed-width fields (everything but the usernames and reaction)
+    pub fn fixed_size() -> usize {
+        MESSAGE_ID_LEN + TIMESTAMP_LEN + SIGNATURE_LEN + REACTION_ACTION_LEN + REACTION_PREFIX_LEN
+    }
+}
+
+impl Message for Reaction {
+    const MESSAGE_TYPE: MessageType = MessageType::Reaction;
+
+    fn serialize(&self) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        buffer.extend_from_slice(&self.message_id);
+        buffer.extend_from_slice(&self.timestamp.to_be_bytes());
+        buffer.extend_from_slice(&self.signature);
+        self.send_uname.encode(&mut buffer);
+        self.recv_uname.encode(&mut buffer);
+        buffer.push(self.action as u8);
+        buffer.push(self.reaction.len() as u8);
+        buffer.extend_from_slice(self.reaction.as_bytes());
+
+        buffer
+    }
+
+    fn deserialize(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        ProtocolError::check_min_len(bytes, Self::fixed_size())?;
+
+        let mut decoder = Decoder::new(bytes);
+        let message_id = decoder.read_array::<MESSAGE_ID_LEN>()?;
+        let timestamp = decoder.read_u64()?;
+        let signature = decoder.read_array::<SIGNATURE_LEN>()?;
+        let send_uname = decoder.read_username("send_uname")?;
+        let recv_uname = decoder.read_username("recv_uname")?;
+        let action = ReactionAction::decode(decoder.read_u8()?)?;
+
+        let len = decoder.read_u8()? as usize;
+        let reaction = std::str::from_utf8(decoder.read_bytes(len)?)
+            .map_err(|_| ProtocolError::InvalidUtf8 { field: "reaction" })?;
+        validate_reaction(reaction)?;
+        decoder.finish()?;
+
+        Ok (Reaction {
+            message_id,
+            timestamp,
+            signature,
+            send_uname,
+            recv_uname,
+            action,
+            reaction: reaction.to_string()
+        })
+    }
+
+    fn length(&self) -> usize {
+        Reaction::fixed_size()
+            + self.send_uname.encoded_len()
+            + self.recv_uname.encoded_len()
+            + self.reaction.len()
+    }
+}
+
+impl fmt::Debug for Reaction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Reaction {{ message_id: {}, timestamp: {}, send_uname: \"{}\", recv_uname: \"{}\", action: {:?}, reaction: \"{}\" }}",
+            crate::shared::message_id_to_string(self.message_id),
+            self.timestamp,
+            self.send_uname,
+            self.recv_uname,
+            self.action,
+            self.reaction
+        )
+    }
+}
@@ -0,0 +1,152 @@
+use std::fmt;
+
+use crate::field_lens::{ MESSAGE_ID_LEN, RECEIPT_KIND_LEN };
+use crate::errors::ProtocolError;
+use crate::decode::Decoder;
+use crate::message::Message;
+use crate::message_types::MessageType;
+use crate::ChatMessage;
+use crate::username::Username;
+
+/**
+How far a chat message has got on its way to the recipient.
+
+Accepted, Delivered and Read are stages, each implying the ones before it (a
+Read message was also Delivered). Rejected isn't: the server sends it in place
+of Accepted when it couldn't take the message, and nothing follows it.
+*/
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ReceiptKind {
+    // server has taken the message and passed it on towards the recipient
+    Accepted = 0,
+    // recipient's client has received and stored the message
+    Delivered = 1,
+    // recipient has seen the message
+    Read = 2,
+    // server has dropped the message: the recipient is offline and their inbox is full
+    Rejected = 3,
+}
+
+impl ReceiptKind {
+    pub fn decode(kind: u8) -> Result<Self, ProtocolError> {
+        match kind {
+            0 => Ok(ReceiptKind::Accepted),
+            1 => Ok(ReceiptKind::Delivered),
+            2 => Ok(ReceiptKind::Read),
+            3 => Ok(ReceiptKind::Rejected),
+            _ => Err(ProtocolError::InvalidValue { field: "kind", value: kind }),
+        }
+    }
+}
+
+impl fmt::Display for ReceiptKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReceiptKind::Accepted => write!(f, "Accepted"),
+            ReceiptKind::Delivered => write!(f, "Delivered"),
+            ReceiptKind::Read => write!(f, "Read"),
+            ReceiptKind::Rejected => write!(f, "Rejected"),
+        }
+    }
+}
+
+/**
+Protocol message: receipt for a chat message, always sent back to that message's sender.
+
+Accepted and Rejected receipts come from the server, Delivered and Read
+receipts from the recipient's client (relayed by the server). send_uname/recv_uname are those of
+the chat message the receipt is for.
+*/
+pub struct Receipt {
+    kind: ReceiptKind,
+    message_id: [u8; MESSAGE_ID_LEN],
+    send_uname: Username,
+    recv_uname: Username,
+}
+
+impl Receipt {
+    pub fn new(kind: ReceiptKind, message_id: [u8; MESSAGE_ID_LEN], send_uname: &Username, recv_uname: &Username) -> Self {
+        Receipt {
+            kind,
+            message_id,
+            send_uname: send_uname.clone(),
+            recv_uname: recv_uname.clone(),
+        }
+    }
+
+    /**
+    Receipt of the given kind for chat_message
+    */
+    pub fn for_message(kind: ReceiptKind, chat_message: &ChatMessage) -> Self {
+        Receipt {
+            kind,
+            message_id: chat_message.message_id(),
+            send_uname: chat_message.send_uname.clone(),
+            recv_uname: chat_message.recv_uname.clone(),
+        }
+    }
+
+    pub fn kind(&self) -> ReceiptKind {
+        self.kind
+    }
+
+    pub fn message_id(&self) -> [u8; MESSAGE_ID_LEN] {
+        self.message_id
+    }
+
+    pub fn send_uname(&self) -> &Username {
+        &self.send_uname
+    }
+
+    pub fn recv_uname(&self) -> &Username {
+        &self.recv_uname
+    }
+}
+
+impl Message for Receipt {
+    const MESSAGE_TYPE: MessageType = MessageType::Receipt;
+
+    fn serialize(&self) -> Vec<u8> {
+        let mut buffer = Vec::new();
+
+        buffer.push(self.kind as u8);
+        buffer.extend_from_slice(&self.message_id);
+        self.send_uname.encode(&mut buffer);
+        self.recv_uname.encode(&mut buffer);
+
+        buffer
+    }
+
+    fn deserialize(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        let mut decoder = Decoder::new(bytes);
+        let kind = ReceiptKind::decode(decoder.read_u8()?)?;
+        let message_id = decoder.read_array::<MESSAGE_ID_LEN>()?;
+        let send_uname = decoder.read_username("send_uname")?;
+        let recv_uname = decoder.read_username("recv_uname")?;
+        decoder.finish()?;
+
+        Ok (Receipt {
+            kind,
+            message_id,
+            send_uname,
+            recv_uname
+        })
+    }
+
+    fn length(&self) -> usize {
+        RECEIPT_KIND_LEN + MESSAGE_ID_LEN + self.send_uname.encoded_len() + self.recv_uname.encoded_len()
+    }
+}
+
+impl fmt::Debug for Receipt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Receipt {{ kind: {}, message_id: {}, send_uname: \"{}\", recv_uname: \"{}\" }}",
+            self.kind,
+            crate::shared::message_id_to_string(self.message_id),
+            self.send_uname(),
+            self.recv_uname()
+        )
+    }
+}
@@ -1,57 +1,64 @@
 use std::fmt;
-use std::error::Error;
 
-use crate::field_lens::{ UNAME_LEN, TOKEN_LEN, ERR_CODE_LEN };
+use crate::field_lens::{ TOKEN_LEN, ERR_CODE_LEN, IDENTITY_KEY_LEN };
 use crate::status_codes::{ self, StatusCode };
-use crate::errors::LengthError;
+use crate::errors::ProtocolError;
+use crate::decode::Decoder;
+use crate::message::Message;
+use crate::message_types::MessageType;
+use crate::username::Username;
 
 /**
-Protocol message: client attempting to sign up with a given username
+Protocol message: client attempting to sign up with a given username, publishing
+the identity key it will sign its messages with (see identity.rs)
 */
 pub struct SignupReq {
-   cli_uname: [u8; UNAME_LEN]
+   cli_uname: Username,
+   identity_key: [u8; IDENTITY_KEY_LEN],
 }
 
 impl SignupReq {
-    pub fn empty() -> Self {
+    pub fn new(cli_uname: &Username, identity_key: [u8; IDENTITY_KEY_LEN]) -> Self {
         SignupReq {
-            cli_uname: [0u8; UNAME_LEN]
+            cli_uname: cli_uname.clone(),
+            identity_key
         }
     }
 
-    pub fn new(new_uname: &str) -> Self {
-        let mut signup = SignupReq::empty();
-        crate::shared::set_uname(&mut signup.cli_uname, new_uname);
+    pub fn uname(&self) -> &Username {
+        &self.cli_uname
+    }
 
-        signup
+    pub fn identity_key(&self) -> &[u8; IDENTITY_KEY_LEN] {
+        &self.identity_key
     }
+}
 
-    pub fn serialize(&self) -> Vec<u8> {
+impl Message for SignupReq {
+    const MESSAGE_TYPE: MessageType = MessageType::SignupReq;
+
+    fn serialize(&self) -> Vec<u8> {
         let mut buffer = Vec::new();
-        buffer.extend_from_slice(&self.cli_uname);
+        self.cli_uname.encode(&mut buffer);
+        buffer.extend_from_slice(&self.identity_key);
 
         buffer
     }
 
-    pub fn deserialize(bytes: &[u8]) -> Result<Self, Box<dyn Error>> {
-        if bytes.len() != SignupReq::fixed_size() {
-            return Err(Box::new(LengthError));
-        }
-
-        let mut cli_uname = [0u8; UNAME_LEN];
-        cli_uname.copy_from_slice(&bytes[..UNAME_LEN]);
+    fn deserialize(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        let mut decoder = Decoder::new(bytes);
+        let cli_uname = decoder.read_username("cli_uname")?;
+        let identity_key = decoder.read_array::<IDENTITY_KEY_LEN>()?;
+        decoder.finish()?;
 
         Ok (SignupReq {
-            cli_uname
+            cli_uname,
+            identity_key
         })
     }
 
-    pub fn length(&self) -> usize {
-        SignupReq::fixed_size()
-    }
-
-    fn fixed_size() -> usize {
-        UNAME_LEN
+    fn length(&self) -> usize {
+        self.cli_uname.encoded_len() + IDENTITY_KEY_LEN
     }
 }
 
@@ -59,8 +66,9 @@ impl fmt::Debug for SignupReq {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
             f,
-            "SignupReq {{ cli_uname: \"{}\" }}",
-            String::from_utf8_lossy(&self.cli_uname).to_string()
+            "SignupReq {{ cli_uname: \"{}\", identity_key: {} }}",
+            self.cli_uname,
+            crate::identity::fingerprint(&self.identity_key)
         )
     }
 }
@@ -81,9 +89,25 @@ impl SignupResp {
         }
     }
 
-    pub fn serialize(&self) -> Vec<u8> {
+    pub fn status_code(&self) -> StatusCode {
+        self.status_code
+    }
+
+    pub fn token(&self) -> [u8; TOKEN_LEN] {
+        self.token
+    }
+
+    fn fixed_size() -> usize {
+        ERR_CODE_LEN + TOKEN_LEN
+    } 
+}
+
+impl Message for SignupResp {
+    const MESSAGE_TYPE: MessageType = MessageType::SignupResp;
+
+    fn serialize(&self) -> Vec<u8> {
         let mut buffer = Vec::new();
-        let status_code = *(&self.status_code) as u8;
+        let status_code = self.status_code as u8;
 
         buffer.push(status_code);
         buffer.extend_from_slice(&self.token);
@@ -91,14 +115,12 @@ impl SignupResp {
         buffer
     }
 
-    pub fn deserialize(bytes: &[u8]) -> Result<Self, Box<dyn Error>> {
-        if bytes.len() != SignupResp::fixed_size() {
-            return Err(Box::new(LengthError));
-        }
+    fn deserialize(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        ProtocolError::check_len(bytes, SignupResp::fixed_size())?;
 
-        let status_code = status_codes::decode_status_code(bytes[0]);
-        let mut token = [0u8; TOKEN_LEN];
-        token.copy_from_slice(&bytes[ERR_CODE_LEN .. (ERR_CODE_LEN + TOKEN_LEN)]);
+        let mut decoder = Decoder::new(bytes);
+        let status_code = status_codes::decode_status_code(decoder.read_u8()?)?;
+        let token = decoder.read_array::<TOKEN_LEN>()?;
 
         Ok (SignupResp {
             status_code,
@@ -106,13 +128,9 @@ impl SignupResp {
         })
     }
 
-    pub fn length(&self) -> usize {
+    fn length(&self) -> usize {
         SignupResp::fixed_size()
     }
-
-    fn fixed_size() -> usize {
-        ERR_CODE_LEN + TOKEN_LEN
-    } 
 }
 
 impl fmt::Debug for SignupResp {
@@ -120,10 +138,8 @@ impl fmt::Debug for SignupResp {
         write!(
             f,
             "SignupResp {{ status_code: {}, token: {} }}",
-            self.status_code.to_string(),
-            String::from_utf8_lossy(&self.token).to_string()
+            self.status_code,
+            String::from_utf8_lossy(&self.token)
         )
     }
 }
-
-
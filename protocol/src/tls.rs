@@ -0,0 +1,241 @@
+use std::fmt;
+use std::io;
+use std::error::Error;
+use std::path::Path;
+use std::sync::Arc;
+
+use rustls::client::danger::{ HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier };
+use rustls::crypto::{ self, CryptoProvider };
+use rustls::pki_types::{ CertificateDer, PrivateKeyDer, ServerName, UnixTime };
+use rustls::pki_types::pem::{ self, PemObject };
+use rustls::{ CertificateError, ClientConfig, DigitallySignedStruct, RootCertStore, ServerConfig, SignatureScheme };
+use sha2::{ Digest, Sha256 };
+
+// length of a certificate fingerprint (SHA-256 of the certificate's DER encoding)
+pub const FINGERPRINT_LEN: usize = 32;
+
+/**
+Everything that can go wrong setting up TLS
+*/
+#[derive(Debug)]
+pub enum TlsError {
+    Io(io::Error),
+
+    // handshake failed, or a config was rejected by rustls
+    Rustls(rustls::Error),
+
+    Pem { path: String, reason: pem::Error },
+
+    NoCertificates { path: String },
+
+    NoPrivateKey { path: String },
+
+    InvalidServerName(String),
+
+    // pinned fingerprint that isn't 64 hex digits
+    InvalidFingerprint(String),
+}
+
+impl fmt::Display for TlsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TlsError::Io(e) => write!(f, "io error: {}", e),
+            TlsError::Rustls(e) => write!(f, "TLS error: {}", e),
+            TlsError::Pem { path, reason } => write!(f, "could not read PEM file '{}': {}", path, reason),
+            TlsError::NoCertificates { path } => write!(f, "no certificates found in '{}'", path),
+            TlsError::NoPrivateKey { path } => write!(f, "no private key found in '{}'", path),
+            TlsError::InvalidServerName(name) => write!(f, "invalid TLS server name: {}", name),
+            TlsError::InvalidFingerprint(fingerprint) => {
+                write!(f, "invalid certificate fingerprint (expected {} hex bytes): {}", FINGERPRINT_LEN, fingerprint)
+            }
+        }
+    }
+}
+
+impl Error for TlsError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            TlsError::Io(e) => Some(e),
+            TlsError::Rustls(e) => Some(e),
+            TlsError::Pem { reason, .. } => Some(reason),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for TlsError {
+    fn from(e: io::Error) -> Self {
+        TlsError::Io(e)
+    }
+}
+
+impl From<rustls::Error> for TlsError {
+    fn from(e: rustls::Error) -> Self {
+        TlsError::Rustls(e)
+    }
+}
+
+// All TLS here uses rustls's pure-Rust 'ring' backend
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(crypto::ring::default_provider())
+}
+
+/**
+Reads every certificate in a PEM file
+*/
+pub fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, TlsError> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|reason| TlsError::Pem { path: path.display().to_string(), reason })?;
+    if certs.is_empty() {
+        return Err(TlsError::NoCertificates { path: path.display().to_string() });
+    }
+
+    Ok(certs)
+}
+
+/**
+Reads the first private key in a PEM file
+*/
+pub fn load_private_key(path: &Path) -> Result<PrivateKeyDer<'static>, TlsError> {
+    match PrivateKeyDer::from_pem_file(path) {
+        Ok(key) => Ok(key),
+        Err(pem::Error::NoItemsFound) => Err(TlsError::NoPrivateKey { path: path.display().to_string() }),
+        Err(reason) => Err(TlsError::Pem { path: path.display().to_string(), reason }),
+    }
+}
+
+/**
+Server config presenting the certificate chain and key in the given PEM files
+*/
+pub fn server_config(cert_path: &Path, key_path: &Path) -> Result<Arc<ServerConfig>, TlsError> {
+    server_config_from_der(load_certs(cert_path)?, load_private_key(key_path)?)
+}
+
+pub fn server_config_from_der(certs: Vec<CertificateDer<'static>>, key: PrivateKeyDer<'static>) -> Result<Arc<ServerConfig>, TlsError> {
+    let config = ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+
+    Ok(Arc::new(config))
+}
+
+/**
+Client config trusting only the CA certificate(s) in the given PEM file
+*/
+pub fn client_config_with_ca(ca_path: &Path) -> Result<Arc<ClientConfig>, TlsError> {
+    client_config_with_roots(load_certs(ca_path)?)
+}
+
+pub fn client_config_with_roots(roots: Vec<CertificateDer<'static>>) -> Result<Arc<ClientConfig>, TlsError> {
+    let mut root_store = RootCertStore::empty();
+    for root in roots {
+        root_store.add(root)?;
+    }
+
+    let config = ClientConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(root_store)
+        .with_no_client_auth();
+
+    Ok(Arc::new(config))
+}
+
+/**
+Client config trusting only a server whose certificate has the given
+fingerprint (see cert_fingerprint), whoever signed it. Suits a self-signed
+server certificate.
+*/
+pub fn client_config_with_pin(fingerprint: [u8; FINGERPRINT_LEN]) -> Result<Arc<ClientConfig>, TlsError> {
+    let provider = provider();
+    let verifier = PinnedCertVerifier {
+        fingerprint,
+        provider: Arc::clone(&provider),
+    };
+
+    let config = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_no_client_auth();
+
+    Ok(Arc::new(config))
+}
+
+/**
+SHA-256 of a certificate's DER encoding, as used for pinning
+*/
+pub fn cert_fingerprint(cert: &CertificateDer<'_>) -> [u8; FINGERPRINT_LEN] {
+    Sha256::digest(cert.as_ref()).into()
+}
+
+/**
+Parses a fingerprint written as hex, optionally with ':' between bytes
+(e.g. as printed by openssl x509 -fingerprint -sha256)
+*/
+pub fn parse_fingerprint(hex: &str) -> Result<[u8; FINGERPRINT_LEN], TlsError> {
+    let digits: String = hex.chars().filter(|&c| c != ':').collect();
+    let invalid = || TlsError::InvalidFingerprint(hex.to_string());
+    if digits.len() != FINGERPRINT_LEN * 2 || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(invalid());
+    }
+
+    let mut fingerprint = [0u8; FINGERPRINT_LEN];
+    for (i, byte) in fingerprint.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&digits[i * 2..i * 2 + 2], 16).map_err(|_| invalid())?;
+    }
+
+    Ok(fingerprint)
+}
+
+/**
+Accepts exactly one server certificate, identified by its fingerprint.
+
+The handshake signatures are still checked, so the server must hold the
+certificate's private key.
+*/
+#[derive(Debug)]
+struct PinnedCertVerifier {
+    fingerprint: [u8; FINGERPRINT_LEN],
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if cert_fingerprint(end_entity) != self.fingerprint {
+            return Err(rustls::Error::InvalidCertificate(CertificateError::ApplicationVerificationFailure));
+        }
+
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
@@ -0,0 +1,394 @@
+/*
+PAT token management.
+
+An account can have several active tokens (e.g. one per device), each known by
+a token id the server assigns when it issues the token. The server only ever
+stores a token's verifier (see auth.rs), so a token can't be recovered, only
+rotated (replaced by a new one) or revoked. A revoked token fails its next
+VerifyReq; sessions already verified with it carry on until they end.
+
+All token messages are only accepted from verified sessions.
+*/
+
+use std::fmt;
+
+use crate::field_lens::{ TOKEN_LEN, TOKEN_ID_LEN, TIMESTAMP_LEN, ERR_CODE_LEN, TOKEN_FLAG_LEN, TOKEN_COUNT_LEN };
+use crate::errors::ProtocolError;
+use crate::decode::Decoder;
+use crate::message::Message;
+use crate::message_types::MessageType;
+use crate::status_codes::{ self, StatusCode };
+
+/**
+Protocol message: client asking for a new token.
+
+The token the session was verified with is revoked once the new one is issued,
+unless keep_current is set, in which case the new token is an additional one
+(e.g. for another device).
+*/
+pub struct RotateTokenReq {
+    keep_current: bool,
+}
+
+impl RotateTokenReq {
+    pub fn new(keep_current: bool) -> Self {
+        RotateTokenReq { keep_current }
+    }
+
+    pub fn keep_current(&self) -> bool {
+        self.keep_current
+    }
+}
+
+impl Message for RotateTokenReq {
+    const MESSAGE_TYPE: MessageType = MessageType::RotateTokenReq;
+
+    fn serialize(&self) -> Vec<u8> {
+        vec![self.keep_current as u8]
+    }
+
+    fn deserialize(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        ProtocolError::check_len(bytes, TOKEN_FLAG_LEN)?;
+
+        let mut decoder = Decoder::new(bytes);
+        let keep_current = read_flag(&mut decoder, "keep_current")?;
+
+        Ok (RotateTokenReq {
+            keep_current
+        })
+    }
+
+    fn length(&self) -> usize {
+        TOKEN_FLAG_LEN
+    }
+}
+
+impl fmt::Debug for RotateTokenReq {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "RotateTokenReq {{ keep_current: {} }}", self.keep_current)
+    }
+}
+
+/**
+Protocol message: server responding to a RotateTokenReq with the new token and
+its id (both meaningless unless status_code is Success)
+*/
+pub struct RotateTokenResp {
+    status_code: StatusCode,
+    token_id: [u8; TOKEN_ID_LEN],
+    token: [u8; TOKEN_LEN],
+}
+
+impl RotateTokenResp {
+    pub fn new(status_code: StatusCode) -> Self {
+        RotateTokenResp {
+            status_code,
+            token_id: crate::shared::generate_token_id(),
+            token: crate::shared::generate_token(),
+        }
+    }
+
+    pub fn status_code(&self) -> StatusCode {
+        self.status_code
+    }
+
+    pub fn token_id(&self) -> [u8; TOKEN_ID_LEN] {
+        self.token_id
+    }
+
+    pub fn token(&self) -> [u8; TOKEN_LEN] {
+        self.token
+    }
+
+    fn fixed_size() -> usize {
+        ERR_CODE_LEN + TOKEN_ID_LEN + TOKEN_LEN
+    }
+}
+
+impl Message for RotateTokenResp {
+    const MESSAGE_TYPE: MessageType = MessageType::RotateTokenResp;
+
+    fn serialize(&self) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        buffer.push(self.status_code as u8);
+        buffer.extend_from_slice(&self.token_id);
+        buffer.extend_from_slice(&self.token);
+
+        buffer
+    }
+
+    fn deserialize(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        ProtocolError::check_len(bytes, RotateTokenResp::fixed_size())?;
+
+        let mut decoder = Decoder::new(bytes);
+        let status_code = status_codes::decode_status_code(decoder.read_u8()?)?;
+        let token_id = decoder.read_array::<TOKEN_ID_LEN>()?;
+        let token = decoder.read_array::<TOKEN_LEN>()?;
+
+        Ok (RotateTokenResp {
+            status_code,
+            token_id,
+            token
+        })
+    }
+
+    fn length(&self) -> usize {
+        RotateTokenResp::fixed_size()
+    }
+}
+
+impl fmt::Debug for RotateTokenResp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // the token itself is a secret, so is left out
+        write!(
+            f,
+            "RotateTokenResp {{ status_code: {}, token_id: {} }}",
+            self.status_code,
+            crate::shared::token_id_to_string(self.token_id)
+        )
+    }
+}
+
+/**
+Protocol message: client asking for the account's active tokens
+*/
+#[derive(Default)]
+pub struct ListTokensReq;
+
+impl ListTokensReq {
+    pub fn new() -> Self {
+        ListTokensReq
+    }
+}
+
+impl Message for ListTokensReq {
+    const MESSAGE_TYPE: MessageType = MessageType::ListTokensReq;
+
+    fn serialize(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    fn deserialize(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        ProtocolError::check_len(bytes, 0)?;
+        Ok(ListTokensReq)
+    }
+
+    fn length(&self) -> usize {
+        0
+    }
+}
+
+impl fmt::Debug for ListTokensReq {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ListTokensReq")
+    }
+}
+
+/**
+One of an account's active tokens, as listed by ListTokensResp
+*/
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct TokenInfo {
+    pub token_id: [u8; TOKEN_ID_LEN],
+
+    // when the token was issued, in ms since the unix epoch
+    pub issued_at: u64,
+
+    // whether it's the token the requesting session was verified with
+    pub current: bool,
+}
+
+impl TokenInfo {
+    const ENCODED_LEN: usize = TOKEN_ID_LEN + TIMESTAMP_LEN + TOKEN_FLAG_LEN;
+}
+
+/**
+Protocol message: server answering a ListTokensReq, oldest token first.
+
+On the wire: a token count, then for each token its id, issue time and a flag
+byte saying whether it is the session's current token.
+*/
+pub struct ListTokensResp {
+    tokens: Vec<TokenInfo>,
+}
+
+impl ListTokensResp {
+    pub fn new(tokens: Vec<TokenInfo>) -> Self {
+        ListTokensResp { tokens }
+    }
+
+    pub fn tokens(&self) -> &[TokenInfo] {
+        &self.tokens
+    }
+}
+
+impl Message for ListTokensResp {
+    const MESSAGE_TYPE: MessageType = MessageType::ListTokensResp;
+
+    fn serialize(&self) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        buffer.extend_from_slice(&(self.tokens.len() as u16).to_be_bytes());
+        for token in &self.tokens {
+            buffer.extend_from_slice(&token.token_id);
+            buffer.extend_from_slice(&token.issued_at.to_be_bytes());
+            buffer.push(token.current as u8);
+        }
+
+        buffer
+    }
+
+    fn deserialize(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        let mut decoder = Decoder::new(bytes);
+        let count = decoder.read_u16()? as usize;
+        ProtocolError::check_len(bytes, TOKEN_COUNT_LEN + count * TokenInfo::ENCODED_LEN)?;
+
+        let mut tokens = Vec::with_capacity(count);
+        for _ in 0..count {
+            tokens.push(TokenInfo {
+                token_id: decoder.read_array::<TOKEN_ID_LEN>()?,
+                issued_at: decoder.read_u64()?,
+                current: read_flag(&mut decoder, "current")?,
+            });
+        }
+        decoder.finish()?;
+
+        Ok (ListTokensResp {
+            tokens
+        })
+    }
+
+    fn length(&self) -> usize {
+        TOKEN_COUNT_LEN + self.tokens.len() * TokenInfo::ENCODED_LEN
+    }
+}
+
+impl fmt::Debug for ListTokensResp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let token_ids: Vec<String> = self.tokens
+            .iter()
+            .map(|token| crate::shared::token_id_to_string(token.token_id))
+            .collect();
+        write!(f, "ListTokensResp {{ tokens: [{}] }}", token_ids.join(", "))
+    }
+}
+
+/**
+Protocol message: client revoking one of the account's tokens
+*/
+pub struct RevokeTokenReq {
+    token_id: [u8; TOKEN_ID_LEN],
+}
+
+impl RevokeTokenReq {
+    pub fn new(token_id: [u8; TOKEN_ID_LEN]) -> Self {
+        RevokeTokenReq { token_id }
+    }
+
+    pub fn token_id(&self) -> [u8; TOKEN_ID_LEN] {
+        self.token_id
+    }
+}
+
+impl Message for RevokeTokenReq {
+    const MESSAGE_TYPE: MessageType = MessageType::RevokeTokenReq;
+
+    fn serialize(&self) -> Vec<u8> {
+        self.token_id.to_vec()
+    }
+
+    fn deserialize(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        ProtocolError::check_len(bytes, TOKEN_ID_LEN)?;
+
+        let mut decoder = Decoder::new(bytes);
+        let token_id = decoder.read_array::<TOKEN_ID_LEN>()?;
+
+        Ok (RevokeTokenReq {
+            token_id
+        })
+    }
+
+    fn length(&self) -> usize {
+        TOKEN_ID_LEN
+    }
+}
+
+impl fmt::Debug for RevokeTokenReq {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "RevokeTokenReq {{ token_id: {} }}", crate::shared::token_id_to_string(self.token_id))
+    }
+}
+
+/**
+Protocol message: server responding to a RevokeTokenReq (Failure if the account
+has no such token)
+*/
+pub struct RevokeTokenResp {
+    status_code: StatusCode,
+    token_id: [u8; TOKEN_ID_LEN],
+}
+
+impl RevokeTokenResp {
+    pub fn new(status_code: StatusCode, token_id: [u8; TOKEN_ID_LEN]) -> Self {
+        RevokeTokenResp { status_code, token_id }
+    }
+
+    pub fn status_code(&self) -> StatusCode {
+        self.status_code
+    }
+
+    pub fn token_id(&self) -> [u8; TOKEN_ID_LEN] {
+        self.token_id
+    }
+
+    fn fixed_size() -> usize {
+        ERR_CODE_LEN + TOKEN_ID_LEN
+    }
+}
+
+impl Message for RevokeTokenResp {
+    const MESSAGE_TYPE: MessageType = MessageType::RevokeTokenResp;
+
+    fn serialize(&self) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        buffer.push(self.status_code as u8);
+        buffer.extend_from_slice(&self.token_id);
+
+        buffer
+    }
+
+    fn deserialize(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        ProtocolError::check_len(bytes, RevokeTokenResp::fixed_size())?;
+
+        let mut decoder = Decoder::new(bytes);
+        let status_code = status_codes::decode_status_code(decoder.read_u8()?)?;
+        let token_id = decoder.read_array::<TOKEN_ID_LEN>()?;
+
+        Ok (RevokeTokenResp {
+            status_code,
+            token_id
+        })
+    }
+
+    fn length(&self) -> usize {
+        RevokeTokenResp::fixed_size()
+    }
+}
+
+impl fmt::Debug for RevokeTokenResp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "RevokeTokenResp {{ status_code: {}, token_id: {} }}",
+            self.status_code,
+            crate::shared::token_id_to_string(self.token_id)
+        )
+    }
+}
+
+fn read_flag(decoder: &mut Decoder, field: &'static str) -> Result<bool, ProtocolError> {
+    match decoder.read_u8()? {
+        0 => Ok(false),
+        1 => Ok(true),
+        flag => Err(ProtocolError::InvalidValue { field, value: flag }),
+    }
+}
@@ -0,0 +1,427 @@
+/*
+File transfer between two connections.
+
+The sender offers a file (FileOffer), the recipient accepts it (FileAccept),
+the sender then sends it FILE_CHUNK_LEN bytes at a time (FileChunk), and the
+recipient finishes the transfer (FileComplete) once it has checked the whole
+file against the SHA-256 digest it was offered, or to decline or give up on it.
+
+A FileAccept says which chunk to send next, so a transfer cut short (e.g. by
+either side reconnecting) can be resumed by accepting it again from the
+first chunk the recipient doesn't have.
+
+The file's name and digest, and every chunk, are sealed end-to-end as
+Envelopes (see envelope.rs); the server relays transfer messages between
+connections but only ever sees a file's size. Nothing is stored by the server,
+so both sides must be online for chunks to get through.
+*/
+
+use std::fmt;
+
+use crate::field_lens::{ TRANSFER_ID_LEN, FILE_SIZE_LEN, CHUNK_INDEX_LEN, ERR_CODE_LEN, FILE_CHUNK_LEN, MAX_FILE_LEN };
+use crate::errors::ProtocolError;
+use crate::decode::Decoder;
+use crate::message::Message;
+use crate::message_types::MessageType;
+use crate::status_codes::{ self, StatusCode };
+use crate::username::Username;
+
+/**
+Number of chunks a file of file_size bytes is sent in
+*/
+pub fn chunk_count(file_size: u64) -> u32 {
+    file_size.div_ceil(FILE_CHUNK_LEN as u64) as u32
+}
+
+/**
+Protocol message: sender offering recv_uname a file.
+
+sealed_info holds the file's name and digest, sealed for the recipient, and
+runs to the end of the message.
+*/
+pub struct FileOffer {
+    transfer_id: [u8; TRANSFER_ID_LEN],
+    send_uname: Username,
+    recv_uname: Username,
+    file_size: u64,
+    sealed_info: Vec<u8>,
+}
+
+impl FileOffer {
+    pub fn new(
+        transfer_id: [u8; TRANSFER_ID_LEN],
+        send_uname: &Username,
+        recv_uname: &Username,
+        file_size: u64,
+        sealed_info: Vec<u8>
+    ) -> Result<Self, ProtocolError> {
+        check_file_size(file_size)?;
+
+        Ok(FileOffer {
+            transfer_id,
+            send_uname: send_uname.clone(),
+            recv_uname: recv_uname.clone(),
+            file_size,
+            sealed_info,
+        })
+    }
+
+    pub fn transfer_id(&self) -> [u8; TRANSFER_ID_LEN] {
+        self.transfer_id
+    }
+
+    pub fn send_uname(&self) -> &Username {
+        &self.send_uname
+    }
+
+    pub fn recv_uname(&self) -> &Username {
+        &self.recv_uname
+    }
+
+    pub fn file_size(&self) -> u64 {
+        self.file_size
+    }
+
+    pub fn sealed_info(&self) -> &[u8] {
+        &self.sealed_info
+    }
+}
+
+impl Message for FileOffer {
+    const MESSAGE_TYPE: MessageType = MessageType::FileOffer;
+
+    fn serialize(&self) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        buffer.extend_from_slice(&self.transfer_id);
+        self.send_uname.encode(&mut buffer);
+        self.recv_uname.encode(&mut buffer);
+        buffer.extend_from_slice(&self.file_size.to_be_bytes());
+        buffer.extend_from_slice(&self.sealed_info);
+
+        buffer
+    }
+
+    fn deserialize(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        let mut decoder = Decoder::new(bytes);
+        let transfer_id = decoder.read_array::<TRANSFER_ID_LEN>()?;
+        let send_uname = decoder.read_username("send_uname")?;
+        let recv_uname = decoder.read_username("recv_uname")?;
+        let file_size = decoder.read_u64()?;
+        check_file_size(file_size)?;
+        let sealed_info = decoder.read_rest().to_vec();
+
+        Ok (FileOffer {
+            transfer_id,
+            send_uname,
+            recv_uname,
+            file_size,
+            sealed_info
+        })
+    }
+
+    fn length(&self) -> usize {
+        TRANSFER_ID_LEN
+            + self.send_uname.encoded_len()
+            + self.recv_uname.encoded_len()
+            + FILE_SIZE_LEN
+            + self.sealed_info.len()
+    }
+}
+
+impl fmt::Debug for FileOffer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "FileOffer {{ transfer_id: {}, send_uname: \"{}\", recv_uname: \"{}\", file_size: {} }}",
+            crate::shared::transfer_id_to_string(self.transfer_id),
+            self.send_uname,
+            self.recv_uname,
+            self.file_size
+        )
+    }
+}
+
+/**
+Protocol message: recipient accepting an offered file, from chunk next_chunk
+onwards (0 for a new transfer). send_uname/recv_uname are those of the offer.
+*/
+pub struct FileAccept {
+    transfer_id: [u8; TRANSFER_ID_LEN],
+    send_uname: Username,
+    recv_uname: Username,
+    next_chunk: u32,
+}
+
+impl FileAccept {
+    pub fn new(transfer_id: [u8; TRANSFER_ID_LEN], send_uname: &Username, recv_uname: &Username, next_chunk: u32) -> Self {
+        FileAccept {
+            transfer_id,
+            send_uname: send_uname.clone(),
+            recv_uname: recv_uname.clone(),
+            next_chunk,
+        }
+    }
+
+    pub fn transfer_id(&self) -> [u8; TRANSFER_ID_LEN] {
+        self.transfer_id
+    }
+
+    pub fn send_uname(&self) -> &Username {
+        &self.send_uname
+    }
+
+    pub fn recv_uname(&self) -> &Username {
+        &self.recv_uname
+    }
+
+    pub fn next_chunk(&self) -> u32 {
+        self.next_chunk
+    }
+}
+
+impl Message for FileAccept {
+    const MESSAGE_TYPE: MessageType = MessageType::FileAccept;
+
+    fn serialize(&self) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        buffer.extend_from_slice(&self.transfer_id);
+        self.send_uname.encode(&mut buffer);
+        self.recv_uname.encode(&mut buffer);
+        buffer.extend_from_slice(&self.next_chunk.to_be_bytes());
+
+        buffer
+    }
+
+    fn deserialize(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        let mut decoder = Decoder::new(bytes);
+        let transfer_id = decoder.read_array::<TRANSFER_ID_LEN>()?;
+        let send_uname = decoder.read_username("send_uname")?;
+        let recv_uname = decoder.read_username("recv_uname")?;
+        let next_chunk = decoder.read_u32()?;
+        decoder.finish()?;
+
+        Ok (FileAccept {
+            transfer_id,
+            send_uname,
+            recv_uname,
+            next_chunk
+        })
+    }
+
+    fn length(&self) -> usize {
+        TRANSFER_ID_LEN + self.send_uname.encoded_len() + self.recv_uname.encoded_len() + CHUNK_INDEX_LEN
+    }
+}
+
+impl fmt::Debug for FileAccept {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "FileAccept {{ transfer_id: {}, send_uname: \"{}\", recv_uname: \"{}\", next_chunk: {} }}",
+            crate::shared::transfer_id_to_string(self.transfer_id),
+            self.send_uname,
+            self.recv_uname,
+            self.next_chunk
+        )
+    }
+}
+
+/**
+Protocol message: one chunk of an accepted file, sealed for the recipient.
+
+Every chunk but the last holds FILE_CHUNK_LEN bytes of the file (before
+sealing); the sealed chunk runs to the end of the message.
+*/
+pub struct FileChunk {
+    transfer_id: [u8; TRANSFER_ID_LEN],
+    send_uname: Username,
+    recv_uname: Username,
+    index: u32,
+    data: Vec<u8>,
+}
+
+impl FileChunk {
+    pub fn new(
+        transfer_id: [u8; TRANSFER_ID_LEN],
+        send_uname: &Username,
+        recv_uname: &Username,
+        index: u32,
+        data: Vec<u8>
+    ) -> Self {
+        FileChunk {
+            transfer_id,
+            send_uname: send_uname.clone(),
+            recv_uname: recv_uname.clone(),
+            index,
+            data,
+        }
+    }
+
+    pub fn transfer_id(&self) -> [u8; TRANSFER_ID_LEN] {
+        self.transfer_id
+    }
+
+    pub fn send_uname(&self) -> &Username {
+        &self.send_uname
+    }
+
+    pub fn recv_uname(&self) -> &Username {
+        &self.recv_uname
+    }
+
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+impl Message for FileChunk {
+    const MESSAGE_TYPE: MessageType = MessageType::FileChunk;
+
+    fn serialize(&self) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        buffer.extend_from_slice(&self.transfer_id);
+        self.send_uname.encode(&mut buffer);
+        self.recv_uname.encode(&mut buffer);
+        buffer.extend_from_slice(&self.index.to_be_bytes());
+        buffer.extend_from_slice(&self.data);
+
+        buffer
+    }
+
+    fn deserialize(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        let mut decoder = Decoder::new(bytes);
+        let transfer_id = decoder.read_array::<TRANSFER_ID_LEN>()?;
+        let send_uname = decoder.read_username("send_uname")?;
+        let recv_uname = decoder.read_username("recv_uname")?;
+        let index = decoder.read_u32()?;
+        let data = decoder.read_rest().to_vec();
+
+        Ok (FileChunk {
+            transfer_id,
+            send_uname,
+            recv_uname,
+            index,
+            data
+        })
+    }
+
+    fn length(&self) -> usize {
+        TRANSFER_ID_LEN
+            + self.send_uname.encoded_len()
+            + self.recv_uname.encoded_len()
+            + CHUNK_INDEX_LEN
+            + self.data.len()
+    }
+}
+
+impl fmt::Debug for FileChunk {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "FileChunk {{ transfer_id: {}, send_uname: \"{}\", recv_uname: \"{}\", index: {}, data: {} bytes }}",
+            crate::shared::transfer_id_to_string(self.transfer_id),
+            self.send_uname,
+            self.recv_uname,
+            self.index,
+            self.data.len()
+        )
+    }
+}
+
+/**
+Protocol message: recipient ending a transfer, with Success once the whole
+file has arrived and matches its digest, or Failure if they declined it or it
+arrived corrupted. send_uname/recv_uname are those of the offer.
+*/
+pub struct FileComplete {
+    transfer_id: [u8; TRANSFER_ID_LEN],
+    send_uname: Username,
+    recv_uname: Username,
+    status_code: StatusCode,
+}
+
+impl FileComplete {
+    pub fn new(transfer_id: [u8; TRANSFER_ID_LEN], send_uname: &Username, recv_uname: &Username, status_code: StatusCode) -> Self {
+        FileComplete {
+            transfer_id,
+            send_uname: send_uname.clone(),
+            recv_uname: recv_uname.clone(),
+            status_code,
+        }
+    }
+
+    pub fn transfer_id(&self) -> [u8; TRANSFER_ID_LEN] {
+        self.transfer_id
+    }
+
+    pub fn send_uname(&self) -> &Username {
+        &self.send_uname
+    }
+
+    pub fn recv_uname(&self) -> &Username {
+        &self.recv_uname
+    }
+
+    pub fn status_code(&self) -> StatusCode {
+        self.status_code
+    }
+}
+
+impl Message for FileComplete {
+    const MESSAGE_TYPE: MessageType = MessageType::FileComplete;
+
+    fn serialize(&self) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        buffer.extend_from_slice(&self.transfer_id);
+        self.send_uname.encode(&mut buffer);
+        self.recv_uname.encode(&mut buffer);
+        buffer.push(self.status_code as u8);
+
+        buffer
+    }
+
+    fn deserialize(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        let mut decoder = Decoder::new(bytes);
+        let transfer_id = decoder.read_array::<TRANSFER_ID_LEN>()?;
+        let send_uname = decoder.read_username("send_uname")?;
+        let recv_uname = decoder.read_username("recv_uname")?;
+        let status_code = status_codes::decode_status_code(decoder.read_u8()?)?;
+        decoder.finish()?;
+
+        Ok (FileComplete {
+            transfer_id,
+            send_uname,
+            recv_uname,
+            status_code
+        })
+    }
+
+    fn length(&self) -> usize {
+        TRANSFER_ID_LEN + self.send_uname.encoded_len() + self.recv_uname.encoded_len() + ERR_CODE_LEN
+    }
+}
+
+impl fmt::Debug for FileComplete {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "FileComplete {{ transfer_id: {}, send_uname: \"{}\", recv_uname: \"{}\", status_code: {} }}",
+            crate::shared::transfer_id_to_string(self.transfer_id),
+            self.send_uname,
+            self.recv_uname,
+            self.status_code
+        )
+    }
+}
+
+fn check_file_size(file_size: u64) -> Result<(), ProtocolError> {
+    if file_size > MAX_FILE_LEN as u64 {
+        return Err(ProtocolError::FileTooLarge { file_len: file_size, max_file_len: MAX_FILE_LEN as u64 });
+    }
+
+    Ok(())
+}
@@ -0,0 +1,125 @@
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use rustls::pki_types::ServerName;
+use rustls::{ClientConfig, ClientConnection, ServerConfig, ServerConnection, StreamOwned};
+
+use crate::tls::TlsError;
+
+type TlsClientStream = StreamOwned<ClientConnection, TcpStream>;
+type TlsServerStream = StreamOwned<ServerConnection, TcpStream>;
+
+/**
+A connection to a peer, either plain TCP or TCP wrapped in TLS (see tls.rs).
+
+Like a TcpStream, a Stream can be cloned into separate handles for a
+PacketReader and a PacketWriter. The TLS state is shared between the handles,
+so they should be used from the same thread (as both the client and the
+server's sessions do).
+*/
+pub enum Stream {
+    Plain(TcpStream),
+    TlsClient(Arc<Mutex<TlsClientStream>>),
+    TlsServer(Arc<Mutex<TlsServerStream>>),
+}
+
+impl Stream {
+    pub fn plain(tcp: TcpStream) -> Self {
+        Stream::Plain(tcp)
+    }
+
+    /**
+    Client side: runs the TLS handshake over tcp, checking the server's
+    certificate against config (see tls::client_config_*)
+    */
+    pub fn tls_client(mut tcp: TcpStream, config: Arc<ClientConfig>, server_name: &str) -> Result<Self, TlsError> {
+        let server_name = ServerName::try_from(server_name.to_string())
+            .map_err(|_| TlsError::InvalidServerName(server_name.to_string()))?;
+        let mut conn = ClientConnection::new(config, server_name)?;
+        while conn.is_handshaking() {
+            conn.complete_io(&mut tcp)?;
+        }
+
+        Ok(Stream::TlsClient(Arc::new(Mutex::new(StreamOwned::new(conn, tcp)))))
+    }
+
+    /**
+    Server side: runs the TLS handshake over tcp, presenting the certificate in
+    config (see tls::server_config). Blocks until the handshake is done, so the
+    caller should set a read timeout on tcp first.
+    */
+    pub fn tls_server(mut tcp: TcpStream, config: Arc<ServerConfig>) -> Result<Self, TlsError> {
+        let mut conn = ServerConnection::new(config)?;
+        while conn.is_handshaking() {
+            conn.complete_io(&mut tcp)?;
+        }
+
+        Ok(Stream::TlsServer(Arc::new(Mutex::new(StreamOwned::new(conn, tcp)))))
+    }
+
+    /**
+    Another handle to the same connection
+    */
+    pub fn try_clone(&self) -> io::Result<Self> {
+        match self {
+            Stream::Plain(tcp) => Ok(Stream::Plain(tcp.try_clone()?)),
+            Stream::TlsClient(tls) => Ok(Stream::TlsClient(Arc::clone(tls))),
+            Stream::TlsServer(tls) => Ok(Stream::TlsServer(Arc::clone(tls))),
+        }
+    }
+
+    pub fn is_tls(&self) -> bool {
+        !matches!(self, Stream::Plain(_))
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Plain(tcp) => tcp.set_read_timeout(timeout),
+            Stream::TlsClient(tls) => lock(tls)?.sock.set_read_timeout(timeout),
+            Stream::TlsServer(tls) => lock(tls)?.sock.set_read_timeout(timeout),
+        }
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            Stream::Plain(tcp) => tcp.peer_addr(),
+            Stream::TlsClient(tls) => lock(tls)?.sock.peer_addr(),
+            Stream::TlsServer(tls) => lock(tls)?.sock.peer_addr(),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(tcp) => tcp.read(buf),
+            Stream::TlsClient(tls) => lock(tls)?.read(buf),
+            Stream::TlsServer(tls) => lock(tls)?.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(tcp) => tcp.write(buf),
+            Stream::TlsClient(tls) => lock(tls)?.write(buf),
+            Stream::TlsServer(tls) => lock(tls)?.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Plain(tcp) => tcp.flush(),
+            Stream::TlsClient(tls) => lock(tls)?.flush(),
+            Stream::TlsServer(tls) => lock(tls)?.flush(),
+        }
+    }
+}
+
+// A handle only panics while holding the lock if the connection is already broken
+fn lock<T>(tls: &Mutex<T>) -> io::Result<MutexGuard<'_, T>> {
+    tls.lock().map_err(|_| io::Error::other("TLS stream lock poisoned"))
+}
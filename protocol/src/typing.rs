@@ -0,0 +1,92 @@
+use std::fmt;
+
+use crate::field_lens::TYPING_FLAG_LEN;
+use crate::errors::ProtocolError;
+use crate::decode::Decoder;
+use crate::message::Message;
+use crate::message_types::MessageType;
+use crate::username::Username;
+
+/**
+Protocol message: sender has started (typing set) or stopped typing a message
+to the recipient.
+
+Ephemeral: the server relays it to the recipient if they're online, and
+otherwise drops it, never storing it. Clients re-send it every so often while
+the user keeps typing, so the recipient can let a "typing" state expire if a
+"stopped" never arrives.
+*/
+pub struct Typing {
+    send_uname: Username,
+    recv_uname: Username,
+    typing: bool,
+}
+
+impl Typing {
+    pub fn new(send_uname: &Username, recv_uname: &Username, typing: bool) -> Self {
+        Typing {
+            send_uname: send_uname.clone(),
+            recv_uname: recv_uname.clone(),
+            typing,
+        }
+    }
+
+    pub fn send_uname(&self) -> &Username {
+        &self.send_uname
+    }
+
+    pub fn recv_uname(&self) -> &Username {
+        &self.recv_uname
+    }
+
+    pub fn typing(&self) -> bool {
+        self.typing
+    }
+}
+
+impl Message for Typing {
+    const MESSAGE_TYPE: MessageType = MessageType::Typing;
+
+    fn serialize(&self) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        self.send_uname.encode(&mut buffer);
+        self.recv_uname.encode(&mut buffer);
+        buffer.push(self.typing as u8);
+
+        buffer
+    }
+
+    fn deserialize(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        let mut decoder = Decoder::new(bytes);
+        let send_uname = decoder.read_username("send_uname")?;
+        let recv_uname = decoder.read_username("recv_uname")?;
+        let typing = match decoder.read_u8()? {
+            0 => false,
+            1 => true,
+            flag => return Err(ProtocolError::InvalidValue { field: "typing", value: flag }),
+        };
+        decoder.finish()?;
+
+        Ok (Typing {
+            send_uname,
+            recv_uname,
+            typing
+        })
+    }
+
+    fn length(&self) -> usize {
+        self.send_uname.encoded_len() + self.recv_uname.encoded_len() + TYPING_FLAG_LEN
+    }
+}
+
+impl fmt::Debug for Typing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Typing {{ send_uname: \"{}\", recv_uname: \"{}\", typing: {} }}",
+            self.send_uname,
+            self.recv_uname,
+            self.typing
+        )
+    }
+}
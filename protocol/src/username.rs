@@ -0,0 +1,137 @@
+use std::fmt;
+use std::str::FromStr;
+
+use crate::field_lens::{ MIN_UNAME_LEN, MAX_UNAME_LEN, UNAME_PREFIX_LEN };
+
+/**
+A validated cli-chat username.
+
+Usernames are MIN_UNAME_LEN..=MAX_UNAME_LEN characters from [A-Za-z0-9_.-],
+starting with a letter or digit. This keeps them safe to use in file names
+(no '/', '\', NUL, '.' or '..') and to show in the UI.
+
+On the wire a username is a one-byte length followed by that many bytes of name.
+*/
+#[derive(Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Username(String);
+
+/**
+Why a string isn't a valid username
+*/
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum UsernameError {
+    TooShort { len: usize },
+    TooLong { len: usize },
+    InvalidChar(char),
+    // first character must be a letter or digit
+    InvalidStart(char),
+}
+
+impl Username {
+    pub fn new(uname: &str) -> Result<Self, UsernameError> {
+        let len = uname.chars().count();
+        if len < MIN_UNAME_LEN {
+            return Err(UsernameError::TooShort { len });
+        }
+        if len > MAX_UNAME_LEN {
+            return Err(UsernameError::TooLong { len });
+        }
+
+        if let Some(c) = uname.chars().find(|&c| !is_uname_char(c)) {
+            return Err(UsernameError::InvalidChar(c));
+        }
+        if let Some(c) = uname.chars().next().filter(|c| !c.is_ascii_alphanumeric()) {
+            return Err(UsernameError::InvalidStart(c));
+        }
+
+        Ok(Username(uname.to_string()))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /**
+    Appends the wire encoding of the username (length prefix + name) to buffer
+    */
+    pub fn encode(&self, buffer: &mut Vec<u8>) {
+        buffer.push(self.0.len() as u8);
+        buffer.extend_from_slice(self.0.as_bytes());
+    }
+
+    // length of the wire encoding, in bytes
+    pub fn encoded_len(&self) -> usize {
+        UNAME_PREFIX_LEN + self.0.len()
+    }
+}
+
+fn is_uname_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '-'
+}
+
+impl FromStr for Username {
+    type Err = UsernameError;
+
+    fn from_str(uname: &str) -> Result<Self, Self::Err> {
+        Username::new(uname)
+    }
+}
+
+impl TryFrom<&str> for Username {
+    type Error = UsernameError;
+
+    fn try_from(uname: &str) -> Result<Self, Self::Error> {
+        Username::new(uname)
+    }
+}
+
+impl AsRef<str> for Username {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl PartialEq<str> for Username {
+    fn eq(&self, other: &str) -> bool {
+        self.0 == other
+    }
+}
+
+impl PartialEq<&str> for Username {
+    fn eq(&self, other: &&str) -> bool {
+        self.0 == *other
+    }
+}
+
+impl fmt::Display for Username {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl fmt::Debug for Username {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "\"{}\"", self.0)
+    }
+}
+
+impl fmt::Display for UsernameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UsernameError::TooShort { len } => {
+                write!(f, "username must be at least {} characters, got {}", MIN_UNAME_LEN, len)
+            }
+            UsernameError::TooLong { len } => {
+                write!(f, "username can be at most {} characters, got {}", MAX_UNAME_LEN, len)
+            }
+            UsernameError::InvalidChar(c) => {
+                write!(f, "username contains {:?}, only letters, digits, '_', '.' and '-' are allowed", c)
+            }
+            UsernameError::InvalidStart(c) => {
+                write!(f, "username must start with a letter or digit, not {:?}", c)
+            }
+        }
+    }
+}
+
+impl std::error::Error for UsernameError {}
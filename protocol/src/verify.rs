@@ -1,68 +1,56 @@
-use crate::field_lens::{ UNAME_LEN, TOKEN_LEN, ERR_CODE_LEN};
+use crate::field_lens::{ PROOF_LEN, ERR_CODE_LEN};
 use crate::status_codes::{self, StatusCode};
 use std::fmt;
-use crate::errors::LengthError;
-use std::error::Error;
+use crate::errors::ProtocolError;
+use crate::decode::Decoder;
+use crate::message::Message;
+use crate::message_types::MessageType;
+use crate::username::Username;
 
 /**
-Protocol message: client verifying itself upon connecting with server
+Protocol message: client verifying itself upon connecting with server, by
+answering the server's AuthChallenge with a proof that it holds the PAT token
+issued to cli_uname at signup (see auth.rs)
 */
 pub struct VerifyReq {
-    pub cli_uname: [u8; UNAME_LEN],
-    pub token: [u8; TOKEN_LEN],
+    pub cli_uname: Username,
+    pub proof: [u8; PROOF_LEN],
 }
 
 impl VerifyReq {
-    pub fn empty() -> Self {
+    pub fn new(cli_uname: &Username, proof: [u8; PROOF_LEN]) -> Self {
         VerifyReq {
-            cli_uname: [0u8; UNAME_LEN],
-            token: [0u8; TOKEN_LEN]
+            cli_uname: cli_uname.clone(),
+            proof
         }
     }
+}
 
-    pub fn new(c_uname: &str, token: [u8; TOKEN_LEN]) -> Self {
-        let mut verify = VerifyReq::empty();
-        crate::shared::set_uname(&mut verify.cli_uname, c_uname);
-        if token.len() != TOKEN_LEN {
-            println!("Tokens must be 32 bytes");
-            return verify;
-        }
-        verify.token.copy_from_slice(&token[..TOKEN_LEN]);
-
-        verify
-    }
+impl Message for VerifyReq {
+    const MESSAGE_TYPE: MessageType = MessageType::VerifyReq;
 
-    pub fn serialize(&self) -> Vec<u8> {
+    fn serialize(&self) -> Vec<u8> {
         let mut buffer = Vec::new();
-        buffer.extend_from_slice(&self.cli_uname);
-        buffer.extend_from_slice(&self.token);
+        self.cli_uname.encode(&mut buffer);
+        buffer.extend_from_slice(&self.proof);
 
         buffer
     }
 
-    pub fn deserialize(bytes: &[u8]) -> Result<Self, Box<dyn Error>> {
-        println!("bytes len: {:?}, fixed size: {:?}", bytes.len(), VerifyReq::fixed_size());
-        if bytes.len() != VerifyReq::fixed_size() {
-            return Err(Box::new(LengthError));
-        }
-
-        let mut cli_uname = [0u8; UNAME_LEN];
-        let mut token = [0u8; TOKEN_LEN];
-        cli_uname.copy_from_slice(&bytes[..UNAME_LEN]);
-        token.copy_from_slice(&bytes[UNAME_LEN .. VerifyReq::fixed_size()]);
+    fn deserialize(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        let mut decoder = Decoder::new(bytes);
+        let cli_uname = decoder.read_username("cli_uname")?;
+        let proof = decoder.read_array::<PROOF_LEN>()?;
+        decoder.finish()?;
 
         Ok (VerifyReq {
             cli_uname,
-            token
+            proof
         })
     }
 
-    pub fn length(&self) -> usize {
-        VerifyReq::fixed_size()
-    }
-
-    fn fixed_size() -> usize {
-        UNAME_LEN + TOKEN_LEN
+    fn length(&self) -> usize {
+        self.cli_uname.encoded_len() + PROOF_LEN
     }
 }
 
@@ -70,9 +58,9 @@ impl fmt::Debug for VerifyReq {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
             f,
-            "VerifyReq {{ cli_uname: {}, token: {} }}",
-            crate::shared::uname_to_string(self.cli_uname),
-            crate::shared::token_to_string(self.token)
+            "VerifyReq {{ cli_uname: {}, proof: 0x{} }}",
+            self.cli_uname,
+            self.proof.iter().map(|byte| format!("{:02x}", byte)).collect::<String>()
         )
     }
 }
@@ -91,32 +79,32 @@ impl VerifyResp {
         }
     }
 
-    pub fn serialize(&self) -> Vec<u8> {
-        let mut buffer = Vec::new();
-        buffer.push(self.status_code.clone() as u8);
+    fn fixed_size() -> usize {
+        ERR_CODE_LEN
+    }
+}
 
-        buffer
+impl Message for VerifyResp {
+    const MESSAGE_TYPE: MessageType = MessageType::VerifyResp;
+
+    fn serialize(&self) -> Vec<u8> {
+        vec![self.status_code as u8]
     }
 
-    pub fn deserialize(bytes: &[u8]) -> Result<Self, Box<dyn Error>> {
-        if bytes.len() != VerifyResp::fixed_size() {
-            return Err(Box::new(LengthError));
-        }
+    fn deserialize(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        ProtocolError::check_len(bytes, VerifyResp::fixed_size())?;
 
-        let status_code = status_codes::decode_status_code(bytes[0]);
+        let mut decoder = Decoder::new(bytes);
+        let status_code = status_codes::decode_status_code(decoder.read_u8()?)?;
 
         Ok (VerifyResp {
             status_code
         })
     }
 
-    pub fn length(&self) -> usize {
+    fn length(&self) -> usize {
         VerifyResp::fixed_size()
     }
-
-    fn fixed_size() -> usize {
-        ERR_CODE_LEN
-    }
 }
 
 impl fmt::Debug for VerifyResp {
@@ -124,7 +112,7 @@ impl fmt::Debug for VerifyResp {
         write!(
             f,
             "VerifyResp {{ status_code: {} }}",
-            self.status_code.to_string()
+            self.status_code
         )
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,90 @@
+use protocol::{AuthChallenge, IdentityKeyPair, Message, Packet, ProtocolError, ProtocolMessage, Username};
+use protocol::auth;
+use protocol::shared;
+
+fn uname(uname: &str) -> Username {
+    Username::new(uname).unwrap()
+}
+
+#[test]
+fn proof_checks_against_verifier_from_same_token() {
+    let token = shared::generate_token();
+    let challenge = *AuthChallenge::new().challenge();
+    let verifier = auth::derive_verifier(&token);
+
+    let proof = auth::prove(&token, &challenge, &uname("Harry"));
+    assert!(auth::check_proof(&verifier, &challenge, &uname("Harry"), &proof));
+}
+
+#[test]
+fn proof_fails_with_wrong_token_challenge_or_uname() {
+    let token = shared::generate_token();
+    let challenge = *AuthChallenge::new().challenge();
+    let verifier = auth::derive_verifier(&token);
+
+    let wrong_token = auth::prove(&shared::generate_token(), &challenge, &uname("Harry"));
+    assert!(!auth::check_proof(&verifier, &challenge, &uname("Harry"), &wrong_token));
+
+    // an answer to an old challenge can't be replayed against a new one
+    let proof = auth::prove(&token, &challenge, &uname("Harry"));
+    let new_challenge = *AuthChallenge::new().challenge();
+    assert!(!auth::check_proof(&verifier, &new_challenge, &uname("Harry"), &proof));
+
+    assert!(!auth::check_proof(&verifier, &challenge, &uname("Eddie"), &proof));
+}
+
+#[test]
+fn verifier_does_not_reveal_token() {
+    let token = shared::generate_token();
+    assert_ne!(auth::derive_verifier(&token), token);
+    assert_eq!(auth::derive_verifier(&token), auth::derive_verifier(&token));
+}
+
+#[test]
+fn verifier_alone_cannot_answer_a_challenge() {
+    let token = shared::generate_token();
+    let challenge = *AuthChallenge::new().challenge();
+    let verifier = auth::derive_verifier(&token);
+
+    // e.g. someone who has read the server's stored verifiers, using one in
+    // place of the token...
+    let proof = auth::prove(&verifier, &challenge, &uname("Harry"));
+    assert!(!auth::check_proof(&verifier, &challenge, &uname("Harry"), &proof));
+
+    // ...or as the secret half of a signing key
+    let mut signed = b"cli-chat verify v2".to_vec();
+    signed.extend_from_slice(&challenge);
+    uname("Harry").encode(&mut signed);
+    let proof = IdentityKeyPair::from_secret_bytes(verifier).sign(&signed);
+    assert!(!auth::check_proof(&verifier, &challenge, &uname("Harry"), &proof));
+}
+
+#[test]
+fn challenges_are_random() {
+    assert_ne!(AuthChallenge::new().challenge(), AuthChallenge::new().challenge());
+}
+
+#[test]
+fn auth_challenge_round_trip() {
+    let auth_challenge = AuthChallenge::new();
+    let packet = Packet::wrap(&auth_challenge);
+    match Packet::deserialize(&packet.serialize()).unwrap().decode().unwrap() {
+        ProtocolMessage::AuthChallenge(decoded) => assert_eq!(decoded.challenge(), auth_challenge.challenge()),
+        other => panic!("decoded as {:?}", other),
+    }
+}
+
+#[test]
+fn auth_challenge_rejects_wrong_length() {
+    let mut bytes = AuthChallenge::new().serialize();
+    assert!(matches!(
+        AuthChallenge::deserialize(&bytes[..bytes.len() - 1]),
+        Err(ProtocolError::Truncated { expected: 32, actual: 31 })
+    ));
+
+    bytes.push(0);
+    assert!(matches!(
+        AuthChallenge::deserialize(&bytes),
+        Err(ProtocolError::LengthMismatch { expected: 32, actual: 33 })
+    ));
+}
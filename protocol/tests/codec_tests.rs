@@ -0,0 +1,173 @@
+use std::io::{self, Cursor, Read};
+
+use protocol::{AuthChallenge, Packet, PacketReader, PacketWriter, ProtocolError, Username, VerifyReq};
+use protocol::message_types::MessageType;
+use protocol::{auth, shared};
+
+// Hands back at most one byte per read, like a very slow connection
+struct OneByteReader {
+    inner: Cursor<Vec<u8>>,
+}
+
+impl Read for OneByteReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = buf.len().min(1);
+        self.inner.read(&mut buf[..len])
+    }
+}
+
+fn verify_packet(uname: &str) -> Packet {
+    let uname = Username::new(uname).unwrap();
+    let proof = auth::prove(&shared::generate_token(), AuthChallenge::new().challenge(), &uname);
+    Packet::wrap(&VerifyReq::new(&uname, proof))
+}
+
+fn assert_same_packet(actual: &Packet, expected: &Packet) {
+    assert_eq!(actual.method, expected.method);
+    assert_eq!(actual.msg_length, expected.msg_length);
+    assert_eq!(actual.msg_buffer, expected.msg_buffer);
+}
+
+#[test]
+fn reads_packet_fed_byte_at_a_time() {
+    let packet = verify_packet("Harry");
+    let mut reader = PacketReader::new(OneByteReader {
+        inner: Cursor::new(packet.serialize()),
+    });
+
+    let read = reader.read_packet().unwrap();
+    assert_same_packet(&read, &packet);
+    assert_eq!(reader.buffered_len(), 0);
+}
+
+#[test]
+fn reads_coalesced_packets_in_order() {
+    let first = verify_packet("Harry");
+    let second = verify_packet("Eddie");
+    let empty = Packet::new(MessageType::ChatMessage as u8, 0, Vec::new());
+
+    let mut bytes = first.serialize();
+    bytes.extend_from_slice(&second.serialize());
+    bytes.extend_from_slice(&empty.serialize());
+
+    let mut reader = PacketReader::new(Cursor::new(bytes));
+    assert_same_packet(&reader.read_packet().unwrap(), &first);
+    assert_same_packet(&reader.read_packet().unwrap(), &second);
+    assert_same_packet(&reader.read_packet().unwrap(), &empty);
+
+    let err = reader.read_packet().unwrap_err();
+    assert_eq!(err.io_kind(), Some(io::ErrorKind::UnexpectedEof));
+}
+
+#[test]
+fn keeps_leftover_bytes_of_next_packet() {
+    let first = verify_packet("Harry");
+    let second = verify_packet("Eddie");
+
+    let mut bytes = first.serialize();
+    let second_bytes = second.serialize();
+    bytes.extend_from_slice(&second_bytes[..3]);
+
+    let mut reader = PacketReader::new(Cursor::new(bytes));
+    assert_same_packet(&reader.read_packet().unwrap(), &first);
+    assert_eq!(reader.buffered_len(), 3);
+
+    // rest of the second packet arrives later
+    let remaining = second_bytes[3..].to_vec();
+    *reader.get_mut() = Cursor::new(remaining);
+    assert_same_packet(&reader.read_packet().unwrap(), &second);
+}
+
+#[test]
+fn rejects_packet_over_default_max_len() {
+    let header = Packet::new(MessageType::ChatMessage as u8, 2048, Vec::new()).serialize();
+    let mut reader = PacketReader::new(Cursor::new(header));
+
+    match reader.read_packet().unwrap_err() {
+        ProtocolError::PacketTooLarge { packet_len, max_packet_len } => {
+            assert_eq!(packet_len, Packet::fixed_size() + 2048);
+            assert_eq!(max_packet_len, protocol::field_lens::MAX_PACKET_LEN);
+        }
+        other => panic!("unexpected error: {:?}", other),
+    }
+}
+
+#[test]
+fn enforces_configured_max_len() {
+    let packet = Packet::new(MessageType::ChatMessage as u8, 16, vec![7u8; 16]);
+    let max_len = Packet::fixed_size() + 8;
+
+    let mut reader = PacketReader::with_max_len(Cursor::new(packet.serialize()), max_len);
+    assert!(matches!(reader.read_packet().unwrap_err(), ProtocolError::PacketTooLarge { .. }));
+
+    let mut writer = PacketWriter::with_max_len(Vec::new(), max_len);
+    assert!(matches!(writer.write_packet(&packet).unwrap_err(), ProtocolError::PacketTooLarge { .. }));
+    assert!(writer.get_ref().is_empty());
+}
+
+#[test]
+fn skips_rejected_packet_and_reads_the_next() {
+    let oversize = Packet::new(MessageType::ChatMessage as u8, 2048, vec![7u8; 2048]);
+    let next = verify_packet("Harry");
+    let mut bytes = oversize.serialize();
+    bytes.extend_from_slice(&next.serialize());
+
+    for mut reader in [
+        PacketReader::new(Box::new(Cursor::new(bytes.clone())) as Box<dyn Read>),
+        PacketReader::new(Box::new(OneByteReader { inner: Cursor::new(bytes) }) as Box<dyn Read>),
+    ] {
+        assert!(matches!(reader.read_packet().unwrap_err(), ProtocolError::PacketTooLarge { .. }));
+        assert_same_packet(&reader.read_packet().unwrap(), &next);
+        assert_eq!(reader.buffered_len(), 0);
+    }
+}
+
+#[test]
+fn reports_stream_closed_mid_packet() {
+    let bytes = verify_packet("Harry").serialize();
+    let mut reader = PacketReader::new(Cursor::new(bytes[..bytes.len() - 1].to_vec()));
+
+    let err = reader.read_packet().unwrap_err();
+    assert_eq!(err.io_kind(), Some(io::ErrorKind::UnexpectedEof));
+}
+
+#[test]
+fn writer_output_round_trips_through_reader() {
+    let packets = vec![verify_packet("Harry"), verify_packet("Kerry")];
+
+    let mut writer = PacketWriter::new(Vec::new());
+    for packet in &packets {
+        writer.write_packet(packet).unwrap();
+    }
+
+    let mut reader = PacketReader::new(Cursor::new(writer.get_ref().clone()));
+    for packet in &packets {
+        assert_same_packet(&reader.read_packet().unwrap(), packet);
+    }
+}
+
+#[test]
+fn writer_rejects_mismatched_msg_length() {
+    let packet = Packet::new(MessageType::ChatMessage as u8, 10, vec![0u8; 4]);
+    let mut writer = PacketWriter::new(Vec::new());
+
+    match writer.write_packet(&packet).unwrap_err() {
+        ProtocolError::LengthMismatch { expected, actual } => {
+            assert_eq!(expected, 10);
+            assert_eq!(actual, 4);
+        }
+        other => panic!("unexpected error: {:?}", other),
+    }
+}
+
+#[test]
+fn read_packet_consumes_exactly_one_packet() {
+    let first = verify_packet("Harry");
+    let second = verify_packet("Eddie");
+    let mut bytes = first.serialize();
+    bytes.extend_from_slice(&second.serialize());
+
+    let mut stream = Cursor::new(bytes);
+    assert_same_packet(&protocol::read_packet(&mut stream).unwrap(), &first);
+    assert_same_packet(&protocol::read_packet(&mut stream).unwrap(), &second);
+}
@@ -0,0 +1,93 @@
+use protocol::{Message, Packet, ProtocolError, ProtocolMessage, Username, ChatMessage, IdentityKeyPair};
+use protocol::{MessageEdit, MessageDelete};
+use protocol::{Capabilities, Hello, NegotiatedSession};
+use protocol::message_types::MessageType;
+
+fn uname(uname: &str) -> Username {
+    Username::new(uname).unwrap()
+}
+
+fn round_trip<M: Message>(message: &M) -> ProtocolMessage {
+    let packet = Packet::wrap(message);
+    assert_eq!(packet.msg_length as usize, message.length());
+
+    Packet::deserialize(&packet.serialize()).unwrap().decode().unwrap()
+}
+
+#[test]
+fn edit_round_trip_and_signature() {
+    let identity = IdentityKeyPair::generate();
+    let mut message_edit = MessageEdit::new([7u8; 16], &uname("Harry"), &uname("Eddie"), "fixed the typo");
+    message_edit.sign(&identity);
+
+    match round_trip(&message_edit) {
+        ProtocolMessage::MessageEdit(decoded) => {
+            assert_eq!(decoded.message_id(), [7u8; 16]);
+            assert_eq!(decoded.timestamp(), message_edit.timestamp());
+            assert_eq!(decoded.send_uname(), &uname("Harry"));
+            assert_eq!(decoded.recv_uname(), &uname("Eddie"));
+            assert_eq!(decoded.body(), b"fixed the typo");
+            assert!(decoded.verify_signature(&identity.identity_key()));
+        }
+        other => panic!("decoded as {:?}", other),
+    }
+
+    // the signature covers the new body
+    let mut changed = message_edit.clone();
+    changed.set_body(b"something else".to_vec());
+    assert!(!changed.verify_signature(&identity.identity_key()));
+}
+
+#[test]
+fn delete_round_trip_and_signature() {
+    let identity = IdentityKeyPair::generate();
+    let mut message_delete = MessageDelete::new([7u8; 16], &uname("Harry"), &uname("Eddie"));
+    message_delete.sign(&identity);
+
+    match round_trip(&message_delete) {
+        ProtocolMessage::MessageDelete(decoded) => {
+            assert_eq!(decoded.message_id(), [7u8; 16]);
+            assert_eq!(decoded.timestamp(), message_delete.timestamp());
+            assert_eq!(decoded.send_uname(), &uname("Harry"));
+            assert_eq!(decoded.recv_uname(), &uname("Eddie"));
+            assert!(decoded.verify_signature(&identity.identity_key()));
+            assert!(!decoded.verify_signature(&IdentityKeyPair::generate().identity_key()));
+        }
+        other => panic!("decoded as {:?}", other),
+    }
+}
+
+#[test]
+fn edit_signature_doesnt_pass_as_chat_message() {
+    let identity = IdentityKeyPair::generate();
+    let mut message_edit = MessageEdit::new([7u8; 16], &uname("Harry"), &uname("Eddie"), "hello");
+    message_edit.sign(&identity);
+
+    // a chat message with the same fields, carrying the edit's signature
+    let mut chat_message = ChatMessage::new(&uname("Harry"), &uname("Eddie"), "hello");
+    chat_message.message_id = message_edit.message_id();
+    chat_message.timestamp = message_edit.timestamp();
+    chat_message.signature.copy_from_slice(&message_edit.serialize()[24..88]);
+    assert!(!chat_message.verify_signature(&identity.identity_key()));
+}
+
+#[test]
+fn trailing_bytes_after_delete_are_rejected() {
+    let mut bytes = MessageDelete::new([7u8; 16], &uname("Harry"), &uname("Eddie")).serialize();
+    bytes.push(0);
+    assert!(matches!(MessageDelete::deserialize(&bytes), Err(ProtocolError::LengthMismatch { .. })));
+
+    assert!(matches!(MessageEdit::deserialize(&bytes[..20]), Err(ProtocolError::Truncated { .. })));
+}
+
+#[test]
+fn edits_need_negotiating() {
+    let hello = Hello::new(Capabilities::EDITS);
+    let negotiated = NegotiatedSession::negotiate(&hello, Capabilities::EDITS).unwrap();
+    assert!(negotiated.allows(MessageType::MessageEdit));
+    assert!(negotiated.allows(MessageType::MessageDelete));
+
+    let negotiated = NegotiatedSession::negotiate(&hello, Capabilities::NONE).unwrap();
+    assert!(!negotiated.allows(MessageType::MessageEdit));
+    assert!(!negotiated.allows(MessageType::MessageDelete));
+}
@@ -0,0 +1,44 @@
+use protocol::{Envelope, ProtocolError};
+use protocol::envelope::ENVELOPE_VERSION;
+
+const NONCE: [u8; 24] = [9u8; 24];
+
+#[test]
+fn envelope_round_trip() {
+    let envelope = Envelope::new(0xdeadbeef, NONCE, vec![1u8; 40]);
+    let bytes = envelope.serialize();
+    assert_eq!(bytes.len(), envelope.length());
+    assert_eq!(bytes[0], ENVELOPE_VERSION);
+    assert_eq!(&bytes[..envelope.header().len()], envelope.header().as_slice());
+
+    let decoded = Envelope::deserialize(&bytes).unwrap();
+    assert_eq!(decoded, envelope);
+    assert_eq!(decoded.key_id(), 0xdeadbeef);
+    assert_eq!(decoded.nonce(), &NONCE);
+    assert_eq!(decoded.ciphertext(), &[1u8; 40][..]);
+}
+
+#[test]
+fn envelope_rejects_unknown_version() {
+    let mut bytes = Envelope::new(1, NONCE, vec![0u8; 16]).serialize();
+    bytes[0] = ENVELOPE_VERSION + 1;
+
+    match Envelope::deserialize(&bytes).unwrap_err() {
+        ProtocolError::InvalidValue { field, value } => {
+            assert_eq!(field, "envelope version");
+            assert_eq!(value, ENVELOPE_VERSION + 1);
+        }
+        other => panic!("unexpected error: {:?}", other),
+    }
+}
+
+#[test]
+fn envelope_too_short_for_a_tag_is_rejected() {
+    // plaintext that happens to start with the version byte isn't mistaken for an envelope
+    let bytes = Envelope::new(1, NONCE, vec![0u8; 15]).serialize();
+    assert!(matches!(
+        Envelope::deserialize(&bytes),
+        Err(ProtocolError::Truncated { expected: 45, actual: 44 })
+    ));
+    assert!(Envelope::deserialize(b"hello").is_err());
+}
@@ -0,0 +1,191 @@
+use std::io::Cursor;
+use std::time::Duration;
+
+use protocol::{Message, Packet, PacketReader, PacketWriter, ProtocolMessage, ProtocolError};
+use protocol::{ChatMessage, Fragment, Reassembler, Username};
+use protocol::field_lens::{MAX_MESSAGE_LEN, MAX_PACKET_LEN};
+use protocol::message_types::MessageType;
+
+fn long_chat_message(body_len: usize) -> ChatMessage {
+    let body = "a pasted log line\n".repeat(body_len / 18 + 1);
+    ChatMessage::new(&Username::new("Harry").unwrap(), &Username::new("Eddie").unwrap(), &body[..body_len])
+}
+
+// Reassembles fragments in the given order, expecting the packet on the last one only
+fn reassemble(reassembler: &mut Reassembler, fragments: Vec<Fragment>) -> Packet {
+    let count = fragments.len();
+    let mut packet = None;
+    for (i, fragment) in fragments.into_iter().enumerate() {
+        let result = reassembler.add(fragment).unwrap();
+        assert_eq!(result.is_some(), i == count - 1);
+        packet = result;
+    }
+
+    packet.unwrap()
+}
+
+#[test]
+fn large_packet_survives_the_stream_in_fragments() {
+    let chat_message = long_chat_message(10_000);
+    let mut writer = PacketWriter::new(Vec::new());
+    writer.write_fragmented(&Packet::wrap(&chat_message)).unwrap();
+
+    let mut reader = PacketReader::new(Cursor::new(writer.get_ref().clone()));
+    let mut reassembler = Reassembler::new();
+    let packet = loop {
+        let packet = reader.read_packet().unwrap();
+        assert!(packet.length() <= MAX_PACKET_LEN);
+        match packet.decode().unwrap() {
+            ProtocolMessage::Fragment(fragment) => {
+                if let Some(packet) = reassembler.add(fragment).unwrap() {
+                    break packet;
+                }
+            }
+            other => panic!("unexpected message: {:?}", other),
+        }
+    };
+
+    match packet.decode().unwrap() {
+        ProtocolMessage::ChatMessage(decoded) => {
+            assert_eq!(decoded.message_id(), chat_message.message_id());
+            assert_eq!(decoded.msg_buffer, chat_message.msg_buffer);
+        }
+        other => panic!("unexpected message: {:?}", other),
+    }
+    assert_eq!(reassembler.pending(), 0);
+    assert_eq!(reassembler.buffered_len(), 0);
+}
+
+#[test]
+fn small_packets_are_not_fragmented() {
+    let packet = Packet::wrap(&long_chat_message(100));
+    let mut writer = PacketWriter::new(Vec::new());
+    writer.write_fragmented(&packet).unwrap();
+
+    assert_eq!(writer.get_ref(), &packet.serialize());
+}
+
+#[test]
+fn fragments_reassemble_in_any_order() {
+    let packet = Packet::wrap(&long_chat_message(5_000));
+    let mut fragments = Fragment::split(&packet, MAX_PACKET_LEN).unwrap();
+    assert!(fragments.len() > 2);
+    fragments.reverse();
+
+    // a repeated fragment is ignored
+    let repeat = Fragment::new(fragments[0].message_id(), fragments[0].index(), fragments[0].total(), fragments[0].payload().to_vec());
+    let mut reassembler = Reassembler::new();
+    assert!(reassembler.add(repeat).unwrap().is_none());
+
+    let reassembled = reassemble(&mut reassembler, fragments);
+    assert_eq!(reassembled.serialize(), packet.serialize());
+}
+
+#[test]
+fn interleaved_packets_reassemble_separately() {
+    let first = Packet::wrap(&long_chat_message(3_000));
+    let second = Packet::wrap(&long_chat_message(4_000));
+    let mut first_fragments = Fragment::split(&first, MAX_PACKET_LEN).unwrap();
+    let second_fragments = Fragment::split(&second, MAX_PACKET_LEN).unwrap();
+    let first_last = first_fragments.pop().unwrap();
+
+    let mut reassembler = Reassembler::new();
+    for fragment in first_fragments {
+        assert!(reassembler.add(fragment).unwrap().is_none());
+    }
+    assert_eq!(reassemble(&mut reassembler, second_fragments).serialize(), second.serialize());
+    assert_eq!(reassembler.pending(), 1);
+    assert_eq!(reassembler.add(first_last).unwrap().unwrap().serialize(), first.serialize());
+}
+
+#[test]
+fn fragment_rejects_bad_index_and_empty_payload() {
+    let out_of_range = Fragment::new([0; 16], 2, 2, vec![1, 2, 3]).serialize();
+    assert!(matches!(
+        Fragment::deserialize(&out_of_range),
+        Err(ProtocolError::InvalidFragment { index: 2, total: 2 })
+    ));
+
+    let empty = Fragment::new([0; 16], 0, 1, Vec::new()).serialize();
+    assert!(matches!(Fragment::deserialize(&empty), Err(ProtocolError::InvalidFragment { .. })));
+
+    let truncated = &out_of_range[..Fragment::fixed_size() - 1];
+    assert!(matches!(Fragment::deserialize(truncated), Err(ProtocolError::Truncated { .. })));
+}
+
+#[test]
+fn mismatched_total_drops_the_packet() {
+    let mut reassembler = Reassembler::new();
+    assert!(reassembler.add(Fragment::new([7; 16], 0, 3, vec![1])).unwrap().is_none());
+
+    let err = reassembler.add(Fragment::new([7; 16], 1, 4, vec![2])).unwrap_err();
+    assert!(matches!(err, ProtocolError::InvalidFragment { index: 1, total: 4 }));
+    assert_eq!(reassembler.pending(), 0);
+    assert_eq!(reassembler.buffered_len(), 0);
+}
+
+#[test]
+fn reassembly_respects_memory_limit() {
+    let packet = Packet::wrap(&long_chat_message(5_000));
+    let fragments = Fragment::split(&packet, MAX_PACKET_LEN).unwrap();
+    let mut reassembler = Reassembler::with_limits(2 * MAX_PACKET_LEN, Duration::from_secs(30));
+
+    let mut fragments = fragments.into_iter();
+    assert!(reassembler.add(fragments.next().unwrap()).unwrap().is_none());
+    assert!(reassembler.add(fragments.next().unwrap()).unwrap().is_none());
+    let err = reassembler.add(fragments.next().unwrap()).unwrap_err();
+    assert!(matches!(err, ProtocolError::ReassemblyBufferFull { .. }));
+
+    // the whole packet is given up on, freeing its memory
+    assert_eq!(reassembler.pending(), 0);
+    assert_eq!(reassembler.buffered_len(), 0);
+}
+
+#[test]
+fn oversized_messages_are_refused() {
+    let packet = Packet::new(MessageType::ChatMessage as u8, MAX_MESSAGE_LEN as u32, vec![0; MAX_MESSAGE_LEN]);
+    let err = Fragment::split(&packet, MAX_PACKET_LEN).unwrap_err();
+    assert!(matches!(err, ProtocolError::MessageTooLarge { .. }));
+
+    // a peer claiming a huge message is cut off once it passes the limit
+    let mut reassembler = Reassembler::with_limits(usize::MAX, Duration::from_secs(30));
+    let chunk = vec![0u8; 64 * 1024];
+    let mut result = Ok(None);
+    for index in 0..u16::MAX {
+        result = reassembler.add(Fragment::new([9; 16], index, u16::MAX, chunk.clone()));
+        if result.is_err() {
+            break;
+        }
+    }
+    assert!(matches!(result, Err(ProtocolError::MessageTooLarge { .. })));
+    assert_eq!(reassembler.buffered_len(), 0);
+}
+
+#[test]
+fn stale_packets_expire() {
+    let mut reassembler = Reassembler::with_limits(MAX_MESSAGE_LEN, Duration::ZERO);
+    assert!(reassembler.add(Fragment::new([1; 16], 0, 2, vec![1])).unwrap().is_none());
+    assert_eq!(reassembler.pending(), 1);
+
+    assert_eq!(reassembler.expire(), 1);
+    assert_eq!(reassembler.pending(), 0);
+    assert_eq!(reassembler.buffered_len(), 0);
+}
+
+#[test]
+fn fragments_cannot_carry_fragments() {
+    let inner = Packet::wrap(&Fragment::new([2; 16], 0, 1, vec![1]));
+    let outer = Fragment::new([3; 16], 0, 1, inner.serialize());
+
+    let err = Reassembler::new().add(outer).unwrap_err();
+    assert!(matches!(err, ProtocolError::UnexpectedMessage(MessageType::Fragment)));
+}
+
+#[test]
+fn reassembled_bytes_must_be_one_whole_packet() {
+    let mut bytes = Packet::wrap(&long_chat_message(10)).serialize();
+    bytes.push(0);
+
+    let err = Reassembler::new().add(Fragment::new([4; 16], 0, 1, bytes)).unwrap_err();
+    assert!(matches!(err, ProtocolError::LengthMismatch { .. }));
+}
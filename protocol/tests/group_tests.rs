@@ -0,0 +1,116 @@
+use protocol::{Message, Packet, ProtocolError, ProtocolMessage, Username, IdentityKeyPair};
+use protocol::{GroupCreate, GroupInfo, GroupInvite, GroupLeave, GroupMessage};
+use protocol::group;
+use protocol::status_codes::StatusCode;
+
+fn uname(uname: &str) -> Username {
+    Username::new(uname).unwrap()
+}
+
+fn round_trip<M: Message>(message: &M) -> ProtocolMessage {
+    let packet = Packet::wrap(message);
+    assert_eq!(packet.msg_length as usize, message.length());
+
+    Packet::deserialize(&packet.serialize()).unwrap().decode().unwrap()
+}
+
+#[test]
+fn create_round_trip() {
+    let group_create = GroupCreate::new("grumba appreciators").unwrap();
+    match round_trip(&group_create) {
+        ProtocolMessage::GroupCreate(decoded) => {
+            assert_eq!(decoded.group_id(), group_create.group_id());
+            assert_eq!(decoded.group_name(), "grumba appreciators");
+        }
+        other => panic!("decoded as {:?}", other),
+    }
+
+    assert_ne!(GroupCreate::new("a").unwrap().group_id(), GroupCreate::new("a").unwrap().group_id());
+}
+
+#[test]
+fn group_names_are_validated() {
+    assert!(group::validate_group_name("team chat").is_ok());
+    assert!(matches!(group::validate_group_name(""), Err(ProtocolError::InvalidGroupName(_))));
+    assert!(matches!(group::validate_group_name(&"a".repeat(51)), Err(ProtocolError::InvalidGroupName(_))));
+    assert!(matches!(group::validate_group_name("two\nlines"), Err(ProtocolError::InvalidGroupName(_))));
+
+    let mut bytes = GroupCreate::new("ab").unwrap().serialize();
+    *bytes.last_mut().unwrap() = b'\n';
+    assert!(matches!(GroupCreate::deserialize(&bytes), Err(ProtocolError::InvalidGroupName(_))));
+}
+
+#[test]
+fn info_round_trip() {
+    let members = vec![uname("Harry"), uname("Eddie"), uname("Kerry")];
+    match round_trip(&GroupInfo::new([1u8; 16], "team", members.clone())) {
+        ProtocolMessage::GroupInfo(decoded) => {
+            assert_eq!(decoded.status_code(), StatusCode::Success);
+            assert_eq!(decoded.group_id(), [1u8; 16]);
+            assert_eq!(decoded.group_name(), "team");
+            assert_eq!(decoded.members(), &members[..]);
+        }
+        other => panic!("decoded as {:?}", other),
+    }
+
+    // failures about unknown groups have no name
+    match round_trip(&GroupInfo::failure([1u8; 16], "")) {
+        ProtocolMessage::GroupInfo(decoded) => {
+            assert_eq!(decoded.status_code(), StatusCode::Failure);
+            assert_eq!(decoded.group_name(), "");
+            assert!(decoded.members().is_empty());
+        }
+        other => panic!("decoded as {:?}", other),
+    }
+}
+
+#[test]
+fn info_rejects_missing_member() {
+    let bytes = GroupInfo::new([1u8; 16], "team", vec![uname("Harry"), uname("Eddie")]).serialize();
+    assert!(matches!(
+        GroupInfo::deserialize(&bytes[..bytes.len() - 6]),
+        Err(ProtocolError::Truncated { .. })
+    ));
+}
+
+#[test]
+fn invite_and_leave_round_trip() {
+    match round_trip(&GroupInvite::new([2u8; 16], &uname("Harry"), &uname("Eddie"), vec![9u8; 40])) {
+        ProtocolMessage::GroupInvite(decoded) => {
+            assert_eq!(decoded.group_id(), [2u8; 16]);
+            assert_eq!(decoded.inviter(), "Harry");
+            assert_eq!(decoded.invitee(), "Eddie");
+            assert_eq!(decoded.sealed_key(), &[9u8; 40][..]);
+        }
+        other => panic!("decoded as {:?}", other),
+    }
+
+    match round_trip(&GroupLeave::new([2u8; 16])) {
+        ProtocolMessage::GroupLeave(decoded) => assert_eq!(decoded.group_id(), [2u8; 16]),
+        other => panic!("decoded as {:?}", other),
+    }
+}
+
+#[test]
+fn message_round_trip_and_signature() {
+    let identity = IdentityKeyPair::generate();
+    let mut group_message = GroupMessage::new([3u8; 16], &uname("Harry"), "hello team");
+    group_message.sign(&identity);
+
+    match round_trip(&group_message) {
+        ProtocolMessage::GroupMessage(decoded) => {
+            assert_eq!(decoded.message_id(), group_message.message_id());
+            assert_eq!(decoded.timestamp(), group_message.timestamp());
+            assert_eq!(decoded.group_id, [3u8; 16]);
+            assert_eq!(decoded.send_uname, "Harry");
+            assert_eq!(decoded.msg_buffer, b"hello team");
+            assert!(decoded.verify_signature(&identity.identity_key()));
+        }
+        other => panic!("decoded as {:?}", other),
+    }
+
+    // the signature covers the group it was sent to
+    let mut moved = group_message.clone();
+    moved.group_id = [4u8; 16];
+    assert!(!moved.verify_signature(&identity.identity_key()));
+}
@@ -0,0 +1,98 @@
+use std::time::{Duration, Instant};
+
+use protocol::{Message, Packet, ProtocolMessage, ProtocolError};
+use protocol::{Ping, Pong, Heartbeat, HeartbeatConfig, LinkStatus};
+
+const INTERVAL: Duration = Duration::from_secs(10);
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+fn heartbeat(start: Instant) -> Heartbeat {
+    Heartbeat::new(HeartbeatConfig::new(INTERVAL, TIMEOUT), start)
+}
+
+#[test]
+fn ping_pong_round_trip() {
+    let ping = Ping::new(42);
+    let bytes = Packet::wrap(&ping).serialize();
+    match Packet::deserialize(&bytes).unwrap().decode().unwrap() {
+        ProtocolMessage::Ping(decoded) => assert_eq!(decoded.nonce(), 42),
+        other => panic!("unexpected message: {:?}", other),
+    }
+
+    let bytes = Packet::wrap(&ping.pong()).serialize();
+    match Packet::deserialize(&bytes).unwrap().decode().unwrap() {
+        ProtocolMessage::Pong(decoded) => assert_eq!(decoded.nonce(), 42),
+        other => panic!("unexpected message: {:?}", other),
+    }
+}
+
+#[test]
+fn ping_rejects_wrong_length() {
+    let bytes = Ping::new(1).serialize();
+    assert!(matches!(Ping::deserialize(&bytes[..7]), Err(ProtocolError::Truncated { expected: 8, actual: 7 })));
+
+    let mut bytes = Pong::new(1).serialize();
+    bytes.push(0);
+    assert!(matches!(Pong::deserialize(&bytes), Err(ProtocolError::LengthMismatch { expected: 8, actual: 9 })));
+}
+
+#[test]
+fn pings_are_sent_every_interval() {
+    let start = Instant::now();
+    let mut heartbeat = heartbeat(start);
+
+    assert!(heartbeat.poll(start).is_none());
+    assert!(heartbeat.poll(start + INTERVAL / 2).is_none());
+
+    let first = heartbeat.poll(start + INTERVAL).unwrap();
+    assert!(heartbeat.poll(start + INTERVAL).is_none());
+    let second = heartbeat.poll(start + INTERVAL * 2).unwrap();
+    assert_ne!(first.nonce(), second.nonce());
+}
+
+#[test]
+fn pong_measures_latency() {
+    let start = Instant::now();
+    let mut heartbeat = heartbeat(start);
+    assert_eq!(heartbeat.latency(), None);
+
+    let ping = heartbeat.poll(start + INTERVAL).unwrap();
+    let rtt = heartbeat.handle_pong(&ping.pong(), start + INTERVAL + Duration::from_millis(40));
+    assert_eq!(rtt, Some(Duration::from_millis(40)));
+    assert_eq!(heartbeat.latency(), Some(Duration::from_millis(40)));
+
+    // a pong for a ping we're not waiting on doesn't change anything
+    assert_eq!(heartbeat.handle_pong(&ping.pong(), start + INTERVAL * 2), None);
+    assert_eq!(heartbeat.latency(), Some(Duration::from_millis(40)));
+}
+
+#[test]
+fn unanswered_pings_degrade_then_kill_the_link() {
+    let start = Instant::now();
+    let mut heartbeat = heartbeat(start);
+    assert_eq!(heartbeat.status(start), LinkStatus::Healthy);
+
+    let first = heartbeat.poll(start + INTERVAL).unwrap();
+    assert_eq!(heartbeat.status(start + INTERVAL), LinkStatus::Healthy);
+
+    let second = heartbeat.poll(start + INTERVAL * 2).unwrap();
+    assert_eq!(heartbeat.status(start + INTERVAL * 2), LinkStatus::Degraded);
+
+    // a late answer to the replaced ping proves the peer is alive, but doesn't count as latency
+    assert_eq!(heartbeat.handle_pong(&first.pong(), start + INTERVAL * 2), None);
+    assert_eq!(heartbeat.status(start + INTERVAL * 2), LinkStatus::Degraded);
+    assert_eq!(heartbeat.status(start + INTERVAL * 2 + TIMEOUT), LinkStatus::Dead);
+
+    assert!(heartbeat.handle_pong(&second.pong(), start + INTERVAL * 3).is_some());
+    assert_eq!(heartbeat.status(start + INTERVAL * 3), LinkStatus::Healthy);
+}
+
+#[test]
+fn any_packet_keeps_the_link_alive() {
+    let start = Instant::now();
+    let mut heartbeat = heartbeat(start);
+    assert_eq!(heartbeat.status(start + TIMEOUT), LinkStatus::Dead);
+
+    heartbeat.received(start + TIMEOUT - Duration::from_secs(1));
+    assert_eq!(heartbeat.status(start + TIMEOUT), LinkStatus::Healthy);
+}
@@ -0,0 +1,96 @@
+use protocol::{Message, Packet, ProtocolMessage, ProtocolError};
+use protocol::{Hello, HelloAck, Capabilities, NegotiatedSession};
+use protocol::hello::{PROTOCOL_VERSION, MIN_PROTOCOL_VERSION};
+use protocol::status_codes::StatusCode;
+use protocol::message_types::MessageType;
+
+const FEATURE_A: Capabilities = Capabilities::from_bits(0b001);
+const FEATURE_B: Capabilities = Capabilities::from_bits(0b010);
+const FEATURE_C: Capabilities = Capabilities::from_bits(0b100);
+
+fn hello_with_version(version: u16, capabilities: Capabilities) -> Hello {
+    let mut bytes = version.to_be_bytes().to_vec();
+    bytes.extend_from_slice(&capabilities.bits().to_be_bytes());
+    Hello::deserialize(&bytes).unwrap()
+}
+
+#[test]
+fn hello_round_trip() {
+    let hello = Hello::new(FEATURE_A.union(FEATURE_C));
+    match Packet::wrap(&hello).decode().unwrap() {
+        ProtocolMessage::Hello(decoded) => {
+            assert_eq!(decoded.version(), PROTOCOL_VERSION);
+            assert_eq!(decoded.capabilities(), FEATURE_A.union(FEATURE_C));
+        }
+        other => panic!("decoded as {:?}", other),
+    }
+}
+
+#[test]
+fn hello_ack_round_trip() {
+    let hello_ack = HelloAck::new(StatusCode::IncompatibleVersion, 7, FEATURE_B);
+    match Packet::wrap(&hello_ack).decode().unwrap() {
+        ProtocolMessage::HelloAck(decoded) => {
+            assert!(matches!(decoded.status_code(), StatusCode::IncompatibleVersion));
+            assert_eq!(decoded.version(), 7);
+            assert_eq!(decoded.capabilities(), FEATURE_B);
+        }
+        other => panic!("decoded as {:?}", other),
+    }
+}
+
+#[test]
+fn negotiation_keeps_only_shared_features() {
+    let hello = Hello::new(FEATURE_A.union(FEATURE_B));
+    let server = NegotiatedSession::negotiate(&hello, FEATURE_B.union(FEATURE_C)).unwrap();
+    let client = NegotiatedSession::from_ack(&server.hello_ack()).unwrap();
+
+    assert_eq!(server, client);
+    assert_eq!(client.version(), PROTOCOL_VERSION);
+    assert!(client.supports(FEATURE_B));
+    assert!(!client.supports(FEATURE_A));
+    assert!(!client.supports(FEATURE_C));
+}
+
+#[test]
+fn newer_client_is_downgraded_to_server_version() {
+    let hello = hello_with_version(PROTOCOL_VERSION + 1, Capabilities::NONE);
+    let negotiated = NegotiatedSession::negotiate(&hello, Capabilities::NONE).unwrap();
+
+    assert_eq!(negotiated.version(), PROTOCOL_VERSION);
+}
+
+#[test]
+fn server_rejects_too_old_client() {
+    let hello = hello_with_version(MIN_PROTOCOL_VERSION - 1, FEATURE_A);
+    match NegotiatedSession::negotiate(&hello, FEATURE_A).unwrap_err() {
+        ProtocolError::IncompatibleVersion { local_version, remote_version } => {
+            assert_eq!(local_version, PROTOCOL_VERSION);
+            assert_eq!(remote_version, MIN_PROTOCOL_VERSION - 1);
+        }
+        other => panic!("unexpected error: {:?}", other),
+    }
+}
+
+#[test]
+fn client_rejects_incompatible_ack() {
+    let rejected = HelloAck::new(StatusCode::IncompatibleVersion, PROTOCOL_VERSION + 1, Capabilities::NONE);
+    let err = NegotiatedSession::from_ack(&rejected).unwrap_err();
+    assert!(matches!(err, ProtocolError::IncompatibleVersion { .. }));
+
+    let too_new = HelloAck::new(StatusCode::Success, PROTOCOL_VERSION + 1, Capabilities::NONE);
+    assert!(NegotiatedSession::from_ack(&too_new).is_err());
+}
+
+#[test]
+fn optional_messages_need_their_capability() {
+    let hello = Hello::new(Capabilities::RECEIPTS);
+    let with_receipts = NegotiatedSession::negotiate(&hello, Capabilities::RECEIPTS).unwrap();
+    let without_receipts = NegotiatedSession::negotiate(&hello, Capabilities::NONE).unwrap();
+
+    assert!(with_receipts.allows(MessageType::Receipt));
+    assert!(!without_receipts.allows(MessageType::Receipt));
+    assert!(without_receipts.allows(MessageType::ChatMessage));
+    assert!(!with_receipts.allows(MessageType::Fragment));
+    assert!(!with_receipts.allows(MessageType::Ping));
+}
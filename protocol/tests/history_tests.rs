@@ -0,0 +1,93 @@
+use protocol::{Message, Packet, ProtocolError, ProtocolMessage, Username, ChatMessage};
+use protocol::{HistoryCursor, HistoryReq, HistoryResp};
+use protocol::{Capabilities, Hello, NegotiatedSession};
+use protocol::message_types::MessageType;
+use protocol::status_codes::StatusCode;
+
+fn uname(uname: &str) -> Username {
+    Username::new(uname).unwrap()
+}
+
+fn round_trip<M: Message>(message: &M) -> ProtocolMessage {
+    let packet = Packet::wrap(message);
+    assert_eq!(packet.msg_length as usize, message.length());
+
+    Packet::deserialize(&packet.serialize()).unwrap().decode().unwrap()
+}
+
+#[test]
+fn history_req_round_trip_for_each_cursor() {
+    for cursor in [HistoryCursor::START, HistoryCursor::AfterTime(1234), HistoryCursor::AfterMessage([7u8; 16])] {
+        match round_trip(&HistoryReq::new(&uname("Eddie"), cursor)) {
+            ProtocolMessage::HistoryReq(decoded) => {
+                assert_eq!(decoded.conn_uname(), &uname("Eddie"));
+                assert_eq!(decoded.cursor(), cursor);
+            }
+            other => panic!("decoded as {:?}", other),
+        }
+    }
+}
+
+#[test]
+fn history_resp_round_trip() {
+    let messages = vec![
+        ChatMessage::new(&uname("Harry"), &uname("Eddie"), "im not grumba grandpa guy"),
+        ChatMessage::reply(&uname("Eddie"), &uname("Harry"), "sure", [7u8; 16]),
+    ];
+    let cursor = HistoryCursor::AfterMessage([1u8; 16]);
+    let history_resp = HistoryResp::new(&uname("Eddie"), cursor, messages.clone(), true);
+
+    match round_trip(&history_resp) {
+        ProtocolMessage::HistoryResp(decoded) => {
+            assert_eq!(decoded.status_code(), StatusCode::Success);
+            assert_eq!(decoded.conn_uname(), &uname("Eddie"));
+            assert_eq!(decoded.cursor(), cursor);
+            assert!(decoded.more());
+            assert_eq!(decoded.messages().len(), 2);
+            for (decoded, message) in decoded.messages().iter().zip(&messages) {
+                assert_eq!(decoded.message_id(), message.message_id());
+                assert_eq!(decoded.msg_buffer, message.msg_buffer);
+                assert_eq!(decoded.in_reply_to, message.in_reply_to);
+            }
+        }
+        other => panic!("decoded as {:?}", other),
+    }
+}
+
+#[test]
+fn history_failure_round_trip() {
+    match round_trip(&HistoryResp::failure(&uname("Eddie"), HistoryCursor::START)) {
+        ProtocolMessage::HistoryResp(decoded) => {
+            assert_eq!(decoded.status_code(), StatusCode::Failure);
+            assert!(!decoded.more());
+            assert!(decoded.messages().is_empty());
+        }
+        other => panic!("decoded as {:?}", other),
+    }
+}
+
+#[test]
+fn bad_history_messages_are_rejected() {
+    // cursor kind follows the username
+    let mut bytes = HistoryReq::new(&uname("Eddie"), HistoryCursor::START).serialize();
+    bytes[6] = 2;
+    assert!(matches!(HistoryReq::deserialize(&bytes), Err(ProtocolError::InvalidValue { field: "cursor", value: 2 })));
+
+    // a message count with no messages behind it
+    let mut bytes = HistoryResp::new(&uname("Eddie"), HistoryCursor::START, Vec::new(), false).serialize();
+    let count_pos = bytes.len() - 1;
+    bytes[count_pos] = 1;
+    assert!(matches!(HistoryResp::deserialize(&bytes), Err(ProtocolError::Truncated { .. })));
+}
+
+#[test]
+fn history_needs_negotiating() {
+    let hello = Hello::new(Capabilities::HISTORY);
+    let negotiated = NegotiatedSession::negotiate(&hello, Capabilities::HISTORY).unwrap();
+    assert!(negotiated.allows(MessageType::HistoryReq));
+    assert!(negotiated.allows(MessageType::HistoryResp));
+
+    let negotiated = NegotiatedSession::negotiate(&hello, Capabilities::NONE).unwrap();
+    assert!(!negotiated.allows(MessageType::HistoryReq));
+    assert!(!negotiated.allows(MessageType::HistoryResp));
+}
@@ -0,0 +1,110 @@
+use protocol::{ChatMessage, IdentityKeyPair, IdentityReq, IdentityResp, Message, Packet, ProtocolError, ProtocolMessage, Username};
+use protocol::identity;
+
+fn uname(uname: &str) -> Username {
+    Username::new(uname).unwrap()
+}
+
+fn signed_message(identity: &IdentityKeyPair) -> ChatMessage {
+    let mut chat_message = ChatMessage::new(&uname("Harry"), &uname("Eddie"), "ciphertext, really");
+    chat_message.sign(identity);
+    chat_message
+}
+
+#[test]
+fn signed_message_verifies_after_the_wire() {
+    let identity = IdentityKeyPair::generate();
+    let bytes = Packet::wrap(&signed_message(&identity)).serialize();
+    match Packet::deserialize(&bytes).unwrap().decode().unwrap() {
+        ProtocolMessage::ChatMessage(decoded) => assert!(decoded.verify_signature(&identity.identity_key())),
+        other => panic!("unexpected message: {:?}", other),
+    }
+}
+
+#[test]
+fn signature_covers_the_whole_message() {
+    let identity = IdentityKeyPair::generate();
+    let identity_key = identity.identity_key();
+
+    let mut chat_message = signed_message(&identity);
+    chat_message.msg_buffer[0] ^= 1;
+    assert!(!chat_message.verify_signature(&identity_key));
+
+    let mut chat_message = signed_message(&identity);
+    chat_message.recv_uname = uname("Kerry");
+    assert!(!chat_message.verify_signature(&identity_key));
+
+    let mut chat_message = signed_message(&identity);
+    chat_message.timestamp += 1;
+    assert!(!chat_message.verify_signature(&identity_key));
+
+    // unsigned, or signed by someone else
+    let chat_message = ChatMessage::new(&uname("Harry"), &uname("Eddie"), "hello");
+    assert!(!chat_message.verify_signature(&identity_key));
+    let chat_message = signed_message(&IdentityKeyPair::generate());
+    assert!(!chat_message.verify_signature(&identity_key));
+}
+
+#[test]
+fn identity_key_pair_survives_storage_as_bytes() {
+    let identity = IdentityKeyPair::generate();
+    let restored = IdentityKeyPair::from_secret_bytes(identity.secret_bytes());
+    assert_eq!(restored.identity_key(), identity.identity_key());
+    assert!(signed_message(&restored).verify_signature(&identity.identity_key()));
+}
+
+#[test]
+fn identity_messages_round_trip() {
+    let identity_key = IdentityKeyPair::generate().identity_key();
+    match Packet::deserialize(&Packet::wrap(&IdentityReq::new(&uname("Eddie"))).serialize()).unwrap().decode().unwrap() {
+        ProtocolMessage::IdentityReq(decoded) => assert_eq!(decoded.uname(), "Eddie"),
+        other => panic!("unexpected message: {:?}", other),
+    }
+
+    for key in [Some(identity_key), None] {
+        let resp = IdentityResp::new(&uname("Eddie"), key);
+        assert_eq!(resp.serialize().len(), resp.length());
+        match Packet::deserialize(&Packet::wrap(&resp).serialize()).unwrap().decode().unwrap() {
+            ProtocolMessage::IdentityResp(decoded) => {
+                assert_eq!(decoded.uname(), "Eddie");
+                assert_eq!(decoded.identity_key(), key.as_ref());
+            }
+            other => panic!("unexpected message: {:?}", other),
+        }
+    }
+
+    let mut bytes = IdentityResp::new(&uname("Eddie"), None).serialize();
+    *bytes.last_mut().unwrap() = 2;
+    assert!(matches!(
+        IdentityResp::deserialize(&bytes),
+        Err(ProtocolError::InvalidValue { field: "has_identity_key", value: 2 })
+    ));
+}
+
+#[test]
+fn fingerprint_is_short_and_stable() {
+    let identity_key = IdentityKeyPair::generate().identity_key();
+    let fingerprint = identity::fingerprint(&identity_key);
+    assert_eq!(fingerprint, identity::fingerprint(&identity_key));
+    assert_eq!(fingerprint.split(' ').count(), 8);
+    assert!(fingerprint.split(' ').all(|group| group.len() == 4));
+}
+
+#[test]
+fn safety_number_is_the_same_for_both_users() {
+    let harry = IdentityKeyPair::generate().identity_key();
+    let eddie = IdentityKeyPair::generate().identity_key();
+
+    let harrys_view = identity::safety_number(&uname("Harry"), &harry, &uname("Eddie"), &eddie);
+    let eddies_view = identity::safety_number(&uname("Eddie"), &eddie, &uname("Harry"), &harry);
+    assert_eq!(harrys_view, eddies_view);
+
+    let groups: Vec<&str> = harrys_view.split(' ').collect();
+    assert_eq!(groups.len(), identity::SAFETY_NUMBER_GROUPS);
+    assert!(groups.iter().all(|group| group.len() == identity::SAFETY_NUMBER_GROUP_LEN));
+    assert!(groups.iter().all(|group| group.chars().all(|c| c.is_ascii_digit())));
+
+    // a swapped key gives a different number
+    let mallory = IdentityKeyPair::generate().identity_key();
+    assert_ne!(harrys_view, identity::safety_number(&uname("Harry"), &harry, &uname("Eddie"), &mallory));
+}
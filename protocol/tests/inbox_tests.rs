@@ -0,0 +1,31 @@
+use protocol::{Message, Packet, ProtocolError, ProtocolMessage, InboxAck};
+use protocol::{Capabilities, Hello, NegotiatedSession};
+use protocol::message_types::MessageType;
+
+#[test]
+fn inbox_ack_round_trip() {
+    let inbox_ack = InboxAck::new([7u8; 16]);
+    let packet = Packet::wrap(&inbox_ack);
+    assert_eq!(packet.msg_length as usize, inbox_ack.length());
+
+    match Packet::deserialize(&packet.serialize()).unwrap().decode().unwrap() {
+        ProtocolMessage::InboxAck(decoded) => assert_eq!(decoded.message_id(), [7u8; 16]),
+        other => panic!("decoded as {:?}", other),
+    }
+}
+
+#[test]
+fn inbox_ack_rejects_wrong_length() {
+    let mut bytes = InboxAck::new([7u8; 16]).serialize();
+    assert!(matches!(InboxAck::deserialize(&bytes[..15]), Err(ProtocolError::Truncated { .. })));
+
+    bytes.push(0);
+    assert!(matches!(InboxAck::deserialize(&bytes), Err(ProtocolError::LengthMismatch { .. })));
+}
+
+#[test]
+fn inbox_needs_negotiating() {
+    let hello = Hello::new(Capabilities::INBOX);
+    assert!(NegotiatedSession::negotiate(&hello, Capabilities::INBOX).unwrap().allows(MessageType::InboxAck));
+    assert!(!NegotiatedSession::negotiate(&hello, Capabilities::NONE).unwrap().allows(MessageType::InboxAck));
+}
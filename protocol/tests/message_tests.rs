@@ -0,0 +1,399 @@
+use protocol::{Message, Packet, ProtocolMessage, ProtocolError};
+use protocol::{ChatMessage, IdentityKeyPair, SignupReq, SignupResp, VerifyReq, VerifyResp};
+use protocol::{C2cConnReq, C2cConnResp, ConnResponse, Receipt, ReceiptKind, Username};
+use protocol::message_types::MessageType;
+use protocol::shared;
+use protocol::status_codes::StatusCode;
+
+const PUBLIC_KEY: [u8; 32] = [3u8; 32];
+
+fn uname(uname: &str) -> Username {
+    Username::new(uname).unwrap()
+}
+
+// Wraps a message in a packet, sends the packet through its byte form and decodes it
+fn round_trip<M: Message>(message: &M) -> ProtocolMessage {
+    let packet = Packet::wrap(message);
+    assert_eq!(packet.method, M::MESSAGE_TYPE as u8);
+    assert_eq!(packet.msg_length as usize, message.length());
+
+    Packet::deserialize(&packet.serialize()).unwrap().decode().unwrap()
+}
+
+#[test]
+fn chat_message_round_trip() {
+    let chat_message = ChatMessage::new(&uname("Harry"), &uname("Eddie"), "im not grumba grandpa guy");
+    match round_trip(&chat_message) {
+        ProtocolMessage::ChatMessage(decoded) => {
+            assert_eq!(decoded.send_uname, "Harry");
+            assert_eq!(decoded.recv_uname, "Eddie");
+            assert_eq!(decoded.msg_buffer, chat_message.msg_buffer);
+            assert_eq!(decoded.message_id(), chat_message.message_id());
+            assert_eq!(decoded.timestamp(), chat_message.timestamp());
+        }
+        other => panic!("decoded as {:?}", other),
+    }
+}
+
+#[test]
+fn chat_messages_get_unique_ids_and_send_time() {
+    let before = shared::timestamp_now();
+    let first = ChatMessage::new(&uname("Harry"), &uname("Eddie"), "hello");
+    let second = ChatMessage::new(&uname("Harry"), &uname("Eddie"), "hello");
+    let after = shared::timestamp_now();
+
+    assert_ne!(first.message_id(), second.message_id());
+    assert!(first.timestamp() >= before && first.timestamp() <= after);
+    assert!(second.timestamp() >= first.timestamp());
+}
+
+#[test]
+fn reply_round_trip_and_signature() {
+    let parent = ChatMessage::new(&uname("Eddie"), &uname("Harry"), "grumba?");
+    let identity = IdentityKeyPair::generate();
+    let mut reply = ChatMessage::reply(&uname("Harry"), &uname("Eddie"), "im not grumba grandpa guy", parent.message_id());
+    reply.sign(&identity);
+
+    match round_trip(&reply) {
+        ProtocolMessage::ChatMessage(decoded) => {
+            assert_eq!(decoded.in_reply_to, Some(parent.message_id()));
+            assert_eq!(decoded.msg_buffer, reply.msg_buffer);
+            assert!(decoded.verify_signature(&identity.identity_key()));
+        }
+        other => panic!("decoded as {:?}", other),
+    }
+
+    // the signature covers which message it replies to
+    let mut moved = reply.clone();
+    moved.in_reply_to = Some([0u8; 16]);
+    assert!(!moved.verify_signature(&identity.identity_key()));
+    moved.in_reply_to = None;
+    assert!(!moved.verify_signature(&identity.identity_key()));
+}
+
+#[test]
+fn reply_id_is_never_read_as_part_of_the_body() {
+    let identity = IdentityKeyPair::generate();
+    let parent_id = [3u8; 16];
+
+    // a plain message whose body happens to end with what looks like a message id...
+    let mut body = b"grumba".to_vec();
+    body.extend_from_slice(&parent_id);
+    let mut plain = ChatMessage::new(&uname("Harry"), &uname("Eddie"), "");
+    plain.set_body(body);
+    plain.sign(&identity);
+
+    // ...and a reply with the same id and the start of that body
+    let mut reply = plain.clone();
+    reply.set_body(b"grumba".to_vec());
+    reply.in_reply_to = Some(parent_id);
+    reply.sign(&identity);
+
+    assert_ne!(plain.serialize(), reply.serialize());
+    for message in [&plain, &reply] {
+        let decoded = ChatMessage::deserialize(&message.serialize()).unwrap();
+        assert_eq!(decoded.msg_buffer, message.msg_buffer);
+        assert_eq!(decoded.in_reply_to, message.in_reply_to);
+        assert!(decoded.verify_signature(&identity.identity_key()));
+    }
+
+    // neither signature can be passed off as the other's
+    let mut forged = reply.clone();
+    forged.signature = plain.signature;
+    assert!(!forged.verify_signature(&identity.identity_key()));
+    let mut forged = plain.clone();
+    forged.signature = reply.signature;
+    assert!(!forged.verify_signature(&identity.identity_key()));
+}
+
+#[test]
+fn chat_message_rejects_bad_reply_flag() {
+    let mut bytes = ChatMessage::new(&uname("Harry"), &uname("Eddie"), "hello").serialize();
+    *bytes.last_mut().unwrap() = 2;
+    assert!(matches!(
+        ChatMessage::deserialize(&bytes),
+        Err(ProtocolError::InvalidValue { field: "in_reply_to", value: 2 })
+    ));
+
+    // flagged as a reply but without the id
+    *bytes.last_mut().unwrap() = 1;
+    assert!(matches!(ChatMessage::deserialize(&bytes), Err(ProtocolError::Truncated { .. })));
+}
+
+#[test]
+fn signature_covers_msg_length() {
+    let identity = IdentityKeyPair::generate();
+    let mut chat_message = ChatMessage::new(&uname("Harry"), &uname("Eddie"), "hello");
+    chat_message.sign(&identity);
+
+    chat_message.msg_length += 1;
+    assert!(!chat_message.verify_signature(&identity.identity_key()));
+}
+
+#[test]
+fn verify_round_trip() {
+    let proof = [9u8; 64];
+    match round_trip(&VerifyReq::new(&uname("Kerry"), proof)) {
+        ProtocolMessage::VerifyReq(decoded) => {
+            assert_eq!(decoded.cli_uname, "Kerry");
+            assert_eq!(decoded.proof, proof);
+        }
+        other => panic!("decoded as {:?}", other),
+    }
+
+    match round_trip(&VerifyResp::new(StatusCode::Failure)) {
+        ProtocolMessage::VerifyResp(decoded) => {
+            assert!(matches!(decoded.status_code, StatusCode::Failure));
+        }
+        other => panic!("decoded as {:?}", other),
+    }
+}
+
+#[test]
+fn signup_round_trip() {
+    match round_trip(&SignupReq::new(&uname("Eddie"), PUBLIC_KEY)) {
+        ProtocolMessage::SignupReq(decoded) => {
+            assert_eq!(decoded.uname(), "Eddie");
+            assert_eq!(decoded.identity_key(), &PUBLIC_KEY);
+        }
+        other => panic!("decoded as {:?}", other),
+    }
+
+    let signup_resp = SignupResp::new(StatusCode::Success);
+    match round_trip(&signup_resp) {
+        ProtocolMessage::SignupResp(decoded) => {
+            assert!(matches!(decoded.status_code(), StatusCode::Success));
+            assert_eq!(decoded.token(), signup_resp.token());
+        }
+        other => panic!("decoded as {:?}", other),
+    }
+}
+
+#[test]
+fn conn_req_round_trip() {
+    match round_trip(&C2cConnReq::new(&uname("Harry"), &uname("Kerry"), PUBLIC_KEY)) {
+        ProtocolMessage::C2cConnReq(decoded) => {
+            assert_eq!(decoded.req_uname(), "Harry");
+            assert_eq!(decoded.resp_uname(), "Kerry");
+            assert_eq!(decoded.public_key(), &PUBLIC_KEY);
+        }
+        other => panic!("decoded as {:?}", other),
+    }
+}
+
+#[test]
+fn conn_resp_round_trip_for_every_response() {
+    let responses = [
+        ConnResponse::Accept,
+        ConnResponse::Reject,
+        ConnResponse::Block,
+        ConnResponse::AlreadyConnected,
+    ];
+
+    for response in responses {
+        for public_key in [Some(PUBLIC_KEY), None] {
+            match round_trip(&C2cConnResp::new(&uname("Harry"), &uname("Kerry"), response, public_key)) {
+                ProtocolMessage::C2cConnResp(decoded) => {
+                    assert_eq!(decoded.req_uname(), "Harry");
+                    assert_eq!(decoded.resp_uname(), "Kerry");
+                    assert_eq!(decoded.response(), response);
+                    assert_eq!(decoded.public_key(), public_key.as_ref());
+                }
+                other => panic!("decoded as {:?}", other),
+            }
+        }
+    }
+}
+
+#[test]
+fn substituted_conn_keys_are_rejected() {
+    let harry = IdentityKeyPair::generate();
+    let kerry = IdentityKeyPair::generate();
+    let server_key = [4u8; 32];
+
+    let mut conn_req = C2cConnReq::new(&uname("Harry"), &uname("Kerry"), PUBLIC_KEY);
+    conn_req.sign(&harry);
+    assert!(C2cConnReq::deserialize(&conn_req.serialize()).unwrap().verify_signature(&harry.identity_key()));
+
+    // the server swapping in a key of its own, keeping the requester's signature...
+    let mut bytes = conn_req.serialize();
+    let key_pos = bytes.len() - 64 - 32;
+    bytes[key_pos..key_pos + 32].copy_from_slice(&server_key);
+    let substituted = C2cConnReq::deserialize(&bytes).unwrap();
+    assert_eq!(substituted.public_key(), &server_key);
+    assert!(!substituted.verify_signature(&harry.identity_key()));
+
+    // ...or signing it itself
+    let mut forged = C2cConnReq::new(&uname("Harry"), &uname("Kerry"), server_key);
+    forged.sign(&IdentityKeyPair::generate());
+    assert!(!forged.verify_signature(&harry.identity_key()));
+
+    let mut conn_resp = C2cConnResp::new(&uname("Harry"), &uname("Kerry"), ConnResponse::Accept, Some(PUBLIC_KEY));
+    conn_resp.sign(&kerry);
+    assert!(C2cConnResp::deserialize(&conn_resp.serialize()).unwrap().verify_signature(&kerry.identity_key()));
+
+    let mut bytes = conn_resp.serialize();
+    let key_pos = bytes.len() - 64 - 32;
+    bytes[key_pos..key_pos + 32].copy_from_slice(&server_key);
+    assert!(!C2cConnResp::deserialize(&bytes).unwrap().verify_signature(&kerry.identity_key()));
+
+    // a key signed for someone else's conversation doesn't carry over
+    let mut elsewhere = C2cConnResp::new(&uname("Eddie"), &uname("Kerry"), ConnResponse::Accept, Some(PUBLIC_KEY));
+    elsewhere.sign(&kerry);
+    let mut bytes = elsewhere.serialize();
+    bytes[..6].copy_from_slice(&conn_resp.serialize()[..6]);
+    assert!(!C2cConnResp::deserialize(&bytes).unwrap().verify_signature(&kerry.identity_key()));
+
+    // nor does a requester's signature pass as a responder's
+    let mut reflected = conn_resp.serialize();
+    let sig_pos = reflected.len() - 64;
+    let mut own_req = C2cConnReq::new(&uname("Harry"), &uname("Kerry"), PUBLIC_KEY);
+    own_req.sign(&kerry);
+    reflected[sig_pos..].copy_from_slice(&own_req.serialize()[sig_pos - 2..]);
+    assert!(!C2cConnResp::deserialize(&reflected).unwrap().verify_signature(&kerry.identity_key()));
+
+    // and a response without a key has nothing to check
+    let keyless = C2cConnResp::new(&uname("Harry"), &uname("Kerry"), ConnResponse::AlreadyConnected, None);
+    assert!(!keyless.verify_signature(&kerry.identity_key()));
+}
+
+#[test]
+fn conn_resp_rejects_unknown_response() {
+    let mut bytes = C2cConnResp::new(&uname("Harry"), &uname("Kerry"), ConnResponse::Accept, None).serialize();
+    let response_pos = bytes.len() - 2;
+    bytes[response_pos] = 9;
+
+    match C2cConnResp::deserialize(&bytes).unwrap_err() {
+        ProtocolError::InvalidValue { field, value } => {
+            assert_eq!(field, "response");
+            assert_eq!(value, 9);
+        }
+        other => panic!("unexpected error: {:?}", other),
+    }
+}
+
+#[test]
+fn conn_resp_rejects_unknown_key_flag() {
+    let mut bytes = C2cConnResp::new(&uname("Harry"), &uname("Kerry"), ConnResponse::Reject, None).serialize();
+    *bytes.last_mut().unwrap() = 2;
+
+    match C2cConnResp::deserialize(&bytes).unwrap_err() {
+        ProtocolError::InvalidValue { field, value } => {
+            assert_eq!(field, "has_public_key");
+            assert_eq!(value, 2);
+        }
+        other => panic!("unexpected error: {:?}", other),
+    }
+}
+
+#[test]
+fn receipt_round_trip_for_every_kind() {
+    let chat_message = ChatMessage::new(&uname("Harry"), &uname("Eddie"), "hello");
+    for kind in [ReceiptKind::Accepted, ReceiptKind::Delivered, ReceiptKind::Read, ReceiptKind::Rejected] {
+        match round_trip(&Receipt::for_message(kind, &chat_message)) {
+            ProtocolMessage::Receipt(decoded) => {
+                assert_eq!(decoded.kind(), kind);
+                assert_eq!(decoded.message_id(), chat_message.message_id());
+                assert_eq!(decoded.send_uname(), "Harry");
+                assert_eq!(decoded.recv_uname(), "Eddie");
+            }
+            other => panic!("decoded as {:?}", other),
+        }
+    }
+}
+
+#[test]
+fn receipt_rejects_unknown_kind() {
+    let mut bytes = Receipt::new(ReceiptKind::Read, [7u8; 16], &uname("Harry"), &uname("Eddie")).serialize();
+    bytes[0] = 4;
+
+    match Receipt::deserialize(&bytes).unwrap_err() {
+        ProtocolError::InvalidValue { field, value } => {
+            assert_eq!(field, "kind");
+            assert_eq!(value, 4);
+        }
+        other => panic!("unexpected error: {:?}", other),
+    }
+}
+
+#[test]
+fn conn_messages_reject_wrong_length() {
+    let bytes = C2cConnReq::new(&uname("Harry"), &uname("Kerry"), PUBLIC_KEY).serialize();
+    assert!(matches!(
+        C2cConnReq::deserialize(&bytes[..bytes.len() - 1]),
+        Err(ProtocolError::Truncated { expected: 108, actual: 107 })
+    ));
+
+    let mut bytes = C2cConnResp::new(&uname("Harry"), &uname("Kerry"), ConnResponse::Reject, None).serialize();
+    bytes.push(0);
+    assert!(matches!(
+        C2cConnResp::deserialize(&bytes),
+        Err(ProtocolError::LengthMismatch { expected: 14, actual: 15 })
+    ));
+
+    // flagged as having a key, but missing it (and its signature)
+    let bytes = C2cConnResp::new(&uname("Harry"), &uname("Kerry"), ConnResponse::Accept, Some(PUBLIC_KEY)).serialize();
+    assert!(matches!(
+        C2cConnResp::deserialize(&bytes[..bytes.len() - 65]),
+        Err(ProtocolError::Truncated { expected: 46, actual: 45 })
+    ));
+    assert!(matches!(
+        C2cConnResp::deserialize(&bytes[..bytes.len() - 1]),
+        Err(ProtocolError::Truncated { expected: 110, actual: 109 })
+    ));
+}
+
+#[test]
+fn decoded_message_reports_its_type() {
+    let decoded = round_trip(&SignupReq::new(&uname("Eddie"), PUBLIC_KEY));
+    assert_eq!(decoded.message_type(), MessageType::SignupReq);
+}
+
+#[test]
+fn decode_rejects_unknown_method() {
+    let packet = Packet::new(200, 0, Vec::new());
+    assert!(matches!(packet.decode(), Err(ProtocolError::UnknownMethod(200))));
+}
+
+#[test]
+fn decode_rejects_invalid_status_byte() {
+    let packet = Packet::new(MessageType::VerifyResp as u8, 1, vec![42]);
+    assert!(matches!(packet.decode(), Err(ProtocolError::InvalidStatus(42))));
+}
+
+#[test]
+fn decode_rejects_non_utf8_username() {
+    let mut bytes = SignupReq::new(&uname("Eddie"), PUBLIC_KEY).serialize();
+    bytes[1] = 0xff;
+
+    match SignupReq::deserialize(&bytes) {
+        Err(ProtocolError::InvalidUtf8 { field }) => assert_eq!(field, "cli_uname"),
+        other => panic!("unexpected result: {:?}", other),
+    }
+}
+
+#[test]
+fn chat_message_rejects_truncated_body() {
+    let bytes = ChatMessage::new(&uname("Harry"), &uname("Eddie"), "hello").serialize();
+    match ChatMessage::deserialize(&bytes[..bytes.len() - 2]) {
+        // the body should end just before the reply flag
+        Err(ProtocolError::Truncated { expected, actual }) => {
+            assert_eq!(expected, bytes.len() - 1);
+            assert_eq!(actual, bytes.len() - 2);
+        }
+        other => panic!("unexpected result: {:?}", other),
+    }
+}
+
+#[test]
+fn chat_message_rejects_trailing_bytes() {
+    let mut bytes = ChatMessage::new(&uname("Harry"), &uname("Eddie"), "hello").serialize();
+    bytes.push(0);
+    match ChatMessage::deserialize(&bytes) {
+        Err(ProtocolError::LengthMismatch { expected, actual }) => {
+            assert_eq!(expected, bytes.len() - 1);
+            assert_eq!(actual, bytes.len());
+        }
+        other => panic!("unexpected result: {:?}", other),
+    }
+}
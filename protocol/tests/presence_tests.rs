@@ -0,0 +1,99 @@
+use protocol::{Message, Packet, ProtocolError, ProtocolMessage, Username};
+use protocol::{Presence, PresenceState, PresenceUpdate};
+use protocol::{Capabilities, Hello, NegotiatedSession};
+use protocol::message_types::MessageType;
+use protocol::presence;
+
+fn uname(uname: &str) -> Username {
+    Username::new(uname).unwrap()
+}
+
+fn round_trip<M: Message>(message: &M) -> ProtocolMessage {
+    let packet = Packet::wrap(message);
+    assert_eq!(packet.msg_length as usize, message.length());
+
+    Packet::deserialize(&packet.serialize()).unwrap().decode().unwrap()
+}
+
+#[test]
+fn update_round_trip() {
+    match round_trip(&PresenceUpdate::new(PresenceState::Away, "out for lunch").unwrap()) {
+        ProtocolMessage::PresenceUpdate(decoded) => {
+            assert_eq!(decoded.state(), PresenceState::Away);
+            assert_eq!(decoded.status(), "out for lunch");
+        }
+        other => panic!("decoded as {:?}", other),
+    }
+
+    match round_trip(&PresenceUpdate::new(PresenceState::Online, "").unwrap()) {
+        ProtocolMessage::PresenceUpdate(decoded) => {
+            assert_eq!(decoded.state(), PresenceState::Online);
+            assert_eq!(decoded.status(), "");
+        }
+        other => panic!("decoded as {:?}", other),
+    }
+}
+
+#[test]
+fn presence_round_trip() {
+    match round_trip(&Presence::new(&uname("Harry"), PresenceState::Online, "coding").unwrap()) {
+        ProtocolMessage::Presence(decoded) => {
+            assert_eq!(decoded.uname(), &uname("Harry"));
+            assert_eq!(decoded.state(), PresenceState::Online);
+            assert_eq!(decoded.status(), "coding");
+        }
+        other => panic!("decoded as {:?}", other),
+    }
+
+    match round_trip(&Presence::offline(&uname("Eddie"))) {
+        ProtocolMessage::Presence(decoded) => {
+            assert_eq!(decoded.state(), PresenceState::Offline);
+            assert_eq!(decoded.status(), "");
+        }
+        other => panic!("decoded as {:?}", other),
+    }
+}
+
+#[test]
+fn statuses_are_validated() {
+    assert!(presence::validate_status(&"a".repeat(64)).is_ok());
+    assert!(matches!(presence::validate_status(&"a".repeat(65)), Err(ProtocolError::InvalidStatusText(_))));
+    assert!(matches!(presence::validate_status("two\nlines"), Err(ProtocolError::InvalidStatusText(_))));
+    assert!(PresenceUpdate::new(PresenceState::Away, "\u{7}").is_err());
+
+    let mut bytes = PresenceUpdate::new(PresenceState::Away, "ab").unwrap().serialize();
+    *bytes.last_mut().unwrap() = b'\t';
+    assert!(matches!(PresenceUpdate::deserialize(&bytes), Err(ProtocolError::InvalidStatusText(_))));
+}
+
+#[test]
+fn unknown_states_are_rejected() {
+    let mut bytes = PresenceUpdate::new(PresenceState::Online, "").unwrap().serialize();
+    bytes[0] = 3;
+    assert!(matches!(
+        PresenceUpdate::deserialize(&bytes),
+        Err(ProtocolError::InvalidValue { field: "state", value: 3 })
+    ));
+}
+
+#[test]
+fn status_length_must_match() {
+    let mut bytes = PresenceUpdate::new(PresenceState::Away, "brb").unwrap().serialize();
+    bytes.push(b'!');
+    assert!(PresenceUpdate::deserialize(&bytes).is_err());
+
+    let bytes = Presence::new(&uname("Kerry"), PresenceState::Away, "brb").unwrap().serialize();
+    assert!(Presence::deserialize(&bytes[..bytes.len() - 1]).is_err());
+}
+
+#[test]
+fn presence_needs_negotiating() {
+    let hello = Hello::new(Capabilities::PRESENCE);
+    let with_presence = NegotiatedSession::negotiate(&hello, Capabilities::PRESENCE).unwrap();
+    let without_presence = NegotiatedSession::negotiate(&hello, Capabilities::NONE).unwrap();
+
+    assert!(with_presence.allows(MessageType::Presence));
+    assert!(with_presence.allows(MessageType::PresenceUpdate));
+    assert!(!without_presence.allows(MessageType::Presence));
+    assert!(!without_presence.allows(MessageType::PresenceUpdate));
+}
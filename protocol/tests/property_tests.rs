@@ -0,0 +1,343 @@
+use std::io::{self, Cursor, Read};
+
+use proptest::prelude::*;
+
+use protocol::{Message, Packet, PacketReader, ProtocolMessage};
+use protocol::{ChatMessage, VerifyReq, VerifyResp, SignupReq, SignupResp};
+use protocol::{C2cConnReq, C2cConnResp, ConnResponse, Hello, HelloAck, Capabilities};
+use protocol::{Receipt, ReceiptKind, Username, Fragment, Reassembler, Ping, Pong, Envelope};
+use protocol::{IdentityReq, IdentityResp, AuthChallenge};
+use protocol::{PresenceUpdate, Presence};
+use protocol::Typing;
+use protocol::{FileOffer, FileAccept, FileChunk, FileComplete};
+use protocol::{MessageEdit, MessageDelete};
+use protocol::Reaction;
+use protocol::InboxAck;
+use protocol::{HistoryReq, HistoryResp};
+use protocol::{GroupCreate, GroupInfo, GroupInvite, GroupLeave, GroupMessage};
+use protocol::{RotateTokenReq, RotateTokenResp, ListTokensReq, ListTokensResp, RevokeTokenReq, RevokeTokenResp};
+use protocol::status_codes::StatusCode;
+use protocol::field_lens::{MAX_PACKET_LEN, MAX_UNAME_LEN, MIN_UNAME_LEN};
+
+// Hands back the stream in reads of the given sizes, like a real connection
+struct ChunkedReader {
+    inner: Cursor<Vec<u8>>,
+    chunk_lens: Vec<usize>,
+}
+
+impl Read for ChunkedReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = match self.chunk_lens.pop() {
+            Some(chunk_len) => chunk_len.clamp(1, buf.len()),
+            None => buf.len(),
+        };
+        self.inner.read(&mut buf[..len])
+    }
+}
+
+fn uname() -> impl Strategy<Value = Username> {
+    let pattern = format!("[A-Za-z0-9][A-Za-z0-9_.-]{{{},{}}}", MIN_UNAME_LEN - 1, MAX_UNAME_LEN - 1);
+    proptest::string::string_regex(&pattern)
+        .unwrap()
+        .prop_map(|uname| Username::new(&uname).unwrap())
+}
+
+fn status_code() -> impl Strategy<Value = StatusCode> {
+    prop_oneof![
+        Just(StatusCode::Success),
+        Just(StatusCode::Failure),
+        Just(StatusCode::IncompatibleVersion),
+    ]
+}
+
+fn receipt_kind() -> impl Strategy<Value = ReceiptKind> {
+    prop_oneof![
+        Just(ReceiptKind::Accepted),
+        Just(ReceiptKind::Delivered),
+        Just(ReceiptKind::Read),
+        Just(ReceiptKind::Rejected),
+    ]
+}
+
+fn conn_response() -> impl Strategy<Value = ConnResponse> {
+    prop_oneof![
+        Just(ConnResponse::Accept),
+        Just(ConnResponse::Reject),
+        Just(ConnResponse::Block),
+        Just(ConnResponse::AlreadyConnected),
+    ]
+}
+
+// Decoding and re-encoding a message that decoded successfully must give back the same bytes
+fn assert_reencodes<M: Message>(bytes: &[u8]) -> Result<(), TestCaseError> {
+    if let Ok(message) = M::deserialize(bytes) {
+        prop_assert_eq!(message.serialize(), bytes.to_vec());
+        prop_assert_eq!(message.length(), bytes.len());
+    }
+    Ok(())
+}
+
+fn decode_wrapped<M: Message>(message: &M) -> ProtocolMessage {
+    let bytes = Packet::wrap(message).serialize();
+    Packet::deserialize(&bytes).unwrap().decode().unwrap()
+}
+
+proptest! {
+    #[test]
+    fn packet_decode_never_panics(method in any::<u8>(), body in proptest::collection::vec(any::<u8>(), 0..512)) {
+        let _ = Packet::new(method, body.len() as u32, body).decode();
+    }
+
+    #[test]
+    fn packet_deserialize_never_panics(bytes in proptest::collection::vec(any::<u8>(), 0..512)) {
+        if let Ok(packet) = Packet::deserialize(&bytes) {
+            prop_assert_eq!(packet.msg_buffer.len(), packet.msg_length as usize);
+            prop_assert!(packet.length() <= bytes.len());
+        }
+    }
+
+    #[test]
+    fn message_deserialize_never_panics(bytes in proptest::collection::vec(any::<u8>(), 0..256)) {
+        assert_reencodes::<ChatMessage>(&bytes)?;
+        assert_reencodes::<VerifyReq>(&bytes)?;
+        assert_reencodes::<VerifyResp>(&bytes)?;
+        assert_reencodes::<SignupReq>(&bytes)?;
+        assert_reencodes::<SignupResp>(&bytes)?;
+        assert_reencodes::<C2cConnReq>(&bytes)?;
+        assert_reencodes::<C2cConnResp>(&bytes)?;
+        assert_reencodes::<Hello>(&bytes)?;
+        assert_reencodes::<HelloAck>(&bytes)?;
+        assert_reencodes::<Receipt>(&bytes)?;
+        assert_reencodes::<Fragment>(&bytes)?;
+        assert_reencodes::<Ping>(&bytes)?;
+        assert_reencodes::<Pong>(&bytes)?;
+        assert_reencodes::<IdentityReq>(&bytes)?;
+        assert_reencodes::<IdentityResp>(&bytes)?;
+        assert_reencodes::<AuthChallenge>(&bytes)?;
+        assert_reencodes::<RotateTokenReq>(&bytes)?;
+        assert_reencodes::<RotateTokenResp>(&bytes)?;
+        assert_reencodes::<ListTokensReq>(&bytes)?;
+        assert_reencodes::<ListTokensResp>(&bytes)?;
+        assert_reencodes::<RevokeTokenReq>(&bytes)?;
+        assert_reencodes::<RevokeTokenResp>(&bytes)?;
+        assert_reencodes::<GroupCreate>(&bytes)?;
+        assert_reencodes::<GroupInfo>(&bytes)?;
+        assert_reencodes::<GroupInvite>(&bytes)?;
+        assert_reencodes::<GroupLeave>(&bytes)?;
+        assert_reencodes::<GroupMessage>(&bytes)?;
+        assert_reencodes::<PresenceUpdate>(&bytes)?;
+        assert_reencodes::<Presence>(&bytes)?;
+        assert_reencodes::<Typing>(&bytes)?;
+        assert_reencodes::<FileOffer>(&bytes)?;
+        assert_reencodes::<FileAccept>(&bytes)?;
+        assert_reencodes::<FileChunk>(&bytes)?;
+        assert_reencodes::<FileComplete>(&bytes)?;
+        assert_reencodes::<MessageEdit>(&bytes)?;
+        assert_reencodes::<MessageDelete>(&bytes)?;
+        assert_reencodes::<Reaction>(&bytes)?;
+        assert_reencodes::<InboxAck>(&bytes)?;
+        assert_reencodes::<HistoryReq>(&bytes)?;
+        assert_reencodes::<HistoryResp>(&bytes)?;
+
+        if let Ok(envelope) = Envelope::deserialize(&bytes) {
+            prop_assert_eq!(envelope.serialize(), bytes.clone());
+            prop_assert_eq!(envelope.length(), bytes.len());
+        }
+    }
+
+    #[test]
+    fn reader_never_panics_on_arbitrary_stream(
+        bytes in proptest::collection::vec(any::<u8>(), 0..2048),
+        chunk_lens in proptest::collection::vec(1usize..64, 0..32),
+    ) {
+        let mut reader = PacketReader::new(ChunkedReader {
+            inner: Cursor::new(bytes.clone()),
+            chunk_lens,
+        });
+        while let Ok(packet) = reader.read_packet() {
+            prop_assert!(packet.length() <= MAX_PACKET_LEN);
+            let _ = packet.decode();
+        }
+
+        let _ = protocol::read_packet(&mut Cursor::new(bytes));
+    }
+
+    #[test]
+    fn packets_survive_arbitrary_read_boundaries(
+        bodies in proptest::collection::vec(proptest::collection::vec(any::<u8>(), 0..256), 1..8),
+        chunk_lens in proptest::collection::vec(1usize..64, 0..64),
+    ) {
+        let packets: Vec<Packet> = bodies
+            .into_iter()
+            .map(|body| Packet::new(0, body.len() as u32, body))
+            .collect();
+        let bytes: Vec<u8> = packets.iter().flat_map(|packet| packet.serialize()).collect();
+
+        let mut reader = PacketReader::new(ChunkedReader {
+            inner: Cursor::new(bytes),
+            chunk_lens,
+        });
+        for packet in &packets {
+            let read = reader.read_packet().unwrap();
+            prop_assert_eq!(&read.msg_buffer, &packet.msg_buffer);
+        }
+        prop_assert_eq!(reader.buffered_len(), 0);
+    }
+
+    #[test]
+    fn chat_message_round_trip(
+        send in uname(),
+        recv in uname(),
+        body in "\\PC{0,200}",
+        message_id in any::<[u8; 16]>(),
+        timestamp in any::<u64>(),
+        signature in proptest::collection::vec(any::<u8>(), 64),
+    ) {
+        let mut chat_message = ChatMessage::new(&send, &recv, &body);
+        chat_message.message_id = message_id;
+        chat_message.timestamp = timestamp;
+        chat_message.signature.copy_from_slice(&signature);
+        match decode_wrapped(&chat_message) {
+            ProtocolMessage::ChatMessage(decoded) => {
+                prop_assert_eq!(decoded.message_id(), message_id);
+                prop_assert_eq!(decoded.timestamp(), timestamp);
+                prop_assert_eq!(decoded.signature.to_vec(), signature);
+                prop_assert_eq!(decoded.send_uname, send);
+                prop_assert_eq!(decoded.recv_uname, recv);
+                prop_assert_eq!(decoded.msg_buffer, body.into_bytes());
+            }
+            other => prop_assert!(false, "unexpected message: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn verify_round_trip(uname in uname(), halves in any::<([u8; 32], [u8; 32])>(), status in status_code()) {
+        let proof: [u8; 64] = [halves.0, halves.1].concat().try_into().unwrap();
+        let req = VerifyReq::new(&uname, proof);
+        match decode_wrapped(&req) {
+            ProtocolMessage::VerifyReq(decoded) => {
+                prop_assert_eq!(decoded.cli_uname, uname);
+                prop_assert_eq!(decoded.proof, proof);
+            }
+            other => prop_assert!(false, "unexpected message: {:?}", other),
+        }
+
+        match decode_wrapped(&VerifyResp::new(status)) {
+            ProtocolMessage::VerifyResp(decoded) => prop_assert_eq!(decoded.status_code, status),
+            other => prop_assert!(false, "unexpected message: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn signup_round_trip(uname in uname(), identity_key in any::<[u8; 32]>(), status in status_code()) {
+        match decode_wrapped(&SignupReq::new(&uname, identity_key)) {
+            ProtocolMessage::SignupReq(decoded) => {
+                prop_assert_eq!(decoded.uname(), &uname);
+                prop_assert_eq!(decoded.identity_key(), &identity_key);
+            }
+            other => prop_assert!(false, "unexpected message: {:?}", other),
+        }
+
+        let resp = SignupResp::new(status);
+        match decode_wrapped(&resp) {
+            ProtocolMessage::SignupResp(decoded) => {
+                prop_assert_eq!(decoded.status_code(), status);
+                prop_assert_eq!(decoded.token(), resp.token());
+            }
+            other => prop_assert!(false, "unexpected message: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn connect_round_trip(
+        req in uname(),
+        resp in uname(),
+        response in conn_response(),
+        public_key in any::<[u8; 32]>(),
+        resp_public_key in proptest::option::of(any::<[u8; 32]>())
+    ) {
+        match decode_wrapped(&C2cConnReq::new(&req, &resp, public_key)) {
+            ProtocolMessage::C2cConnReq(decoded) => {
+                prop_assert_eq!(decoded.req_uname(), &req);
+                prop_assert_eq!(decoded.resp_uname(), &resp);
+                prop_assert_eq!(decoded.public_key(), &public_key);
+            }
+            other => prop_assert!(false, "unexpected message: {:?}", other),
+        }
+
+        match decode_wrapped(&C2cConnResp::new(&req, &resp, response, resp_public_key)) {
+            ProtocolMessage::C2cConnResp(decoded) => {
+                prop_assert_eq!(decoded.req_uname(), &req);
+                prop_assert_eq!(decoded.resp_uname(), &resp);
+                prop_assert_eq!(decoded.response(), response);
+                prop_assert_eq!(decoded.public_key(), resp_public_key.as_ref());
+            }
+            other => prop_assert!(false, "unexpected message: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn hello_round_trip(version in any::<u16>(), bits in any::<u32>(), status in status_code()) {
+        let capabilities = Capabilities::from_bits(bits);
+        match decode_wrapped(&Hello::new(capabilities)) {
+            ProtocolMessage::Hello(decoded) => prop_assert_eq!(decoded.capabilities(), capabilities),
+            other => prop_assert!(false, "unexpected message: {:?}", other),
+        }
+
+        match decode_wrapped(&HelloAck::new(status, version, capabilities)) {
+            ProtocolMessage::HelloAck(decoded) => {
+                prop_assert_eq!(decoded.status_code(), status);
+                prop_assert_eq!(decoded.version(), version);
+                prop_assert_eq!(decoded.capabilities(), capabilities);
+            }
+            other => prop_assert!(false, "unexpected message: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn receipt_round_trip(kind in receipt_kind(), message_id in any::<[u8; 16]>(), send in uname(), recv in uname()) {
+        match decode_wrapped(&Receipt::new(kind, message_id, &send, &recv)) {
+            ProtocolMessage::Receipt(decoded) => {
+                prop_assert_eq!(decoded.kind(), kind);
+                prop_assert_eq!(decoded.message_id(), message_id);
+                prop_assert_eq!(decoded.send_uname(), &send);
+                prop_assert_eq!(decoded.recv_uname(), &recv);
+            }
+            other => prop_assert!(false, "unexpected message: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn fragments_reassemble_in_any_order(
+        body in proptest::collection::vec(any::<u8>(), 0..8192),
+        max_packet_len in 64usize..=MAX_PACKET_LEN,
+        order in any::<proptest::sample::Index>(),
+    ) {
+        let packet = Packet::new(0, body.len() as u32, body);
+        let mut fragments = Fragment::split(&packet, max_packet_len).unwrap();
+        for fragment in &fragments {
+            prop_assert!(Packet::wrap(fragment).length() <= max_packet_len);
+        }
+
+        // move one fragment to the end, so it completes the packet
+        let last = fragments.remove(order.index(fragments.len()));
+        fragments.push(last);
+
+        let mut reassembler = Reassembler::new();
+        let mut reassembled = None;
+        for fragment in fragments {
+            prop_assert!(reassembled.is_none());
+            reassembled = reassembler.add(fragment).unwrap();
+        }
+        prop_assert_eq!(reassembled.unwrap().serialize(), packet.serialize());
+        prop_assert_eq!(reassembler.buffered_len(), 0);
+    }
+
+    #[test]
+    fn username_new_never_panics(uname in "\\PC{0,64}") {
+        if let Ok(username) = Username::new(&uname) {
+            prop_assert_eq!(username.as_str(), uname.as_str());
+            prop_assert!(!uname.contains(['/', '\\', '\0']));
+        }
+    }
+}
@@ -0,0 +1,98 @@
+use protocol::{Message, Packet, ProtocolError, ProtocolMessage, Username, Reaction, ReactionAction};
+use protocol::{Capabilities, Hello, NegotiatedSession, IdentityKeyPair, MessageDelete};
+use protocol::message_types::MessageType;
+
+fn uname(uname: &str) -> Username {
+    Username::new(uname).unwrap()
+}
+
+fn round_trip<M: Message>(message: &M) -> ProtocolMessage {
+    let packet = Packet::wrap(message);
+    assert_eq!(packet.msg_length as usize, message.length());
+
+    Packet::deserialize(&packet.serialize()).unwrap().decode().unwrap()
+}
+
+#[test]
+fn reaction_round_trip_and_signature() {
+    let identity = IdentityKeyPair::generate();
+    for action in [ReactionAction::Add, ReactionAction::Remove] {
+        let mut reaction = Reaction::new([7u8; 16], &uname("Harry"), &uname("Eddie"), action, "👍").unwrap();
+        reaction.sign(&identity);
+        match round_trip(&reaction) {
+            ProtocolMessage::Reaction(decoded) => {
+                assert_eq!(decoded.message_id(), [7u8; 16]);
+                assert_eq!(decoded.timestamp(), reaction.timestamp());
+                assert_eq!(decoded.send_uname(), &uname("Harry"));
+                assert_eq!(decoded.recv_uname(), &uname("Eddie"));
+                assert_eq!(decoded.action(), action);
+                assert_eq!(decoded.reaction(), "👍");
+                assert!(decoded.verify_signature(&identity.identity_key()));
+                assert!(!decoded.verify_signature(&IdentityKeyPair::generate().identity_key()));
+            }
+            other => panic!("decoded as {:?}", other),
+        }
+    }
+}
+
+#[test]
+fn bad_reactions_are_rejected() {
+    for reaction in ["", "thumbs up", "tab\t", &"x".repeat(33)] {
+        assert!(matches!(
+            Reaction::new([7u8; 16], &uname("Harry"), &uname("Eddie"), ReactionAction::Add, reaction),
+            Err(ProtocolError::InvalidReaction(_))
+        ));
+    }
+    assert!(Reaction::new([7u8; 16], &uname("Harry"), &uname("Eddie"), ReactionAction::Add, &"x".repeat(32)).is_ok());
+}
+
+#[test]
+fn bad_action_is_rejected() {
+    let reaction = Reaction::new([7u8; 16], &uname("Harry"), &uname("Eddie"), ReactionAction::Add, "+1").unwrap();
+    let mut bytes = reaction.serialize();
+
+    // action comes just before the reaction's length prefix
+    let action_pos = bytes.len() - 4;
+    bytes[action_pos] = 2;
+    assert!(matches!(
+        Reaction::deserialize(&bytes),
+        Err(ProtocolError::InvalidValue { field: "action", value: 2 })
+    ));
+}
+
+#[test]
+fn signature_covers_the_reaction() {
+    let identity = IdentityKeyPair::generate();
+    let mut reaction = Reaction::new([7u8; 16], &uname("Harry"), &uname("Eddie"), ReactionAction::Add, "+1").unwrap();
+    reaction.sign(&identity);
+
+    // e.g. the server turning an added reaction into a removal, or changing it
+    let mut bytes = reaction.serialize();
+    let action_pos = bytes.len() - 4;
+    bytes[action_pos] = ReactionAction::Remove as u8;
+    assert!(!Reaction::deserialize(&bytes).unwrap().verify_signature(&identity.identity_key()));
+
+    let mut bytes = reaction.serialize();
+    let last = bytes.len() - 1;
+    bytes[last] = b'2';
+    assert!(!Reaction::deserialize(&bytes).unwrap().verify_signature(&identity.identity_key()));
+}
+
+#[test]
+fn reaction_signature_doesnt_pass_as_deletion() {
+    let identity = IdentityKeyPair::generate();
+    let mut reaction = Reaction::new([7u8; 16], &uname("Harry"), &uname("Eddie"), ReactionAction::Remove, "+1").unwrap();
+    reaction.sign(&identity);
+
+    // a deletion of the same message, carrying the reaction's signature
+    let mut bytes = MessageDelete::new([7u8; 16], &uname("Harry"), &uname("Eddie")).serialize();
+    bytes[..88].copy_from_slice(&reaction.serialize()[..88]);
+    assert!(!MessageDelete::deserialize(&bytes).unwrap().verify_signature(&identity.identity_key()));
+}
+
+#[test]
+fn reactions_need_negotiating() {
+    let hello = Hello::new(Capabilities::REACTIONS);
+    assert!(NegotiatedSession::negotiate(&hello, Capabilities::REACTIONS).unwrap().allows(MessageType::Reaction));
+    assert!(!NegotiatedSession::negotiate(&hello, Capabilities::NONE).unwrap().allows(MessageType::Reaction));
+}
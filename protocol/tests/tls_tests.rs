@@ -0,0 +1,161 @@
+use std::io::Write;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+use rcgen::CertifiedKey;
+use protocol::{AuthChallenge, Packet, PacketReader, PacketWriter, ProtocolMessage, Stream, TlsError, Username, VerifyReq};
+use protocol::rustls::ServerConfig;
+use protocol::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use protocol::{auth, shared, tls};
+
+struct TestCert {
+    cert: CertificateDer<'static>,
+    key: PrivateKeyDer<'static>,
+    cert_pem: String,
+    key_pem: String,
+}
+
+fn self_signed(name: &str) -> TestCert {
+    let CertifiedKey { cert, key_pair } = rcgen::generate_simple_self_signed(vec![name.to_string()]).unwrap();
+    TestCert {
+        cert: cert.der().clone(),
+        key: PrivateKeyDer::Pkcs8(key_pair.serialize_der().into()),
+        cert_pem: cert.pem(),
+        key_pem: key_pair.serialize_pem(),
+    }
+}
+
+// Accepts one TLS connection and echoes back the first packet it receives
+fn echo_server(config: Arc<ServerConfig>) -> (SocketAddr, JoinHandle<Result<(), String>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let handle = thread::spawn(move || {
+        let (tcp, _) = listener.accept().map_err(|e| e.to_string())?;
+        let stream = Stream::tls_server(tcp, config).map_err(|e| e.to_string())?;
+        let mut reader = PacketReader::new(stream.try_clone().map_err(|e| e.to_string())?);
+        let mut writer = PacketWriter::new(stream);
+
+        let packet = reader.read_packet().map_err(|e| e.to_string())?;
+        writer.write_packet(&packet).map_err(|e| e.to_string())
+    });
+
+    (addr, handle)
+}
+
+// Sends a VerifyReq over the stream and checks it comes back intact
+fn assert_echoes(stream: Stream) {
+    assert!(stream.is_tls());
+    let uname = Username::new("Harry").unwrap();
+    let proof = auth::prove(&shared::generate_token(), AuthChallenge::new().challenge(), &uname);
+
+    let mut reader = PacketReader::new(stream.try_clone().unwrap());
+    let mut writer = PacketWriter::new(stream);
+    writer.write_packet(&Packet::wrap(&VerifyReq::new(&uname, proof))).unwrap();
+
+    match reader.read_packet().unwrap().decode().unwrap() {
+        ProtocolMessage::VerifyReq(echoed) => {
+            assert_eq!(echoed.cli_uname, uname);
+            assert_eq!(echoed.proof, proof);
+        }
+        other => panic!("unexpected message: {:?}", other),
+    }
+}
+
+#[test]
+fn client_trusting_server_ca_can_talk() {
+    let server_cert = self_signed("localhost");
+    let server_config = tls::server_config_from_der(vec![server_cert.cert.clone()], server_cert.key).unwrap();
+    let (addr, server) = echo_server(server_config);
+
+    let client_config = tls::client_config_with_roots(vec![server_cert.cert]).unwrap();
+    let stream = Stream::tls_client(TcpStream::connect(addr).unwrap(), client_config, "localhost").unwrap();
+    assert_echoes(stream);
+    server.join().unwrap().unwrap();
+}
+
+#[test]
+fn client_rejects_untrusted_server() {
+    let server_cert = self_signed("localhost");
+    let other_cert = self_signed("localhost");
+    let server_config = tls::server_config_from_der(vec![server_cert.cert], server_cert.key).unwrap();
+    let (addr, server) = echo_server(server_config);
+
+    let client_config = tls::client_config_with_roots(vec![other_cert.cert]).unwrap();
+    let result = Stream::tls_client(TcpStream::connect(addr).unwrap(), client_config, "localhost");
+    assert!(result.is_err());
+    assert!(server.join().unwrap().is_err());
+}
+
+#[test]
+fn client_rejects_certificate_for_another_name() {
+    let server_cert = self_signed("chat.example.com");
+    let server_config = tls::server_config_from_der(vec![server_cert.cert.clone()], server_cert.key).unwrap();
+    let (addr, server) = echo_server(server_config);
+
+    let client_config = tls::client_config_with_roots(vec![server_cert.cert]).unwrap();
+    let result = Stream::tls_client(TcpStream::connect(addr).unwrap(), client_config, "localhost");
+    assert!(result.is_err());
+    assert!(server.join().unwrap().is_err());
+}
+
+#[test]
+fn pinned_certificate_is_accepted_whatever_its_name() {
+    let server_cert = self_signed("not-the-server-name");
+    let fingerprint = tls::cert_fingerprint(&server_cert.cert);
+    let server_config = tls::server_config_from_der(vec![server_cert.cert], server_cert.key).unwrap();
+    let (addr, server) = echo_server(server_config);
+
+    let client_config = tls::client_config_with_pin(fingerprint).unwrap();
+    let stream = Stream::tls_client(TcpStream::connect(addr).unwrap(), client_config, "localhost").unwrap();
+    assert_echoes(stream);
+    server.join().unwrap().unwrap();
+}
+
+#[test]
+fn wrong_pin_is_rejected() {
+    let server_cert = self_signed("localhost");
+    let other_cert = self_signed("localhost");
+    let server_config = tls::server_config_from_der(vec![server_cert.cert], server_cert.key).unwrap();
+    let (addr, server) = echo_server(server_config);
+
+    let client_config = tls::client_config_with_pin(tls::cert_fingerprint(&other_cert.cert)).unwrap();
+    let result = Stream::tls_client(TcpStream::connect(addr).unwrap(), client_config, "localhost");
+    assert!(matches!(result, Err(TlsError::Io(_)) | Err(TlsError::Rustls(_))));
+    assert!(server.join().unwrap().is_err());
+}
+
+#[test]
+fn configs_load_from_pem_files() {
+    let server_cert = self_signed("localhost");
+    let dir = tempfile::tempdir().unwrap();
+    let cert_path = dir.path().join("cert.pem");
+    let key_path = dir.path().join("key.pem");
+    std::fs::File::create(&cert_path).unwrap().write_all(server_cert.cert_pem.as_bytes()).unwrap();
+    std::fs::File::create(&key_path).unwrap().write_all(server_cert.key_pem.as_bytes()).unwrap();
+
+    let server_config = tls::server_config(&cert_path, &key_path).unwrap();
+    let (addr, server) = echo_server(server_config);
+
+    let client_config = tls::client_config_with_ca(&cert_path).unwrap();
+    let stream = Stream::tls_client(TcpStream::connect(addr).unwrap(), client_config, "localhost").unwrap();
+    assert_echoes(stream);
+    server.join().unwrap().unwrap();
+
+    // cert and key files mixed up
+    assert!(matches!(tls::load_private_key(&cert_path), Err(TlsError::NoPrivateKey { .. })));
+    assert!(matches!(tls::load_certs(&key_path), Err(TlsError::NoCertificates { .. })));
+    assert!(tls::load_certs(&dir.path().join("missing.pem")).is_err());
+}
+
+#[test]
+fn fingerprints_parse_from_hex() {
+    let fingerprint = tls::cert_fingerprint(&self_signed("localhost").cert);
+    let hex: String = fingerprint.iter().map(|byte| format!("{:02x}", byte)).collect();
+    let openssl_style = fingerprint.iter().map(|byte| format!("{:02X}", byte)).collect::<Vec<_>>().join(":");
+
+    assert_eq!(tls::parse_fingerprint(&hex).unwrap(), fingerprint);
+    assert_eq!(tls::parse_fingerprint(&openssl_style).unwrap(), fingerprint);
+    assert!(matches!(tls::parse_fingerprint(&hex[2..]), Err(TlsError::InvalidFingerprint(_))));
+    assert!(matches!(tls::parse_fingerprint(&"zz".repeat(32)), Err(TlsError::InvalidFingerprint(_))));
+}
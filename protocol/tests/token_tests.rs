@@ -0,0 +1,126 @@
+use protocol::{Message, Packet, ProtocolError, ProtocolMessage};
+use protocol::{RotateTokenReq, RotateTokenResp, ListTokensReq, ListTokensResp, TokenInfo};
+use protocol::{RevokeTokenReq, RevokeTokenResp};
+use protocol::shared;
+use protocol::status_codes::StatusCode;
+
+fn round_trip<M: Message>(message: &M) -> ProtocolMessage {
+    let packet = Packet::wrap(message);
+    assert_eq!(packet.msg_length as usize, message.length());
+
+    Packet::deserialize(&packet.serialize()).unwrap().decode().unwrap()
+}
+
+fn token_info(current: bool) -> TokenInfo {
+    TokenInfo {
+        token_id: shared::generate_token_id(),
+        issued_at: shared::timestamp_now(),
+        current,
+    }
+}
+
+#[test]
+fn rotate_round_trip() {
+    for keep_current in [false, true] {
+        match round_trip(&RotateTokenReq::new(keep_current)) {
+            ProtocolMessage::RotateTokenReq(decoded) => assert_eq!(decoded.keep_current(), keep_current),
+            other => panic!("decoded as {:?}", other),
+        }
+    }
+
+    let rotate_resp = RotateTokenResp::new(StatusCode::Success);
+    match round_trip(&rotate_resp) {
+        ProtocolMessage::RotateTokenResp(decoded) => {
+            assert_eq!(decoded.status_code(), StatusCode::Success);
+            assert_eq!(decoded.token_id(), rotate_resp.token_id());
+            assert_eq!(decoded.token(), rotate_resp.token());
+        }
+        other => panic!("decoded as {:?}", other),
+    }
+}
+
+#[test]
+fn rotate_resp_debug_leaves_out_token() {
+    let rotate_resp = RotateTokenResp::new(StatusCode::Success);
+    let debug = format!("{:?}", rotate_resp);
+    assert!(debug.contains(&shared::token_id_to_string(rotate_resp.token_id())));
+    assert!(!debug.contains(&shared::token_to_string(rotate_resp.token())));
+}
+
+#[test]
+fn rotate_req_rejects_unknown_flag() {
+    match RotateTokenReq::deserialize(&[2]).unwrap_err() {
+        ProtocolError::InvalidValue { field, value } => {
+            assert_eq!(field, "keep_current");
+            assert_eq!(value, 2);
+        }
+        other => panic!("unexpected error: {:?}", other),
+    }
+}
+
+#[test]
+fn list_round_trip() {
+    assert!(matches!(round_trip(&ListTokensReq::new()), ProtocolMessage::ListTokensReq(_)));
+
+    for tokens in [vec![], vec![token_info(true)], vec![token_info(false), token_info(true), token_info(false)]] {
+        match round_trip(&ListTokensResp::new(tokens.clone())) {
+            ProtocolMessage::ListTokensResp(decoded) => assert_eq!(decoded.tokens(), &tokens[..]),
+            other => panic!("decoded as {:?}", other),
+        }
+    }
+}
+
+#[test]
+fn list_resp_rejects_wrong_length() {
+    let bytes = ListTokensResp::new(vec![token_info(false), token_info(true)]).serialize();
+    assert!(matches!(
+        ListTokensResp::deserialize(&bytes[..bytes.len() - 1]),
+        Err(ProtocolError::Truncated { expected: 36, actual: 35 })
+    ));
+
+    let mut bytes = bytes;
+    bytes.push(0);
+    assert!(matches!(
+        ListTokensResp::deserialize(&bytes),
+        Err(ProtocolError::LengthMismatch { expected: 36, actual: 37 })
+    ));
+
+    let mut bytes = ListTokensResp::new(vec![token_info(false)]).serialize();
+    *bytes.last_mut().unwrap() = 7;
+    match ListTokensResp::deserialize(&bytes).unwrap_err() {
+        ProtocolError::InvalidValue { field, value } => {
+            assert_eq!(field, "current");
+            assert_eq!(value, 7);
+        }
+        other => panic!("unexpected error: {:?}", other),
+    }
+}
+
+#[test]
+fn revoke_round_trip() {
+    let token_id = shared::generate_token_id();
+    match round_trip(&RevokeTokenReq::new(token_id)) {
+        ProtocolMessage::RevokeTokenReq(decoded) => assert_eq!(decoded.token_id(), token_id),
+        other => panic!("decoded as {:?}", other),
+    }
+
+    for status_code in [StatusCode::Success, StatusCode::Failure] {
+        match round_trip(&RevokeTokenResp::new(status_code, token_id)) {
+            ProtocolMessage::RevokeTokenResp(decoded) => {
+                assert_eq!(decoded.status_code(), status_code);
+                assert_eq!(decoded.token_id(), token_id);
+            }
+            other => panic!("decoded as {:?}", other),
+        }
+    }
+}
+
+#[test]
+fn token_id_string_round_trip() {
+    let token_id = shared::generate_token_id();
+    let hex = shared::token_id_to_string(token_id);
+    assert_eq!(shared::token_id_from_string(&hex), Some(token_id));
+
+    assert_eq!(shared::token_id_from_string(&hex[1..]), None);
+    assert_eq!(shared::token_id_from_string(&hex.replace(&hex[..1], "g")), None);
+}
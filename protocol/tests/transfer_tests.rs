@@ -0,0 +1,104 @@
+use protocol::{Message, Packet, ProtocolError, ProtocolMessage, Username, Envelope};
+use protocol::{FileOffer, FileAccept, FileChunk, FileComplete};
+use protocol::{Capabilities, Hello, NegotiatedSession};
+use protocol::field_lens::{FILE_CHUNK_LEN, MAX_FILE_LEN, MAX_PACKET_LEN, MAX_UNAME_LEN, AEAD_TAG_LEN};
+use protocol::message_types::MessageType;
+use protocol::status_codes::StatusCode;
+use protocol::transfer;
+
+fn uname(uname: &str) -> Username {
+    Username::new(uname).unwrap()
+}
+
+fn round_trip<M: Message>(message: &M) -> ProtocolMessage {
+    let packet = Packet::wrap(message);
+    assert_eq!(packet.msg_length as usize, message.length());
+
+    Packet::deserialize(&packet.serialize()).unwrap().decode().unwrap()
+}
+
+#[test]
+fn offer_round_trip() {
+    let offer = FileOffer::new([1u8; 16], &uname("Harry"), &uname("Eddie"), 1234, vec![9u8; 80]).unwrap();
+    match round_trip(&offer) {
+        ProtocolMessage::FileOffer(decoded) => {
+            assert_eq!(decoded.transfer_id(), [1u8; 16]);
+            assert_eq!(decoded.send_uname(), &uname("Harry"));
+            assert_eq!(decoded.recv_uname(), &uname("Eddie"));
+            assert_eq!(decoded.file_size(), 1234);
+            assert_eq!(decoded.sealed_info(), &[9u8; 80][..]);
+        }
+        other => panic!("decoded as {:?}", other),
+    }
+}
+
+#[test]
+fn accept_chunk_and_complete_round_trip() {
+    match round_trip(&FileAccept::new([1u8; 16], &uname("Harry"), &uname("Eddie"), 17)) {
+        ProtocolMessage::FileAccept(decoded) => {
+            assert_eq!(decoded.transfer_id(), [1u8; 16]);
+            assert_eq!(decoded.next_chunk(), 17);
+        }
+        other => panic!("decoded as {:?}", other),
+    }
+
+    match round_trip(&FileChunk::new([1u8; 16], &uname("Harry"), &uname("Eddie"), 17, vec![5u8; 100])) {
+        ProtocolMessage::FileChunk(decoded) => {
+            assert_eq!(decoded.index(), 17);
+            assert_eq!(decoded.data(), &[5u8; 100][..]);
+        }
+        other => panic!("decoded as {:?}", other),
+    }
+
+    match round_trip(&FileComplete::new([1u8; 16], &uname("Harry"), &uname("Eddie"), StatusCode::Success)) {
+        ProtocolMessage::FileComplete(decoded) => {
+            assert_eq!(decoded.send_uname(), &uname("Harry"));
+            assert_eq!(decoded.recv_uname(), &uname("Eddie"));
+            assert_eq!(decoded.status_code(), StatusCode::Success);
+        }
+        other => panic!("decoded as {:?}", other),
+    }
+}
+
+#[test]
+fn oversized_files_are_rejected() {
+    let too_large = MAX_FILE_LEN as u64 + 1;
+    assert!(matches!(
+        FileOffer::new([1u8; 16], &uname("Harry"), &uname("Eddie"), too_large, Vec::new()),
+        Err(ProtocolError::FileTooLarge { .. })
+    ));
+
+    let mut bytes = FileOffer::new([1u8; 16], &uname("Harry"), &uname("Eddie"), 0, Vec::new()).unwrap().serialize();
+    let size_pos = bytes.len() - 8;
+    bytes[size_pos..].copy_from_slice(&too_large.to_be_bytes());
+    assert!(matches!(FileOffer::deserialize(&bytes), Err(ProtocolError::FileTooLarge { .. })));
+}
+
+#[test]
+fn files_are_sent_in_whole_chunks() {
+    assert_eq!(transfer::chunk_count(0), 0);
+    assert_eq!(transfer::chunk_count(1), 1);
+    assert_eq!(transfer::chunk_count(FILE_CHUNK_LEN as u64), 1);
+    assert_eq!(transfer::chunk_count(FILE_CHUNK_LEN as u64 + 1), 2);
+}
+
+#[test]
+fn full_sealed_chunk_fits_in_a_packet() {
+    let longest = uname(&"a".repeat(MAX_UNAME_LEN));
+    let sealed = Envelope::new(1, [0u8; 24], vec![0u8; FILE_CHUNK_LEN + AEAD_TAG_LEN]).serialize();
+    let chunk = FileChunk::new([1u8; 16], &longest, &longest, u32::MAX, sealed);
+
+    assert!(Packet::wrap(&chunk).length() <= MAX_PACKET_LEN);
+}
+
+#[test]
+fn transfers_need_negotiating() {
+    let hello = Hello::new(Capabilities::FILE_TRANSFER);
+    let with_transfer = NegotiatedSession::negotiate(&hello, Capabilities::FILE_TRANSFER).unwrap();
+    let without_transfer = NegotiatedSession::negotiate(&hello, Capabilities::NONE).unwrap();
+
+    for message_type in [MessageType::FileOffer, MessageType::FileAccept, MessageType::FileChunk, MessageType::FileComplete] {
+        assert!(with_transfer.allows(message_type));
+        assert!(!without_transfer.allows(message_type));
+    }
+}
@@ -0,0 +1,48 @@
+use protocol::{Message, Packet, ProtocolError, ProtocolMessage, Username, Typing};
+use protocol::{Capabilities, Hello, NegotiatedSession};
+use protocol::message_types::MessageType;
+
+fn uname(uname: &str) -> Username {
+    Username::new(uname).unwrap()
+}
+
+fn round_trip<M: Message>(message: &M) -> ProtocolMessage {
+    let packet = Packet::wrap(message);
+    assert_eq!(packet.msg_length as usize, message.length());
+
+    Packet::deserialize(&packet.serialize()).unwrap().decode().unwrap()
+}
+
+#[test]
+fn typing_round_trip() {
+    for typing in [true, false] {
+        match round_trip(&Typing::new(&uname("Harry"), &uname("Eddie"), typing)) {
+            ProtocolMessage::Typing(decoded) => {
+                assert_eq!(decoded.send_uname(), &uname("Harry"));
+                assert_eq!(decoded.recv_uname(), &uname("Eddie"));
+                assert_eq!(decoded.typing(), typing);
+            }
+            other => panic!("decoded as {:?}", other),
+        }
+    }
+}
+
+#[test]
+fn bad_flag_is_rejected() {
+    let mut bytes = Typing::new(&uname("Harry"), &uname("Eddie"), true).serialize();
+    *bytes.last_mut().unwrap() = 2;
+    assert!(matches!(
+        Typing::deserialize(&bytes),
+        Err(ProtocolError::InvalidValue { field: "typing", value: 2 })
+    ));
+
+    bytes.pop();
+    assert!(Typing::deserialize(&bytes).is_err());
+}
+
+#[test]
+fn typing_needs_negotiating() {
+    let hello = Hello::new(Capabilities::TYPING);
+    assert!(NegotiatedSession::negotiate(&hello, Capabilities::TYPING).unwrap().allows(MessageType::Typing));
+    assert!(!NegotiatedSession::negotiate(&hello, Capabilities::NONE).unwrap().allows(MessageType::Typing));
+}
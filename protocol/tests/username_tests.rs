@@ -0,0 +1,62 @@
+use protocol::{Message, ProtocolError, SignupReq, Username, UsernameError};
+use protocol::field_lens::{MAX_UNAME_LEN, MIN_UNAME_LEN};
+
+#[test]
+fn accepts_valid_usernames() {
+    for uname in ["Harry", "eddie_99", "k.e-r_r.y", "ab", &"x".repeat(MAX_UNAME_LEN)] {
+        assert_eq!(Username::new(uname).unwrap().as_str(), uname);
+    }
+}
+
+#[test]
+fn rejects_bad_lengths() {
+    assert_eq!(Username::new(""), Err(UsernameError::TooShort { len: 0 }));
+    assert_eq!(
+        Username::new(&"a".repeat(MIN_UNAME_LEN - 1)),
+        Err(UsernameError::TooShort { len: MIN_UNAME_LEN - 1 })
+    );
+    assert_eq!(
+        Username::new(&"a".repeat(MAX_UNAME_LEN + 1)),
+        Err(UsernameError::TooLong { len: MAX_UNAME_LEN + 1 })
+    );
+}
+
+#[test]
+fn rejects_nul_path_and_other_characters() {
+    assert_eq!(Username::new("ha\0rry"), Err(UsernameError::InvalidChar('\0')));
+    assert_eq!(Username::new("../etc"), Err(UsernameError::InvalidChar('/')));
+    assert_eq!(Username::new("a\\b"), Err(UsernameError::InvalidChar('\\')));
+    assert_eq!(Username::new("har ry"), Err(UsernameError::InvalidChar(' ')));
+    assert_eq!(Username::new("hårry"), Err(UsernameError::InvalidChar('å')));
+}
+
+#[test]
+fn rejects_names_not_starting_with_letter_or_digit() {
+    assert_eq!(Username::new(".."), Err(UsernameError::InvalidStart('.')));
+    assert_eq!(Username::new("-rf"), Err(UsernameError::InvalidStart('-')));
+    assert_eq!(Username::new("_harry"), Err(UsernameError::InvalidStart('_')));
+}
+
+#[test]
+fn encodes_with_length_prefix() {
+    let uname = Username::new("Harry").unwrap();
+    let mut buffer = Vec::new();
+    uname.encode(&mut buffer);
+
+    assert_eq!(buffer, b"\x05Harry");
+    assert_eq!(uname.encoded_len(), buffer.len());
+}
+
+#[test]
+fn decode_rejects_invalid_username() {
+    let mut bytes = SignupReq::new(&Username::new("Eddie").unwrap(), [0u8; 32]).serialize();
+    bytes[1] = b'.';
+
+    match SignupReq::deserialize(&bytes).unwrap_err() {
+        ProtocolError::InvalidUsername { field, reason } => {
+            assert_eq!(field, "cli_uname");
+            assert_eq!(reason, UsernameError::InvalidStart('.'));
+        }
+        other => panic!("unexpected error: {:?}", other),
+    }
+}
@@ -0,0 +1,234 @@
+/*
+Accounts, and who is connected with or has blocked whom, kept on disk so users
+can still verify (and be sent messages) after the server restarts.
+
+Each account is a file in the accounts directory, named after its user, holding
+their identity key then their active tokens; it's rewritten whenever their
+tokens change. Connections and blocks are only ever added, so each is a single
+file of username pairs, appended to as they're made. Groups change as members
+come and go, so all of them are kept in one file, rewritten whenever one does.
+*/
+
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use protocol::{Decoder, GroupInfo, Message, ProtocolError, Username};
+use protocol::field_lens::{VERIFIER_LEN, IDENTITY_KEY_LEN, TOKEN_ID_LEN, GROUP_ID_LEN};
+
+use crate::records;
+
+// '+' can't appear in a username, so these can't clash with an account's file
+const CONNECTIONS_FILE: &str = "+connections";
+const BLOCKS_FILE: &str = "+blocks";
+const GROUPS_FILE: &str = "+groups";
+
+// an active PAT token, which the server only knows by its verifier
+#[derive(Clone)]
+pub struct Token {
+    pub token_id: [u8; TOKEN_ID_LEN],
+    pub verifier: [u8; VERIFIER_LEN],
+    pub issued_at: u64,
+}
+
+// what the server knows about each user
+#[derive(Clone)]
+pub struct Account {
+    // oldest first; the first is issued at signup
+    pub tokens: Vec<Token>,
+
+    // published to other users on request (see protocol::identity)
+    pub identity_key: [u8; IDENTITY_KEY_LEN],
+}
+
+// a group conversation (see protocol::group)
+#[derive(Clone)]
+pub struct Group {
+    pub name: String,
+
+    // in the order they joined
+    pub members: Vec<Username>,
+}
+
+impl Group {
+    pub fn is_member(&self, uname: &Username) -> bool {
+        self.members.contains(uname)
+    }
+}
+
+pub struct AccountStore {
+    dir: PathBuf,
+}
+
+impl AccountStore {
+    pub fn new(dir: PathBuf) -> Self {
+        AccountStore { dir }
+    }
+
+    /**
+    Every account kept (none if the accounts directory doesn't exist yet)
+    */
+    pub fn accounts(&self) -> io::Result<HashMap<Username, Account>> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(HashMap::new()),
+            Err(e) => return Err(e),
+        };
+
+        let mut accounts = HashMap::new();
+        for entry in entries {
+            let entry = entry?;
+            // skips the connection and block files, and any left over temporary files
+            let Some(uname) = entry.file_name().to_str().and_then(|name| Username::new(name).ok()) else {
+                continue;
+            };
+            let path = entry.path();
+            let account = decode_account(&fs::read(&path)?).map_err(|e| records::corrupt(&path, &e.to_string()))?;
+            accounts.insert(uname, account);
+        }
+
+        Ok(accounts)
+    }
+
+    /**
+    Writes uname's account, replacing whatever was kept for them before
+    */
+    pub fn save_account(&self, uname: &Username, account: &Account) -> io::Result<()> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&account.identity_key);
+        bytes.push(account.tokens.len() as u8);
+        for token in &account.tokens {
+            bytes.extend_from_slice(&token.token_id);
+            bytes.extend_from_slice(&token.verifier);
+            bytes.extend_from_slice(&token.issued_at.to_be_bytes());
+        }
+
+        // written alongside then renamed over the account, so it's never left half-written
+        fs::create_dir_all(&self.dir)?;
+        let tmp_path = self.dir.join(format!(".{}.tmp", uname));
+        fs::write(&tmp_path, bytes)?;
+        fs::rename(tmp_path, self.dir.join(uname.as_str()))
+    }
+
+    /**
+    Every connection kept, as (lesser uname, greater uname)
+    */
+    pub fn connections(&self) -> io::Result<HashSet<(Username, Username)>> {
+        read_pairs(&self.dir.join(CONNECTIONS_FILE))
+    }
+
+    pub fn add_connection(&self, conn: &(Username, Username)) -> io::Result<()> {
+        self.append_pair(CONNECTIONS_FILE, conn)
+    }
+
+    /**
+    Every block kept, as (blocker, blocked)
+    */
+    pub fn blocks(&self) -> io::Result<HashSet<(Username, Username)>> {
+        read_pairs(&self.dir.join(BLOCKS_FILE))
+    }
+
+    pub fn add_block(&self, block: &(Username, Username)) -> io::Result<()> {
+        self.append_pair(BLOCKS_FILE, block)
+    }
+
+    /**
+    Every group kept, by id
+    */
+    pub fn groups(&self) -> io::Result<HashMap<[u8; GROUP_ID_LEN], Group>> {
+        let path = self.dir.join(GROUPS_FILE);
+        let bytes = match fs::read(&path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(HashMap::new()),
+            Err(e) => return Err(e),
+        };
+
+        let mut groups = HashMap::new();
+        let mut decoder = Decoder::new(&bytes);
+        while decoder.remaining() > 0 {
+            let (group_id, group) = read_group(&mut decoder).map_err(|e| records::corrupt(&path, &e.to_string()))?;
+            groups.insert(group_id, group);
+        }
+
+        Ok(groups)
+    }
+
+    /**
+    Writes every group, replacing those kept before
+    */
+    pub fn save_groups(&self, groups: &HashMap<[u8; GROUP_ID_LEN], Group>) -> io::Result<()> {
+        // each as the GroupInfo its members are sent, after its length
+        let mut bytes = Vec::new();
+        for (group_id, group) in groups {
+            let group_info = GroupInfo::new(*group_id, &group.name, group.members.clone()).serialize();
+            bytes.extend_from_slice(&(group_info.len() as u32).to_be_bytes());
+            bytes.extend_from_slice(&group_info);
+        }
+
+        fs::create_dir_all(&self.dir)?;
+        let tmp_path = self.dir.join(format!(".{}.tmp", GROUPS_FILE));
+        fs::write(&tmp_path, bytes)?;
+        fs::rename(tmp_path, self.dir.join(GROUPS_FILE))
+    }
+
+    fn append_pair(&self, file_name: &str, (uname1, uname2): &(Username, Username)) -> io::Result<()> {
+        let mut bytes = Vec::new();
+        uname1.encode(&mut bytes);
+        uname2.encode(&mut bytes);
+
+        fs::create_dir_all(&self.dir)?;
+        let mut file = OpenOptions::new().create(true).append(true).open(self.dir.join(file_name))?;
+        file.write_all(&bytes)
+    }
+}
+
+fn decode_account(bytes: &[u8]) -> Result<Account, ProtocolError> {
+    let mut decoder = Decoder::new(bytes);
+    let identity_key = decoder.read_array::<IDENTITY_KEY_LEN>()?;
+    let count = decoder.read_u8()?;
+
+    let mut tokens = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        tokens.push(Token {
+            token_id: decoder.read_array::<TOKEN_ID_LEN>()?,
+            verifier: decoder.read_array::<VERIFIER_LEN>()?,
+            issued_at: decoder.read_u64()?,
+        });
+    }
+    decoder.finish()?;
+
+    Ok(Account { tokens, identity_key })
+}
+
+// Reads a file of username pairs (none if there is no such file)
+fn read_pairs(path: &Path) -> io::Result<HashSet<(Username, Username)>> {
+    let bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(HashSet::new()),
+        Err(e) => return Err(e),
+    };
+
+    let mut pairs = HashSet::new();
+    let mut decoder = Decoder::new(&bytes);
+    while decoder.remaining() > 0 {
+        let pair = read_pair(&mut decoder).map_err(|e| records::corrupt(path, &e.to_string()))?;
+        pairs.insert(pair);
+    }
+
+    Ok(pairs)
+}
+
+fn read_pair(decoder: &mut Decoder) -> Result<(Username, Username), ProtocolError> {
+    Ok((decoder.read_username("uname1")?, decoder.read_username("uname2")?))
+}
+
+fn read_group(decoder: &mut Decoder) -> Result<([u8; GROUP_ID_LEN], Group), ProtocolError> {
+    let len = decoder.read_u32()? as usize;
+    let group_info = GroupInfo::deserialize(decoder.read_bytes(len)?)?;
+    let group = Group {
+        name: group_info.group_name().to_string(),
+        members: group_info.members().to_vec(),
+    };
+    Ok((group_info.group_id(), group))
+}
@@ -0,0 +1,291 @@
+/*
+Every chat message the server has accepted, kept so clients can fetch their
+conversations again (see protocol::history).
+
+Each conversation is a file in the history directory (created when the first
+message is kept), named after its two users, holding its messages in the order
+the server accepted them, as records (see records.rs). Messages are kept as
+they were sent: signed, and with their bodies end-to-end encrypted.
+
+Only a conversation's latest MAX_MESSAGES are kept. Once it has COMPACT_SLACK
+more than that, its file is rewritten without the oldest, so a long
+conversation isn't rewritten on every message. A client whose cursor points at
+a message that has been dropped is told the server doesn't have it, and can
+ask again from that message's timestamp.
+
+The first time a conversation is used, its file is read through once to find
+where each message is in it. From then on, a request reads only the messages it
+sends back.
+*/
+
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use protocol::{ChatMessage, HistoryCursor, Username};
+use protocol::field_lens::MESSAGE_ID_LEN;
+
+use crate::records::{self, Location};
+
+// most messages kept of each conversation
+pub const MAX_MESSAGES: usize = 10_000;
+
+// how many messages over MAX_MESSAGES a conversation can go before it's compacted
+const COMPACT_SLACK: usize = MAX_MESSAGES / 4;
+
+// where one message is in its conversation's file, what a cursor needs to
+// know of it, and who sent it
+struct Entry {
+    message_id: [u8; MESSAGE_ID_LEN],
+    timestamp: u64,
+    send_uname: Username,
+    location: Location,
+}
+
+// the messages of a conversation, oldest first, as they are in its file
+#[derive(Default)]
+struct Conversation {
+    entries: Vec<Entry>,
+
+    // message id -> position in entries (of its first copy, if sent more than once)
+    positions: HashMap<[u8; MESSAGE_ID_LEN], usize>,
+
+    // length of the file, i.e. where the next message goes
+    end: u64,
+}
+
+impl Conversation {
+    fn push(&mut self, chat_message: &ChatMessage, location: Location) {
+        self.positions.entry(chat_message.message_id()).or_insert(self.entries.len());
+        self.entries.push(Entry {
+            message_id: chat_message.message_id(),
+            timestamp: chat_message.timestamp(),
+            send_uname: chat_message.send_uname.clone(),
+            location,
+        });
+        self.end = location.offset + location.len;
+    }
+}
+
+pub struct History {
+    dir: PathBuf,
+    max_messages: usize,
+    compact_slack: usize,
+
+    // conversations used since the server started, by file
+    conversations: HashMap<PathBuf, Conversation>,
+}
+
+impl History {
+    pub fn new(dir: PathBuf) -> Self {
+        History {
+            dir,
+            max_messages: MAX_MESSAGES,
+            compact_slack: COMPACT_SLACK,
+            conversations: HashMap::new(),
+        }
+    }
+
+    /**
+    Adds a message to the end of its conversation, dropping the oldest
+    messages if that takes it too far over MAX_MESSAGES
+    */
+    pub fn append(&mut self, chat_message: &ChatMessage) -> io::Result<()> {
+        let mut bytes = Vec::new();
+        records::encode(chat_message, &mut bytes);
+
+        fs::create_dir_all(&self.dir)?;
+        let path = self.path(&chat_message.send_uname, &chat_message.recv_uname);
+        let conversation = self.conversation(&path)?;
+        let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+        file.write_all(&bytes)?;
+        conversation.push(chat_message, Location { offset: conversation.end, len: bytes.len() as u64 });
+
+        let len = conversation.entries.len();
+        if len > self.max_messages + self.compact_slack {
+            self.compact(&path, len - self.max_messages)?;
+        }
+        Ok(())
+    }
+
+    /**
+    Up to limit of the messages between uname1 and uname2 that come after
+    cursor, oldest first, and whether there are more after them.
+
+    Returns None if the cursor is after a message that isn't in the conversation.
+    */
+    pub fn messages_after(
+        &mut self,
+        uname1: &Username,
+        uname2: &Username,
+        cursor: HistoryCursor,
+        limit: usize
+    ) -> io::Result<Option<(Vec<ChatMessage>, bool)>> {
+        let path = self.path(uname1, uname2);
+        let conversation = self.conversation(&path)?;
+        let mut after: Box<dyn Iterator<Item = &Entry>> = match cursor {
+            HistoryCursor::AfterMessage(message_id) => {
+                let Some(pos) = conversation.positions.get(&message_id) else {
+                    return Ok(None);
+                };
+                Box::new(conversation.entries[pos + 1..].iter())
+            }
+            // senders' clocks differ, so a conversation isn't always in timestamp order
+            HistoryCursor::AfterTime(timestamp) => Box::new(
+                conversation.entries.iter().filter(move |entry| entry.timestamp > timestamp)
+            ),
+        };
+
+        let locations: Vec<Location> = after.by_ref().take(limit).map(|entry| entry.location).collect();
+        let more = after.next().is_some();
+        if locations.is_empty() {
+            return Ok(Some((Vec::new(), more)));
+        }
+
+        let mut file = File::open(&path)?;
+        let messages = locations
+            .into_iter()
+            .map(|location| records::read_at(&mut file, &path, location))
+            .collect::<io::Result<_>>()?;
+
+        Ok(Some((messages, more)))
+    }
+
+    /**
+    Who sent the message with the given id in the conversation between uname1
+    and uname2, if it's kept
+    */
+    pub fn sender(
+        &mut self,
+        uname1: &Username,
+        uname2: &Username,
+        message_id: [u8; MESSAGE_ID_LEN]
+    ) -> io::Result<Option<Username>> {
+        let path = self.path(uname1, uname2);
+        let conversation = self.conversation(&path)?;
+        Ok(conversation.positions.get(&message_id).map(|&pos| conversation.entries[pos].send_uname.clone()))
+    }
+
+    // The index of the conversation kept in path, reading through its file the first time
+    fn conversation(&mut self, path: &Path) -> io::Result<&mut Conversation> {
+        if !self.conversations.contains_key(path) {
+            let mut conversation = Conversation::default();
+            for (location, chat_message) in records::read_located(path)? {
+                conversation.push(&chat_message, location);
+            }
+            self.conversations.insert(path.to_path_buf(), conversation);
+        }
+
+        Ok(self.conversations.get_mut(path).unwrap())
+    }
+
+    // Rewrites the conversation kept in path without its first excess messages
+    fn compact(&mut self, path: &Path, excess: usize) -> io::Result<()> {
+        let Some(conversation) = self.conversations.get_mut(path) else {
+            return Ok(());
+        };
+        let start = conversation.entries[excess].location.offset;
+
+        let mut bytes = Vec::new();
+        let mut file = File::open(path)?;
+        file.seek(SeekFrom::Start(start))?;
+        file.read_to_end(&mut bytes)?;
+
+        // written alongside then renamed over the conversation, so it's never left half-written
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+        let tmp_path = path.with_file_name(format!(".{}.tmp", file_name));
+        fs::write(&tmp_path, bytes)?;
+        fs::rename(tmp_path, path)?;
+
+        let mut compacted = Conversation::default();
+        for entry in conversation.entries.drain(excess..) {
+            let location = Location { offset: entry.location.offset - start, len: entry.location.len };
+            compacted.positions.entry(entry.message_id).or_insert(compacted.entries.len());
+            compacted.end = location.offset + location.len;
+            compacted.entries.push(Entry { location, ..entry });
+        }
+        *conversation = compacted;
+
+        Ok(())
+    }
+
+    // Conversations are symmetric, so always name them in the same order; '+'
+    // can't appear in a username, so names can't run together
+    fn path(&self, uname1: &Username, uname2: &Username) -> PathBuf {
+        let (first, second) = if uname1 <= uname2 { (uname1, uname2) } else { (uname2, uname1) };
+        self.dir.join(format!("{}+{}", first, second))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn uname(name: &str) -> Username {
+        Username::new(name).unwrap()
+    }
+
+    fn history_with(dir: &Path, max_messages: usize, compact_slack: usize) -> History {
+        History { max_messages, compact_slack, ..History::new(dir.to_path_buf()) }
+    }
+
+    // Appends count messages to the conversation between Harry and Eddie, returning their ids
+    fn append_many(history: &mut History, count: usize) -> Vec<[u8; MESSAGE_ID_LEN]> {
+        (0..count)
+            .map(|i| {
+                let (send, recv) = if i % 2 == 0 { ("Harry", "Eddie") } else { ("Eddie", "Harry") };
+                let chat_message = ChatMessage::new(&uname(send), &uname(recv), &format!("message {}", i));
+                history.append(&chat_message).unwrap();
+                chat_message.message_id()
+            })
+            .collect()
+    }
+
+    // Every message after cursor, fetched a batch at a time as a client would
+    fn page_through(history: &mut History, cursor: HistoryCursor) -> Option<Vec<[u8; MESSAGE_ID_LEN]>> {
+        let mut ids = Vec::new();
+        let mut cursor = cursor;
+        loop {
+            let (messages, more) = history.messages_after(&uname("Eddie"), &uname("Harry"), cursor, 64).unwrap()?;
+            assert!(messages.len() <= 64);
+            ids.extend(messages.iter().map(ChatMessage::message_id));
+            match messages.last() {
+                Some(last) if more => cursor = HistoryCursor::AfterMessage(last.message_id()),
+                _ => return Some(ids),
+            }
+        }
+    }
+
+    #[test]
+    fn pages_through_many_messages_by_cursor() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut history = History::new(dir.path().to_path_buf());
+        let sent = append_many(&mut history, 1000);
+
+        assert_eq!(page_through(&mut history, HistoryCursor::START), Some(sent.clone()));
+        assert_eq!(page_through(&mut history, HistoryCursor::AfterMessage(sent[499])), Some(sent[500..].to_vec()));
+        assert_eq!(page_through(&mut history, HistoryCursor::AfterMessage([0u8; MESSAGE_ID_LEN])), None);
+
+        // a server restarting reads the same conversation back from its file
+        let mut restarted = History::new(dir.path().to_path_buf());
+        assert_eq!(page_through(&mut restarted, HistoryCursor::START), Some(sent.clone()));
+        assert_eq!(restarted.sender(&uname("Harry"), &uname("Eddie"), sent[0]).unwrap(), Some(uname("Harry")));
+        assert_eq!(restarted.sender(&uname("Harry"), &uname("Eddie"), sent[1]).unwrap(), Some(uname("Eddie")));
+    }
+
+    #[test]
+    fn drops_oldest_messages_past_the_cap() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut history = history_with(dir.path(), 100, 25);
+        let sent = append_many(&mut history, 1000);
+
+        let kept = page_through(&mut history, HistoryCursor::START).unwrap();
+        assert!((100..=125).contains(&kept.len()));
+        assert_eq!(kept[..], sent[sent.len() - kept.len()..]);
+        assert_eq!(page_through(&mut history, HistoryCursor::AfterMessage(sent[0])), None);
+
+        let mut restarted = history_with(dir.path(), 100, 25);
+        assert_eq!(page_through(&mut restarted, HistoryCursor::START), Some(kept));
+    }
+}
@@ -0,0 +1,155 @@
+/*
+Per-user inboxes of chat messages stored for offline recipients.
+
+Each user's inbox is a file, named after them, in the inbox directory (created
+when the first message is stored). It holds the stored messages oldest first,
+as records (see records.rs), so they survive the server restarting.
+
+A message stays in the inbox until its recipient acknowledges it (see
+protocol::inbox), or is sent it if their client can't acknowledge it, at which
+point the inbox is rewritten without it.
+
+Group invites and messages for offline members are kept apart, in a file named
+after each member in the +groups subdirectory, as the packets they'll be sent
+(one after another, as on the wire). They aren't acknowledged, so they're all
+sent, and the file removed, the next time the member verifies.
+*/
+
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+use protocol::{ChatMessage, Packet, Username};
+use protocol::field_lens::MESSAGE_ID_LEN;
+
+use crate::records;
+
+// where group packets are kept (named so it can't clash with a username)
+const GROUPS_DIR: &str = "+groups";
+
+// most bytes of messages (as stored) a user's inbox can hold
+pub const MAX_INBOX_BYTES: u64 = 4 * 1024 * 1024;
+
+pub struct Inbox {
+    dir: PathBuf,
+}
+
+impl Inbox {
+    pub fn new(dir: PathBuf) -> Self {
+        Inbox { dir }
+    }
+
+    /**
+    Adds a message to the end of its recipient's inbox.
+
+    Returns false if the message would take the inbox over MAX_INBOX_BYTES.
+    */
+    pub fn store(&self, chat_message: &ChatMessage) -> io::Result<bool> {
+        let path = self.path(&chat_message.recv_uname);
+        let mut bytes = Vec::new();
+        records::encode(chat_message, &mut bytes);
+        let size = fs::metadata(&path).map_or(0, |metadata| metadata.len());
+        if size + bytes.len() as u64 > MAX_INBOX_BYTES {
+            return Ok(false);
+        }
+
+        fs::create_dir_all(&self.dir)?;
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        file.write_all(&bytes)?;
+
+        Ok(true)
+    }
+
+    /**
+    The messages in uname's inbox, oldest first
+    */
+    pub fn messages(&self, uname: &Username) -> io::Result<Vec<ChatMessage>> {
+        records::read(&self.path(uname))
+    }
+
+    /**
+    Deletes the message with the given id from uname's inbox, removing the
+    inbox once it's empty.
+
+    Returns false if there was no such message.
+    */
+    pub fn remove(&self, uname: &Username, message_id: [u8; MESSAGE_ID_LEN]) -> io::Result<bool> {
+        let mut messages = self.messages(uname)?;
+        let before = messages.len();
+        messages.retain(|chat_message| chat_message.message_id() != message_id);
+        if messages.len() == before {
+            return Ok(false);
+        }
+
+        let path = self.path(uname);
+        if messages.is_empty() {
+            fs::remove_file(path)?;
+            return Ok(true);
+        }
+
+        // written alongside then renamed over the inbox, so it's never left half-written
+        let mut bytes = Vec::new();
+        for chat_message in &messages {
+            records::encode(chat_message, &mut bytes);
+        }
+        let tmp_path = self.dir.join(format!(".{}.tmp", uname));
+        fs::write(&tmp_path, bytes)?;
+        fs::rename(tmp_path, path)?;
+
+        Ok(true)
+    }
+
+    /**
+    Adds a group invite or message (as a packet) to the end of uname's group
+    inbox.
+
+    Returns false if the packet would take it over MAX_INBOX_BYTES.
+    */
+    pub fn store_group_packet(&self, uname: &Username, packet: &Packet) -> io::Result<bool> {
+        let path = self.group_path(uname);
+        let bytes = packet.serialize();
+        let size = fs::metadata(&path).map_or(0, |metadata| metadata.len());
+        if size + bytes.len() as u64 > MAX_INBOX_BYTES {
+            return Ok(false);
+        }
+
+        fs::create_dir_all(self.dir.join(GROUPS_DIR))?;
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        file.write_all(&bytes)?;
+
+        Ok(true)
+    }
+
+    /**
+    Removes and returns the group packets in uname's group inbox, oldest first
+    */
+    pub fn take_group_packets(&self, uname: &Username) -> io::Result<Vec<Packet>> {
+        let path = self.group_path(uname);
+        let bytes = match fs::read(&path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+
+        let mut packets = Vec::new();
+        let mut offset = 0;
+        while offset < bytes.len() {
+            let packet = Packet::deserialize(&bytes[offset..]).map_err(|e| records::corrupt(&path, &e.to_string()))?;
+            offset += packet.length();
+            packets.push(packet);
+        }
+        fs::remove_file(path)?;
+
+        Ok(packets)
+    }
+
+    fn group_path(&self, uname: &Username) -> PathBuf {
+        self.dir.join(GROUPS_DIR).join(uname.as_str())
+    }
+
+    // Usernames are limited to characters that are safe in file names, and
+    // start with a letter or digit, so can't clash with temporary files (see protocol::username)
+    fn path(&self, uname: &Username) -> PathBuf {
+        self.dir.join(uname.as_str())
+    }
+}
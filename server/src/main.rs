@@ -1,25 +1,114 @@
-use std::net::{TcpListener, TcpStream};
-use std::io::{self, Read, Write};
+/*
+cli_chat server
 
-use protocol::{Packet, ChatMessage, SignupReq, SignupResp, VerifyReq, VerifyResp, C2cConnReq, C2cConnResp};
-use protocol::{self, field_lens, message_types, errors, shared, status_codes};
+Accepts client connections and runs each one as a session on its own thread
+(see session.rs). State shared between sessions (accounts, who is online)
+lives in a single ServerState (see state.rs).
+
+Accounts, who is connected with or has blocked whom, and groups are kept on
+disk in the directory named by CLI_CHAT_ACCOUNTS_DIR (by default, "accounts";
+see accounts.rs), so users can verify again after the server restarts.
+Presence and unanswered connection requests are only held in memory.
+
+Chat messages, group invites and group messages for users who are offline are
+kept on disk, in the directory named by CLI_CHAT_INBOX_DIR (by default, "inbox"
+in the working directory), until they next verify (see inbox.rs). Every chat message
+accepted is also kept, for clients to fetch again, in the directory named by
+CLI_CHAT_HISTORY_DIR (by default, "history"; see history.rs).
+
+TLS is turned on by pointing CLI_CHAT_TLS_CERT and CLI_CHAT_TLS_KEY at PEM files
+holding the server's certificate chain and private key. Without them, the
+server speaks plain TCP.
+*/
+
+mod session;
+mod state;
+mod accounts;
+mod inbox;
+mod history;
+mod records;
+
+use std::env;
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Arc;
+use std::thread;
+
+use protocol::{tls, HeartbeatConfig};
+use protocol::rustls::ServerConfig;
+
+use state::ServerState;
+
+// where accounts, connections and blocks are kept, unless CLI_CHAT_ACCOUNTS_DIR says otherwise
+const DEFAULT_ACCOUNTS_DIR: &str = "accounts";
+
+// where offline users' chat messages are kept, unless CLI_CHAT_INBOX_DIR says otherwise
+const DEFAULT_INBOX_DIR: &str = "inbox";
+
+// where every accepted chat message is kept, unless CLI_CHAT_HISTORY_DIR says otherwise
+const DEFAULT_HISTORY_DIR: &str = "history";
 
 fn main() {
     let listener = TcpListener::bind("127.0.0.1:8081").unwrap();
+    let accounts_dir = env::var("CLI_CHAT_ACCOUNTS_DIR").unwrap_or_else(|_| DEFAULT_ACCOUNTS_DIR.to_string());
+    let inbox_dir = env::var("CLI_CHAT_INBOX_DIR").unwrap_or_else(|_| DEFAULT_INBOX_DIR.to_string());
+    let history_dir = env::var("CLI_CHAT_HISTORY_DIR").unwrap_or_else(|_| DEFAULT_HISTORY_DIR.to_string());
+    let server_state = match ServerState::new(
+        PathBuf::from(accounts_dir),
+        PathBuf::from(inbox_dir),
+        PathBuf::from(history_dir)
+    ) {
+        Ok(server_state) => Arc::new(server_state),
+        Err(e) => {
+            eprintln!("error loading accounts: {}", e);
+            process::exit(1);
+        }
+    };
+    let heartbeat = HeartbeatConfig::default();
+    let tls = tls_config();
 
     for stream in listener.incoming() {
-        let stream = stream.unwrap();
-        handle_connection(stream);
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("error accepting connection: {}", e);
+                continue;
+            }
+        };
+
+        let server_state = Arc::clone(&server_state);
+        let tls = tls.clone();
+        thread::spawn(move || {
+            if let Err(e) = session::run(stream, server_state, heartbeat, tls) {
+                eprintln!("session error: {}", e);
+            }
+        });
     }
 }
 
-fn handle_connection(mut stream: TcpStream) -> io::Result<()> {
-    println!("client connected");
-    let mut buffer = [0u8; 82];
-    let bytes_read = stream.read(&mut buffer)?;
-    println!("read {} bytes from client", bytes_read);
-    let verify_req = VerifyReq::deserialize(&buffer);
-    println!("{:?}", verify_req);
-    // stream.write_all(&buffer[..bytes_read]).unwrap();
-    Ok(())
+/**
+Loads the TLS config named by the environment, if any. Exits if TLS was asked
+for but can't be set up, rather than falling back to plain TCP.
+*/
+fn tls_config() -> Option<Arc<ServerConfig>> {
+    let (cert_path, key_path) = match (env::var("CLI_CHAT_TLS_CERT"), env::var("CLI_CHAT_TLS_KEY")) {
+        (Ok(cert_path), Ok(key_path)) => (cert_path, key_path),
+        (Err(_), Err(_)) => return None,
+        _ => {
+            eprintln!("TLS needs both CLI_CHAT_TLS_CERT and CLI_CHAT_TLS_KEY to be set");
+            process::exit(1);
+        }
+    };
+
+    match tls::server_config(Path::new(&cert_path), Path::new(&key_path)) {
+        Ok(config) => {
+            println!("TLS enabled, using certificate {}", cert_path);
+            Some(config)
+        }
+        Err(e) => {
+            eprintln!("error setting up TLS: {}", e);
+            process::exit(1);
+        }
+    }
 }
@@ -0,0 +1,96 @@
+/*
+How the server keeps chat messages on disk (see inbox.rs and history.rs): one
+after another in a file, oldest first, each as a 4 byte (big endian) length
+followed by the serialized ChatMessage.
+*/
+
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+
+use protocol::{ChatMessage, Message};
+use protocol::field_lens::MAX_MESSAGE_LEN;
+
+// bytes before each stored message giving its length
+const RECORD_LEN_LEN: usize = 4;
+
+/**
+Appends a message to buffer as a record
+*/
+pub fn encode(chat_message: &ChatMessage, buffer: &mut Vec<u8>) {
+    let record = chat_message.serialize();
+    buffer.extend_from_slice(&(record.len() as u32).to_be_bytes());
+    buffer.extend_from_slice(&record);
+}
+
+/**
+Where a record is in its file, in bytes (the length includes the record's own
+length prefix)
+*/
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Location {
+    pub offset: u64,
+    pub len: u64,
+}
+
+/**
+Reads every message in a file of records (none if there is no such file)
+*/
+pub fn read(path: &Path) -> io::Result<Vec<ChatMessage>> {
+    Ok(read_located(path)?.into_iter().map(|(_, chat_message)| chat_message).collect())
+}
+
+/**
+Reads every message in a file of records, along with where each one is
+*/
+pub fn read_located(path: &Path) -> io::Result<Vec<(Location, ChatMessage)>> {
+    let bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    let mut messages = Vec::new();
+    let mut offset = 0;
+    while offset < bytes.len() {
+        let (chat_message, len) = decode(path, &bytes[offset..])?;
+        messages.push((Location { offset: offset as u64, len: len as u64 }, chat_message));
+        offset += len;
+    }
+
+    Ok(messages)
+}
+
+/**
+Reads the one message at location in file (which is at path)
+*/
+pub fn read_at(file: &mut File, path: &Path, location: Location) -> io::Result<ChatMessage> {
+    let mut bytes = vec![0u8; location.len as usize];
+    file.seek(SeekFrom::Start(location.offset))?;
+    file.read_exact(&mut bytes)?;
+
+    let (chat_message, len) = decode(path, &bytes)?;
+    if len != bytes.len() {
+        return Err(corrupt(path, "record doesn't match its location"));
+    }
+    Ok(chat_message)
+}
+
+// Decodes the record at the start of bytes, returning its message and length
+fn decode(path: &Path, bytes: &[u8]) -> io::Result<(ChatMessage, usize)> {
+    let Some((len, body)) = bytes.split_first_chunk::<RECORD_LEN_LEN>() else {
+        return Err(corrupt(path, "truncated record length"));
+    };
+    let len = u32::from_be_bytes(*len) as usize;
+    if len > MAX_MESSAGE_LEN || len > body.len() {
+        return Err(corrupt(path, "bad record length"));
+    }
+    let chat_message = ChatMessage::deserialize(&body[..len])
+        .map_err(|e| corrupt(path, &e.to_string()))?;
+
+    Ok((chat_message, RECORD_LEN_LEN + len))
+}
+
+pub fn corrupt(path: &Path, what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{} is corrupt: {}", path.display(), what))
+}
@@ -0,0 +1,1702 @@
+/*
+A single client session.
+
+Each session runs on its own thread. The socket is read with a short timeout so
+that, between reads, the session can also forward packets that other sessions
+have queued for this user (e.g. a relayed ChatMessage).
+
+Packets too large to send whole go out as Fragments to clients that support them,
+and incoming Fragments are reassembled before being handled like any other message.
+
+If the server has a TLS config, the TLS handshake is the first thing a session does.
+
+Once the Hello has been accepted, the client is sent an AuthChallenge, which it
+answers (in a VerifyReq) to prove it holds its PAT token. Each challenge can be
+answered only once; a failed VerifyReq gets a fresh one.
+
+Group invites and messages are passed on to every other member's session, or
+kept in their inbox if they're offline, and every change to a group's
+membership is sent to all its online members as a GroupInfo. Members are sent
+what was kept for them, and a GroupInfo for each of their groups, when they
+verify. The session remembers
+which of the account's tokens it was verified with, so that token can be
+rotated; revoking a token doesn't end sessions already verified with it.
+
+Clients that support presence are told their connections' presence when they
+verify, and whenever it changes after that: a user is online once their session
+verifies and offline once it ends, and can set themselves away (or appear
+offline) with a custom status in between.
+
+Typing indicators are relayed between connections as they come in, and dropped
+if the recipient is offline or didn't negotiate them; the server never stores them.
+
+File transfer messages are relayed between connections in the same way: offers
+and chunks from the file's sender, accepts and completions from its recipient.
+Offers and chunks must be sealed, as chat message bodies are.
+
+Edits and deletions of chat messages are relayed too, once their signature has
+been checked, and only by the user who sent the message: the server looks the
+message up in the history it keeps, so can't relay edits of a message it no
+longer has (or never had). Reactions to messages are relayed between
+connections in the same way, also once their signature has been checked and
+the message found in the history, but from either side of the conversation.
+
+Chat messages for a recipient who is offline are stored in their inbox (see
+inbox.rs), as long as it has room, and sent to them in order the next time
+they verify, ahead of anything sent to them since. Each is sent again on every
+verification until the client acknowledges it with an InboxAck. Clients that
+didn't negotiate the inbox can't acknowledge them, so are sent them once and
+they're taken out of the inbox straight away.
+
+Every chat message the server accepts is also kept in its conversation's
+history (see history.rs), which either side can fetch with HistoryReqs, a
+batch at a time. Only chat messages are kept: edits, deletions and reactions
+aren't.
+
+Clients that support heartbeats are pinged every so often, and their session is
+ended if nothing is heard from them within the heartbeat timeout.
+*/
+
+use std::io;
+use std::error::Error;
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::time::{Duration, Instant};
+
+use protocol::{Packet, PacketReader, PacketWriter, Message, ProtocolMessage, ProtocolError};
+use protocol::{ChatMessage, SignupReq, SignupResp, VerifyReq, VerifyResp};
+use protocol::{C2cConnReq, C2cConnResp, ConnResponse};
+use protocol::{Hello, HelloAck, Capabilities, NegotiatedSession};
+use protocol::{Receipt, ReceiptKind};
+use protocol::Envelope;
+use protocol::{IdentityReq, IdentityResp};
+use protocol::AuthChallenge;
+use protocol::PresenceUpdate;
+use protocol::Typing;
+use protocol::{FileOffer, FileAccept, FileChunk, FileComplete};
+use protocol::{MessageEdit, MessageDelete};
+use protocol::Reaction;
+use protocol::InboxAck;
+use protocol::{HistoryCursor, HistoryReq, HistoryResp};
+use protocol::{GroupCreate, GroupInfo, GroupInvite, GroupLeave, GroupMessage};
+use protocol::{RotateTokenReq, RotateTokenResp, ListTokensReq, ListTokensResp, RevokeTokenReq, RevokeTokenResp};
+use protocol::{Fragment, Reassembler};
+use protocol::{Ping, Pong, Heartbeat, HeartbeatConfig, LinkStatus};
+use protocol::Stream;
+use protocol::rustls::ServerConfig;
+use protocol::message_types::{self, MessageType};
+use protocol::field_lens::{CHALLENGE_LEN, TOKEN_ID_LEN, GROUP_ID_LEN, MAX_PACKET_LEN, MAX_MESSAGE_LEN};
+use protocol::field_lens::MESSAGE_ID_LEN;
+use protocol::hello::PROTOCOL_VERSION;
+use protocol::Username;
+use protocol::{auth, shared};
+use protocol::status_codes::StatusCode;
+
+use crate::state::{Delivery, ServerState};
+
+// how long a read blocks before the session checks its outbound queue
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+// most chat messages sent in one HistoryResp
+const MAX_HISTORY_BATCH_LEN: usize = 64;
+
+// how long a client gets to complete the TLS handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// optional protocol features this server supports
+const SERVER_CAPABILITIES: Capabilities = Capabilities::RECEIPTS
+    .union(Capabilities::FRAGMENTATION)
+    .union(Capabilities::HEARTBEAT)
+    .union(Capabilities::PRESENCE)
+    .union(Capabilities::TYPING)
+    .union(Capabilities::FILE_TRANSFER)
+    .union(Capabilities::EDITS)
+    .union(Capabilities::REACTIONS)
+    .union(Capabilities::INBOX)
+    .union(Capabilities::HISTORY);
+
+struct Session {
+    id: u64,
+    uname: Option<Username>,
+
+    // set once the client's Hello has been accepted
+    negotiated: Option<NegotiatedSession>,
+
+    // the AuthChallenge the client has yet to answer
+    challenge: Option<[u8; CHALLENGE_LEN]>,
+
+    // id of the token the client verified with
+    token_id: Option<[u8; TOKEN_ID_LEN]>,
+
+    reader: PacketReader<Stream>,
+    writer: PacketWriter<Stream>,
+
+    // fragmented packets the client is part-way through sending
+    reassembler: Reassembler,
+
+    // only checked once the client has agreed to heartbeats
+    heartbeat: Heartbeat,
+
+    outbound_tx: Sender<Packet>,
+    outbound_rx: Receiver<Packet>,
+    server: Arc<ServerState>,
+}
+
+/**
+Runs a session until the client disconnects or the connection fails.
+
+With a TLS config, the client must complete a TLS handshake first.
+*/
+pub fn run(
+    tcp: TcpStream,
+    server: Arc<ServerState>,
+    heartbeat: HeartbeatConfig,
+    tls: Option<Arc<ServerConfig>>,
+) -> Result<(), Box<dyn Error>> {
+    println!("client connected: {}", tcp.peer_addr()?);
+    let stream = match tls {
+        Some(tls) => {
+            tcp.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+            Stream::tls_server(tcp, tls)?
+        }
+        None => Stream::plain(tcp),
+    };
+    stream.set_read_timeout(Some(POLL_INTERVAL))?;
+
+    let (outbound_tx, outbound_rx) = mpsc::channel();
+    let mut session = Session {
+        id: server.next_session_id(),
+        uname: None,
+        negotiated: None,
+        challenge: None,
+        token_id: None,
+        reader: PacketReader::new(stream.try_clone()?),
+        writer: PacketWriter::new(stream),
+        reassembler: Reassembler::new(),
+        heartbeat: Heartbeat::new(heartbeat, Instant::now()),
+        outbound_tx,
+        outbound_rx,
+        server,
+    };
+
+    let result = session.serve();
+    if let Some(uname) = &session.uname {
+        if session.server.remove_session(uname, session.id) {
+            session.server.broadcast_presence(uname);
+        }
+        println!("{} disconnected", uname);
+    }
+
+    result
+}
+
+impl Session {
+    fn serve(&mut self) -> Result<(), Box<dyn Error>> {
+        loop {
+            self.flush_outbound()?;
+            self.reassembler.expire();
+            if !self.check_heartbeat()? {
+                return Ok(());
+            }
+
+            let packet = match self.reader.read_packet() {
+                Ok(packet) => {
+                    self.heartbeat.received(Instant::now());
+                    packet
+                }
+                Err(e) if e.io_kind().is_some_and(is_timeout) => continue,
+                Err(e) if e.io_kind().is_some_and(is_disconnect) => return Ok(()),
+                Err(e) => return Err(Box::new(e)),
+            };
+
+            // an undecodable message doesn't affect framing, so the session can carry on
+            match packet.decode() {
+                Ok(message) => self.handle_message(message)?,
+                Err(e) => eprintln!("dropping packet (method {}): {}", packet.method, e),
+            }
+        }
+    }
+
+    fn handle_message(&mut self, message: ProtocolMessage) -> Result<(), Box<dyn Error>> {
+        if self.negotiated.is_none() {
+            return match message {
+                ProtocolMessage::Hello(hello) => self.handle_hello(hello),
+                _ => {
+                    self.send(&HelloAck::new(StatusCode::IncompatibleVersion, PROTOCOL_VERSION, Capabilities::NONE))?;
+                    Err(Box::new(ProtocolError::UnexpectedMessage(message.message_type())))
+                }
+            };
+        }
+
+        match message {
+            ProtocolMessage::SignupReq(signup_req) => self.handle_signup_req(signup_req),
+            ProtocolMessage::VerifyReq(verify_req) => self.handle_verify_req(verify_req),
+            ProtocolMessage::ChatMessage(chat_message) => self.handle_chat_message(chat_message),
+            ProtocolMessage::C2cConnReq(conn_req) => self.handle_conn_req(conn_req),
+            ProtocolMessage::C2cConnResp(conn_resp) => self.handle_conn_resp(conn_resp),
+            ProtocolMessage::Receipt(receipt) => self.handle_receipt(receipt),
+            ProtocolMessage::Fragment(fragment) => self.handle_fragment(fragment),
+            ProtocolMessage::Ping(ping) => self.handle_ping(ping),
+            ProtocolMessage::Pong(pong) => self.handle_pong(pong),
+            ProtocolMessage::IdentityReq(identity_req) => self.handle_identity_req(identity_req),
+            ProtocolMessage::RotateTokenReq(rotate_req) => self.handle_rotate_token_req(rotate_req),
+            ProtocolMessage::ListTokensReq(list_req) => self.handle_list_tokens_req(list_req),
+            ProtocolMessage::RevokeTokenReq(revoke_req) => self.handle_revoke_token_req(revoke_req),
+            ProtocolMessage::GroupCreate(group_create) => self.handle_group_create(group_create),
+            ProtocolMessage::GroupInvite(group_invite) => self.handle_group_invite(group_invite),
+            ProtocolMessage::GroupLeave(group_leave) => self.handle_group_leave(group_leave),
+            ProtocolMessage::GroupMessage(group_message) => self.handle_group_message(group_message),
+            ProtocolMessage::PresenceUpdate(presence_update) => self.handle_presence_update(presence_update),
+            ProtocolMessage::Typing(typing) => self.handle_typing(typing),
+            ProtocolMessage::FileOffer(file_offer) => self.handle_file_offer(file_offer),
+            ProtocolMessage::FileAccept(file_accept) => self.handle_file_accept(file_accept),
+            ProtocolMessage::FileChunk(file_chunk) => self.handle_file_chunk(file_chunk),
+            ProtocolMessage::FileComplete(file_complete) => self.handle_file_complete(file_complete),
+            ProtocolMessage::MessageEdit(message_edit) => self.handle_message_edit(message_edit),
+            ProtocolMessage::MessageDelete(message_delete) => self.handle_message_delete(message_delete),
+            ProtocolMessage::Reaction(reaction) => self.handle_reaction(reaction),
+            ProtocolMessage::InboxAck(inbox_ack) => self.handle_inbox_ack(inbox_ack),
+            ProtocolMessage::HistoryReq(history_req) => self.handle_history_req(history_req),
+            ProtocolMessage::Hello(_) => {
+                eprintln!("ignoring repeated Hello from client");
+                Ok(())
+            }
+            ProtocolMessage::VerifyResp(_)
+            | ProtocolMessage::SignupResp(_)
+            | ProtocolMessage::HelloAck(_)
+            | ProtocolMessage::IdentityResp(_)
+            | ProtocolMessage::AuthChallenge(_)
+            | ProtocolMessage::RotateTokenResp(_)
+            | ProtocolMessage::ListTokensResp(_)
+            | ProtocolMessage::RevokeTokenResp(_)
+            | ProtocolMessage::GroupInfo(_)
+            | ProtocolMessage::Presence(_)
+            | ProtocolMessage::HistoryResp(_) => {
+                eprintln!("ignoring server-only message from client: {:?}", message.message_type());
+                Ok(())
+            }
+        }
+    }
+
+    /**
+    Agrees a protocol version and feature set with the client, then challenges
+    it to verify itself, or rejects the client (ending the session) if its
+    version is not supported
+    */
+    fn handle_hello(&mut self, hello: Hello) -> Result<(), Box<dyn Error>> {
+        match NegotiatedSession::negotiate(&hello, SERVER_CAPABILITIES) {
+            Ok(negotiated) => {
+                self.send(&negotiated.hello_ack())?;
+                self.negotiated = Some(negotiated);
+                self.send_challenge()
+            }
+            Err(e) => {
+                self.send(&HelloAck::new(StatusCode::IncompatibleVersion, PROTOCOL_VERSION, Capabilities::NONE))?;
+                Err(Box::new(e))
+            }
+        }
+    }
+
+    fn handle_signup_req(&mut self, signup_req: SignupReq) -> Result<(), Box<dyn Error>> {
+        // usernames are validated when the message is decoded, so only need to check it's free
+        let uname = signup_req.uname();
+        let mut signup_resp = SignupResp::new(StatusCode::Failure);
+
+        let new_signup_resp = SignupResp::new(StatusCode::Success);
+        let verifier = auth::derive_verifier(&new_signup_resp.token());
+        match self.server.add_account(uname, shared::generate_token_id(), verifier, *signup_req.identity_key()) {
+            Ok(true) => {
+                println!("new user signed up: {}", uname);
+                signup_resp = new_signup_resp;
+            }
+            Ok(false) => {}
+            Err(e) => eprintln!("error saving account of {}: {}", uname, e),
+        }
+
+        self.send(&signup_resp)
+    }
+
+    fn handle_verify_req(&mut self, verify_req: VerifyReq) -> Result<(), Box<dyn Error>> {
+        let uname = verify_req.cli_uname;
+        let token_id = self.challenge.take()
+            .and_then(|challenge| self.server.verify_account(&uname, &challenge, &verify_req.proof));
+        let Some(token_id) = token_id else {
+            self.send(&VerifyResp::new(StatusCode::Failure))?;
+            return self.send_challenge();
+        };
+
+        if let Some(old_uname) = self.uname.take() {
+            if self.server.remove_session(&old_uname, self.id) {
+                self.server.broadcast_presence(&old_uname);
+            }
+        }
+        self.server.add_session(&uname, self.id, self.outbound_tx.clone());
+        self.server.broadcast_presence(&uname);
+        println!("{} verified", uname);
+        let conns = self.server.connections_of(&uname);
+        self.uname = Some(uname);
+        self.token_id = Some(token_id);
+
+        self.send(&VerifyResp::new(StatusCode::Success))?;
+        for conn in conns {
+            self.send_presence(&conn)?;
+        }
+
+        self.send_stored_messages()?;
+        self.send_stored_group_packets()
+    }
+
+    /**
+    Sends the client the chat messages stored for it while it was offline,
+    oldest first. If it didn't negotiate the inbox it can't acknowledge them,
+    so they're taken as delivered once sent.
+    */
+    fn send_stored_messages(&mut self) -> Result<(), Box<dyn Error>> {
+        let Some(uname) = self.uname.clone() else {
+            return Ok(());
+        };
+        let stored = match self.server.stored_messages(&uname) {
+            Ok(stored) => stored,
+            Err(e) => {
+                eprintln!("error reading inbox of {}: {}", uname, e);
+                return Ok(());
+            }
+        };
+        if !stored.is_empty() {
+            println!("sending {} stored message(s) to {}", stored.len(), uname);
+        }
+        for chat_message in &stored {
+            self.send(chat_message)?;
+            if self.allows(MessageType::InboxAck) {
+                continue;
+            }
+            if let Err(e) = self.server.remove_stored(&uname, chat_message.message_id()) {
+                eprintln!("error removing message sent to {} from their inbox: {}", uname, e);
+            }
+        }
+
+        Ok(())
+    }
+
+    /**
+    Sends the client the group invites and messages stored for it while it was
+    offline, oldest first, then where each of its groups stands now (which
+    may have changed since)
+    */
+    fn send_stored_group_packets(&mut self) -> Result<(), Box<dyn Error>> {
+        let Some(uname) = self.uname.clone() else {
+            return Ok(());
+        };
+        let stored = match self.server.take_stored_group_packets(&uname) {
+            Ok(stored) => stored,
+            Err(e) => {
+                eprintln!("error reading group inbox of {}: {}", uname, e);
+                Vec::new()
+            }
+        };
+        if !stored.is_empty() {
+            println!("sending {} stored group packet(s) to {}", stored.len(), uname);
+        }
+        for packet in &stored {
+            if self.allows(message_types::method_num_to_message_type(packet.method)) {
+                self.write(packet)?;
+            }
+        }
+
+        if !self.allows(MessageType::GroupInfo) {
+            return Ok(());
+        }
+        for (group_id, group) in self.server.groups_of(&uname) {
+            self.send(&GroupInfo::new(group_id, &group.name, group.members))?;
+        }
+        Ok(())
+    }
+
+    /**
+    Answers a HistoryReq with the next batch of the conversation's messages
+    after its cursor, or a Failure if the client isn't connected with the
+    other user or the cursor is after a message the server doesn't have
+    */
+    fn handle_history_req(&mut self, history_req: HistoryReq) -> Result<(), Box<dyn Error>> {
+        let Some(uname) = self.uname.clone() else {
+            eprintln!("dropping history request from unverified session");
+            return Ok(());
+        };
+        if !self.allows(MessageType::HistoryResp) {
+            eprintln!("dropping history request from client that didn't negotiate history");
+            return Ok(());
+        }
+
+        let conn_uname = history_req.conn_uname();
+        let cursor = history_req.cursor();
+        if !self.server.are_connected(&uname, conn_uname) {
+            eprintln!("refusing history request from {} for non-connection {}", uname, conn_uname);
+            return self.send(&HistoryResp::failure(conn_uname, cursor));
+        }
+
+        let mut after = cursor;
+        loop {
+            let (messages, more) = match self.server.history_after(&uname, conn_uname, after, MAX_HISTORY_BATCH_LEN) {
+                Ok(Some(found)) => found,
+                Ok(None) => return self.send(&HistoryResp::failure(conn_uname, cursor)),
+                Err(e) => {
+                    eprintln!("error reading history of {} and {}: {}", uname, conn_uname, e);
+                    return self.send(&HistoryResp::failure(conn_uname, cursor));
+                }
+            };
+
+            // the client carries on after the last message it's sent, so if
+            // every one fetched was too large to send, carry on past them here
+            let last = messages.last().map(ChatMessage::message_id);
+            let (batch, left_over) = self.history_batch(conn_uname, cursor, messages);
+            match last {
+                Some(last) if batch.is_empty() && more => after = HistoryCursor::AfterMessage(last),
+                _ => return self.send(&HistoryResp::new(conn_uname, cursor, batch, left_over || more)),
+            }
+        }
+    }
+
+    // The first of messages that fit in a HistoryResp to this client, and
+    // whether any were left over. One too large to be sent in a batch of its
+    // own is left out, so can't hold up the rest.
+    fn history_batch(
+        &self,
+        conn_uname: &Username,
+        cursor: HistoryCursor,
+        messages: Vec<ChatMessage>
+    ) -> (Vec<ChatMessage>, bool) {
+        let max_len = if self.allows(MessageType::Fragment) { MAX_MESSAGE_LEN } else { MAX_PACKET_LEN };
+        let mut len = HistoryResp::failure(conn_uname, cursor).length();
+
+        let mut batch = Vec::new();
+        for chat_message in messages {
+            let message_len = HistoryResp::message_len(&chat_message);
+            if batch.is_empty() && len + message_len > max_len {
+                eprintln!("leaving message too large to send out of history batch for {}", conn_uname);
+                continue;
+            }
+            if len + message_len > max_len {
+                return (batch, true);
+            }
+            len += message_len;
+            batch.push(chat_message);
+        }
+
+        (batch, false)
+    }
+
+    /**
+    Deletes a chat message the client has acknowledged from its inbox
+    */
+    fn handle_inbox_ack(&mut self, inbox_ack: InboxAck) -> Result<(), Box<dyn Error>> {
+        let Some(uname) = &self.uname else {
+            eprintln!("dropping inbox ack from unverified session");
+            return Ok(());
+        };
+
+        if let Err(e) = self.server.remove_stored(uname, inbox_ack.message_id()) {
+            eprintln!("error removing acknowledged message from inbox of {}: {}", uname, e);
+        }
+
+        Ok(())
+    }
+
+    /**
+    Sends the client a fresh AuthChallenge, replacing any unanswered one
+    */
+    fn send_challenge(&mut self) -> Result<(), Box<dyn Error>> {
+        let auth_challenge = AuthChallenge::new();
+        self.challenge = Some(*auth_challenge.challenge());
+        self.send(&auth_challenge)
+    }
+
+    /**
+    Relays a chat message on to its recipient (or stores it for them, if
+    they're offline), and lets the sender know whether it has been accepted
+    */
+    fn handle_chat_message(&mut self, chat_message: ChatMessage) -> Result<(), Box<dyn Error>> {
+        let Some(uname) = self.uname.clone() else {
+            eprintln!("dropping chat message from unverified session");
+            return Ok(());
+        };
+
+        // clients may only send as themselves
+        if chat_message.send_uname != uname {
+            eprintln!("dropping chat message with forged sender from {}", uname);
+            return Ok(());
+        }
+
+        // ...and must sign what they send, as the recipient will check
+        let signed = self.server.identity_key(&uname)
+            .is_some_and(|identity_key| chat_message.verify_signature(&identity_key));
+        if !signed {
+            eprintln!("dropping chat message with bad signature from {}", uname);
+            return Ok(());
+        }
+
+        // bodies are end-to-end encrypted; anything else would be relayed in the clear
+        if let Err(e) = Envelope::deserialize(&chat_message.msg_buffer) {
+            eprintln!("dropping unencrypted chat message from {}: {}", uname, e);
+            return Ok(());
+        }
+
+        let recv_uname = &chat_message.recv_uname;
+        if !self.server.are_connected(&uname, recv_uname) {
+            eprintln!("dropping chat message from {} to non-connection {}", uname, recv_uname);
+            return Ok(());
+        }
+
+        match self.server.send_or_store(&chat_message) {
+            Ok(Delivery::Sent) => {}
+            Ok(Delivery::Stored) => println!("{} is offline, storing message from {}", recv_uname, uname),
+            Ok(Delivery::InboxFull) => {
+                println!("{} is offline and their inbox is full, rejecting message from {}", recv_uname, uname);
+                if self.allows(MessageType::Receipt) {
+                    self.send(&Receipt::for_message(ReceiptKind::Rejected, &chat_message))?;
+                }
+                return Ok(());
+            }
+            Err(e) => {
+                eprintln!("error storing message from {} for {}: {}", uname, recv_uname, e);
+                return Ok(());
+            }
+        }
+        if let Err(e) = self.server.add_to_history(&chat_message) {
+            eprintln!("error adding message from {} to history: {}", uname, e);
+        }
+
+        if self.allows(MessageType::Receipt) {
+            self.send(&Receipt::for_message(ReceiptKind::Accepted, &chat_message))?;
+        }
+        Ok(())
+    }
+
+    fn handle_group_create(&mut self, group_create: GroupCreate) -> Result<(), Box<dyn Error>> {
+        let Some(uname) = self.uname.clone() else {
+            eprintln!("dropping group creation from unverified session");
+            return Ok(());
+        };
+
+        let group_id = group_create.group_id();
+        let group_name = group_create.group_name();
+        match self.server.create_group(group_id, group_name, &uname) {
+            Ok(true) => {}
+            Ok(false) => return self.send(&GroupInfo::failure(group_id, group_name)),
+            Err(e) => {
+                eprintln!("error creating group for {}: {}", uname, e);
+                return self.send(&GroupInfo::failure(group_id, group_name));
+            }
+        }
+
+        println!("{} created group {}", uname, shared::group_id_to_string(group_id));
+        self.send(&GroupInfo::new(group_id, group_name, vec![uname]))
+    }
+
+    /**
+    Adds one of a member's connections to a group, passing the invite (and the
+    group key it carries) on to them, or into their inbox if they're offline.
+    The invite fails if it can't be kept for them.
+    */
+    fn handle_group_invite(&mut self, group_invite: GroupInvite) -> Result<(), Box<dyn Error>> {
+        let Some(uname) = self.uname.clone() else {
+            eprintln!("dropping group invite from unverified session");
+            return Ok(());
+        };
+
+        let group_id = group_invite.group_id();
+        let invitee = group_invite.invitee();
+        let Some(group) = self.server.group(&group_id).filter(|group| group.is_member(&uname)) else {
+            return self.send(&GroupInfo::failure(group_id, ""));
+        };
+        let allowed = group_invite.inviter() == &uname
+            && self.server.are_connected(&uname, invitee)
+            && !group_invite.sealed_key().is_empty();
+        if !allowed {
+            eprintln!("refusing group invite from {} to {}", uname, invitee);
+            return self.send(&GroupInfo::failure(group_id, &group.name));
+        }
+
+        let group = match self.server.add_group_member(&group_id, invitee) {
+            Ok(Some(group)) => group,
+            Ok(None) => return self.send(&GroupInfo::failure(group_id, &group.name)),
+            Err(e) => {
+                eprintln!("error adding {} to group: {}", invitee, e);
+                return self.send(&GroupInfo::failure(group_id, &group.name));
+            }
+        };
+        let delivered = match self.server.send_or_store_group(invitee, Packet::wrap(&group_invite)) {
+            Ok(Delivery::Sent) => true,
+            Ok(Delivery::Stored) => {
+                println!("{} is offline, storing group invite from {}", invitee, uname);
+                true
+            }
+            Ok(Delivery::InboxFull) => {
+                println!("{} is offline and their inbox is full, refusing group invite from {}", invitee, uname);
+                false
+            }
+            Err(e) => {
+                eprintln!("error storing group invite from {} for {}: {}", uname, invitee, e);
+                false
+            }
+        };
+        if !delivered {
+            if let Err(e) = self.server.remove_group_member(&group_id, invitee) {
+                eprintln!("error removing {} from group: {}", invitee, e);
+            }
+            return self.send(&GroupInfo::failure(group_id, &group.name));
+        }
+
+        println!("{} added {} to group {}", uname, invitee, shared::group_id_to_string(group_id));
+        self.broadcast_group_info(group_id, &group.name, &group.members);
+        Ok(())
+    }
+
+    fn handle_group_leave(&mut self, group_leave: GroupLeave) -> Result<(), Box<dyn Error>> {
+        let Some(uname) = self.uname.clone() else {
+            eprintln!("dropping group leave from unverified session");
+            return Ok(());
+        };
+
+        let group_id = group_leave.group_id();
+        let group = match self.server.remove_group_member(&group_id, &uname) {
+            Ok(Some(group)) => group,
+            Ok(None) => return self.send(&GroupInfo::failure(group_id, "")),
+            Err(e) => {
+                eprintln!("error removing {} from group: {}", uname, e);
+                return self.send(&GroupInfo::failure(group_id, ""));
+            }
+        };
+
+        println!("{} left group {}", uname, shared::group_id_to_string(group_id));
+        self.broadcast_group_info(group_id, &group.name, &group.members);
+        // ...and the leaver, who is no longer in the member list
+        self.send(&GroupInfo::new(group_id, &group.name, group.members.clone()))
+    }
+
+    /**
+    Fans a group message out to every other member of the group, storing it
+    for those who are offline
+    */
+    fn handle_group_message(&mut self, group_message: GroupMessage) -> Result<(), Box<dyn Error>> {
+        let Some(uname) = self.uname.clone() else {
+            eprintln!("dropping group message from unverified session");
+            return Ok(());
+        };
+
+        if group_message.send_uname != uname {
+            eprintln!("dropping group message with forged sender from {}", uname);
+            return Ok(());
+        }
+
+        let signed = self.server.identity_key(&uname)
+            .is_some_and(|identity_key| group_message.verify_signature(&identity_key));
+        if !signed {
+            eprintln!("dropping group message with bad signature from {}", uname);
+            return Ok(());
+        }
+
+        if let Err(e) = Envelope::deserialize(&group_message.msg_buffer) {
+            eprintln!("dropping unencrypted group message from {}: {}", uname, e);
+            return Ok(());
+        }
+
+        let Some(group) = self.server.group(&group_message.group_id).filter(|group| group.is_member(&uname)) else {
+            eprintln!("dropping group message from {} to a group they're not in", uname);
+            return Ok(());
+        };
+
+        for member in group.members.iter().filter(|member| **member != uname) {
+            match self.server.send_or_store_group(member, Packet::wrap(&group_message)) {
+                Ok(Delivery::Sent) => {}
+                Ok(Delivery::Stored) => println!("{} is offline, storing group message from {}", member, uname),
+                Ok(Delivery::InboxFull) => {
+                    println!("{} is offline and their inbox is full, dropping group message from {}", member, uname);
+                }
+                Err(e) => eprintln!("error storing group message from {} for {}: {}", uname, member, e),
+            }
+        }
+        Ok(())
+    }
+
+    // Tells every member of a group (including this session's user, if a member) who is in it
+    fn broadcast_group_info(&self, group_id: [u8; GROUP_ID_LEN], group_name: &str, members: &[Username]) {
+        let group_info = GroupInfo::new(group_id, group_name, members.to_vec());
+        for member in members {
+            self.server.send_to(member, Packet::wrap(&group_info));
+        }
+    }
+
+    /**
+    Looks up the identity key a user published at signup
+    */
+    fn handle_identity_req(&mut self, identity_req: IdentityReq) -> Result<(), Box<dyn Error>> {
+        if self.uname.is_none() {
+            eprintln!("dropping identity request from unverified session");
+            return Ok(());
+        }
+
+        let uname = identity_req.uname();
+        self.send(&IdentityResp::new(uname, self.server.identity_key(uname)))
+    }
+
+    /**
+    Issues the client a new token, replacing the one it verified with unless
+    it asked to keep it
+    */
+    fn handle_rotate_token_req(&mut self, rotate_req: RotateTokenReq) -> Result<(), Box<dyn Error>> {
+        let (Some(uname), Some(token_id)) = (&self.uname, &self.token_id) else {
+            eprintln!("dropping token rotation from unverified session");
+            return Ok(());
+        };
+
+        let rotate_resp = RotateTokenResp::new(StatusCode::Success);
+        let verifier = auth::derive_verifier(&rotate_resp.token());
+        let replacing = if rotate_req.keep_current() { None } else { Some(token_id) };
+        match self.server.issue_token(uname, rotate_resp.token_id(), verifier, replacing) {
+            Ok(true) => {}
+            Ok(false) => return self.send(&RotateTokenResp::new(StatusCode::Failure)),
+            Err(e) => {
+                eprintln!("error saving token issued to {}: {}", uname, e);
+                return self.send(&RotateTokenResp::new(StatusCode::Failure));
+            }
+        }
+
+        println!("{} was issued token {}", uname, shared::token_id_to_string(rotate_resp.token_id()));
+        if !rotate_req.keep_current() {
+            self.token_id = Some(rotate_resp.token_id());
+        }
+        self.send(&rotate_resp)
+    }
+
+    fn handle_list_tokens_req(&mut self, _list_req: ListTokensReq) -> Result<(), Box<dyn Error>> {
+        let Some(uname) = &self.uname else {
+            eprintln!("dropping token list request from unverified session");
+            return Ok(());
+        };
+
+        let tokens = self.server.tokens(uname, self.token_id.as_ref());
+        self.send(&ListTokensResp::new(tokens))
+    }
+
+    /**
+    Revokes one of the client's tokens. Sessions verified with it (including
+    this one, if it's the current token) carry on, but it can't verify again.
+    */
+    fn handle_revoke_token_req(&mut self, revoke_req: RevokeTokenReq) -> Result<(), Box<dyn Error>> {
+        let Some(uname) = &self.uname else {
+            eprintln!("dropping token revocation from unverified session");
+            return Ok(());
+        };
+
+        let token_id = revoke_req.token_id();
+        let status_code = match self.server.revoke_token(uname, &token_id) {
+            Ok(true) => {
+                println!("{} revoked token {}", uname, shared::token_id_to_string(token_id));
+                StatusCode::Success
+            }
+            Ok(false) => StatusCode::Failure,
+            Err(e) => {
+                eprintln!("error saving revocation of {}'s token: {}", uname, e);
+                StatusCode::Failure
+            }
+        };
+        self.send(&RevokeTokenResp::new(status_code, token_id))
+    }
+
+    /**
+    Relays a Delivered/Read receipt from a message's recipient back to its sender
+    */
+    fn handle_receipt(&mut self, receipt: Receipt) -> Result<(), Box<dyn Error>> {
+        let Some(uname) = &self.uname else {
+            eprintln!("dropping receipt from unverified session");
+            return Ok(());
+        };
+
+        // only the server accepts or rejects messages, and only a message's recipient can receipt it
+        let from_server = matches!(receipt.kind(), ReceiptKind::Accepted | ReceiptKind::Rejected);
+        if from_server || receipt.recv_uname() != uname {
+            eprintln!("dropping forged receipt from {}", uname);
+            return Ok(());
+        }
+
+        let send_uname = receipt.send_uname();
+        if !self.server.are_connected(uname, send_uname) {
+            eprintln!("dropping receipt from {} to non-connection {}", uname, send_uname);
+            return Ok(());
+        }
+
+        if !self.server.send_to(send_uname, Packet::wrap(&receipt)) {
+            println!("{} is offline, dropping receipt from {}", send_uname, uname);
+        }
+
+        Ok(())
+    }
+
+    /**
+    Adds a fragment to the packet it belongs to, handling that packet once all
+    of it has arrived
+    */
+    fn handle_fragment(&mut self, fragment: Fragment) -> Result<(), Box<dyn Error>> {
+        if !self.allows(MessageType::Fragment) {
+            eprintln!("dropping fragment from client that didn't negotiate fragmentation");
+            return Ok(());
+        }
+
+        let packet = match self.reassembler.add(fragment) {
+            Ok(Some(packet)) => packet,
+            Ok(None) => return Ok(()),
+            Err(e) => {
+                eprintln!("dropping fragmented packet: {}", e);
+                return Ok(());
+            }
+        };
+
+        match packet.decode() {
+            Ok(message) => self.handle_message(message),
+            Err(e) => {
+                eprintln!("dropping reassembled packet (method {}): {}", packet.method, e);
+                Ok(())
+            }
+        }
+    }
+
+    fn handle_ping(&mut self, ping: Ping) -> Result<(), Box<dyn Error>> {
+        if !self.allows(MessageType::Pong) {
+            eprintln!("dropping ping from client that didn't negotiate heartbeats");
+            return Ok(());
+        }
+        self.send(&ping.pong())
+    }
+
+    fn handle_pong(&mut self, pong: Pong) -> Result<(), Box<dyn Error>> {
+        self.heartbeat.handle_pong(&pong, Instant::now());
+        Ok(())
+    }
+
+    /**
+    Pings the client if a ping is due. Returns false if the client has missed
+    its heartbeats, meaning the session should end.
+    */
+    fn check_heartbeat(&mut self) -> Result<bool, Box<dyn Error>> {
+        if !self.allows(MessageType::Ping) {
+            return Ok(true);
+        }
+
+        let now = Instant::now();
+        if self.heartbeat.status(now) == LinkStatus::Dead {
+            let uname = self.uname.as_ref().map_or("unverified client".to_string(), |uname| uname.to_string());
+            println!("{} missed heartbeats, ending session", uname);
+            return Ok(false);
+        }
+
+        if let Some(ping) = self.heartbeat.poll(now) {
+            self.send(&ping)?;
+        }
+        Ok(true)
+    }
+
+    /**
+    Relays a connection request on to the requested user, or answers it
+    directly if the request can't go ahead
+    */
+    fn handle_conn_req(&mut self, conn_req: C2cConnReq) -> Result<(), Box<dyn Error>> {
+        let Some(uname) = self.uname.clone() else {
+            eprintln!("dropping connection request from unverified session");
+            return Ok(());
+        };
+
+        if *conn_req.req_uname() != uname {
+            eprintln!("dropping connection request with forged requester from {}", uname);
+            return Ok(());
+        }
+
+        let resp_uname = conn_req.resp_uname();
+        let response = if *resp_uname == uname || !self.server.account_exists(resp_uname) {
+            Some(ConnResponse::Reject)
+        } else if self.server.are_connected(&uname, resp_uname) {
+            Some(ConnResponse::AlreadyConnected)
+        } else if self.server.is_blocked(resp_uname, &uname) {
+            Some(ConnResponse::Block)
+        } else {
+            None
+        };
+
+        if let Some(response) = response {
+            return self.send(&C2cConnResp::new(&uname, resp_uname, response, None));
+        }
+
+        self.server.add_conn_req(&uname, resp_uname);
+        if !self.server.send_to(resp_uname, Packet::wrap(&conn_req)) {
+            println!("{} is offline, dropping connection request from {}", resp_uname, uname);
+        }
+
+        Ok(())
+    }
+
+    /**
+    Records the outcome of a connection request and relays it back to the requester
+    */
+    fn handle_conn_resp(&mut self, conn_resp: C2cConnResp) -> Result<(), Box<dyn Error>> {
+        let Some(uname) = self.uname.clone() else {
+            eprintln!("dropping connection response from unverified session");
+            return Ok(());
+        };
+
+        let req_uname = conn_resp.req_uname();
+        if *conn_resp.resp_uname() != uname || !self.server.take_conn_req(req_uname, &uname) {
+            eprintln!("dropping unsolicited connection response from {}", uname);
+            return Ok(());
+        }
+
+        let saved = match conn_resp.response() {
+            ConnResponse::Accept | ConnResponse::AlreadyConnected => self.server.add_connection(req_uname, &uname),
+            ConnResponse::Block => self.server.add_block(&uname, req_uname),
+            ConnResponse::Reject => Ok(()),
+        };
+        // not passed on unless kept, so the requester never thinks they're connected when the server doesn't
+        if let Err(e) = saved {
+            eprintln!("error saving connection response from {} to {}, dropping it: {}", uname, req_uname, e);
+            return Ok(());
+        }
+
+        if !self.server.send_to(req_uname, Packet::wrap(&conn_resp)) {
+            println!("{} is offline, dropping connection response from {}", req_uname, uname);
+        }
+
+        // new connections start out knowing each other's presence
+        if conn_resp.response() == ConnResponse::Accept {
+            self.server.send_to(req_uname, Packet::wrap(&self.server.presence(&uname)));
+            self.send_presence(req_uname)?;
+        }
+
+        Ok(())
+    }
+
+    /**
+    Records the user's new presence and passes it on to their connections
+    */
+    fn handle_presence_update(&mut self, presence_update: PresenceUpdate) -> Result<(), Box<dyn Error>> {
+        let Some(uname) = &self.uname else {
+            eprintln!("dropping presence update from unverified session");
+            return Ok(());
+        };
+
+        self.server.set_presence(uname, presence_update.state(), presence_update.status());
+        self.server.broadcast_presence(uname);
+        Ok(())
+    }
+
+    /**
+    Passes a typing indicator on to its recipient, if they're online
+    */
+    fn handle_typing(&mut self, typing: Typing) -> Result<(), Box<dyn Error>> {
+        let Some(uname) = &self.uname else {
+            eprintln!("dropping typing indicator from unverified session");
+            return Ok(());
+        };
+
+        let recv_uname = typing.recv_uname();
+        if typing.send_uname() != uname || !self.server.are_connected(uname, recv_uname) {
+            eprintln!("dropping typing indicator from {} to non-connection {}", uname, recv_uname);
+            return Ok(());
+        }
+
+        // ephemeral, so no point telling anyone if the recipient is offline
+        self.server.send_to(recv_uname, Packet::wrap(&typing));
+        Ok(())
+    }
+
+    fn handle_file_offer(&mut self, file_offer: FileOffer) -> Result<(), Box<dyn Error>> {
+        if let Err(e) = Envelope::deserialize(file_offer.sealed_info()) {
+            eprintln!("dropping unencrypted file offer: {}", e);
+            return Ok(());
+        }
+
+        let (send_uname, recv_uname) = (file_offer.send_uname(), file_offer.recv_uname());
+        if self.relay(send_uname, recv_uname, Packet::wrap(&file_offer), "file offer") == Some(false) {
+            println!("{} is offline, dropping file offer from {}", recv_uname, send_uname);
+        }
+        Ok(())
+    }
+
+    fn handle_file_accept(&mut self, file_accept: FileAccept) -> Result<(), Box<dyn Error>> {
+        let (send_uname, recv_uname) = (file_accept.send_uname(), file_accept.recv_uname());
+        if self.relay(recv_uname, send_uname, Packet::wrap(&file_accept), "file accept") == Some(false) {
+            println!("{} is offline, dropping file accept from {}", send_uname, recv_uname);
+        }
+        Ok(())
+    }
+
+    fn handle_file_chunk(&mut self, file_chunk: FileChunk) -> Result<(), Box<dyn Error>> {
+        if let Err(e) = Envelope::deserialize(file_chunk.data()) {
+            eprintln!("dropping unencrypted file chunk: {}", e);
+            return Ok(());
+        }
+
+        // a recipient who drops out part-way asks for the rest when they accept again
+        self.relay(file_chunk.send_uname(), file_chunk.recv_uname(), Packet::wrap(&file_chunk), "file chunk");
+        Ok(())
+    }
+
+    fn handle_file_complete(&mut self, file_complete: FileComplete) -> Result<(), Box<dyn Error>> {
+        let (send_uname, recv_uname) = (file_complete.send_uname(), file_complete.recv_uname());
+        if self.relay(recv_uname, send_uname, Packet::wrap(&file_complete), "file complete") == Some(false) {
+            println!("{} is offline, dropping file complete from {}", send_uname, recv_uname);
+        }
+        Ok(())
+    }
+
+    /**
+    Passes an edit of one of this user's chat messages on to its recipient,
+    if it's signed and sealed as the chat message itself had to be
+    */
+    fn handle_message_edit(&mut self, message_edit: MessageEdit) -> Result<(), Box<dyn Error>> {
+        if let Err(e) = Envelope::deserialize(message_edit.body()) {
+            eprintln!("dropping unencrypted message edit: {}", e);
+            return Ok(());
+        }
+
+        let (send_uname, recv_uname) = (message_edit.send_uname(), message_edit.recv_uname());
+        let signed = self.server.identity_key(send_uname)
+            .is_some_and(|identity_key| message_edit.verify_signature(&identity_key));
+        if !signed {
+            eprintln!("dropping message edit with bad signature from {}", send_uname);
+            return Ok(());
+        }
+        if !self.sent_by(send_uname, recv_uname, message_edit.message_id(), "message edit") {
+            return Ok(());
+        }
+
+        if self.relay(send_uname, recv_uname, Packet::wrap(&message_edit), "message edit") == Some(false) {
+            println!("{} is offline, dropping message edit from {}", recv_uname, send_uname);
+        }
+        Ok(())
+    }
+
+    fn handle_message_delete(&mut self, message_delete: MessageDelete) -> Result<(), Box<dyn Error>> {
+        let (send_uname, recv_uname) = (message_delete.send_uname(), message_delete.recv_uname());
+        let signed = self.server.identity_key(send_uname)
+            .is_some_and(|identity_key| message_delete.verify_signature(&identity_key));
+        if !signed {
+            eprintln!("dropping message deletion with bad signature from {}", send_uname);
+            return Ok(());
+        }
+        if !self.sent_by(send_uname, recv_uname, message_delete.message_id(), "message deletion") {
+            return Ok(());
+        }
+
+        if self.relay(send_uname, recv_uname, Packet::wrap(&message_delete), "message deletion") == Some(false) {
+            println!("{} is offline, dropping message deletion from {}", recv_uname, send_uname);
+        }
+        Ok(())
+    }
+
+    fn handle_reaction(&mut self, reaction: Reaction) -> Result<(), Box<dyn Error>> {
+        let (send_uname, recv_uname) = (reaction.send_uname(), reaction.recv_uname());
+        let signed = self.server.identity_key(send_uname)
+            .is_some_and(|identity_key| reaction.verify_signature(&identity_key));
+        if !signed {
+            eprintln!("dropping reaction with bad signature from {}", send_uname);
+            return Ok(());
+        }
+        if self.message_sender(send_uname, recv_uname, reaction.message_id(), "reaction").is_none() {
+            return Ok(());
+        }
+
+        if self.relay(send_uname, recv_uname, Packet::wrap(&reaction), "reaction") == Some(false) {
+            println!("{} is offline, dropping reaction from {}", recv_uname, send_uname);
+        }
+        Ok(())
+    }
+
+    /**
+    Checks the chat message with the given id, between send_uname and
+    recv_uname, was sent by send_uname, before relaying what (an edit or
+    deletion of it) from them
+    */
+    fn sent_by(&self, send_uname: &Username, recv_uname: &Username, message_id: [u8; MESSAGE_ID_LEN], what: &str) -> bool {
+        match self.message_sender(send_uname, recv_uname, message_id, what) {
+            Some(sender) if sender == *send_uname => true,
+            Some(sender) => {
+                eprintln!("dropping {} from {} of a message sent by {}", what, send_uname, sender);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /**
+    Who sent the chat message with the given id between send_uname and
+    recv_uname, checked before relaying what (e.g. a reaction to it) from
+    send_uname. None if it isn't in their history, in which case what is
+    dropped.
+    */
+    fn message_sender(
+        &self,
+        send_uname: &Username,
+        recv_uname: &Username,
+        message_id: [u8; MESSAGE_ID_LEN],
+        what: &str
+    ) -> Option<Username> {
+        match self.server.message_sender(send_uname, recv_uname, message_id) {
+            Ok(Some(sender)) => Some(sender),
+            Ok(None) => {
+                eprintln!("dropping {} from {} of a message not in history", what, send_uname);
+                None
+            }
+            Err(e) => {
+                eprintln!("error reading history of {} and {}, dropping {}: {}", send_uname, recv_uname, what, e);
+                None
+            }
+        }
+    }
+
+    /**
+    Relays a message (e.g. a file transfer message) from this session's user
+    (who must be from) to their connection to.
+
+    Returns whether to is online to receive it, or None if it was refused.
+    */
+    fn relay(&self, from: &Username, to: &Username, packet: Packet, what: &str) -> Option<bool> {
+        let Some(uname) = &self.uname else {
+            eprintln!("dropping {} from unverified session", what);
+            return None;
+        };
+        if from != uname || !self.server.are_connected(uname, to) {
+            eprintln!("dropping {} from {} to non-connection {}", what, uname, to);
+            return None;
+        }
+
+        Some(self.server.send_to(to, packet))
+    }
+
+    /**
+    Tells the client uname's presence, if it negotiated presence
+    */
+    fn send_presence(&mut self, uname: &Username) -> Result<(), Box<dyn Error>> {
+        if !self.allows(MessageType::Presence) {
+            return Ok(());
+        }
+        let presence = self.server.presence(uname);
+        self.send(&presence)
+    }
+
+    fn send<M: Message>(&mut self, message: &M) -> Result<(), Box<dyn Error>> {
+        Ok(self.write(&Packet::wrap(message))?)
+    }
+
+    // Writes out any packets other sessions have queued for this user,
+    // skipping those for features the client didn't ask for
+    fn flush_outbound(&mut self) -> Result<(), Box<dyn Error>> {
+        while let Ok(packet) = self.outbound_rx.try_recv() {
+            if !self.allows(message_types::method_num_to_message_type(packet.method)) {
+                continue;
+            }
+
+            match self.write(&packet) {
+                Ok(()) => {}
+                Err(e @ (ProtocolError::PacketTooLarge { .. } | ProtocolError::MessageTooLarge { .. })) => {
+                    eprintln!("dropping relayed packet (method {}): {}", packet.method, e);
+                }
+                Err(e) => return Err(Box::new(e)),
+            }
+        }
+
+        Ok(())
+    }
+
+    // Writes a packet, in fragments if it's too large to send whole and the client supports them
+    fn write(&mut self, packet: &Packet) -> Result<(), ProtocolError> {
+        if self.allows(MessageType::Fragment) {
+            self.writer.write_fragmented(packet)
+        } else {
+            self.writer.write_packet(packet)
+        }
+    }
+
+    // true if the given message type can be sent to this client
+    fn allows(&self, message_type: MessageType) -> bool {
+        self.negotiated.is_some_and(|negotiated| negotiated.allows(message_type))
+    }
+}
+
+fn is_timeout(kind: io::ErrorKind) -> bool {
+    kind == io::ErrorKind::WouldBlock || kind == io::ErrorKind::TimedOut
+}
+
+fn is_disconnect(kind: io::ErrorKind) -> bool {
+    kind == io::ErrorKind::UnexpectedEof
+        || kind == io::ErrorKind::ConnectionReset
+        || kind == io::ErrorKind::ConnectionAborted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{SocketAddr, TcpListener};
+    use std::thread;
+
+    use protocol::{IdentityKeyPair, ReactionAction};
+    use protocol::field_lens::{ENVELOPE_NONCE_LEN, TOKEN_LEN};
+    use tempfile::TempDir;
+
+    // everything but heartbeats and presence, which would only get in the way
+    const TEST_CAPABILITIES: Capabilities = Capabilities::RECEIPTS
+        .union(Capabilities::FRAGMENTATION)
+        .union(Capabilities::TYPING)
+        .union(Capabilities::FILE_TRANSFER)
+        .union(Capabilities::EDITS)
+        .union(Capabilities::REACTIONS)
+        .union(Capabilities::INBOX)
+        .union(Capabilities::HISTORY);
+
+    // longest to wait for something the server should send
+    const RECV_TIMEOUT: Duration = Duration::from_secs(5);
+
+    // how long the server has to stay quiet for it to be sending nothing
+    const QUIET_TIMEOUT: Duration = Duration::from_millis(300);
+
+    // A server listening on localhost over plain TCP, keeping what it stores in a tempdir
+    struct TestServer {
+        state: Arc<ServerState>,
+        addr: SocketAddr,
+        dir: TempDir,
+    }
+
+    // A user signed up with a TestServer
+    struct User {
+        uname: Username,
+        identity: IdentityKeyPair,
+        token: [u8; TOKEN_LEN],
+    }
+
+    // One client's session with a TestServer, driven by hand
+    struct TestClient {
+        reader: PacketReader<Stream>,
+        writer: PacketWriter<Stream>,
+        challenge: [u8; CHALLENGE_LEN],
+    }
+
+    impl TestServer {
+        fn start() -> Self {
+            let dir = tempfile::tempdir().unwrap();
+            let state = Arc::new(ServerState::new(
+                dir.path().join("accounts"),
+                dir.path().join("inbox"),
+                dir.path().join("history"),
+            ).unwrap());
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+
+            let server = Arc::clone(&state);
+            thread::spawn(move || {
+                for tcp in listener.incoming().flatten() {
+                    let server = Arc::clone(&server);
+                    thread::spawn(move || {
+                        let _ = run(tcp, server, HeartbeatConfig::default(), None);
+                    });
+                }
+            });
+            TestServer { state, addr, dir }
+        }
+
+        // The state a restarted server would have, read back from what this one kept
+        fn restarted_state(&self) -> ServerState {
+            ServerState::new(
+                self.dir.path().join("accounts"),
+                self.dir.path().join("inbox"),
+                self.dir.path().join("history"),
+            ).unwrap()
+        }
+
+        fn connect(&self, capabilities: Capabilities) -> TestClient {
+            let stream = Stream::plain(TcpStream::connect(self.addr).unwrap());
+            let mut client = TestClient {
+                reader: PacketReader::new(stream.try_clone().unwrap()),
+                writer: PacketWriter::new(stream),
+                challenge: [0u8; CHALLENGE_LEN],
+            };
+            client.send(&Hello::new(capabilities));
+            match client.recv() {
+                ProtocolMessage::HelloAck(hello_ack) => assert_eq!(hello_ack.status_code(), StatusCode::Success),
+                message => panic!("expected HelloAck, got {:?}", message.message_type()),
+            }
+            client.expect_challenge();
+            client
+        }
+
+        fn sign_up(&self, name: &str) -> User {
+            let uname = Username::new(name).unwrap();
+            let identity = IdentityKeyPair::generate();
+            let mut client = self.connect(TEST_CAPABILITIES);
+            client.send(&SignupReq::new(&uname, identity.identity_key()));
+            let token = match client.recv() {
+                ProtocolMessage::SignupResp(signup_resp) => {
+                    assert_eq!(signup_resp.status_code(), StatusCode::Success);
+                    signup_resp.token()
+                }
+                message => panic!("expected SignupResp, got {:?}", message.message_type()),
+            };
+            User { uname, identity, token }
+        }
+
+        // A client verified as user, having negotiated capabilities
+        fn log_in_with(&self, user: &User, capabilities: Capabilities) -> TestClient {
+            let mut client = self.connect(capabilities);
+            assert!(client.verify(&user.uname, &user.token));
+            client
+        }
+
+        fn log_in(&self, user: &User) -> TestClient {
+            self.log_in_with(user, TEST_CAPABILITIES)
+        }
+
+        fn connect_users(&self, user1: &User, user2: &User) {
+            self.state.add_connection(&user1.uname, &user2.uname).unwrap();
+        }
+    }
+
+    impl TestClient {
+        fn send<M: Message>(&mut self, message: &M) {
+            self.writer.write_packet(&Packet::wrap(message)).unwrap();
+        }
+
+        // Answers the outstanding challenge with a proof from token; returns whether the server accepted it
+        fn verify(&mut self, uname: &Username, token: &[u8; TOKEN_LEN]) -> bool {
+            let proof = auth::prove(token, &self.challenge, uname);
+            self.send(&VerifyReq::new(uname, proof));
+            self.expect_verify_resp()
+        }
+
+        fn expect_verify_resp(&mut self) -> bool {
+            match self.recv() {
+                ProtocolMessage::VerifyResp(verify_resp) if verify_resp.status_code == StatusCode::Success => true,
+                ProtocolMessage::VerifyResp(_) => {
+                    self.expect_challenge();
+                    false
+                }
+                message => panic!("expected VerifyResp, got {:?}", message.message_type()),
+            }
+        }
+
+        fn expect_challenge(&mut self) {
+            match self.recv() {
+                ProtocolMessage::AuthChallenge(auth_challenge) => self.challenge = *auth_challenge.challenge(),
+                message => panic!("expected AuthChallenge, got {:?}", message.message_type()),
+            }
+        }
+
+        fn recv(&mut self) -> ProtocolMessage {
+            self.try_recv(RECV_TIMEOUT).expect("nothing from the server")
+        }
+
+        fn try_recv(&mut self, timeout: Duration) -> Option<ProtocolMessage> {
+            self.reader.get_ref().set_read_timeout(Some(timeout)).unwrap();
+            match self.reader.read_packet() {
+                Ok(packet) => Some(packet.decode().unwrap()),
+                Err(e) if e.io_kind().is_some_and(is_timeout) => None,
+                Err(e) => panic!("error reading from server: {}", e),
+            }
+        }
+
+        fn expect_nothing(&mut self) {
+            if let Some(message) = self.try_recv(QUIET_TIMEOUT) {
+                panic!("expected nothing, got {:?}", message.message_type());
+            }
+        }
+
+        fn expect_receipt(&mut self, kind: ReceiptKind, message_id: [u8; MESSAGE_ID_LEN]) {
+            match self.recv() {
+                ProtocolMessage::Receipt(receipt) => {
+                    assert_eq!(receipt.kind(), kind);
+                    assert_eq!(receipt.message_id(), message_id);
+                }
+                message => panic!("expected Receipt, got {:?}", message.message_type()),
+            }
+        }
+
+        // Sends a chat message from sender to recv_uname, returning its id once the server has accepted it
+        fn send_chat_message(&mut self, sender: &User, recv_uname: &Username) -> [u8; MESSAGE_ID_LEN] {
+            let chat_message = sealed_message(sender, recv_uname);
+            self.send(&chat_message);
+            self.expect_receipt(ReceiptKind::Accepted, chat_message.message_id());
+            chat_message.message_id()
+        }
+
+        // Expects a GroupInfo for the group, with the given members, in the order they joined
+        fn expect_group_info(&mut self, group_id: [u8; GROUP_ID_LEN], members: &[&Username]) {
+            match self.recv() {
+                ProtocolMessage::GroupInfo(group_info) => {
+                    assert_eq!(group_info.group_id(), group_id);
+                    assert_eq!(group_info.members().iter().collect::<Vec<_>>(), members);
+                }
+                message => panic!("expected GroupInfo, got {:?}", message.message_type()),
+            }
+        }
+    }
+
+    // A chat message from sender with a sealed body, signed
+    fn sealed_message(sender: &User, recv_uname: &Username) -> ChatMessage {
+        let mut chat_message = ChatMessage::new(&sender.uname, recv_uname, "");
+        chat_message.set_body(sealed_body());
+        chat_message.sign(&sender.identity);
+        chat_message
+    }
+
+    // A body that passes for an encrypted one
+    fn sealed_body() -> Vec<u8> {
+        Envelope::new(1, [0u8; ENVELOPE_NONCE_LEN], b"sealed under a conversation key".to_vec()).serialize()
+    }
+
+    // A group message from sender with a sealed body, signed
+    fn sealed_group_message(sender: &User, group_id: [u8; GROUP_ID_LEN]) -> GroupMessage {
+        let mut group_message = GroupMessage::new(group_id, &sender.uname, "");
+        group_message.set_body(sealed_body());
+        group_message.sign(&sender.identity);
+        group_message
+    }
+
+    fn message_edit(sender: &User, recv_uname: &Username, message_id: [u8; MESSAGE_ID_LEN]) -> MessageEdit {
+        let mut message_edit = MessageEdit::new(message_id, &sender.uname, recv_uname, "");
+        message_edit.set_body(sealed_body());
+        message_edit.sign(&sender.identity);
+        message_edit
+    }
+
+    fn message_delete(sender: &User, recv_uname: &Username, message_id: [u8; MESSAGE_ID_LEN]) -> MessageDelete {
+        let mut message_delete = MessageDelete::new(message_id, &sender.uname, recv_uname);
+        message_delete.sign(&sender.identity);
+        message_delete
+    }
+
+    fn reaction(sender: &User, recv_uname: &Username, message_id: [u8; MESSAGE_ID_LEN]) -> Reaction {
+        let mut reaction = Reaction::new(message_id, &sender.uname, recv_uname, ReactionAction::Add, "👍").unwrap();
+        reaction.sign(&sender.identity);
+        reaction
+    }
+
+    #[test]
+    fn relays_reactions_only_to_messages_in_the_conversation() {
+        let server = TestServer::start();
+        let (harry, eddie, kerry) = (server.sign_up("Harry"), server.sign_up("Eddie"), server.sign_up("Kerry"));
+        server.connect_users(&harry, &eddie);
+        server.connect_users(&harry, &kerry);
+        let mut harry_client = server.log_in(&harry);
+        let mut eddie_client = server.log_in(&eddie);
+        let mut kerry_client = server.log_in(&kerry);
+
+        let to_eddie = harry_client.send_chat_message(&harry, &eddie.uname);
+        assert!(matches!(eddie_client.recv(), ProtocolMessage::ChatMessage(_)));
+        let to_kerry = harry_client.send_chat_message(&harry, &kerry.uname);
+        assert!(matches!(kerry_client.recv(), ProtocolMessage::ChatMessage(_)));
+
+        // a message from another conversation, or one never sent, isn't passed on
+        eddie_client.send(&reaction(&eddie, &harry.uname, to_kerry));
+        eddie_client.send(&reaction(&eddie, &harry.uname, shared::generate_message_id()));
+        harry_client.expect_nothing();
+
+        eddie_client.send(&reaction(&eddie, &harry.uname, to_eddie));
+        match harry_client.recv() {
+            ProtocolMessage::Reaction(reaction) => assert_eq!(reaction.message_id(), to_eddie),
+            message => panic!("expected Reaction, got {:?}", message.message_type()),
+        }
+    }
+
+    #[test]
+    fn relays_edits_and_deletions_only_from_the_sender() {
+        let server = TestServer::start();
+        let (harry, eddie) = (server.sign_up("Harry"), server.sign_up("Eddie"));
+        server.connect_users(&harry, &eddie);
+        let mut harry_client = server.log_in(&harry);
+        let mut eddie_client = server.log_in(&eddie);
+
+        let message_id = harry_client.send_chat_message(&harry, &eddie.uname);
+        assert!(matches!(eddie_client.recv(), ProtocolMessage::ChatMessage(_)));
+
+        // Eddie can't change what Harry said, and nobody can change what was never said
+        eddie_client.send(&message_edit(&eddie, &harry.uname, message_id));
+        eddie_client.send(&message_delete(&eddie, &harry.uname, message_id));
+        harry_client.send(&message_edit(&harry, &eddie.uname, shared::generate_message_id()));
+        harry_client.send(&message_delete(&harry, &eddie.uname, shared::generate_message_id()));
+        harry_client.expect_nothing();
+        eddie_client.expect_nothing();
+
+        harry_client.send(&message_edit(&harry, &eddie.uname, message_id));
+        match eddie_client.recv() {
+            ProtocolMessage::MessageEdit(message_edit) => assert_eq!(message_edit.message_id(), message_id),
+            message => panic!("expected MessageEdit, got {:?}", message.message_type()),
+        }
+        harry_client.send(&message_delete(&harry, &eddie.uname, message_id));
+        match eddie_client.recv() {
+            ProtocolMessage::MessageDelete(message_delete) => assert_eq!(message_delete.message_id(), message_id),
+            message => panic!("expected MessageDelete, got {:?}", message.message_type()),
+        }
+    }
+
+    #[test]
+    fn stores_messages_for_offline_users_until_acknowledged() {
+        let server = TestServer::start();
+        let (harry, eddie) = (server.sign_up("Harry"), server.sign_up("Eddie"));
+        server.connect_users(&harry, &eddie);
+        let mut harry_client = server.log_in(&harry);
+        let message_id = harry_client.send_chat_message(&harry, &eddie.uname);
+
+        // sent on every verification until acknowledged
+        for _ in 0..2 {
+            let mut eddie_client = server.log_in(&eddie);
+            match eddie_client.recv() {
+                ProtocolMessage::ChatMessage(chat_message) => assert_eq!(chat_message.message_id(), message_id),
+                message => panic!("expected ChatMessage, got {:?}", message.message_type()),
+            }
+        }
+
+        let mut eddie_client = server.log_in(&eddie);
+        assert!(matches!(eddie_client.recv(), ProtocolMessage::ChatMessage(_)));
+        eddie_client.send(&InboxAck::new(message_id));
+        // answered once the ack before it has been dealt with
+        eddie_client.send(&IdentityReq::new(&harry.uname));
+        assert!(matches!(eddie_client.recv(), ProtocolMessage::IdentityResp(_)));
+        assert!(server.state.stored_messages(&eddie.uname).unwrap().is_empty());
+        server.log_in(&eddie).expect_nothing();
+    }
+
+    #[test]
+    fn sends_stored_messages_once_to_clients_without_the_inbox() {
+        let server = TestServer::start();
+        let (harry, eddie) = (server.sign_up("Harry"), server.sign_up("Eddie"));
+        server.connect_users(&harry, &eddie);
+        let mut harry_client = server.log_in(&harry);
+        let message_id = harry_client.send_chat_message(&harry, &eddie.uname);
+
+        let without_inbox = Capabilities::from_bits(TEST_CAPABILITIES.bits() & !Capabilities::INBOX.bits());
+        let mut eddie_client = server.log_in_with(&eddie, without_inbox);
+        match eddie_client.recv() {
+            ProtocolMessage::ChatMessage(chat_message) => assert_eq!(chat_message.message_id(), message_id),
+            message => panic!("expected ChatMessage, got {:?}", message.message_type()),
+        }
+        assert!(server.state.stored_messages(&eddie.uname).unwrap().is_empty());
+        server.log_in_with(&eddie, without_inbox).expect_nothing();
+    }
+
+    #[test]
+    fn rejects_messages_for_a_full_inbox() {
+        let server = TestServer::start();
+        let (harry, eddie) = (server.sign_up("Harry"), server.sign_up("Eddie"));
+        server.connect_users(&harry, &eddie);
+
+        // fill Eddie's inbox with large messages, then with ones as small as Harry's
+        for body_len in [MAX_MESSAGE_LEN / 2, sealed_body().len()] {
+            loop {
+                let mut filler = ChatMessage::new(&harry.uname, &eddie.uname, "");
+                filler.set_body(vec![0u8; body_len]);
+                if matches!(server.state.send_or_store(&filler).unwrap(), Delivery::InboxFull) {
+                    break;
+                }
+            }
+        }
+        let stored = server.state.stored_messages(&eddie.uname).unwrap().len();
+
+        let mut harry_client = server.log_in(&harry);
+        let chat_message = sealed_message(&harry, &eddie.uname);
+        harry_client.send(&chat_message);
+        harry_client.expect_receipt(ReceiptKind::Rejected, chat_message.message_id());
+        assert_eq!(server.state.stored_messages(&eddie.uname).unwrap().len(), stored);
+    }
+
+    #[test]
+    fn fans_group_invites_and_messages_out_to_online_and_offline_members() {
+        let server = TestServer::start();
+        let harry = server.sign_up("harry");
+        let eddie = server.sign_up("eddie");
+        let kerry = server.sign_up("kerry");
+        server.connect_users(&harry, &eddie);
+        server.connect_users(&harry, &kerry);
+        let mut harry_client = server.log_in(&harry);
+        let mut eddie_client = server.log_in(&eddie);
+        // answered once eddie has been sent what was kept for them (nothing yet)
+        eddie_client.send(&IdentityReq::new(&harry.uname));
+        assert!(matches!(eddie_client.recv(), ProtocolMessage::IdentityResp(_)));
+
+        let group_create = GroupCreate::new("tuesday club").unwrap();
+        let group_id = group_create.group_id();
+        harry_client.send(&group_create);
+        harry_client.expect_group_info(group_id, &[&harry.uname]);
+
+        // eddie is online, so gets the invite straight away
+        harry_client.send(&GroupInvite::new(group_id, &harry.uname, &eddie.uname, b"sealed group key".to_vec()));
+        match eddie_client.recv() {
+            ProtocolMessage::GroupInvite(group_invite) => assert_eq!(group_invite.group_id(), group_id),
+            message => panic!("expected GroupInvite, got {:?}", message.message_type()),
+        }
+        eddie_client.expect_group_info(group_id, &[&harry.uname, &eddie.uname]);
+        harry_client.expect_group_info(group_id, &[&harry.uname, &eddie.uname]);
+
+        // kerry is offline, so their invite is kept for them
+        harry_client.send(&GroupInvite::new(group_id, &harry.uname, &kerry.uname, b"sealed group key".to_vec()));
+        let members = [&harry.uname, &eddie.uname, &kerry.uname];
+        harry_client.expect_group_info(group_id, &members);
+        eddie_client.expect_group_info(group_id, &members);
+
+        let group_message = sealed_group_message(&harry, group_id);
+        harry_client.send(&group_message);
+        match eddie_client.recv() {
+            ProtocolMessage::GroupMessage(received) => assert_eq!(received.message_id(), group_message.message_id()),
+            message => panic!("expected GroupMessage, got {:?}", message.message_type()),
+        }
+        harry_client.expect_nothing();
+
+        // ...and gets both, then where the group stands, once they verify
+        let mut kerry_client = server.log_in(&kerry);
+        match kerry_client.recv() {
+            ProtocolMessage::GroupInvite(group_invite) => assert_eq!(group_invite.inviter(), &harry.uname),
+            message => panic!("expected GroupInvite, got {:?}", message.message_type()),
+        }
+        match kerry_client.recv() {
+            ProtocolMessage::GroupMessage(received) => assert_eq!(received.message_id(), group_message.message_id()),
+            message => panic!("expected GroupMessage, got {:?}", message.message_type()),
+        }
+        kerry_client.expect_group_info(group_id, &members);
+        drop(kerry_client);
+
+        // only once
+        let mut kerry_client = server.log_in(&kerry);
+        kerry_client.expect_group_info(group_id, &members);
+        kerry_client.expect_nothing();
+
+        let group = server.restarted_state().group(&group_id).expect("group wasn't kept");
+        assert_eq!(group.name, "tuesday club");
+        assert_eq!(group.members.iter().collect::<Vec<_>>(), members);
+    }
+
+    #[test]
+    fn refuses_bad_proofs_and_replayed_challenges() {
+        let server = TestServer::start();
+        let harry = server.sign_up("harry");
+        let eddie = server.sign_up("eddie");
+
+        // a proof from someone else's token
+        let mut harry_client = server.connect(TEST_CAPABILITIES);
+        assert!(!harry_client.verify(&harry.uname, &eddie.token));
+
+        // a proof signed over the challenge, then altered
+        let mut proof = auth::prove(&harry.token, &harry_client.challenge, &harry.uname);
+        proof[0] ^= 1;
+        harry_client.send(&VerifyReq::new(&harry.uname, proof));
+        assert!(!harry_client.expect_verify_resp());
+
+        // a good proof, which can't then be used again, on this session or another
+        let proof = auth::prove(&harry.token, &harry_client.challenge, &harry.uname);
+        harry_client.send(&VerifyReq::new(&harry.uname, proof));
+        assert!(harry_client.expect_verify_resp());
+        harry_client.send(&VerifyReq::new(&harry.uname, proof));
+        assert!(!harry_client.expect_verify_resp());
+
+        let mut replay_client = server.connect(TEST_CAPABILITIES);
+        replay_client.send(&VerifyReq::new(&harry.uname, proof));
+        assert!(!replay_client.expect_verify_resp());
+
+        // the fresh challenge that follows a failure can still be answered
+        assert!(replay_client.verify(&harry.uname, &harry.token));
+    }
+}
@@ -0,0 +1,589 @@
+/*
+State shared between all client sessions
+*/
+
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Sender};
+
+use protocol::{ChatMessage, HistoryCursor, Packet, Username};
+use protocol::{auth, shared};
+use protocol::TokenInfo;
+use protocol::{Presence, PresenceState};
+use protocol::field_lens::{CHALLENGE_LEN, VERIFIER_LEN, PROOF_LEN, IDENTITY_KEY_LEN, TOKEN_ID_LEN, GROUP_ID_LEN};
+use protocol::field_lens::MESSAGE_ID_LEN;
+
+use crate::accounts::{Account, AccountStore, Group, Token};
+use crate::inbox::Inbox;
+use crate::history::History;
+
+// most tokens an account can have active at once
+pub const MAX_TOKENS: usize = 16;
+
+// most members a group can have
+pub const MAX_GROUP_MEMBERS: usize = 32;
+
+/**
+What became of a chat message passed on towards its recipient
+*/
+pub enum Delivery {
+    // queued for the recipient's session
+    Sent,
+
+    // recipient is offline, so it's in their inbox for when they next verify
+    Stored,
+
+    // recipient is offline and their inbox is full
+    InboxFull,
+}
+
+pub struct ServerState {
+    accounts: Mutex<HashMap<Username, Account>>,
+
+    // accounts, connections and blocks as kept on disk; each is written while
+    // holding the lock on its in-memory copy, and before that copy is changed
+    account_store: AccountStore,
+
+    // username -> (session id, outbound packet queue) of that user's verified session
+    sessions: Mutex<HashMap<Username, (u64, Sender<Packet>)>>,
+
+    next_session_id: AtomicU64,
+
+    // mutually connected users, stored as (lesser uname, greater uname)
+    connections: Mutex<HashSet<(Username, Username)>>,
+
+    // (requester, requestee) of connection requests awaiting a response
+    conn_reqs: Mutex<HashSet<(Username, Username)>>,
+
+    // (blocker, blocked)
+    blocks: Mutex<HashSet<(Username, Username)>>,
+
+    // kept on disk by the account store, in the same way as accounts
+    groups: Mutex<HashMap<[u8; GROUP_ID_LEN], Group>>,
+
+    // presence state and custom status of each user with a verified session
+    presence: Mutex<HashMap<Username, (PresenceState, String)>>,
+
+    // chat messages stored for offline users, kept on disk
+    inbox: Mutex<Inbox>,
+
+    // every chat message accepted, kept on disk
+    history: Mutex<History>,
+}
+
+impl ServerState {
+    /**
+    Server state with the accounts, connections, blocks and groups kept in
+    accounts_dir
+    */
+    pub fn new(accounts_dir: PathBuf, inbox_dir: PathBuf, history_dir: PathBuf) -> io::Result<Self> {
+        let account_store = AccountStore::new(accounts_dir);
+        Ok (ServerState {
+            accounts: Mutex::new(account_store.accounts()?),
+            connections: Mutex::new(account_store.connections()?),
+            blocks: Mutex::new(account_store.blocks()?),
+            groups: Mutex::new(account_store.groups()?),
+            account_store,
+            sessions: Mutex::new(HashMap::new()),
+            next_session_id: AtomicU64::new(0),
+            conn_reqs: Mutex::new(HashSet::new()),
+            presence: Mutex::new(HashMap::new()),
+            inbox: Mutex::new(Inbox::new(inbox_dir)),
+            history: Mutex::new(History::new(history_dir)),
+        })
+    }
+
+    pub fn next_session_id(&self) -> u64 {
+        self.next_session_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /**
+    Creates a new account with the given identity key and first token (known
+    by its id and verifier, see protocol::auth).
+
+    Returns false if the username is already taken.
+    */
+    pub fn add_account(
+        &self,
+        uname: &Username,
+        token_id: [u8; TOKEN_ID_LEN],
+        verifier: [u8; VERIFIER_LEN],
+        identity_key: [u8; IDENTITY_KEY_LEN]
+    ) -> io::Result<bool> {
+        let mut accounts = self.accounts.lock().unwrap();
+        if accounts.contains_key(uname) {
+            return Ok(false);
+        }
+        let token = Token { token_id, verifier, issued_at: shared::timestamp_now() };
+        let account = Account { tokens: vec![token], identity_key };
+        self.account_store.save_account(uname, &account)?;
+        accounts.insert(uname.clone(), account);
+
+        Ok(true)
+    }
+
+    /**
+    Checks a client's answer to challenge against each of uname's active
+    tokens, i.e. that the client holds one of them.
+
+    Returns the id of the token the client proved it holds, if any.
+    */
+    pub fn verify_account(
+        &self,
+        uname: &Username,
+        challenge: &[u8; CHALLENGE_LEN],
+        proof: &[u8; PROOF_LEN]
+    ) -> Option<[u8; TOKEN_ID_LEN]> {
+        let accounts = self.accounts.lock().unwrap();
+        accounts.get(uname)?
+            .tokens
+            .iter()
+            .find(|token| auth::check_proof(&token.verifier, challenge, uname, proof))
+            .map(|token| token.token_id)
+    }
+
+    /**
+    Adds a newly issued token to uname's account, in place of the replacing
+    token if given (so a rotation either happens entirely or not at all).
+
+    Returns false if there is no such account, the token to replace has been
+    revoked, or the account already has MAX_TOKENS.
+    */
+    pub fn issue_token(
+        &self,
+        uname: &Username,
+        token_id: [u8; TOKEN_ID_LEN],
+        verifier: [u8; VERIFIER_LEN],
+        replacing: Option<&[u8; TOKEN_ID_LEN]>
+    ) -> io::Result<bool> {
+        let mut accounts = self.accounts.lock().unwrap();
+        let Some(account) = accounts.get_mut(uname) else {
+            return Ok(false);
+        };
+
+        let replaced_pos = match replacing {
+            Some(replacing) => match account.tokens.iter().position(|token| token.token_id == *replacing) {
+                Some(pos) => Some(pos),
+                None => return Ok(false),
+            },
+            None => None,
+        };
+        if replaced_pos.is_none() && account.tokens.len() >= MAX_TOKENS {
+            return Ok(false);
+        }
+
+        let mut updated = account.clone();
+        if let Some(pos) = replaced_pos {
+            updated.tokens.remove(pos);
+        }
+        updated.tokens.push(Token { token_id, verifier, issued_at: shared::timestamp_now() });
+        self.account_store.save_account(uname, &updated)?;
+        *account = updated;
+
+        Ok(true)
+    }
+
+    /**
+    Revokes one of uname's tokens, so it fails its next verification.
+
+    Returns false if uname has no such token.
+    */
+    pub fn revoke_token(&self, uname: &Username, token_id: &[u8; TOKEN_ID_LEN]) -> io::Result<bool> {
+        let mut accounts = self.accounts.lock().unwrap();
+        let Some(account) = accounts.get_mut(uname) else {
+            return Ok(false);
+        };
+
+        let mut updated = account.clone();
+        updated.tokens.retain(|token| token.token_id != *token_id);
+        if updated.tokens.len() == account.tokens.len() {
+            return Ok(false);
+        }
+        self.account_store.save_account(uname, &updated)?;
+        *account = updated;
+
+        Ok(true)
+    }
+
+    /**
+    uname's active tokens, oldest first, with current_id's marked as current
+    */
+    pub fn tokens(&self, uname: &Username, current_id: Option<&[u8; TOKEN_ID_LEN]>) -> Vec<TokenInfo> {
+        match self.accounts.lock().unwrap().get(uname) {
+            Some(account) => account.tokens
+                .iter()
+                .map(|token| TokenInfo {
+                    token_id: token.token_id,
+                    issued_at: token.issued_at,
+                    current: current_id == Some(&token.token_id),
+                })
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    pub fn account_exists(&self, uname: &Username) -> bool {
+        self.accounts.lock().unwrap().contains_key(uname)
+    }
+
+    /**
+    The identity key uname published at signup, if they have an account
+    */
+    pub fn identity_key(&self, uname: &Username) -> Option<[u8; IDENTITY_KEY_LEN]> {
+        self.accounts.lock().unwrap().get(uname).map(|account| account.identity_key)
+    }
+
+    /**
+    Registers a verified session so packets can be routed to it, and marks
+    uname as online with no custom status.
+
+    Replaces any previous session for the same user.
+    */
+    pub fn add_session(&self, uname: &Username, session_id: u64, outbound: Sender<Packet>) {
+        self.sessions.lock().unwrap().insert(uname.clone(), (session_id, outbound));
+        self.presence.lock().unwrap().insert(uname.clone(), (PresenceState::Online, String::new()));
+    }
+
+    /**
+    Deregisters uname's session (and so their presence), unless it has since
+    been replaced by a newer one.
+
+    Returns false if the session had been replaced.
+    */
+    pub fn remove_session(&self, uname: &Username, session_id: u64) -> bool {
+        let mut sessions = self.sessions.lock().unwrap();
+        match sessions.get(uname) {
+            Some((id, _)) if *id == session_id => {
+                sessions.remove(uname);
+                self.presence.lock().unwrap().remove(uname);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /**
+    Sets the presence of a user with a verified session
+    */
+    pub fn set_presence(&self, uname: &Username, state: PresenceState, status: &str) {
+        self.presence.lock().unwrap().insert(uname.clone(), (state, status.to_string()));
+    }
+
+    /**
+    uname's presence as their connections should see it (Offline, with no
+    status, if they have no session or have hidden themselves)
+    */
+    pub fn presence(&self, uname: &Username) -> Presence {
+        match self.presence.lock().unwrap().get(uname) {
+            Some((PresenceState::Offline, _)) | None => Presence::offline(uname),
+            // statuses are validated before they're stored
+            Some((state, status)) => Presence::new(uname, *state, status).unwrap_or_else(|_| Presence::offline(uname)),
+        }
+    }
+
+    /**
+    Sends uname's presence to each of their online connections
+    */
+    pub fn broadcast_presence(&self, uname: &Username) {
+        let presence = self.presence(uname);
+        for conn in self.connections_of(uname) {
+            self.send_to(&conn, Packet::wrap(&presence));
+        }
+    }
+
+    /**
+    Queues a packet for delivery to uname's session.
+
+    Returns false if uname is not online.
+    */
+    pub fn send_to(&self, uname: &Username, packet: Packet) -> bool {
+        let mut sessions = self.sessions.lock().unwrap();
+        match sessions.get(uname) {
+            Some((_, outbound)) => {
+                if outbound.send(packet).is_ok() {
+                    return true;
+                }
+                // session thread has gone away without deregistering
+                sessions.remove(uname);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /**
+    Queues a chat message for delivery to its recipient's session or, if they
+    aren't online, stores it in their inbox.
+
+    Messages are stored while holding the sessions lock, so none can be stored
+    after the recipient's session has been added (and so missed until the next
+    time they verify).
+    */
+    pub fn send_or_store(&self, chat_message: &ChatMessage) -> io::Result<Delivery> {
+        let recv_uname = &chat_message.recv_uname;
+        let mut sessions = self.sessions.lock().unwrap();
+        if let Some((_, outbound)) = sessions.get(recv_uname) {
+            if outbound.send(Packet::wrap(chat_message)).is_ok() {
+                return Ok(Delivery::Sent);
+            }
+            // session thread has gone away without deregistering
+            sessions.remove(recv_uname);
+        }
+
+        match self.inbox.lock().unwrap().store(chat_message)? {
+            true => Ok(Delivery::Stored),
+            false => Ok(Delivery::InboxFull),
+        }
+    }
+
+    /**
+    Queues a group invite or message (as a packet) for delivery to uname's
+    session or, if they aren't online, stores it in their inbox.
+
+    Stored while holding the sessions lock, as in send_or_store.
+    */
+    pub fn send_or_store_group(&self, uname: &Username, packet: Packet) -> io::Result<Delivery> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let packet = match sessions.get(uname) {
+            Some((_, outbound)) => match outbound.send(packet) {
+                Ok(()) => return Ok(Delivery::Sent),
+                Err(mpsc::SendError(packet)) => {
+                    // session thread has gone away without deregistering
+                    sessions.remove(uname);
+                    packet
+                }
+            }
+            None => packet,
+        };
+
+        match self.inbox.lock().unwrap().store_group_packet(uname, &packet)? {
+            true => Ok(Delivery::Stored),
+            false => Ok(Delivery::InboxFull),
+        }
+    }
+
+    /**
+    Removes and returns the group invites and messages stored for uname while
+    they were offline, oldest first
+    */
+    pub fn take_stored_group_packets(&self, uname: &Username) -> io::Result<Vec<Packet>> {
+        self.inbox.lock().unwrap().take_group_packets(uname)
+    }
+
+    /**
+    The chat messages stored for uname while they were offline, oldest first
+    */
+    pub fn stored_messages(&self, uname: &Username) -> io::Result<Vec<ChatMessage>> {
+        self.inbox.lock().unwrap().messages(uname)
+    }
+
+    /**
+    Deletes a chat message uname has acknowledged from their inbox.
+
+    Returns false if it wasn't there (e.g. it was sent to them while online).
+    */
+    pub fn remove_stored(&self, uname: &Username, message_id: [u8; MESSAGE_ID_LEN]) -> io::Result<bool> {
+        self.inbox.lock().unwrap().remove(uname, message_id)
+    }
+
+    /**
+    Keeps an accepted chat message in its conversation's history
+    */
+    pub fn add_to_history(&self, chat_message: &ChatMessage) -> io::Result<()> {
+        self.history.lock().unwrap().append(chat_message)
+    }
+
+    /**
+    Up to limit of the chat messages between uname1 and uname2 after cursor,
+    oldest first, and whether there are more after them.
+
+    Returns None if the cursor is after a message the server doesn't have.
+    */
+    pub fn history_after(
+        &self,
+        uname1: &Username,
+        uname2: &Username,
+        cursor: HistoryCursor,
+        limit: usize
+    ) -> io::Result<Option<(Vec<ChatMessage>, bool)>> {
+        self.history.lock().unwrap().messages_after(uname1, uname2, cursor, limit)
+    }
+
+    /**
+    Who sent the chat message with the given id between uname1 and uname2, if
+    it's kept in their history
+    */
+    pub fn message_sender(
+        &self,
+        uname1: &Username,
+        uname2: &Username,
+        message_id: [u8; MESSAGE_ID_LEN]
+    ) -> io::Result<Option<Username>> {
+        self.history.lock().unwrap().sender(uname1, uname2, message_id)
+    }
+
+    pub fn are_connected(&self, uname1: &Username, uname2: &Username) -> bool {
+        self.connections.lock().unwrap().contains(&conn_key(uname1, uname2))
+    }
+
+    pub fn add_connection(&self, uname1: &Username, uname2: &Username) -> io::Result<()> {
+        let mut connections = self.connections.lock().unwrap();
+        let conn = conn_key(uname1, uname2);
+        if connections.contains(&conn) {
+            return Ok(());
+        }
+        self.account_store.add_connection(&conn)?;
+        connections.insert(conn);
+
+        Ok(())
+    }
+
+    /**
+    Everyone uname is mutually connected with
+    */
+    pub fn connections_of(&self, uname: &Username) -> Vec<Username> {
+        self.connections.lock().unwrap()
+            .iter()
+            .filter_map(|(uname1, uname2)| {
+                if uname1 == uname {
+                    Some(uname2.clone())
+                } else if uname2 == uname {
+                    Some(uname1.clone())
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /**
+    Records that req_uname has asked to connect with resp_uname
+    */
+    pub fn add_conn_req(&self, req_uname: &Username, resp_uname: &Username) {
+        self.conn_reqs.lock().unwrap().insert((req_uname.clone(), resp_uname.clone()));
+    }
+
+    /**
+    Removes a pending connection request.
+
+    Returns false if no such request was pending.
+    */
+    pub fn take_conn_req(&self, req_uname: &Username, resp_uname: &Username) -> bool {
+        self.conn_reqs.lock().unwrap().remove(&(req_uname.clone(), resp_uname.clone()))
+    }
+
+    pub fn add_block(&self, blocker: &Username, blocked: &Username) -> io::Result<()> {
+        let mut blocks = self.blocks.lock().unwrap();
+        let block = (blocker.clone(), blocked.clone());
+        if blocks.contains(&block) {
+            return Ok(());
+        }
+        self.account_store.add_block(&block)?;
+        blocks.insert(block);
+
+        Ok(())
+    }
+
+    pub fn is_blocked(&self, blocker: &Username, blocked: &Username) -> bool {
+        self.blocks.lock().unwrap().contains(&(blocker.clone(), blocked.clone()))
+    }
+
+    /**
+    Creates a group with creator as its only member.
+
+    Returns false if the group id is already taken.
+    */
+    pub fn create_group(&self, group_id: [u8; GROUP_ID_LEN], name: &str, creator: &Username) -> io::Result<bool> {
+        let created = self.update_groups(|groups| {
+            if groups.contains_key(&group_id) {
+                return None;
+            }
+            groups.insert(group_id, Group { name: name.to_string(), members: vec![creator.clone()] });
+            Some(())
+        })?;
+
+        Ok(created.is_some())
+    }
+
+    pub fn group(&self, group_id: &[u8; GROUP_ID_LEN]) -> Option<Group> {
+        self.groups.lock().unwrap().get(group_id).cloned()
+    }
+
+    /**
+    The groups uname is a member of, by id
+    */
+    pub fn groups_of(&self, uname: &Username) -> Vec<([u8; GROUP_ID_LEN], Group)> {
+        self.groups.lock().unwrap()
+            .iter()
+            .filter(|(_, group)| group.is_member(uname))
+            .map(|(group_id, group)| (*group_id, group.clone()))
+            .collect()
+    }
+
+    /**
+    Adds uname to a group, returning the group as it now stands.
+
+    Returns None if there is no such group, uname is already a member, or the
+    group is full.
+    */
+    pub fn add_group_member(&self, group_id: &[u8; GROUP_ID_LEN], uname: &Username) -> io::Result<Option<Group>> {
+        self.update_groups(|groups| {
+            let group = groups.get_mut(group_id)?;
+            if group.is_member(uname) || group.members.len() >= MAX_GROUP_MEMBERS {
+                return None;
+            }
+            group.members.push(uname.clone());
+
+            Some(group.clone())
+        })
+    }
+
+    /**
+    Removes uname from a group, deleting the group if they were the last
+    member, and returns the group as it now stands.
+
+    Returns None if uname wasn't a member of such a group.
+    */
+    pub fn remove_group_member(&self, group_id: &[u8; GROUP_ID_LEN], uname: &Username) -> io::Result<Option<Group>> {
+        self.update_groups(|groups| {
+            let group = groups.get_mut(group_id)?;
+            let pos = group.members.iter().position(|member| member == uname)?;
+            group.members.remove(pos);
+
+            let group = group.clone();
+            if group.members.is_empty() {
+                groups.remove(group_id);
+            }
+            Some(group)
+        })
+    }
+
+    // Makes a change to the groups, which is kept (and, like changes to accounts,
+    // written before it's made in memory) unless change returns None
+    fn update_groups<T>(
+        &self,
+        change: impl FnOnce(&mut HashMap<[u8; GROUP_ID_LEN], Group>) -> Option<T>
+    ) -> io::Result<Option<T>> {
+        let mut groups = self.groups.lock().unwrap();
+        let mut updated = groups.clone();
+        let Some(changed) = change(&mut updated) else {
+            return Ok(None);
+        };
+
+        self.account_store.save_groups(&updated)?;
+        *groups = updated;
+        Ok(Some(changed))
+    }
+}
+
+// Connections are symmetric, so always key them in the same order
+fn conn_key(uname1: &Username, uname2: &Username) -> (Username, Username) {
+    if uname1 <= uname2 {
+        (uname1.clone(), uname2.clone())
+    } else {
+        (uname2.clone(), uname1.clone())
+    }
+}